@@ -0,0 +1,98 @@
+//! Frame size and CPU cost of each wire encoding
+//!
+//!   cargo bench -p pulse-protocol
+//!
+//! Frame sizes are printed before the timings.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use pulse_protocol::{Encoding, WsMessage};
+
+const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::MessagePack, Encoding::Cbor];
+
+fn chat(content: String) -> WsMessage {
+    WsMessage::ChatMessage {
+        id: "4f5c2a1e-8d3b-4c6f-9a7e-2b1d0c9e8f7a".to_string(),
+        chat_id: "9c8b7a6f-5e4d-4c3b-2a1f-0e9d8c7b6a5f".to_string(),
+        sender_id: "1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5d".to_string(),
+        sender_name: "Alice".to_string(),
+        recipient_id: "5d4c3b2a-1f0e-4d9c-8b7a-6f5e4d3c2b1a".to_string(),
+        content,
+        timestamp: 1_700_000_000_000,
+        reply_to_id: None,
+        url_preview: None,
+    }
+}
+
+/// An `enc:` payload the way the desktop client writes it
+fn encrypted(plaintext_len: usize) -> String {
+    let payload = serde_json::json!({
+        // AES-GCM adds a 16-byte tag
+        "ciphertext": (0..plaintext_len + 16).map(|i| (i * 31 % 256) as u8).collect::<Vec<_>>(),
+        "nonce": (0..12u8).collect::<Vec<_>>(),
+        "sender_public_key": (0..32u8).map(|i| i.wrapping_mul(7)).collect::<Vec<_>>(),
+    });
+    format!("enc:{}", payload)
+}
+
+fn samples() -> Vec<(&'static str, WsMessage)> {
+    vec![
+        ("plain_chat", chat("See you at 7, I'll bring the tickets".to_string())),
+        ("encrypted_chat_64", chat(encrypted(64))),
+        ("encrypted_chat_4k", chat(encrypted(4096))),
+        (
+            "typing",
+            serde_json::from_str(
+                r#"{"type":"typing","chat_id":"9c8b7a6f-5e4d-4c3b-2a1f-0e9d8c7b6a5f","user_id":"1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5d","is_typing":true}"#,
+            )
+            .unwrap(),
+        ),
+    ]
+}
+
+fn print_sizes(samples: &[(&str, WsMessage)]) {
+    println!(
+        "{:<20} {:>10} {:>12} {:>10}",
+        "frame", "json", "messagepack", "cbor"
+    );
+    for (name, msg) in samples {
+        let sizes: Vec<usize> = ENCODINGS
+            .iter()
+            .map(|encoding| encoding.encode(msg).unwrap().len())
+            .collect();
+        println!(
+            "{:<20} {:>10} {:>12} {:>10}",
+            name, sizes[0], sizes[1], sizes[2]
+        );
+    }
+}
+
+fn bench_encodings(c: &mut Criterion) {
+    let samples = samples();
+    print_sizes(&samples);
+
+    let mut encode = c.benchmark_group("encode");
+    for (name, msg) in &samples {
+        for encoding in ENCODINGS {
+            let id = BenchmarkId::new(format!("{:?}", encoding), name);
+            encode.bench_with_input(id, msg, |b, msg| {
+                b.iter(|| encoding.encode(black_box(msg)).unwrap())
+            });
+        }
+    }
+    encode.finish();
+
+    let mut decode = c.benchmark_group("decode");
+    for (name, msg) in &samples {
+        for encoding in ENCODINGS {
+            let bytes = encoding.encode(msg).unwrap();
+            let id = BenchmarkId::new(format!("{:?}", encoding), name);
+            decode.bench_with_input(id, &bytes, |b, bytes| {
+                b.iter(|| encoding.decode(black_box(bytes)).unwrap())
+            });
+        }
+    }
+    decode.finish();
+}
+
+criterion_group!(benches, bench_encodings);
+criterion_main!(benches);
@@ -0,0 +1,24 @@
+//! Write the protocol JSON Schema used by the TypeScript frontend.
+//!
+//!   cargo run -p pulse-protocol --bin export-schema [output path]
+//!
+//! Defaults to `src/types/protocol.schema.json` at the workspace root.
+
+use std::path::{Path, PathBuf};
+
+fn main() {
+    let path = std::env::args_os()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            let workspace = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+            workspace.join("src/types/protocol.schema.json")
+        });
+
+    let schema = serde_json::to_string_pretty(&pulse_protocol::json_schema()).unwrap();
+    if let Err(e) = std::fs::write(&path, schema + "\n") {
+        eprintln!("Failed to write {}: {}", path.display(), e);
+        std::process::exit(1);
+    }
+    println!("Wrote {}", path.display());
+}
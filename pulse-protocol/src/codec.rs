@@ -0,0 +1,248 @@
+//! Frame encodings.
+//!
+//! Frames are JSON text unless a connection negotiated a binary encoding
+//! (MessagePack or CBOR). Binary encodings also carry end-to-end encrypted chat
+//! content (`enc:` followed by a JSON object of byte arrays) as raw bytes
+//! instead of JSON number arrays; JSON frames are unchanged.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::messages::WsMessage;
+use crate::version::Capability;
+
+/// How frames are encoded on a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// JSON text frames (every peer understands these)
+    #[default]
+    Json,
+    /// MessagePack binary frames
+    MessagePack,
+    /// CBOR binary frames
+    Cbor,
+}
+
+impl Encoding {
+    /// Encoding for a negotiated capability set, preferring MessagePack over CBOR
+    pub fn negotiated(capabilities: &[Capability]) -> Self {
+        if capabilities.contains(&Capability::Msgpack) {
+            Self::MessagePack
+        } else if capabilities.contains(&Capability::Cbor) {
+            Self::Cbor
+        } else {
+            Self::Json
+        }
+    }
+
+    /// Whether frames go out as WebSocket binary messages
+    pub fn is_binary(self) -> bool {
+        self != Self::Json
+    }
+
+    pub fn encode(self, msg: &WsMessage) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Json => serde_json::to_vec(msg).map_err(|e| e.to_string()),
+            Self::MessagePack => rmp_serde::to_vec_named(msg).map_err(|e| e.to_string()),
+            Self::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(msg, &mut bytes).map_err(|e| e.to_string())?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Result<WsMessage, String> {
+        match self {
+            Self::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Self::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            Self::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// `serde(with)` adapter for chat message content
+pub(crate) mod content {
+    use super::*;
+
+    const ENCRYPTED_PREFIX: &str = "enc:";
+
+    /// The JSON object behind the `enc:` prefix, as the desktop client writes it
+    #[derive(Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct EncryptedJson {
+        ciphertext: Vec<u8>,
+        nonce: Vec<u8>,
+        sender_public_key: Vec<u8>,
+    }
+
+    /// The same payload with its byte fields as binary strings
+    #[derive(Serialize, Deserialize)]
+    struct EncryptedBytes {
+        #[serde(with = "serde_bytes")]
+        ciphertext: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        sender_public_key: Vec<u8>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum WireContent {
+        Text(String),
+        Encrypted(EncryptedBytes),
+    }
+
+    /// The encrypted payload of `content`, if rebuilding it from the bytes gives back the same text
+    fn encrypted_payload(content: &str) -> Option<EncryptedJson> {
+        let json = content.strip_prefix(ENCRYPTED_PREFIX)?;
+        let payload: EncryptedJson = serde_json::from_str(json).ok()?;
+        (serde_json::to_string(&payload).ok()? == json).then_some(payload)
+    }
+
+    pub fn serialize<S: Serializer>(content: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return serializer.serialize_str(content);
+        }
+        match encrypted_payload(content) {
+            Some(payload) => EncryptedBytes {
+                ciphertext: payload.ciphertext,
+                nonce: payload.nonce,
+                sender_public_key: payload.sender_public_key,
+            }
+            .serialize(serializer),
+            None => serializer.serialize_str(content),
+        }
+    }
+
+    // Frames are internally tagged, so fields are read back from serde's buffered
+    // content, which always claims to be human-readable; accept either form.
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        match WireContent::deserialize(deserializer)? {
+            WireContent::Text(text) => Ok(text),
+            WireContent::Encrypted(bytes) => {
+                let payload = EncryptedJson {
+                    ciphertext: bytes.ciphertext,
+                    nonce: bytes.nonce,
+                    sender_public_key: bytes.sender_public_key,
+                };
+                let json = serde_json::to_string(&payload).map_err(serde::de::Error::custom)?;
+                Ok(format!("{}{}", ENCRYPTED_PREFIX, json))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BINARY: [Encoding; 2] = [Encoding::MessagePack, Encoding::Cbor];
+
+    fn chat(content: String) -> WsMessage {
+        WsMessage::ChatMessage {
+            id: "msg-1".to_string(),
+            chat_id: "chat-1".to_string(),
+            sender_id: "alice".to_string(),
+            sender_name: "Alice".to_string(),
+            recipient_id: "bob".to_string(),
+            content,
+            timestamp: 1_700_000_000_000,
+            reply_to_id: None,
+            url_preview: None,
+        }
+    }
+
+    fn encrypted_content() -> String {
+        let payload = serde_json::json!({
+            "ciphertext": (0..=255u8).cycle().take(512).collect::<Vec<_>>(),
+            "nonce": vec![7u8; 12],
+            "sender_public_key": vec![9u8; 32],
+        });
+        format!("enc:{}", payload)
+    }
+
+    fn content_of(msg: &WsMessage) -> &str {
+        match msg {
+            WsMessage::ChatMessage { content, .. } => content,
+            other => panic!("Expected ChatMessage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_negotiated_encoding_prefers_msgpack() {
+        assert_eq!(Encoding::negotiated(&[Capability::Acks]), Encoding::Json);
+        assert_eq!(Encoding::negotiated(&[Capability::Cbor]), Encoding::Cbor);
+        assert_eq!(
+            Encoding::negotiated(&[Capability::Cbor, Capability::Msgpack]),
+            Encoding::MessagePack
+        );
+    }
+
+    #[test]
+    fn test_encrypted_content_round_trips_exactly() {
+        for encoding in BINARY {
+            let msg = chat(encrypted_content());
+            let decoded = encoding.decode(&encoding.encode(&msg).unwrap()).unwrap();
+            assert_eq!(content_of(&decoded), encrypted_content(), "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn test_binary_carries_ciphertext_as_bytes() {
+        let msg = chat(encrypted_content());
+        let json = Encoding::Json.encode(&msg).unwrap();
+        for encoding in BINARY {
+            let binary = encoding.encode(&msg).unwrap();
+            // As a JSON number array each ciphertext byte costs up to four characters
+            assert!(binary.len() < 800, "{:?}: {} bytes", encoding, binary.len());
+            assert!(binary.len() * 2 < json.len(), "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn test_plain_and_irregular_content_kept_as_text() {
+        // Reordered keys would not come back byte-for-byte, so they stay text
+        let reordered = r#"enc:{"nonce":[1],"ciphertext":[2],"sender_public_key":[3]}"#;
+        for content in ["hello", "enc:not json", reordered] {
+            for encoding in BINARY {
+                let msg = chat(content.to_string());
+                let decoded = encoding.decode(&encoding.encode(&msg).unwrap()).unwrap();
+                assert_eq!(content_of(&decoded), content);
+            }
+        }
+    }
+
+    #[test]
+    fn test_json_encoding_unchanged() {
+        let msg = chat(encrypted_content());
+        let json = Encoding::Json.encode(&msg).unwrap();
+        assert_eq!(json, serde_json::to_vec(&msg).unwrap());
+        assert!(String::from_utf8(json)
+            .unwrap()
+            .contains(r#""content":"enc:{"#));
+    }
+
+    #[test]
+    fn test_every_frame_type_survives_binary() {
+        let frames = [
+            r#"{"type":"connect","user_id":"alice","protocol_version":2,"capabilities":["acks","msgpack"]}"#,
+            r#"{"type":"presence","user_id":"alice","is_online":false,"last_seen":null}"#,
+            r#"{"type":"server_ack","message_id":"m1","status":"queued"}"#,
+            r#"{"type":"subscribe_presence","user_ids":["bob"],"chats":[{"chat_id":"c1","participant_ids":["bob"]}]}"#,
+            r#"{"type":"rtc_offer","call_id":"c","from_user_id":"a","to_user_id":"b","sdp":"v=0"}"#,
+        ];
+        for frame in frames {
+            let msg: WsMessage = serde_json::from_str(frame).unwrap();
+            for encoding in BINARY {
+                let decoded = encoding.decode(&encoding.encode(&msg).unwrap()).unwrap();
+                assert_eq!(
+                    serde_json::to_value(&decoded).unwrap(),
+                    serde_json::to_value(&msg).unwrap(),
+                    "{:?}",
+                    encoding
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Pulse wire protocol
+//!
+//! The frames exchanged between the desktop client and the relay server, how
+//! they are encoded, the protocol version and capabilities negotiated in
+//! `Connect`, and the checks both ends apply to frames before trusting them.
+
+mod codec;
+mod messages;
+mod schema;
+mod validate;
+mod version;
+
+pub use codec::Encoding;
+pub use messages::{
+    AckStatus, AuthErrorCode, DeliveryFailureReason, ErrorCode, WsChatParticipants, WsMessage,
+    WsUrlPreview,
+};
+pub use schema::json_schema;
+pub use validate::{decode_key, is_valid_device_id, MAX_DEVICE_ID_LEN};
+pub use version::{
+    min_version_for, Capability, LEGACY_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
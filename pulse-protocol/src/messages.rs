@@ -0,0 +1,771 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::version::Capability;
+
+/// URL preview data for WebSocket messages
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WsUrlPreview {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site_name: Option<String>,
+}
+
+/// Participants of a chat, declared by a client so typing indicators can be routed
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WsChatParticipants {
+    pub chat_id: String,
+    pub participant_ids: Vec<String>,
+}
+
+/// Why a queued message was given up on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryFailureReason {
+    /// The recipient did not come online before the queue TTL ran out
+    Expired,
+    /// Evicted to stay within the recipient's message or byte quota
+    QueueFull,
+    /// Dropped by an operator purging the recipient's queue
+    Purged,
+}
+
+/// What the relay did with a chat message, reported back to its sender
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AckStatus {
+    /// Handed to at least one live connection of the recipient
+    Delivered,
+    /// Stored in the recipient's offline queue
+    Queued,
+    /// Not accepted by the relay (see `reason`)
+    Error,
+}
+
+/// Reason an authentication attempt was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthErrorCode {
+    /// An access token is configured and the client sent none
+    MissingToken,
+    /// The client's access token does not match the configured one
+    InvalidToken,
+    /// The offered public key is not a 32-byte hex-encoded X25519 key
+    InvalidKey,
+    /// The user_id is already bound to a different public key
+    KeyMismatch,
+    /// The challenge proof did not verify against the bound public key
+    InvalidProof,
+    /// The server requires an identity key and the client offered none
+    IdentityRequired,
+    /// The client's protocol version is older than the server still accepts
+    UnsupportedVersion,
+    /// The device_id is empty, too long or has characters outside `[A-Za-z0-9_-]`
+    InvalidDevice,
+    /// The account already has the maximum number of devices registered
+    TooManyDevices,
+    /// The user has been banned by the server operator
+    Banned,
+    /// A code added by a newer server
+    #[serde(other)]
+    Unknown,
+}
+
+/// Why the server rejected a frame, in an `error` reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame is not valid JSON (or the negotiated binary encoding), or does not
+    /// match its type
+    InvalidFrame,
+    /// The frame's `type` is not one this server knows
+    UnknownType,
+    /// A binary frame arrived on a connection that did not negotiate a binary encoding
+    UnsupportedEncoding,
+    /// The frame, or one of its fields, is larger than the server accepts
+    PayloadTooLarge,
+    /// The sender exceeded its rate limit for this kind of frame
+    RateLimited,
+    /// A frame other than `connect` or `auth_proof` arrived before authentication
+    NotAuthenticated,
+    /// Authentication did not complete within the server's auth timeout
+    AuthTimeout,
+    /// A published public key is not a 32-byte hex-encoded X25519 key
+    InvalidKey,
+    /// A published public key differs from the account's bound identity key
+    KeyMismatch,
+    /// A block or unblock named no user, or the sender itself
+    InvalidTarget,
+    /// The sender's block list already has the maximum number of users
+    BlockListFull,
+    /// Call signaling that does not fit the state of the call
+    InvalidCall,
+    /// The server operator closed the connection
+    Kicked,
+    /// A code added by a newer server
+    #[serde(other)]
+    Unknown,
+}
+
+/// WebSocket message types (shared between server and client)
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum WsMessage {
+    #[serde(rename = "message")]
+    ChatMessage {
+        id: String,
+        chat_id: String,
+        sender_id: String,
+        sender_name: String,
+        recipient_id: String,
+        /// Plain text, or `enc:` followed by the encrypted payload as JSON
+        #[serde(with = "crate::codec::content")]
+        #[schemars(with = "String")]
+        content: String,
+        timestamp: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reply_to_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        url_preview: Option<WsUrlPreview>,
+    },
+    #[serde(rename = "typing")]
+    Typing {
+        chat_id: String,
+        user_id: String,
+        is_typing: bool,
+    },
+    #[serde(rename = "presence")]
+    Presence {
+        user_id: String,
+        is_online: bool,
+        last_seen: Option<i64>,
+    },
+    #[serde(rename = "delivery_receipt")]
+    DeliveryReceipt {
+        message_id: String,
+        chat_id: String,
+        sender_id: String,
+        delivered_to: String,
+    },
+    #[serde(rename = "read_receipt")]
+    ReadReceipt {
+        chat_id: String,
+        sender_id: String,
+        user_id: String,
+        message_ids: Vec<String>,
+    },
+    #[serde(rename = "connect")]
+    Connect {
+        user_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        /// Hex-encoded X25519 identity public key to bind this user_id to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        public_key: Option<String>,
+        /// Protocol version the client speaks (absent from clients that predate negotiation)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol_version: Option<u32>,
+        /// Optional features the client understands
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        capabilities: Vec<Capability>,
+        /// Stable id of the installation connecting, so one account can run on
+        /// several devices (absent: the account's default device)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device_id: Option<String>,
+    },
+    /// Server asks the client to prove possession of its identity key
+    #[serde(rename = "auth_challenge")]
+    AuthChallenge {
+        /// Hex-encoded random nonce
+        nonce: String,
+        /// Hex-encoded ephemeral X25519 public key of the server
+        server_key: String,
+    },
+    /// Client answer to an AuthChallenge
+    #[serde(rename = "auth_proof")]
+    AuthProof {
+        /// Hex-encoded HMAC-SHA256 over the challenge, keyed by the X25519 shared secret
+        proof: String,
+    },
+    #[serde(rename = "auth_response")]
+    AuthResponse {
+        success: bool,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<AuthErrorCode>,
+        /// Negotiated protocol version (the server's own when rejecting an old client)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol_version: Option<u32>,
+        /// Capabilities both sides support; only these optional frames will be sent
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        capabilities: Vec<Capability>,
+    },
+    /// A frame from this connection was rejected
+    #[serde(rename = "error")]
+    Error {
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+        /// Id of the rejected frame (message id or call id), when it had one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ref_id: Option<String>,
+    },
+    /// Relay acknowledgement of a chat message, sent to its sender only
+    #[serde(rename = "server_ack")]
+    ServerAck {
+        message_id: String,
+        status: AckStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// A queued chat message was dropped before reaching its recipient; sent to its sender
+    #[serde(rename = "delivery_failed")]
+    DeliveryFailed {
+        message_id: String,
+        chat_id: String,
+        recipient_id: String,
+        reason: DeliveryFailureReason,
+    },
+    /// Notice from the server operator, sent to every connected user
+    #[serde(rename = "announcement")]
+    Announcement {
+        message: String,
+        /// Unix millis when the announcement was sent
+        timestamp: i64,
+    },
+    /// The server is shutting down and is about to close the connection
+    #[serde(rename = "server_shutdown")]
+    ServerShutdown {
+        /// Seconds the client should wait before reconnecting
+        reconnect_after_secs: u64,
+    },
+    /// Profile update broadcast to subscribers
+    #[serde(rename = "profile_update")]
+    ProfileUpdate {
+        user_id: String,
+        name: String,
+        phone: Option<String>,
+        avatar_url: Option<String>,
+        about: Option<String>,
+        /// Base64-encoded avatar image bytes (only when avatar changes)
+        avatar_data: Option<String>,
+    },
+    /// Replaces the sender's subscriptions: presence/profile updates of `user_ids`
+    /// are delivered to it, and its typing indicators go to each chat's participants
+    #[serde(rename = "subscribe_presence")]
+    SubscribePresence {
+        user_ids: Vec<String>,
+        #[serde(default)]
+        chats: Vec<WsChatParticipants>,
+    },
+    /// Publishes the sender's identity public key to the server's key directory
+    #[serde(rename = "publish_key")]
+    PublishKey {
+        /// Hex-encoded X25519 public key
+        public_key: String,
+    },
+    /// Asks the key directory for a user's identity public key
+    #[serde(rename = "key_request")]
+    KeyRequest { user_id: String },
+    /// Key directory answer to a `key_request` or `publish_key`
+    #[serde(rename = "key_response")]
+    KeyResponse {
+        user_id: String,
+        /// Hex-encoded X25519 public key (absent when the user has none on file)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        public_key: Option<String>,
+    },
+    /// Adds `user_id` to the sender's block list: no messages, typing, calls or presence
+    /// are relayed between the two users in either direction
+    #[serde(rename = "block_user")]
+    BlockUser { user_id: String },
+    /// Removes `user_id` from the sender's block list
+    #[serde(rename = "unblock_user")]
+    UnblockUser { user_id: String },
+    /// The sender's block list once a `block_user` or `unblock_user` has been applied,
+    /// confirming the change
+    #[serde(rename = "block_list")]
+    BlockList { user_ids: Vec<String> },
+
+    // === Video Call Control Messages ===
+    #[serde(rename = "call_invite")]
+    CallInvite {
+        call_id: String,
+        from_user_id: String,
+        to_user_id: String,
+        kind: String, // "video" or "audio"
+    },
+    #[serde(rename = "call_ringing")]
+    CallRinging {
+        call_id: String,
+        from_user_id: String,
+        to_user_id: String,
+    },
+    #[serde(rename = "call_accept")]
+    CallAccept {
+        call_id: String,
+        from_user_id: String,
+        to_user_id: String,
+    },
+    #[serde(rename = "call_reject")]
+    CallReject {
+        call_id: String,
+        from_user_id: String,
+        to_user_id: String,
+        reason: String,
+    },
+    #[serde(rename = "call_hangup")]
+    CallHangup {
+        call_id: String,
+        from_user_id: String,
+        to_user_id: String,
+    },
+    /// A call that never reached `to_user_id` or went unanswered; sent by the server
+    /// to the callee, queued like a chat message while it is offline
+    #[serde(rename = "missed_call")]
+    MissedCall {
+        call_id: String,
+        from_user_id: String,
+        to_user_id: String,
+        kind: String,
+        /// Unix millis when the call was placed
+        timestamp: i64,
+    },
+
+    // === WebRTC Signaling Messages ===
+    #[serde(rename = "rtc_offer")]
+    RtcOffer {
+        call_id: String,
+        from_user_id: String,
+        to_user_id: String,
+        sdp: String,
+    },
+    #[serde(rename = "rtc_answer")]
+    RtcAnswer {
+        call_id: String,
+        from_user_id: String,
+        to_user_id: String,
+        sdp: String,
+    },
+    #[serde(rename = "rtc_ice_candidate")]
+    RtcIceCandidate {
+        call_id: String,
+        from_user_id: String,
+        to_user_id: String,
+        candidate: String,
+    },
+}
+
+impl WsMessage {
+    /// The frame's `type` tag, e.g. for logs and metrics labels
+    pub fn frame_type(&self) -> &'static str {
+        match self {
+            WsMessage::ChatMessage { .. } => "message",
+            WsMessage::Typing { .. } => "typing",
+            WsMessage::Presence { .. } => "presence",
+            WsMessage::DeliveryReceipt { .. } => "delivery_receipt",
+            WsMessage::ReadReceipt { .. } => "read_receipt",
+            WsMessage::Connect { .. } => "connect",
+            WsMessage::AuthChallenge { .. } => "auth_challenge",
+            WsMessage::AuthProof { .. } => "auth_proof",
+            WsMessage::AuthResponse { .. } => "auth_response",
+            WsMessage::Error { .. } => "error",
+            WsMessage::ServerAck { .. } => "server_ack",
+            WsMessage::DeliveryFailed { .. } => "delivery_failed",
+            WsMessage::Announcement { .. } => "announcement",
+            WsMessage::ServerShutdown { .. } => "server_shutdown",
+            WsMessage::ProfileUpdate { .. } => "profile_update",
+            WsMessage::SubscribePresence { .. } => "subscribe_presence",
+            WsMessage::PublishKey { .. } => "publish_key",
+            WsMessage::KeyRequest { .. } => "key_request",
+            WsMessage::KeyResponse { .. } => "key_response",
+            WsMessage::BlockUser { .. } => "block_user",
+            WsMessage::UnblockUser { .. } => "unblock_user",
+            WsMessage::BlockList { .. } => "block_list",
+            WsMessage::CallInvite { .. } => "call_invite",
+            WsMessage::CallRinging { .. } => "call_ringing",
+            WsMessage::CallAccept { .. } => "call_accept",
+            WsMessage::CallReject { .. } => "call_reject",
+            WsMessage::CallHangup { .. } => "call_hangup",
+            WsMessage::MissedCall { .. } => "missed_call",
+            WsMessage::RtcOffer { .. } => "rtc_offer",
+            WsMessage::RtcAnswer { .. } => "rtc_answer",
+            WsMessage::RtcIceCandidate { .. } => "rtc_ice_candidate",
+        }
+    }
+
+    /// Every `type` tag this version knows, to tell a frame of an unknown type from a
+    /// malformed one
+    pub const FRAME_TYPES: &'static [&'static str] = &[
+        "message",
+        "typing",
+        "presence",
+        "delivery_receipt",
+        "read_receipt",
+        "connect",
+        "auth_challenge",
+        "auth_proof",
+        "auth_response",
+        "error",
+        "server_ack",
+        "delivery_failed",
+        "announcement",
+        "server_shutdown",
+        "profile_update",
+        "subscribe_presence",
+        "publish_key",
+        "key_request",
+        "key_response",
+        "block_user",
+        "unblock_user",
+        "block_list",
+        "call_invite",
+        "call_ringing",
+        "call_accept",
+        "call_reject",
+        "call_hangup",
+        "missed_call",
+        "rtc_offer",
+        "rtc_answer",
+        "rtc_ice_candidate",
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_message_serialization() {
+        let msg = WsMessage::Connect {
+            user_id: "user123".to_string(),
+            token: None,
+            public_key: None,
+            protocol_version: Some(2),
+            capabilities: vec![Capability::Acks],
+            device_id: None,
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"connect\""));
+        assert!(!json.contains("device_id"));
+        assert!(json.contains("\"user_id\":\"user123\""));
+
+        // Deserialize back
+        let parsed: WsMessage = serde_json::from_str(&json).unwrap();
+        if let WsMessage::Connect {
+            user_id,
+            protocol_version,
+            capabilities,
+            ..
+        } = parsed
+        {
+            assert_eq!(user_id, "user123");
+            assert_eq!(protocol_version, Some(2));
+            assert_eq!(capabilities, vec![Capability::Acks]);
+        } else {
+            panic!("Expected Connect message");
+        }
+
+        // Clients from before negotiation send neither field
+        let legacy: WsMessage =
+            serde_json::from_str(r#"{"type":"connect","user_id":"old"}"#).unwrap();
+        if let WsMessage::Connect {
+            protocol_version,
+            capabilities,
+            ..
+        } = legacy
+        {
+            assert_eq!(protocol_version, None);
+            assert!(capabilities.is_empty());
+        } else {
+            panic!("Expected Connect message");
+        }
+    }
+
+    #[test]
+    fn test_chat_message_serialization() {
+        let msg = WsMessage::ChatMessage {
+            id: "msg1".to_string(),
+            chat_id: "chat1".to_string(),
+            sender_id: "user1".to_string(),
+            sender_name: "Alice".to_string(),
+            recipient_id: "user2".to_string(),
+            content: "Hello, world!".to_string(),
+            timestamp: 1234567890,
+            reply_to_id: None,
+            url_preview: None,
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"message\""));
+        assert!(json.contains("\"id\":\"msg1\""));
+        assert!(json.contains("\"content\":\"Hello, world!\""));
+
+        let parsed: WsMessage = serde_json::from_str(&json).unwrap();
+        if let WsMessage::ChatMessage { id, content, .. } = parsed {
+            assert_eq!(id, "msg1");
+            assert_eq!(content, "Hello, world!");
+        } else {
+            panic!("Expected ChatMessage");
+        }
+    }
+
+    #[test]
+    fn test_typing_message_serialization() {
+        let msg = WsMessage::Typing {
+            chat_id: "chat1".to_string(),
+            user_id: "user1".to_string(),
+            is_typing: true,
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"typing\""));
+        assert!(json.contains("\"is_typing\":true"));
+
+        let parsed: WsMessage = serde_json::from_str(&json).unwrap();
+        if let WsMessage::Typing { is_typing, .. } = parsed {
+            assert!(is_typing);
+        } else {
+            panic!("Expected Typing message");
+        }
+    }
+
+    #[test]
+    fn test_presence_message_serialization() {
+        // Online presence
+        let msg = WsMessage::Presence {
+            user_id: "user1".to_string(),
+            is_online: true,
+            last_seen: None,
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"presence\""));
+        assert!(json.contains("\"is_online\":true"));
+
+        // Offline presence with last_seen
+        let msg = WsMessage::Presence {
+            user_id: "user1".to_string(),
+            is_online: false,
+            last_seen: Some(1234567890),
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"is_online\":false"));
+        assert!(json.contains("\"last_seen\":1234567890"));
+    }
+
+    #[test]
+    fn test_delivery_receipt_serialization() {
+        let msg = WsMessage::DeliveryReceipt {
+            message_id: "msg1".to_string(),
+            chat_id: "chat1".to_string(),
+            sender_id: "user1".to_string(),
+            delivered_to: "user2".to_string(),
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"delivery_receipt\""));
+        assert!(json.contains("\"message_id\":\"msg1\""));
+        assert!(json.contains("\"delivered_to\":\"user2\""));
+    }
+
+    #[test]
+    fn test_read_receipt_serialization() {
+        let msg = WsMessage::ReadReceipt {
+            chat_id: "chat1".to_string(),
+            sender_id: "user2".to_string(),
+            user_id: "user1".to_string(),
+            message_ids: vec!["msg1".to_string(), "msg2".to_string()],
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"read_receipt\""));
+        assert!(json.contains("\"message_ids\":[\"msg1\",\"msg2\"]"));
+    }
+
+    #[test]
+    fn test_auth_response_serialization() {
+        let msg = WsMessage::AuthResponse {
+            success: true,
+            message: "Connected".to_string(),
+            code: None,
+            protocol_version: Some(2),
+            capabilities: vec![Capability::Acks],
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"auth_response\""));
+        assert!(json.contains("\"success\":true"));
+        assert!(!json.contains("\"code\""));
+        assert!(json.contains("\"capabilities\":[\"acks\"]"));
+
+        let msg = WsMessage::AuthResponse {
+            success: false,
+            message: "Invalid user".to_string(),
+            code: Some(AuthErrorCode::KeyMismatch),
+            protocol_version: None,
+            capabilities: Vec::new(),
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"success\":false"));
+        assert!(json.contains("\"code\":\"key_mismatch\""));
+        assert!(!json.contains("\"capabilities\""));
+    }
+
+    #[test]
+    fn test_auth_challenge_serialization() {
+        let msg = WsMessage::AuthChallenge {
+            nonce: "00ff".to_string(),
+            server_key: "abcd".to_string(),
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"auth_challenge\""));
+        assert!(json.contains("\"server_key\":\"abcd\""));
+
+        let json = r#"{"type":"auth_proof","proof":"beef"}"#;
+        let parsed: WsMessage = serde_json::from_str(json).unwrap();
+        if let WsMessage::AuthProof { proof } = parsed {
+            assert_eq!(proof, "beef");
+        } else {
+            panic!("Expected AuthProof");
+        }
+    }
+
+    #[test]
+    fn test_error_message_serialization() {
+        let msg = WsMessage::Error {
+            message: "Something went wrong".to_string(),
+            code: Some(ErrorCode::InvalidFrame),
+            ref_id: None,
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"error\""));
+        assert!(json.contains("\"message\":\"Something went wrong\""));
+        assert!(json.contains("\"code\":\"invalid_frame\""));
+        assert!(!json.contains("ref_id"));
+    }
+
+    #[test]
+    fn test_server_ack_serialization() {
+        let msg = WsMessage::ServerAck {
+            message_id: "msg1".to_string(),
+            status: AckStatus::Queued,
+            reason: None,
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"server_ack\""));
+        assert!(json.contains("\"status\":\"queued\""));
+        assert!(!json.contains("reason"));
+    }
+
+    #[test]
+    fn test_delivery_failed_serialization() {
+        let msg = WsMessage::DeliveryFailed {
+            message_id: "msg1".to_string(),
+            chat_id: "chat1".to_string(),
+            recipient_id: "user2".to_string(),
+            reason: DeliveryFailureReason::QueueFull,
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"delivery_failed\""));
+        assert!(json.contains("\"reason\":\"queue_full\""));
+    }
+
+    #[test]
+    fn test_profile_update_serialization() {
+        let msg = WsMessage::ProfileUpdate {
+            user_id: "user1".to_string(),
+            name: "Alice".to_string(),
+            phone: Some("+1234567890".to_string()),
+            avatar_url: None,
+            about: Some("Hello!".to_string()),
+            avatar_data: None,
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"profile_update\""));
+        assert!(json.contains("\"name\":\"Alice\""));
+        assert!(json.contains("\"phone\":\"+1234567890\""));
+        assert!(json.contains("\"about\":\"Hello!\""));
+    }
+
+    #[test]
+    fn test_subscribe_presence_deserialization() {
+        let json = r#"{
+            "type":"subscribe_presence",
+            "user_ids":["bob","carol"],
+            "chats":[{"chat_id":"chat1","participant_ids":["bob"]}]
+        }"#;
+        let parsed: WsMessage = serde_json::from_str(json).unwrap();
+        if let WsMessage::SubscribePresence { user_ids, chats } = parsed {
+            assert_eq!(user_ids, vec!["bob", "carol"]);
+            assert_eq!(chats.len(), 1);
+            assert_eq!(chats[0].chat_id, "chat1");
+            assert_eq!(chats[0].participant_ids, vec!["bob"]);
+        } else {
+            panic!("Expected SubscribePresence");
+        }
+
+        // chats may be omitted
+        let json = r#"{"type":"subscribe_presence","user_ids":[]}"#;
+        let parsed: WsMessage = serde_json::from_str(json).unwrap();
+        assert!(matches!(parsed, WsMessage::SubscribePresence { chats, .. } if chats.is_empty()));
+    }
+
+    #[test]
+    fn test_key_response_serialization() {
+        let msg = WsMessage::KeyResponse {
+            user_id: "bob".to_string(),
+            public_key: None,
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, r#"{"type":"key_response","user_id":"bob"}"#);
+
+        let json = r#"{"type":"key_response","user_id":"bob","public_key":"ab"}"#;
+        let parsed: WsMessage = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            parsed,
+            WsMessage::KeyResponse { public_key: Some(key), .. } if key == "ab"
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_from_frontend_format() {
+        // Test parsing JSON in the format the frontend sends
+        let json = r#"{"type":"connect","user_id":"abc-123"}"#;
+        let msg: WsMessage = serde_json::from_str(json).unwrap();
+        if let WsMessage::Connect { user_id, .. } = msg {
+            assert_eq!(user_id, "abc-123");
+        } else {
+            panic!("Expected Connect");
+        }
+
+        let json = r#"{"type":"typing","chat_id":"chat1","user_id":"user1","is_typing":true}"#;
+        let msg: WsMessage = serde_json::from_str(json).unwrap();
+        if let WsMessage::Typing {
+            chat_id,
+            user_id,
+            is_typing,
+        } = msg
+        {
+            assert_eq!(chat_id, "chat1");
+            assert_eq!(user_id, "user1");
+            assert!(is_typing);
+        } else {
+            panic!("Expected Typing");
+        }
+    }
+}
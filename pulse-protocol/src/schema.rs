@@ -0,0 +1,11 @@
+//! JSON Schema of the wire protocol, for the TypeScript frontend.
+//!
+//! The generated schema is checked in at `src/types/protocol.schema.json`;
+//! regenerate it with `cargo run -p pulse-protocol --bin export-schema`.
+
+use crate::messages::WsMessage;
+
+/// JSON Schema describing every `WsMessage` frame
+pub fn json_schema() -> serde_json::Value {
+    serde_json::to_value(schemars::schema_for!(WsMessage)).expect("schema serializes")
+}
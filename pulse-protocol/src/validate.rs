@@ -0,0 +1,199 @@
+//! Checks and rewrites applied to frames before they are trusted.
+
+use crate::messages::WsMessage;
+
+impl WsMessage {
+    /// Overwrite every field that names the frame's author with the
+    /// authenticated `user_id`, so a connection cannot speak for someone else
+    pub fn enforce_sender(&mut self, user_id: &str) {
+        match self {
+            WsMessage::ChatMessage { sender_id, .. } => *sender_id = user_id.to_string(),
+            WsMessage::Typing { user_id: uid, .. } => *uid = user_id.to_string(),
+            WsMessage::Presence { user_id: uid, .. } => *uid = user_id.to_string(),
+
+            // In receipts, 'sender_id' is the DESTINATION (original sender of the message);
+            // it is 'delivered_to' / 'user_id' that must match the connection.
+            WsMessage::DeliveryReceipt { delivered_to, .. } => *delivered_to = user_id.to_string(),
+            WsMessage::ReadReceipt { user_id: uid, .. } => *uid = user_id.to_string(),
+
+            WsMessage::ProfileUpdate { user_id: uid, .. } => *uid = user_id.to_string(),
+
+            // Video Call & WebRTC
+            WsMessage::CallInvite { from_user_id, .. }
+            | WsMessage::CallRinging { from_user_id, .. }
+            | WsMessage::CallAccept { from_user_id, .. }
+            | WsMessage::CallReject { from_user_id, .. }
+            | WsMessage::CallHangup { from_user_id, .. }
+            | WsMessage::RtcOffer { from_user_id, .. }
+            | WsMessage::RtcAnswer { from_user_id, .. }
+            | WsMessage::RtcIceCandidate { from_user_id, .. } => {
+                *from_user_id = user_id.to_string()
+            }
+
+            // Frames that are not relayed or carry no author
+            WsMessage::Connect { .. }
+            | WsMessage::SubscribePresence { .. }
+            | WsMessage::PublishKey { .. }
+            | WsMessage::KeyRequest { .. }
+            | WsMessage::KeyResponse { .. }
+            | WsMessage::BlockUser { .. }
+            | WsMessage::UnblockUser { .. }
+            | WsMessage::BlockList { .. }
+            | WsMessage::AuthChallenge { .. }
+            | WsMessage::AuthProof { .. }
+            | WsMessage::AuthResponse { .. }
+            | WsMessage::Error { .. }
+            | WsMessage::ServerAck { .. }
+            | WsMessage::DeliveryFailed { .. }
+            | WsMessage::Announcement { .. }
+            | WsMessage::ServerShutdown { .. }
+            | WsMessage::MissedCall { .. } => {}
+        }
+    }
+
+    /// Whether the relay keeps this frame for a recipient that is offline
+    /// (chat messages, receipts, delivery failures and missed calls)
+    pub fn is_queueable(&self) -> bool {
+        matches!(
+            self,
+            WsMessage::ChatMessage { .. }
+                | WsMessage::DeliveryReceipt { .. }
+                | WsMessage::ReadReceipt { .. }
+                | WsMessage::DeliveryFailed { .. }
+                | WsMessage::MissedCall { .. }
+        )
+    }
+
+    /// The user a frame is relayed to, for frames addressed to exactly one user
+    /// (chat messages, receipts and call signaling)
+    pub fn recipient(&self) -> Option<&str> {
+        match self {
+            WsMessage::ChatMessage { recipient_id, .. } => Some(recipient_id),
+            // Receipts go back to the original sender of the message
+            WsMessage::DeliveryReceipt { sender_id, .. }
+            | WsMessage::ReadReceipt { sender_id, .. } => Some(sender_id),
+            WsMessage::CallInvite { to_user_id, .. }
+            | WsMessage::CallRinging { to_user_id, .. }
+            | WsMessage::CallAccept { to_user_id, .. }
+            | WsMessage::CallReject { to_user_id, .. }
+            | WsMessage::CallHangup { to_user_id, .. }
+            | WsMessage::RtcOffer { to_user_id, .. }
+            | WsMessage::RtcAnswer { to_user_id, .. }
+            | WsMessage::RtcIceCandidate { to_user_id, .. } => Some(to_user_id),
+            _ => None,
+        }
+    }
+
+    /// The id an `error` reply about this frame refers to: the message id of chat
+    /// messages and delivery receipts, the call id of call signaling
+    pub fn ref_id(&self) -> Option<&str> {
+        match self {
+            WsMessage::ChatMessage { id, .. } => Some(id),
+            WsMessage::DeliveryReceipt { message_id, .. } => Some(message_id),
+            WsMessage::CallInvite { call_id, .. }
+            | WsMessage::CallRinging { call_id, .. }
+            | WsMessage::CallAccept { call_id, .. }
+            | WsMessage::CallReject { call_id, .. }
+            | WsMessage::CallHangup { call_id, .. }
+            | WsMessage::RtcOffer { call_id, .. }
+            | WsMessage::RtcAnswer { call_id, .. }
+            | WsMessage::RtcIceCandidate { call_id, .. } => Some(call_id),
+            _ => None,
+        }
+    }
+}
+
+/// Decode a hex-encoded 32-byte X25519 key
+pub fn decode_key(hex_key: &str) -> Option<[u8; 32]> {
+    hex::decode(hex_key).ok()?.try_into().ok()
+}
+
+/// Longest device_id a client may send in Connect
+pub const MAX_DEVICE_ID_LEN: usize = 64;
+
+/// Whether a device_id is 1 to 64 ASCII letters, digits, `-` or `_` (e.g. a UUID)
+pub fn is_valid_device_id(device_id: &str) -> bool {
+    !device_id.is_empty()
+        && device_id.len() <= MAX_DEVICE_ID_LEN
+        && device_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enforce_sender_overwrites_author() {
+        let mut msg: WsMessage = serde_json::from_str(
+            r#"{"type":"read_receipt","chat_id":"c1","sender_id":"alice","user_id":"admin","message_ids":["m1"]}"#,
+        )
+        .unwrap();
+        msg.enforce_sender("bob");
+
+        if let WsMessage::ReadReceipt {
+            sender_id, user_id, ..
+        } = msg
+        {
+            // The original sender is the destination and stays untouched
+            assert_eq!(sender_id, "alice");
+            assert_eq!(user_id, "bob");
+        } else {
+            panic!("Expected ReadReceipt");
+        }
+    }
+
+    #[test]
+    fn test_recipient_of_routed_frames() {
+        let receipt: WsMessage = serde_json::from_str(
+            r#"{"type":"read_receipt","chat_id":"c1","sender_id":"alice","user_id":"bob","message_ids":["m1"]}"#,
+        )
+        .unwrap();
+        assert_eq!(receipt.recipient(), Some("alice"));
+
+        let invite: WsMessage = serde_json::from_str(
+            r#"{"type":"call_invite","call_id":"c","from_user_id":"bob","to_user_id":"carol","kind":"audio"}"#,
+        )
+        .unwrap();
+        assert_eq!(invite.recipient(), Some("carol"));
+
+        let typing: WsMessage = serde_json::from_str(
+            r#"{"type":"typing","chat_id":"c1","user_id":"bob","is_typing":true}"#,
+        )
+        .unwrap();
+        assert_eq!(typing.recipient(), None);
+    }
+
+    #[test]
+    fn test_ref_id_of_rejectable_frames() {
+        let invite: WsMessage = serde_json::from_str(
+            r#"{"type":"call_invite","call_id":"c","from_user_id":"bob","to_user_id":"carol","kind":"audio"}"#,
+        )
+        .unwrap();
+        assert_eq!(invite.ref_id(), Some("c"));
+
+        // Read receipts cover several messages, so none of them is the reference
+        let receipt: WsMessage = serde_json::from_str(
+            r#"{"type":"read_receipt","chat_id":"c1","sender_id":"alice","user_id":"bob","message_ids":["m1"]}"#,
+        )
+        .unwrap();
+        assert_eq!(receipt.ref_id(), None);
+    }
+
+    #[test]
+    fn test_decode_key_requires_32_bytes() {
+        assert_eq!(decode_key(&"ab".repeat(32)), Some([0xab; 32]));
+        assert_eq!(decode_key(&"ab".repeat(31)), None);
+        assert_eq!(decode_key("not hex"), None);
+    }
+
+    #[test]
+    fn test_device_id_charset_and_length() {
+        assert!(is_valid_device_id("4f5c2a1e-8d3b-4c6f-9a7e-2b1d0c9e8f7a"));
+        assert!(is_valid_device_id("desk_1"));
+        assert!(!is_valid_device_id(""));
+        assert!(!is_valid_device_id("desk 1"));
+        assert!(!is_valid_device_id(&"a".repeat(MAX_DEVICE_ID_LEN + 1)));
+    }
+}
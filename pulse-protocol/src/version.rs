@@ -0,0 +1,94 @@
+//! Protocol versions and optional capabilities.
+//!
+//! A client names the version it speaks and the capabilities it understands
+//! in `Connect`; the server answers in `AuthResponse` with what both sides
+//! support and only sends the optional frames that were negotiated.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Current protocol version
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest client protocol version the server still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Version assumed when `Connect` carries none (clients from before negotiation)
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features a connection can opt into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// `server_ack` for every chat message and `delivery_failed` for dropped ones
+    Acks,
+    /// MessagePack binary frames
+    Msgpack,
+    /// CBOR binary frames
+    Cbor,
+    /// `publish_key` and `key_request`/`key_response` against the server's key directory
+    Keys,
+    /// A capability this side does not know (offered by a newer peer); never granted
+    #[serde(other)]
+    Unknown,
+}
+
+impl Capability {
+    /// Capability a peer must have negotiated before a frame of this `type` is sent to it
+    pub fn required_for(frame_type: &str) -> Option<Self> {
+        match frame_type {
+            "server_ack" | "delivery_failed" => Some(Self::Acks),
+            "key_response" => Some(Self::Keys),
+            _ => None,
+        }
+    }
+}
+
+/// Oldest protocol version a peer must speak before a frame of this `type` is sent to it
+pub fn min_version_for(frame_type: &str) -> u32 {
+    match frame_type {
+        "missed_call" | "announcement" | "server_shutdown" | "block_list" => 2,
+        _ => LEGACY_PROTOCOL_VERSION,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_capability_names_parse() {
+        let caps: Vec<Capability> = serde_json::from_str(r#"["acks","teleport"]"#).unwrap();
+        assert_eq!(caps, vec![Capability::Acks, Capability::Unknown]);
+    }
+
+    #[test]
+    fn test_ack_frames_require_acks() {
+        assert_eq!(
+            Capability::required_for("server_ack"),
+            Some(Capability::Acks)
+        );
+        assert_eq!(
+            Capability::required_for("delivery_failed"),
+            Some(Capability::Acks)
+        );
+        assert_eq!(Capability::required_for("message"), None);
+    }
+
+    #[test]
+    fn test_newer_frames_require_version() {
+        assert_eq!(min_version_for("missed_call"), 2);
+        assert_eq!(min_version_for("announcement"), 2);
+        assert_eq!(min_version_for("server_shutdown"), 2);
+        assert_eq!(min_version_for("block_list"), 2);
+        assert_eq!(min_version_for("message"), LEGACY_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn test_key_response_requires_keys() {
+        assert_eq!(
+            Capability::required_for("key_response"),
+            Some(Capability::Keys)
+        );
+    }
+}
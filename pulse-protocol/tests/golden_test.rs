@@ -0,0 +1,180 @@
+//! Golden-file tests for the wire protocol
+//!
+//! Every frame type has a checked-in example under `tests/golden/`, named after
+//! its `type` tag. Each must parse and serialize back to the same JSON, so a
+//! serde change that would break the other end of the connection fails here.
+
+use pulse_protocol::{AuthErrorCode, Encoding, ErrorCode, WsMessage};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+fn golden_files() -> Vec<(String, String)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut files: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            (name, std::fs::read_to_string(&path).unwrap())
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// The `type` tag of every frame, from the exported schema
+fn schema_frame_types() -> BTreeSet<String> {
+    let schema = pulse_protocol::json_schema();
+    schema["oneOf"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|variant| {
+            variant["properties"]["type"]["enum"][0]
+                .as_str()
+                .unwrap()
+                .to_string()
+        })
+        .collect()
+}
+
+#[test]
+fn test_golden_frames_round_trip() {
+    for (name, text) in golden_files() {
+        let expected: Value = serde_json::from_str(&text).unwrap();
+        let msg: WsMessage = serde_json::from_str(&text)
+            .unwrap_or_else(|e| panic!("{}.json does not parse: {}", name, e));
+        assert_eq!(
+            expected["type"],
+            name.as_str(),
+            "{}.json has the wrong type",
+            name
+        );
+        assert_eq!(
+            msg.frame_type(),
+            name,
+            "frame_type() disagrees with the tag"
+        );
+
+        let reserialized = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            reserialized, expected,
+            "{}.json changed on round trip",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_golden_frames_survive_binary_encodings() {
+    for (name, text) in golden_files() {
+        let expected: Value = serde_json::from_str(&text).unwrap();
+        let msg: WsMessage = serde_json::from_str(&text).unwrap();
+
+        for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+            let decoded = encoding.decode(&encoding.encode(&msg).unwrap()).unwrap();
+            assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                expected,
+                "{}.json changed through {:?}",
+                name,
+                encoding
+            );
+        }
+    }
+}
+
+#[test]
+fn test_every_frame_type_has_golden_file() {
+    let golden: BTreeSet<String> = golden_files().into_iter().map(|(name, _)| name).collect();
+    assert_eq!(golden, schema_frame_types());
+}
+
+#[test]
+fn test_frame_types_list_matches_schema() {
+    let listed: BTreeSet<String> = WsMessage::FRAME_TYPES
+        .iter()
+        .map(|tag| tag.to_string())
+        .collect();
+    assert_eq!(
+        listed.len(),
+        WsMessage::FRAME_TYPES.len(),
+        "duplicate frame type"
+    );
+    assert_eq!(listed, schema_frame_types());
+}
+
+#[test]
+fn test_frames_from_older_peers_still_parse() {
+    // Connect and AuthResponse from before protocol negotiation and devices
+    let connect: WsMessage =
+        serde_json::from_str(r#"{"type":"connect","user_id":"alice"}"#).unwrap();
+    assert!(matches!(
+        connect,
+        WsMessage::Connect {
+            protocol_version: None,
+            ref capabilities,
+            device_id: None,
+            ..
+        } if capabilities.is_empty()
+    ));
+
+    let response: WsMessage =
+        serde_json::from_str(r#"{"type":"auth_response","success":true,"message":"Connected"}"#)
+            .unwrap();
+    assert!(matches!(
+        response,
+        WsMessage::AuthResponse {
+            code: None,
+            protocol_version: None,
+            ..
+        }
+    ));
+
+    // subscribe_presence from before chat participants were declared
+    let subscribe: WsMessage =
+        serde_json::from_str(r#"{"type":"subscribe_presence","user_ids":["bob"]}"#).unwrap();
+    assert!(
+        matches!(subscribe, WsMessage::SubscribePresence { ref chats, .. } if chats.is_empty())
+    );
+
+    // error from before error codes
+    let error: WsMessage =
+        serde_json::from_str(r#"{"type":"error","message":"Rate limit exceeded"}"#).unwrap();
+    assert!(matches!(
+        error,
+        WsMessage::Error {
+            code: None,
+            ref_id: None,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_frames_from_newer_peers_still_parse() {
+    let response: WsMessage = serde_json::from_str(
+        r#"{"type":"auth_response","success":false,"message":"Nope","code":"account_frozen","capabilities":["acks","teleport"]}"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        response,
+        WsMessage::AuthResponse {
+            code: Some(AuthErrorCode::Unknown),
+            ..
+        }
+    ));
+
+    let error: WsMessage =
+        serde_json::from_str(r#"{"type":"error","message":"Nope","code":"quota_exceeded"}"#)
+            .unwrap();
+    assert!(matches!(
+        error,
+        WsMessage::Error {
+            code: Some(ErrorCode::Unknown),
+            ..
+        }
+    ));
+}
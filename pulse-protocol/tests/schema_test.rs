@@ -0,0 +1,16 @@
+//! Keeps the checked-in JSON Schema in step with the Rust types
+
+use std::path::PathBuf;
+
+#[test]
+fn test_checked_in_schema_is_current() {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../src/types/protocol.schema.json");
+    let checked_in: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+    assert!(
+        checked_in == pulse_protocol::json_schema(),
+        "{} is stale; run `cargo run -p pulse-protocol --bin export-schema`",
+        path.display()
+    );
+}
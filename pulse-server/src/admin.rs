@@ -0,0 +1,416 @@
+//! Operator API under `/admin` on the loopback admin listener, and the client the
+//! `pulse-server admin` subcommand uses to call it.
+//!
+//! Every request needs `Authorization: Bearer <server.admin_token>`; when no
+//! token is configured the routes do not exist. Responses are JSON.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::info;
+
+use crate::http::{Request, Response};
+use crate::state::ServerState;
+
+/// Longest announcement accepted, in characters
+const MAX_ANNOUNCEMENT_CHARS: usize = 1000;
+
+/// Time the CLI waits for the server to answer
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answer one `/admin` request
+pub(crate) fn route(request: &Request, state: &ServerState, token: Option<&str>) -> Response {
+    let Some(token) = token else {
+        return Response::text(404, "Not found\n");
+    };
+    let presented = request
+        .authorization
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !presented.is_some_and(|presented| constant_time_eq(presented, token)) {
+        return error(401, "missing or invalid admin token");
+    }
+
+    let Some(segments) = path_segments(request.path) else {
+        return error(400, "malformed path");
+    };
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    match (request.method, segments.as_slice()) {
+        ("GET", ["users"]) => list_users(state),
+        ("GET", ["users", user_id]) => show_user(state, user_id),
+        ("POST", ["users", user_id, "kick"]) => {
+            let closed = state.kick_user(user_id);
+            info!("Admin kicked {} ({} connections)", user_id, closed);
+            ok(json!({ "user_id": user_id, "connections_closed": closed }))
+        }
+        ("POST", ["users", user_id, "ban"]) => {
+            let closed = state.ban_user(user_id);
+            info!("Admin banned {} ({} connections)", user_id, closed);
+            ok(json!({ "user_id": user_id, "banned": true, "connections_closed": closed }))
+        }
+        ("DELETE", ["users", user_id, "ban"]) => {
+            let was_banned = state.unban_user(user_id);
+            info!("Admin unbanned {}", user_id);
+            ok(json!({ "user_id": user_id, "banned": false, "was_banned": was_banned }))
+        }
+        ("DELETE", ["users", user_id, "queue"]) => {
+            let purged = state.purge_pending(user_id);
+            info!("Admin purged {} queued messages for {}", purged, user_id);
+            ok(json!({ "user_id": user_id, "purged": purged }))
+        }
+        ("GET", ["bans"]) => ok(json!({ "users": state.banned_users() })),
+        ("POST", ["announce"]) => announce(state, request.body),
+        _ => error(404, "no such admin route"),
+    }
+}
+
+fn list_users(state: &ServerState) -> Response {
+    let mut users = state.online_users();
+    users.sort();
+    let users: Vec<_> = users
+        .iter()
+        .map(|user_id| {
+            json!({
+                "user_id": user_id,
+                "connections": state.connection_count(user_id),
+                "pending": state.pending_count(user_id),
+            })
+        })
+        .collect();
+    ok(json!({ "users": users }))
+}
+
+fn show_user(state: &ServerState, user_id: &str) -> Response {
+    let mut devices = state.devices_of(user_id);
+    devices.sort();
+    ok(json!({
+        "user_id": user_id,
+        "online": state.is_online(user_id),
+        "connections": state.connection_count(user_id),
+        "devices": devices,
+        "pending": state.pending_count(user_id),
+        "banned": state.is_banned(user_id),
+    }))
+}
+
+fn announce(state: &ServerState, body: &[u8]) -> Response {
+    let Ok(message) = std::str::from_utf8(body) else {
+        return error(400, "announcement must be UTF-8 text");
+    };
+    let message = message.trim();
+    if message.is_empty() {
+        return error(400, "announcement is empty");
+    }
+    if message.chars().count() > MAX_ANNOUNCEMENT_CHARS {
+        return error(
+            400,
+            &format!(
+                "announcement is longer than {} characters",
+                MAX_ANNOUNCEMENT_CHARS
+            ),
+        );
+    }
+    let recipients = state.announce(message);
+    info!("Admin sent an announcement to {} users", recipients);
+    ok(json!({ "recipients": recipients }))
+}
+
+fn ok(body: serde_json::Value) -> Response {
+    Response::json(200, body)
+}
+
+fn error(status: u16, message: &str) -> Response {
+    Response::json(status, json!({ "error": message }))
+}
+
+/// Percent-decoded path segments after `/admin`, or None if one is not valid UTF-8
+fn path_segments(path: &str) -> Option<Vec<String>> {
+    path.strip_prefix("/admin")?
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect()
+}
+
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            // from_str_radix alone would also take a sign, as in `%+1`
+            let hex = segment.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~+".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Compare secrets without revealing how long a matching prefix is
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// One `pulse-server admin` subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// Online users with their connection and queue counts
+    Users,
+    /// One user's connections, devices, queue and ban status
+    User(String),
+    Kick(String),
+    Ban(String),
+    Unban(String),
+    Bans,
+    /// Drop every message queued for a user
+    Purge(String),
+    /// Send a notice to every online user
+    Announce(String),
+}
+
+impl AdminCommand {
+    /// Parse a subcommand and its arguments, e.g. `["kick", "alice"]`
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (name, rest) = args.split_first().ok_or("Missing admin command")?;
+        let user = || match rest {
+            [user_id] => Ok(user_id.clone()),
+            _ => Err(format!("{} takes exactly one user_id", name)),
+        };
+        let none = |command: AdminCommand| match rest {
+            [] => Ok(command),
+            _ => Err(format!("{} takes no arguments", name)),
+        };
+        match name.as_str() {
+            "users" => none(AdminCommand::Users),
+            "bans" => none(AdminCommand::Bans),
+            "user" => user().map(AdminCommand::User),
+            "kick" => user().map(AdminCommand::Kick),
+            "ban" => user().map(AdminCommand::Ban),
+            "unban" => user().map(AdminCommand::Unban),
+            "purge" => user().map(AdminCommand::Purge),
+            "announce" if !rest.is_empty() => Ok(AdminCommand::Announce(rest.join(" "))),
+            "announce" => Err("announce needs a message".to_string()),
+            other => Err(format!("Unknown admin command: {}", other)),
+        }
+    }
+
+    /// Method, path and body of the request this command makes
+    fn request(&self) -> (&'static str, String, String) {
+        let user_path = |user_id: &str, action: &str| {
+            format!("/admin/users/{}{}", percent_encode(user_id), action)
+        };
+        match self {
+            AdminCommand::Users => ("GET", "/admin/users".to_string(), String::new()),
+            AdminCommand::Bans => ("GET", "/admin/bans".to_string(), String::new()),
+            AdminCommand::User(user_id) => ("GET", user_path(user_id, ""), String::new()),
+            AdminCommand::Kick(user_id) => ("POST", user_path(user_id, "/kick"), String::new()),
+            AdminCommand::Ban(user_id) => ("POST", user_path(user_id, "/ban"), String::new()),
+            AdminCommand::Unban(user_id) => ("DELETE", user_path(user_id, "/ban"), String::new()),
+            AdminCommand::Purge(user_id) => ("DELETE", user_path(user_id, "/queue"), String::new()),
+            AdminCommand::Announce(message) => {
+                ("POST", "/admin/announce".to_string(), message.clone())
+            }
+        }
+    }
+
+    /// Run the command against a server's admin API; Ok holds the JSON response body
+    pub async fn send(&self, addr: SocketAddr, token: &str) -> Result<String, String> {
+        let (method, path, body) = self.request();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            method,
+            path,
+            addr,
+            token,
+            body.len(),
+            body
+        );
+
+        let response = tokio::time::timeout(CLIENT_TIMEOUT, async {
+            let mut stream = TcpStream::connect(addr).await?;
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        })
+        .await
+        .map_err(|_| format!("No response from {} within {:?}", addr, CLIENT_TIMEOUT))?
+        .map_err(|e| format!("Failed to reach {}: {}", addr, e))?;
+
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or("Malformed response from server")?;
+        let status = head
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or("Malformed response from server")?;
+        if (200..300).contains(&status) {
+            Ok(body.to_string())
+        } else {
+            Err(format!("Server answered {}: {}", status, body.trim()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "s3cret";
+
+    fn request<'a>(method: &'a str, path: &'a str, body: &'a [u8]) -> Request<'a> {
+        Request {
+            method,
+            path,
+            authorization: Some("Bearer s3cret"),
+            content_length: body.len(),
+            body,
+        }
+    }
+
+    fn body(response: &Response) -> serde_json::Value {
+        serde_json::from_str(&response.body).unwrap()
+    }
+
+    #[test]
+    fn test_requires_token() {
+        let state = ServerState::new();
+        let mut req = request("GET", "/admin/users", b"");
+        assert_eq!(route(&req, &state, None).status, 404);
+
+        req.authorization = None;
+        assert_eq!(route(&req, &state, Some(TOKEN)).status, 401);
+        req.authorization = Some("Bearer wrong!");
+        assert_eq!(route(&req, &state, Some(TOKEN)).status, 401);
+        req.authorization = Some("s3cret");
+        assert_eq!(route(&req, &state, Some(TOKEN)).status, 401);
+    }
+
+    #[test]
+    fn test_user_routes() {
+        let state = ServerState::new();
+        let (tx, _rx) = tokio::sync::mpsc::channel(16);
+        state.add_client("+15550100".to_string(), tx);
+        state.queue_message("bob", "queued".to_string());
+
+        let users = route(&request("GET", "/admin/users", b""), &state, Some(TOKEN));
+        assert_eq!(users.status, 200);
+        assert_eq!(
+            body(&users),
+            json!({ "users": [{ "user_id": "+15550100", "connections": 1, "pending": 0 }] })
+        );
+
+        let bob = route(
+            &request("GET", "/admin/users/bob", b""),
+            &state,
+            Some(TOKEN),
+        );
+        assert_eq!(body(&bob)["pending"], 1);
+        assert_eq!(body(&bob)["online"], false);
+
+        let purge = route(
+            &request("DELETE", "/admin/users/bob/queue", b""),
+            &state,
+            Some(TOKEN),
+        );
+        assert_eq!(body(&purge)["purged"], 1);
+
+        let ban = route(
+            &request("POST", "/admin/users/%2B15550100/ban", b""),
+            &state,
+            Some(TOKEN),
+        );
+        assert_eq!(body(&ban)["connections_closed"], 1);
+        assert!(state.is_banned("+15550100"));
+        let bans = route(&request("GET", "/admin/bans", b""), &state, Some(TOKEN));
+        assert_eq!(body(&bans), json!({ "users": ["+15550100"] }));
+
+        let missing = route(
+            &request("PUT", "/admin/users/bob", b""),
+            &state,
+            Some(TOKEN),
+        );
+        assert_eq!(missing.status, 404);
+    }
+
+    #[test]
+    fn test_announce_validates_body() {
+        let state = ServerState::new();
+        let empty = route(
+            &request("POST", "/admin/announce", b"  \n"),
+            &state,
+            Some(TOKEN),
+        );
+        assert_eq!(empty.status, 400);
+        let sent = route(
+            &request("POST", "/admin/announce", b"Back soon"),
+            &state,
+            Some(TOKEN),
+        );
+        assert_eq!(body(&sent), json!({ "recipients": 0 }));
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            AdminCommand::parse(&args(&["kick", "alice"])),
+            Ok(AdminCommand::Kick("alice".to_string()))
+        );
+        assert_eq!(
+            AdminCommand::parse(&args(&["announce", "Back", "soon"])),
+            Ok(AdminCommand::Announce("Back soon".to_string()))
+        );
+        assert!(AdminCommand::parse(&args(&["users", "extra"])).is_err());
+        assert!(AdminCommand::parse(&args(&["ban"])).is_err());
+        assert!(AdminCommand::parse(&args(&["reboot"])).is_err());
+    }
+
+    #[test]
+    fn test_user_ids_roundtrip_through_path() {
+        let (_, path, _) = AdminCommand::Purge("+1 555/0100".to_string()).request();
+        assert_eq!(path, "/admin/users/+1%20555%2F0100/queue");
+        assert_eq!(
+            path_segments(&path),
+            Some(vec![
+                "users".to_string(),
+                "+1 555/0100".to_string(),
+                "queue".to_string()
+            ])
+        );
+        assert_eq!(path_segments("/admin/users/%FF"), None);
+        assert_eq!(path_segments("/admin/users/%4"), None);
+        assert_eq!(path_segments("/admin/users/%+1"), None);
+        assert_eq!(path_segments("/admin/users/%-1"), None);
+    }
+}
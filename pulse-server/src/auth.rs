@@ -0,0 +1,177 @@
+//! Identity challenge-response for `Connect`.
+//!
+//! Identity keys are X25519, which cannot sign, so possession is proven with a
+//! Diffie-Hellman exchange instead: the server sends an ephemeral public key and
+//! a nonce, and the client answers with an HMAC over the nonce keyed by the
+//! shared secret between its identity key and the server's ephemeral key. Only
+//! the holder of the identity private key can derive that secret.
+
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use pulse_protocol::WsMessage;
+
+/// Domain separation label mixed into every proof
+const PROOF_LABEL: &[u8] = b"pulse-auth-v1";
+
+/// Length of the random challenge nonce in bytes
+const NONCE_LEN: usize = 32;
+
+/// Who may connect and how long they have to prove it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthConfig {
+    /// Shared token every client must send in `Connect` (None disables the check)
+    pub access_token: Option<String>,
+    /// Reject users that have no identity key instead of admitting them on token alone
+    pub require_identity: bool,
+    /// How long a new connection has to finish authenticating
+    pub timeout: Duration,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            access_token: None,
+            require_identity: false,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A pending challenge for a single connection attempt
+pub struct Challenge {
+    secret: EphemeralSecret,
+    server_key: PublicKey,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl Challenge {
+    /// Create a fresh challenge with a new ephemeral key and nonce
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let server_key = PublicKey::from(&secret);
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        Self {
+            secret,
+            server_key,
+            nonce,
+        }
+    }
+
+    /// The frame to send to the client
+    pub fn to_message(&self) -> WsMessage {
+        WsMessage::AuthChallenge {
+            nonce: hex::encode(self.nonce),
+            server_key: hex::encode(self.server_key.as_bytes()),
+        }
+    }
+
+    /// Check a hex-encoded proof against the identity key the user_id is bound to
+    pub fn verify(self, user_id: &str, identity_key: &[u8; 32], proof_hex: &str) -> bool {
+        let Ok(proof) = hex::decode(proof_hex) else {
+            return false;
+        };
+
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*identity_key));
+        // A low-order identity key yields a predictable secret anyone could compute
+        if !shared.was_contributory() {
+            return false;
+        }
+
+        proof_mac(shared.as_bytes(), &self.nonce, user_id)
+            .verify_slice(&proof)
+            .is_ok()
+    }
+}
+
+impl Default for Challenge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compute the proof a client sends for a challenge (exposed for tests and clients)
+pub fn compute_proof(shared_secret: &[u8; 32], nonce: &[u8], user_id: &str) -> Vec<u8> {
+    proof_mac(shared_secret, nonce, user_id)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+fn proof_mac(shared_secret: &[u8; 32], nonce: &[u8], user_id: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(shared_secret).expect("HMAC accepts any key length");
+    mac.update(PROOF_LABEL);
+    mac.update(nonce);
+    mac.update(user_id.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::StaticSecret;
+
+    /// Answer a challenge the way the desktop client does
+    fn answer(challenge: &WsMessage, identity: &StaticSecret, user_id: &str) -> String {
+        let WsMessage::AuthChallenge { nonce, server_key } = challenge else {
+            panic!("Expected AuthChallenge");
+        };
+        let server_key = pulse_protocol::decode_key(server_key).unwrap();
+        let shared = identity.diffie_hellman(&PublicKey::from(server_key));
+        hex::encode(compute_proof(
+            shared.as_bytes(),
+            &hex::decode(nonce).unwrap(),
+            user_id,
+        ))
+    }
+
+    #[test]
+    fn test_valid_proof_verifies() {
+        let identity = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&identity);
+
+        let challenge = Challenge::new();
+        let proof = answer(&challenge.to_message(), &identity, "alice");
+        assert!(challenge.verify("alice", public.as_bytes(), &proof));
+    }
+
+    #[test]
+    fn test_proof_from_wrong_key_rejected() {
+        let identity = StaticSecret::random_from_rng(OsRng);
+        let impostor = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&identity);
+
+        let challenge = Challenge::new();
+        let proof = answer(&challenge.to_message(), &impostor, "alice");
+        assert!(!challenge.verify("alice", public.as_bytes(), &proof));
+    }
+
+    #[test]
+    fn test_proof_bound_to_user_id() {
+        let identity = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&identity);
+
+        let challenge = Challenge::new();
+        let proof = answer(&challenge.to_message(), &identity, "alice");
+        assert!(!challenge.verify("mallory", public.as_bytes(), &proof));
+    }
+
+    #[test]
+    fn test_low_order_key_rejected() {
+        // The all-zero point is low order; the shared secret would be all zeros
+        let challenge = Challenge::new();
+        let WsMessage::AuthChallenge { nonce, .. } = challenge.to_message() else {
+            panic!("Expected AuthChallenge");
+        };
+        let forged = compute_proof(&[0u8; 32], &hex::decode(nonce).unwrap(), "alice");
+        assert!(!challenge.verify("alice", &[0u8; 32], &hex::encode(forged)));
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use pulse_protocol::WsMessage;
+
+/// Call signaling settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallConfig {
+    /// How long an invite may ring unanswered before the server ends the call
+    pub ring_timeout: Duration,
+}
+
+impl Default for CallConfig {
+    fn default() -> Self {
+        Self {
+            ring_timeout: Duration::from_secs(45),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CallState {
+    /// Invited, not yet answered
+    Ringing,
+    /// Accepted by the callee
+    Active,
+}
+
+/// A call the relay knows about
+#[derive(Debug, Clone)]
+pub(crate) struct Call {
+    pub call_id: String,
+    pub caller: String,
+    pub callee: String,
+    /// "video" or "audio", as given in the invite
+    pub kind: String,
+    pub state: CallState,
+    /// Connection the invite came from
+    pub caller_connection: u64,
+    /// Connection the call was accepted on (None while ringing)
+    pub callee_connection: Option<u64>,
+    pub invited_at: Instant,
+    /// Unix millis when the invite arrived
+    pub placed_at: i64,
+}
+
+impl Call {
+    /// The other participant
+    pub fn peer_of(&self, user_id: &str) -> &str {
+        if user_id == self.caller {
+            &self.callee
+        } else {
+            &self.caller
+        }
+    }
+
+    /// `missed_call` frame telling the callee about this call
+    pub fn missed_call(&self) -> WsMessage {
+        missed_call(
+            &self.call_id,
+            &self.caller,
+            &self.callee,
+            &self.kind,
+            self.placed_at,
+        )
+    }
+
+    fn is_between(&self, user_id: &str, other: &str) -> bool {
+        (self.caller == user_id && self.callee == other)
+            || (self.callee == user_id && self.caller == other)
+    }
+}
+
+/// `missed_call` frame for a call from `caller` that `callee` did not pick up
+pub(crate) fn missed_call(
+    call_id: &str,
+    caller: &str,
+    callee: &str,
+    kind: &str,
+    timestamp: i64,
+) -> WsMessage {
+    WsMessage::MissedCall {
+        call_id: call_id.to_string(),
+        from_user_id: caller.to_string(),
+        to_user_id: callee.to_string(),
+        kind: kind.to_string(),
+        timestamp,
+    }
+}
+
+/// What to do with a call signaling frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CallDecision {
+    /// Forward the frame to the other participant
+    Relay,
+    /// The invited user is already in a call
+    Busy,
+    /// The frame belongs to a call that has already ended; drop it quietly
+    Stale,
+    /// The frame does not fit the call's state
+    Invalid(&'static str),
+}
+
+#[derive(Default)]
+struct Calls {
+    calls: HashMap<String, Call>,
+    /// The call each participant is in (a user is in at most one)
+    by_user: HashMap<String, String>,
+}
+
+impl Calls {
+    fn remove(&mut self, call_id: &str) -> Option<Call> {
+        let call = self.calls.remove(call_id)?;
+        self.by_user.remove(&call.caller);
+        self.by_user.remove(&call.callee);
+        Some(call)
+    }
+}
+
+/// Ringing and active calls, keyed by call_id
+#[derive(Default)]
+pub(crate) struct CallRegistry {
+    inner: Mutex<Calls>,
+}
+
+impl CallRegistry {
+    /// Check a call signaling frame from `user_id` against the call it belongs to
+    /// and apply the transition it makes. Frames other than call signaling are relayed.
+    pub fn apply(&self, user_id: &str, connection_id: u64, msg: &WsMessage) -> CallDecision {
+        let mut inner = self.inner.lock().unwrap();
+        match msg {
+            WsMessage::CallInvite {
+                call_id,
+                to_user_id,
+                kind,
+                ..
+            } => {
+                if call_id.is_empty() || inner.calls.contains_key(call_id) {
+                    return CallDecision::Invalid("Invalid call id");
+                }
+                if to_user_id == user_id {
+                    return CallDecision::Invalid("Cannot call yourself");
+                }
+                if inner.by_user.contains_key(user_id) {
+                    return CallDecision::Invalid("Already in a call");
+                }
+                if inner.by_user.contains_key(to_user_id) {
+                    return CallDecision::Busy;
+                }
+                let call = Call {
+                    call_id: call_id.clone(),
+                    caller: user_id.to_string(),
+                    callee: to_user_id.clone(),
+                    kind: kind.clone(),
+                    state: CallState::Ringing,
+                    caller_connection: connection_id,
+                    callee_connection: None,
+                    invited_at: Instant::now(),
+                    placed_at: chrono::Utc::now().timestamp_millis(),
+                };
+                inner.by_user.insert(call.caller.clone(), call_id.clone());
+                inner.by_user.insert(call.callee.clone(), call_id.clone());
+                inner.calls.insert(call_id.clone(), call);
+                CallDecision::Relay
+            }
+            WsMessage::CallRinging {
+                call_id,
+                to_user_id,
+                ..
+            }
+            | WsMessage::CallAccept {
+                call_id,
+                to_user_id,
+                ..
+            }
+            | WsMessage::CallReject {
+                call_id,
+                to_user_id,
+                ..
+            } => {
+                let Some(call) = inner.calls.get_mut(call_id) else {
+                    return CallDecision::Stale;
+                };
+                if call.callee != user_id || call.caller != *to_user_id {
+                    return CallDecision::Invalid("Only the invited user can answer a call");
+                }
+                if call.state != CallState::Ringing {
+                    return CallDecision::Invalid("Call already answered");
+                }
+                match msg {
+                    WsMessage::CallAccept { .. } => {
+                        call.state = CallState::Active;
+                        call.callee_connection = Some(connection_id);
+                    }
+                    WsMessage::CallReject { .. } => {
+                        inner.remove(call_id);
+                    }
+                    _ => {}
+                }
+                CallDecision::Relay
+            }
+            WsMessage::CallHangup {
+                call_id,
+                to_user_id,
+                ..
+            } => {
+                let Some(call) = inner.calls.get(call_id) else {
+                    return CallDecision::Stale;
+                };
+                if !call.is_between(user_id, to_user_id) {
+                    return CallDecision::Invalid("Not a participant in this call");
+                }
+                inner.remove(call_id);
+                CallDecision::Relay
+            }
+            WsMessage::RtcOffer {
+                call_id,
+                to_user_id,
+                ..
+            }
+            | WsMessage::RtcAnswer {
+                call_id,
+                to_user_id,
+                ..
+            }
+            | WsMessage::RtcIceCandidate {
+                call_id,
+                to_user_id,
+                ..
+            } => match inner.calls.get(call_id) {
+                // Trickled candidates can still arrive after a hangup
+                None => CallDecision::Stale,
+                Some(call) if !call.is_between(user_id, to_user_id) => {
+                    CallDecision::Invalid("Not a participant in this call")
+                }
+                Some(_) => CallDecision::Relay,
+            },
+            _ => CallDecision::Relay,
+        }
+    }
+
+    /// Forget a call without a transition (e.g. its invite could not be delivered)
+    pub fn end(&self, call_id: &str) -> Option<Call> {
+        self.inner.lock().unwrap().remove(call_id)
+    }
+
+    /// Number of ringing and active calls
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().calls.len()
+    }
+
+    /// Whether a user is ringing or in an active call
+    pub fn in_call(&self, user_id: &str) -> bool {
+        self.inner.lock().unwrap().by_user.contains_key(user_id)
+    }
+
+    /// End the call a closed connection was carrying: one it placed or accepted, or
+    /// an invite ringing for its user if none of the user's connections are left
+    pub fn drop_connection(
+        &self,
+        user_id: &str,
+        connection_id: u64,
+        still_online: bool,
+    ) -> Option<Call> {
+        let mut inner = self.inner.lock().unwrap();
+        let call_id = inner.by_user.get(user_id)?.clone();
+        let ended = inner.calls.get(&call_id).is_some_and(|call| {
+            if call.caller == user_id {
+                call.caller_connection == connection_id
+            } else {
+                match call.callee_connection {
+                    Some(answered_on) => answered_on == connection_id,
+                    None => !still_online,
+                }
+            }
+        });
+        if ended {
+            inner.remove(&call_id)
+        } else {
+            None
+        }
+    }
+
+    /// End the invites that have been ringing for longer than `timeout`
+    pub fn expire(&self, timeout: Duration) -> Vec<Call> {
+        self.expire_at(timeout, Instant::now())
+    }
+
+    fn expire_at(&self, timeout: Duration, now: Instant) -> Vec<Call> {
+        let mut inner = self.inner.lock().unwrap();
+        let expired: Vec<String> = inner
+            .calls
+            .values()
+            .filter(|call| {
+                call.state == CallState::Ringing && now.duration_since(call.invited_at) > timeout
+            })
+            .map(|call| call.call_id.clone())
+            .collect();
+        expired
+            .iter()
+            .filter_map(|call_id| inner.remove(call_id))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite(call_id: &str, from: &str, to: &str) -> WsMessage {
+        WsMessage::CallInvite {
+            call_id: call_id.to_string(),
+            from_user_id: from.to_string(),
+            to_user_id: to.to_string(),
+            kind: "video".to_string(),
+        }
+    }
+
+    fn accept(call_id: &str, from: &str, to: &str) -> WsMessage {
+        WsMessage::CallAccept {
+            call_id: call_id.to_string(),
+            from_user_id: from.to_string(),
+            to_user_id: to.to_string(),
+        }
+    }
+
+    fn hangup(call_id: &str, from: &str, to: &str) -> WsMessage {
+        WsMessage::CallHangup {
+            call_id: call_id.to_string(),
+            from_user_id: from.to_string(),
+            to_user_id: to.to_string(),
+        }
+    }
+
+    fn ice(call_id: &str, from: &str, to: &str) -> WsMessage {
+        WsMessage::RtcIceCandidate {
+            call_id: call_id.to_string(),
+            from_user_id: from.to_string(),
+            to_user_id: to.to_string(),
+            candidate: "{}".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_call_lifecycle() {
+        let calls = CallRegistry::default();
+        assert_eq!(
+            calls.apply("alice", 1, &invite("c1", "alice", "bob")),
+            CallDecision::Relay
+        );
+        assert!(calls.in_call("alice") && calls.in_call("bob"));
+        assert_eq!(
+            calls.apply("bob", 2, &accept("c1", "bob", "alice")),
+            CallDecision::Relay
+        );
+        assert_eq!(
+            calls.apply("alice", 1, &ice("c1", "alice", "bob")),
+            CallDecision::Relay
+        );
+        assert_eq!(
+            calls.apply("bob", 2, &hangup("c1", "bob", "alice")),
+            CallDecision::Relay
+        );
+        assert!(!calls.in_call("alice") && !calls.in_call("bob"));
+
+        // Late candidates and a crossing hangup are dropped without an error
+        assert_eq!(
+            calls.apply("alice", 1, &ice("c1", "alice", "bob")),
+            CallDecision::Stale
+        );
+        assert_eq!(
+            calls.apply("alice", 1, &hangup("c1", "alice", "bob")),
+            CallDecision::Stale
+        );
+    }
+
+    #[test]
+    fn test_second_caller_gets_busy() {
+        let calls = CallRegistry::default();
+        calls.apply("alice", 1, &invite("c1", "alice", "bob"));
+        assert_eq!(
+            calls.apply("carol", 3, &invite("c2", "carol", "bob")),
+            CallDecision::Busy
+        );
+        assert_eq!(
+            calls.apply("carol", 3, &invite("c3", "carol", "alice")),
+            CallDecision::Busy
+        );
+        // The busy invite left no trace
+        assert!(!calls.in_call("carol"));
+    }
+
+    #[test]
+    fn test_invalid_transitions_rejected() {
+        let calls = CallRegistry::default();
+        assert!(matches!(
+            calls.apply("alice", 1, &invite("c0", "alice", "alice")),
+            CallDecision::Invalid(_)
+        ));
+        calls.apply("alice", 1, &invite("c1", "alice", "bob"));
+        // Duplicate call id, the caller answering its own call, a stranger hanging it up
+        assert!(matches!(
+            calls.apply("carol", 3, &invite("c1", "carol", "dave")),
+            CallDecision::Invalid(_)
+        ));
+        assert!(matches!(
+            calls.apply("alice", 1, &accept("c1", "alice", "bob")),
+            CallDecision::Invalid(_)
+        ));
+        assert!(matches!(
+            calls.apply("carol", 3, &hangup("c1", "carol", "bob")),
+            CallDecision::Invalid(_)
+        ));
+        assert!(matches!(
+            calls.apply("alice", 1, &invite("c2", "alice", "carol")),
+            CallDecision::Invalid(_)
+        ));
+
+        calls.apply("bob", 2, &accept("c1", "bob", "alice"));
+        assert!(matches!(
+            calls.apply("bob", 2, &accept("c1", "bob", "alice")),
+            CallDecision::Invalid(_)
+        ));
+        assert!(calls.in_call("alice"));
+    }
+
+    #[test]
+    fn test_dropped_connection_ends_its_calls() {
+        let calls = CallRegistry::default();
+        calls.apply("alice", 1, &invite("c1", "alice", "bob"));
+        calls.apply("bob", 2, &accept("c1", "bob", "alice"));
+
+        // Another of Bob's connections closing leaves the call up
+        assert!(calls.drop_connection("bob", 5, true).is_none());
+        let ended = calls.drop_connection("bob", 2, true).unwrap();
+        assert_eq!(ended.peer_of("bob"), "alice");
+        assert!(!calls.in_call("alice"));
+    }
+
+    #[test]
+    fn test_ringing_callee_drops_only_when_fully_offline() {
+        let calls = CallRegistry::default();
+        calls.apply("alice", 1, &invite("c1", "alice", "bob"));
+        assert!(calls.drop_connection("bob", 2, true).is_none());
+        assert!(calls.drop_connection("bob", 3, false).is_some());
+    }
+
+    #[test]
+    fn test_unanswered_invites_expire() {
+        let calls = CallRegistry::default();
+        calls.apply("alice", 1, &invite("c1", "alice", "bob"));
+        calls.apply("carol", 3, &invite("c2", "carol", "dave"));
+        calls.apply("dave", 4, &accept("c2", "dave", "carol"));
+
+        let timeout = Duration::from_secs(45);
+        assert!(calls.expire(timeout).is_empty());
+        let expired = calls.expire_at(timeout, Instant::now() + Duration::from_secs(46));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].call_id, "c1");
+        // Answered calls do not time out
+        assert!(calls.in_call("carol"));
+    }
+}
@@ -0,0 +1,873 @@
+//! Server configuration.
+//!
+//! Settings come from three layers, each overriding the last: built-in
+//! defaults, an optional TOML file passed with `--config`, and environment
+//! variables. The merged result is validated once at startup so a bad value
+//! stops the server instead of surfacing later as odd behavior.
+//!
+//! The [`RuntimeConfig`] part can be swapped on a running server (SIGHUP);
+//! everything else (bind address, data directory, TLS paths, log format) needs
+//! a restart. The certificate files themselves are re-read on SIGHUP.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::auth::AuthConfig;
+use crate::calls::CallConfig;
+use crate::heartbeat::HeartbeatConfig;
+use crate::limits::LimitsConfig;
+use crate::outbound::OutboundConfig;
+use crate::queue::QueueConfig;
+use crate::rate_limit::{BucketConfig, RateLimitConfig};
+use crate::shutdown::ShutdownConfig;
+
+/// Address the server binds to when neither the file nor the environment sets one
+pub const DEFAULT_BIND: &str = "0.0.0.0:9001";
+
+/// Address the admin API binds to when neither the file nor the environment sets one
+pub const DEFAULT_ADMIN_BIND: &str = "127.0.0.1:9002";
+
+/// Complete server configuration as read from the TOML file
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server: ServerSection,
+    /// TLS certificate and key; plain `ws://` when absent
+    pub tls: Option<TlsSection>,
+    pub auth: AuthSection,
+    pub queue: QueueSection,
+    pub outbound: OutboundSection,
+    pub rate_limits: RateLimitSection,
+    pub heartbeat: HeartbeatSection,
+    pub calls: CallSection,
+    pub shutdown: ShutdownSection,
+    pub limits: LimitsSection,
+    pub log: LogSection,
+}
+
+/// `[server]`: where to listen and where to keep state (restart required)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub bind: SocketAddr,
+    /// Directory for `pulse-server.db`; in-memory when unset
+    pub data_dir: Option<PathBuf>,
+    /// Plain-HTTP address serving `/metrics`, `/healthz` and `/readyz`; off when unset
+    pub metrics_bind: Option<SocketAddr>,
+    /// Plain-HTTP address serving the `/admin` API; must be a loopback address
+    pub admin_bind: SocketAddr,
+    /// Bearer token for the `/admin` API on `admin_bind`; the API is off when unset
+    pub admin_token: Option<String>,
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        Self {
+            bind: DEFAULT_BIND.parse().expect("default bind address"),
+            data_dir: None,
+            metrics_bind: None,
+            admin_bind: DEFAULT_ADMIN_BIND.parse().expect("default admin address"),
+            admin_token: None,
+        }
+    }
+}
+
+/// `[tls]`: PEM certificate chain and private key (paths need a restart, contents reload)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// `[auth]`: access token, identity requirement and handshake timeout
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    pub access_token: Option<String>,
+    pub require_identity: bool,
+    pub timeout_secs: u64,
+}
+
+impl Default for AuthSection {
+    fn default() -> Self {
+        let auth = AuthConfig::default();
+        Self {
+            access_token: auth.access_token,
+            require_identity: auth.require_identity,
+            timeout_secs: auth.timeout.as_secs(),
+        }
+    }
+}
+
+/// `[queue]`: offline queue TTL and per-device quotas
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueSection {
+    pub ttl_secs: u64,
+    pub max_messages: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for QueueSection {
+    fn default() -> Self {
+        let queue = QueueConfig::default();
+        Self {
+            ttl_secs: queue.ttl.as_secs(),
+            max_messages: queue.max_messages,
+            max_bytes: queue.max_bytes,
+        }
+    }
+}
+
+/// `[outbound]`: per-connection send buffer and slow-client policy
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundSection {
+    pub queue_depth: usize,
+    pub max_overflows: u32,
+}
+
+impl Default for OutboundSection {
+    fn default() -> Self {
+        let outbound = OutboundConfig::default();
+        Self {
+            queue_depth: outbound.queue_depth,
+            max_overflows: outbound.max_overflows,
+        }
+    }
+}
+
+/// `[calls]`: call signaling tracked by the relay
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CallSection {
+    pub ring_timeout_secs: u64,
+}
+
+impl Default for CallSection {
+    fn default() -> Self {
+        Self {
+            ring_timeout_secs: CallConfig::default().ring_timeout.as_secs(),
+        }
+    }
+}
+
+/// `[shutdown]`: how long to wait for connections on exit and when clients should return
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownSection {
+    pub grace_period_secs: u64,
+    pub reconnect_delay_secs: u64,
+}
+
+impl Default for ShutdownSection {
+    fn default() -> Self {
+        let shutdown = ShutdownConfig::default();
+        Self {
+            grace_period_secs: shutdown.grace_period.as_secs(),
+            reconnect_delay_secs: shutdown.reconnect_delay.as_secs(),
+        }
+    }
+}
+
+/// `[limits]`: largest frame, message and field sizes accepted from clients, in bytes
+/// (new connections pick up frame and message limits)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub max_frame_bytes: usize,
+    pub max_message_bytes: usize,
+    pub max_content_bytes: usize,
+    pub max_sdp_bytes: usize,
+    pub max_candidate_bytes: usize,
+    pub max_avatar_bytes: usize,
+}
+
+impl Default for LimitsSection {
+    fn default() -> Self {
+        let limits = LimitsConfig::default();
+        Self {
+            max_frame_bytes: limits.max_frame_bytes,
+            max_message_bytes: limits.max_message_bytes,
+            max_content_bytes: limits.max_content_bytes,
+            max_sdp_bytes: limits.max_sdp_bytes,
+            max_candidate_bytes: limits.max_candidate_bytes,
+            max_avatar_bytes: limits.max_avatar_bytes,
+        }
+    }
+}
+
+/// `[rate_limits]`: one token bucket per message class plus the disconnect threshold
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSection {
+    pub chat: BucketSection,
+    pub typing: BucketSection,
+    pub presence: BucketSection,
+    pub call: BucketSection,
+    pub profile: BucketSection,
+    pub keys: BucketSection,
+    pub max_violations: u32,
+    pub violation_window_secs: u64,
+}
+
+impl Default for RateLimitSection {
+    fn default() -> Self {
+        let limits = RateLimitConfig::default();
+        Self {
+            chat: limits.chat.into(),
+            typing: limits.typing.into(),
+            presence: limits.presence.into(),
+            call: limits.call.into(),
+            profile: limits.profile.into(),
+            keys: limits.keys.into(),
+            max_violations: limits.max_violations,
+            violation_window_secs: limits.violation_window.as_secs(),
+        }
+    }
+}
+
+/// `[rate_limits.<class>]`: both fields are required when the table is present
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketSection {
+    pub capacity: u32,
+    pub refill_per_sec: f64,
+}
+
+impl From<BucketConfig> for BucketSection {
+    fn from(bucket: BucketConfig) -> Self {
+        Self {
+            capacity: bucket.capacity,
+            refill_per_sec: bucket.refill_per_sec,
+        }
+    }
+}
+
+/// `[heartbeat]`: ping interval and idle timeout (new connections pick up changes)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatSection {
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for HeartbeatSection {
+    fn default() -> Self {
+        let heartbeat = HeartbeatConfig::default();
+        Self {
+            interval_secs: heartbeat.interval.as_secs(),
+            timeout_secs: heartbeat.timeout.as_secs(),
+        }
+    }
+}
+
+/// `[log]`: filter directives (reloadable) and output format (restart required)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    /// `tracing` filter, e.g. `info` or `pulse_server=debug,info`
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogSection {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+/// How log lines are written to stdout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => Err(format!("expected `text` or `json`, got `{}`", other)),
+        }
+    }
+}
+
+/// The settings a running server can swap without restarting
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuntimeConfig {
+    pub auth: AuthConfig,
+    pub rate_limits: RateLimitConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub outbound: OutboundConfig,
+    pub queue: QueueConfig,
+    pub calls: CallConfig,
+    pub shutdown: ShutdownConfig,
+    pub limits: LimitsConfig,
+}
+
+impl ServerConfig {
+    /// Build the startup configuration: defaults, then `path` (if any), then the
+    /// process environment, validated as a whole
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let mut config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?
+            }
+            None => Self::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse a TOML document; unknown keys are rejected so typos are not silently ignored
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+
+    /// Override file values with environment variables looked up through `env`
+    pub fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        // PULSE_SERVER_ADDR takes priority, then PORT (Railway) on the configured host
+        if let Some(addr) = env("PULSE_SERVER_ADDR") {
+            self.server.bind = parse_env("PULSE_SERVER_ADDR", &addr)?;
+        } else if let Some(port) = env("PORT") {
+            let port = parse_env("PORT", &port)?;
+            self.server.bind.set_port(port);
+        }
+        if let Some(dir) = env("PULSE_DATA_DIR") {
+            self.server.data_dir = (!dir.is_empty()).then(|| PathBuf::from(dir));
+        }
+        if let Some(addr) = env("PULSE_METRICS_ADDR") {
+            self.server.metrics_bind = match addr.as_str() {
+                "" => None,
+                addr => Some(parse_env("PULSE_METRICS_ADDR", addr)?),
+            };
+        }
+        if let Some(addr) = env("PULSE_ADMIN_ADDR") {
+            self.server.admin_bind = parse_env("PULSE_ADMIN_ADDR", &addr)?;
+        }
+        if let Some(token) = env("PULSE_ADMIN_TOKEN") {
+            self.server.admin_token = (!token.is_empty()).then_some(token);
+        }
+        match (env("PULSE_TLS_CERT"), env("PULSE_TLS_KEY")) {
+            (Some(cert), Some(key)) => {
+                self.tls = Some(TlsSection {
+                    cert_path: PathBuf::from(cert),
+                    key_path: PathBuf::from(key),
+                });
+            }
+            (None, None) => {}
+            _ => return Err("PULSE_TLS_CERT and PULSE_TLS_KEY must be set together".to_string()),
+        }
+        if let Some(token) = env("PULSE_ACCESS_TOKEN") {
+            self.auth.access_token = (!token.is_empty()).then_some(token);
+        }
+        if let Some(value) = env("PULSE_REQUIRE_IDENTITY") {
+            self.auth.require_identity = parse_env_bool("PULSE_REQUIRE_IDENTITY", &value)?;
+        }
+        if let Some(secs) = env("PULSE_AUTH_TIMEOUT_SECS") {
+            self.auth.timeout_secs = parse_env("PULSE_AUTH_TIMEOUT_SECS", &secs)?;
+        }
+        if let Some(secs) = env("PULSE_HEARTBEAT_INTERVAL_SECS") {
+            self.heartbeat.interval_secs = parse_env("PULSE_HEARTBEAT_INTERVAL_SECS", &secs)?;
+        }
+        if let Some(secs) = env("PULSE_HEARTBEAT_TIMEOUT_SECS") {
+            self.heartbeat.timeout_secs = parse_env("PULSE_HEARTBEAT_TIMEOUT_SECS", &secs)?;
+        }
+        if let Some(depth) = env("PULSE_OUTBOUND_QUEUE_DEPTH") {
+            self.outbound.queue_depth = parse_env("PULSE_OUTBOUND_QUEUE_DEPTH", &depth)?;
+        }
+        if let Some(secs) = env("PULSE_QUEUE_TTL_SECS") {
+            self.queue.ttl_secs = parse_env("PULSE_QUEUE_TTL_SECS", &secs)?;
+        }
+        if let Some(count) = env("PULSE_QUEUE_MAX_MESSAGES") {
+            self.queue.max_messages = parse_env("PULSE_QUEUE_MAX_MESSAGES", &count)?;
+        }
+        if let Some(bytes) = env("PULSE_QUEUE_MAX_BYTES") {
+            self.queue.max_bytes = parse_env("PULSE_QUEUE_MAX_BYTES", &bytes)?;
+        }
+        if let Some(secs) = env("PULSE_CALL_RING_TIMEOUT_SECS") {
+            self.calls.ring_timeout_secs = parse_env("PULSE_CALL_RING_TIMEOUT_SECS", &secs)?;
+        }
+        if let Some(secs) = env("PULSE_SHUTDOWN_GRACE_SECS") {
+            self.shutdown.grace_period_secs = parse_env("PULSE_SHUTDOWN_GRACE_SECS", &secs)?;
+        }
+        if let Some(secs) = env("PULSE_RECONNECT_DELAY_SECS") {
+            self.shutdown.reconnect_delay_secs = parse_env("PULSE_RECONNECT_DELAY_SECS", &secs)?;
+        }
+        if let Some(bytes) = env("PULSE_MAX_FRAME_BYTES") {
+            self.limits.max_frame_bytes = parse_env("PULSE_MAX_FRAME_BYTES", &bytes)?;
+        }
+        if let Some(bytes) = env("PULSE_MAX_MESSAGE_BYTES") {
+            self.limits.max_message_bytes = parse_env("PULSE_MAX_MESSAGE_BYTES", &bytes)?;
+        }
+        if let Some(bytes) = env("PULSE_MAX_CONTENT_BYTES") {
+            self.limits.max_content_bytes = parse_env("PULSE_MAX_CONTENT_BYTES", &bytes)?;
+        }
+        if let Some(bytes) = env("PULSE_MAX_SDP_BYTES") {
+            self.limits.max_sdp_bytes = parse_env("PULSE_MAX_SDP_BYTES", &bytes)?;
+        }
+        if let Some(bytes) = env("PULSE_MAX_CANDIDATE_BYTES") {
+            self.limits.max_candidate_bytes = parse_env("PULSE_MAX_CANDIDATE_BYTES", &bytes)?;
+        }
+        if let Some(bytes) = env("PULSE_MAX_AVATAR_BYTES") {
+            self.limits.max_avatar_bytes = parse_env("PULSE_MAX_AVATAR_BYTES", &bytes)?;
+        }
+        if let Some(filter) = env("RUST_LOG") {
+            self.log.level = filter;
+        }
+        if let Some(format) = env("PULSE_LOG_FORMAT") {
+            self.log.format = parse_env("PULSE_LOG_FORMAT", &format)?;
+        }
+        Ok(())
+    }
+
+    /// Check every setting, reporting all problems at once
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        if let Some(tls) = &self.tls {
+            for (key, path) in [
+                ("tls.cert_path", &tls.cert_path),
+                ("tls.key_path", &tls.key_path),
+            ] {
+                if !path.is_file() {
+                    errors.push(format!(
+                        "{}: {} is not a readable file",
+                        key,
+                        path.display()
+                    ));
+                }
+            }
+        }
+
+        if self.server.admin_token.as_deref() == Some("") {
+            errors.push(
+                "server.admin_token must not be empty (omit it to disable the admin API)"
+                    .to_string(),
+            );
+        }
+        // The admin API is plain HTTP carrying a bearer token
+        if !self.server.admin_bind.ip().is_loopback() {
+            errors.push(format!(
+                "server.admin_bind ({}) must be a loopback address",
+                self.server.admin_bind
+            ));
+        }
+        if self.auth.access_token.as_deref() == Some("") {
+            errors.push(
+                "auth.access_token must not be empty (omit it to disable the token check)"
+                    .to_string(),
+            );
+        }
+        require_positive(&mut errors, "auth.timeout_secs", self.auth.timeout_secs);
+
+        require_positive(&mut errors, "queue.ttl_secs", self.queue.ttl_secs);
+        require_positive(
+            &mut errors,
+            "queue.max_messages",
+            self.queue.max_messages as u64,
+        );
+        require_positive(&mut errors, "queue.max_bytes", self.queue.max_bytes as u64);
+
+        require_positive(
+            &mut errors,
+            "outbound.queue_depth",
+            self.outbound.queue_depth as u64,
+        );
+        require_positive(
+            &mut errors,
+            "outbound.max_overflows",
+            self.outbound.max_overflows as u64,
+        );
+
+        require_positive(
+            &mut errors,
+            "calls.ring_timeout_secs",
+            self.calls.ring_timeout_secs,
+        );
+        require_positive(
+            &mut errors,
+            "shutdown.grace_period_secs",
+            self.shutdown.grace_period_secs,
+        );
+
+        let sizes = &self.limits;
+        for (key, bytes) in [
+            ("limits.max_frame_bytes", sizes.max_frame_bytes),
+            ("limits.max_message_bytes", sizes.max_message_bytes),
+            ("limits.max_content_bytes", sizes.max_content_bytes),
+            ("limits.max_sdp_bytes", sizes.max_sdp_bytes),
+            ("limits.max_candidate_bytes", sizes.max_candidate_bytes),
+            ("limits.max_avatar_bytes", sizes.max_avatar_bytes),
+        ] {
+            require_positive(&mut errors, key, bytes as u64);
+        }
+        if sizes.max_frame_bytes > sizes.max_message_bytes {
+            errors.push(format!(
+                "limits.max_frame_bytes ({}) must not exceed limits.max_message_bytes ({})",
+                sizes.max_frame_bytes, sizes.max_message_bytes
+            ));
+        }
+
+        let limits = &self.rate_limits;
+        for (class, bucket) in [
+            ("chat", limits.chat),
+            ("typing", limits.typing),
+            ("presence", limits.presence),
+            ("call", limits.call),
+            ("profile", limits.profile),
+            ("keys", limits.keys),
+        ] {
+            if bucket.capacity == 0 {
+                errors.push(format!(
+                    "rate_limits.{}.capacity must be greater than 0",
+                    class
+                ));
+            }
+            if !bucket.refill_per_sec.is_finite() || bucket.refill_per_sec < 0.0 {
+                errors.push(format!(
+                    "rate_limits.{}.refill_per_sec must be a non-negative number, got {}",
+                    class, bucket.refill_per_sec
+                ));
+            }
+        }
+        require_positive(
+            &mut errors,
+            "rate_limits.violation_window_secs",
+            limits.violation_window_secs,
+        );
+
+        require_positive(
+            &mut errors,
+            "heartbeat.interval_secs",
+            self.heartbeat.interval_secs,
+        );
+        if self.heartbeat.timeout_secs <= self.heartbeat.interval_secs {
+            errors.push(format!(
+                "heartbeat.timeout_secs ({}) must be greater than heartbeat.interval_secs ({})",
+                self.heartbeat.timeout_secs, self.heartbeat.interval_secs
+            ));
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.log.level) {
+            errors.push(format!(
+                "log.level: invalid filter `{}`: {}",
+                self.log.level, e
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Invalid configuration:\n  - {}",
+                errors.join("\n  - ")
+            ))
+        }
+    }
+
+    /// Settings applied to connections, swappable at runtime
+    pub fn runtime(&self) -> RuntimeConfig {
+        let limits = &self.rate_limits;
+        RuntimeConfig {
+            auth: AuthConfig {
+                access_token: self.auth.access_token.clone(),
+                require_identity: self.auth.require_identity,
+                timeout: Duration::from_secs(self.auth.timeout_secs),
+            },
+            rate_limits: RateLimitConfig {
+                chat: limits.chat.into(),
+                typing: limits.typing.into(),
+                presence: limits.presence.into(),
+                call: limits.call.into(),
+                profile: limits.profile.into(),
+                keys: limits.keys.into(),
+                max_violations: limits.max_violations,
+                violation_window: Duration::from_secs(limits.violation_window_secs),
+            },
+            heartbeat: HeartbeatConfig {
+                interval: Duration::from_secs(self.heartbeat.interval_secs),
+                timeout: Duration::from_secs(self.heartbeat.timeout_secs),
+            },
+            outbound: OutboundConfig {
+                queue_depth: self.outbound.queue_depth,
+                max_overflows: self.outbound.max_overflows,
+            },
+            queue: QueueConfig {
+                ttl: Duration::from_secs(self.queue.ttl_secs),
+                max_messages: self.queue.max_messages,
+                max_bytes: self.queue.max_bytes,
+            },
+            calls: CallConfig {
+                ring_timeout: Duration::from_secs(self.calls.ring_timeout_secs),
+            },
+            shutdown: ShutdownConfig {
+                grace_period: Duration::from_secs(self.shutdown.grace_period_secs),
+                reconnect_delay: Duration::from_secs(self.shutdown.reconnect_delay_secs),
+            },
+            limits: LimitsConfig {
+                max_frame_bytes: self.limits.max_frame_bytes,
+                max_message_bytes: self.limits.max_message_bytes,
+                max_content_bytes: self.limits.max_content_bytes,
+                max_sdp_bytes: self.limits.max_sdp_bytes,
+                max_candidate_bytes: self.limits.max_candidate_bytes,
+                max_avatar_bytes: self.limits.max_avatar_bytes,
+            },
+        }
+    }
+
+    /// Keys that differ from `running` but only take effect after a restart
+    pub fn restart_required(&self, running: &ServerConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.server.bind != running.server.bind {
+            changed.push("server.bind");
+        }
+        if self.server.data_dir != running.server.data_dir {
+            changed.push("server.data_dir");
+        }
+        if self.server.metrics_bind != running.server.metrics_bind {
+            changed.push("server.metrics_bind");
+        }
+        if self.server.admin_bind != running.server.admin_bind {
+            changed.push("server.admin_bind");
+        }
+        if self.server.admin_token != running.server.admin_token {
+            changed.push("server.admin_token");
+        }
+        if self.tls != running.tls {
+            changed.push("tls");
+        }
+        if self.log.format != running.log.format {
+            changed.push("log.format");
+        }
+        changed
+    }
+}
+
+impl From<BucketSection> for BucketConfig {
+    fn from(bucket: BucketSection) -> Self {
+        BucketConfig::new(bucket.capacity, bucket.refill_per_sec)
+    }
+}
+
+fn parse_env<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("Invalid {}={:?}: {}", name, value, e))
+}
+
+fn parse_env_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(format!(
+            "Invalid {}={:?}: expected true, false, 1 or 0",
+            name, value
+        )),
+    }
+}
+
+fn require_positive(errors: &mut Vec<String>, key: &str, value: u64) {
+    if value == 0 {
+        errors.push(format!("{} must be greater than 0", key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_example_file_matches_defaults() {
+        let config = ServerConfig::parse(include_str!("../pulse-server.example.toml")).unwrap();
+        assert_eq!(config, ServerConfig::default());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.runtime(), RuntimeConfig::default());
+    }
+
+    #[test]
+    fn test_partial_file_keeps_other_defaults() {
+        let config = ServerConfig::parse(
+            r#"
+            [server]
+            bind = "127.0.0.1:9100"
+
+            [rate_limits.chat]
+            capacity = 60
+            refill_per_sec = 10.0
+
+            [log]
+            format = "json"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.bind.to_string(), "127.0.0.1:9100");
+        assert_eq!(config.rate_limits.chat.capacity, 60);
+        assert_eq!(
+            config.rate_limits.typing,
+            RateLimitSection::default().typing
+        );
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.queue, QueueSection::default());
+    }
+
+    #[test]
+    fn test_unknown_keys_rejected() {
+        let err = ServerConfig::parse("[queue]\nmax_mesages = 10\n").unwrap_err();
+        assert!(err.contains("max_mesages"), "{}", err);
+
+        let err = ServerConfig::parse("[rate_limits.chat]\ncapacity = 10\n").unwrap_err();
+        assert!(err.contains("refill_per_sec"), "{}", err);
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let mut config =
+            ServerConfig::parse("[server]\nbind = \"127.0.0.1:9100\"\n[queue]\nttl_secs = 60\n")
+                .unwrap();
+        config
+            .apply_env(env(&[
+                ("PORT", "8080"),
+                ("PULSE_QUEUE_TTL_SECS", "120"),
+                ("PULSE_REQUIRE_IDENTITY", "true"),
+                ("PULSE_ACCESS_TOKEN", ""),
+                ("PULSE_METRICS_ADDR", "127.0.0.1:9090"),
+                ("PULSE_ADMIN_ADDR", "[::1]:9100"),
+                ("PULSE_ADMIN_TOKEN", "s3cret"),
+                ("PULSE_SHUTDOWN_GRACE_SECS", "30"),
+                ("PULSE_MAX_SDP_BYTES", "65536"),
+            ]))
+            .unwrap();
+
+        // PORT only replaces the port of the configured host
+        assert_eq!(config.server.bind.to_string(), "127.0.0.1:8080");
+        assert_eq!(config.queue.ttl_secs, 120);
+        assert!(config.auth.require_identity);
+        assert_eq!(config.auth.access_token, None);
+        assert_eq!(
+            config
+                .server
+                .metrics_bind
+                .map(|addr| addr.to_string())
+                .as_deref(),
+            Some("127.0.0.1:9090")
+        );
+        assert_eq!(config.server.admin_bind.to_string(), "[::1]:9100");
+        assert_eq!(config.server.admin_token.as_deref(), Some("s3cret"));
+        assert_eq!(config.shutdown.grace_period_secs, 30);
+        assert_eq!(config.limits.max_sdp_bytes, 65536);
+
+        config
+            .apply_env(env(&[
+                ("PULSE_SERVER_ADDR", "10.0.0.1:7000"),
+                ("PORT", "1"),
+            ]))
+            .unwrap();
+        assert_eq!(config.server.bind.to_string(), "10.0.0.1:7000");
+
+        config
+            .apply_env(env(&[
+                ("PULSE_TLS_CERT", "cert.pem"),
+                ("PULSE_TLS_KEY", "key.pem"),
+            ]))
+            .unwrap();
+        assert_eq!(config.tls.unwrap().key_path, PathBuf::from("key.pem"));
+    }
+
+    #[test]
+    fn test_invalid_env_fails() {
+        let mut config = ServerConfig::default();
+        let err = config
+            .apply_env(env(&[("PULSE_HEARTBEAT_INTERVAL_SECS", "soon")]))
+            .unwrap_err();
+        assert!(err.contains("PULSE_HEARTBEAT_INTERVAL_SECS"), "{}", err);
+
+        let err = config
+            .apply_env(env(&[("PULSE_LOG_FORMAT", "xml")]))
+            .unwrap_err();
+        assert!(err.contains("PULSE_LOG_FORMAT"), "{}", err);
+
+        let err = config
+            .apply_env(env(&[("PULSE_TLS_CERT", "cert.pem")]))
+            .unwrap_err();
+        assert!(err.contains("PULSE_TLS_KEY"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let mut config = ServerConfig::default();
+        config.heartbeat.interval_secs = 60;
+        config.heartbeat.timeout_secs = 30;
+        config.outbound.queue_depth = 0;
+        config.outbound.max_overflows = 0;
+        config.rate_limits.call.refill_per_sec = -1.0;
+        config.server.admin_bind = "0.0.0.0:9002".parse().unwrap();
+        config.limits.max_frame_bytes = 2 * config.limits.max_message_bytes;
+        config.limits.max_candidate_bytes = 0;
+        config.tls = Some(TlsSection {
+            cert_path: PathBuf::from("/nonexistent/cert.pem"),
+            key_path: PathBuf::from("/nonexistent/key.pem"),
+        });
+
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("heartbeat.timeout_secs (30)"), "{}", err);
+        assert!(err.contains("outbound.queue_depth"), "{}", err);
+        assert!(err.contains("outbound.max_overflows"), "{}", err);
+        assert!(err.contains("rate_limits.call.refill_per_sec"), "{}", err);
+        assert!(err.contains("tls.cert_path"), "{}", err);
+        assert!(err.contains("tls.key_path"), "{}", err);
+        assert!(err.contains("server.admin_bind (0.0.0.0:9002)"), "{}", err);
+        assert!(err.contains("limits.max_frame_bytes"), "{}", err);
+        assert!(err.contains("limits.max_candidate_bytes"), "{}", err);
+    }
+
+    #[test]
+    fn test_restart_required_fields() {
+        let running = ServerConfig::default();
+        let mut reloaded = running.clone();
+        reloaded.queue.max_messages = 10;
+        reloaded.log.level = "debug".to_string();
+        reloaded.limits.max_content_bytes = 1024;
+        assert!(reloaded.restart_required(&running).is_empty());
+
+        reloaded.server.bind.set_port(1);
+        reloaded.log.format = LogFormat::Json;
+        assert_eq!(
+            reloaded.restart_required(&running),
+            vec!["server.bind", "log.format"]
+        );
+    }
+}
@@ -1,43 +1,100 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures_util::{SinkExt, StreamExt};
-use tokio::net::TcpStream;
+use pulse_protocol::{
+    decode_key, is_valid_device_id, min_version_for, AckStatus, AuthErrorCode, Capability,
+    Encoding, ErrorCode, WsMessage, LEGACY_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc;
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
-use tracing::{error, info, warn};
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, error, info, warn};
 
-use crate::messages::WsMessage;
-use crate::state::ServerState;
+use crate::auth::Challenge;
+use crate::calls::{missed_call, CallDecision};
+use crate::heartbeat::Liveness;
+use crate::protocol::Negotiated;
+use crate::rate_limit::{MessageClass, RateDecision};
+use crate::state::{ServerState, DEFAULT_DEVICE_ID};
 
-/// Handle a single WebSocket connection
-pub async fn handle_connection(ws_stream: WebSocketStream<TcpStream>, state: Arc<ServerState>) {
+/// Source of `Session::connection_id`
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// An authenticated connection: who it is, from which device, and what protocol it negotiated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub user_id: String,
+    pub device_id: String,
+    pub protocol: Negotiated,
+    /// Tells this connection apart from the user's others (e.g. for the calls it carries)
+    pub connection_id: u64,
+}
+
+impl Session {
+    /// A session on the user's default device
+    pub fn new(user_id: impl Into<String>, protocol: Negotiated) -> Self {
+        Self {
+            user_id: user_id.into(),
+            device_id: DEFAULT_DEVICE_ID.to_string(),
+            protocol,
+            connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub fn with_device(mut self, device_id: impl Into<String>) -> Self {
+        self.device_id = device_id.into();
+        self
+    }
+}
+
+/// Handle a single WebSocket connection (over plain TCP or TLS)
+pub async fn handle_connection<S>(ws_stream: WebSocketStream<S>, state: Arc<ServerState>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
     // Wait for Connect message to authenticate
-    let user_id = match wait_for_connect(&mut ws_receiver).await {
-        Some(id) => id,
+    let session = match authenticate(&mut ws_sender, &mut ws_receiver, &state).await {
+        Some(session) => session,
         None => {
             warn!("Connection closed before authentication");
             return;
         }
     };
+    let user_id = session.user_id.clone();
+    let device_id = session.device_id.as_str();
+    // Everything after the auth response uses the negotiated encoding
+    let encoding = session.protocol.encoding();
 
-    info!("User connected: {}", user_id);
+    info!(
+        "User connected: {} on device {} (protocol v{}, capabilities {:?})",
+        user_id, device_id, session.protocol.version, session.protocol.capabilities
+    );
 
-    // Create channel for sending messages to this client
-    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    // Create bounded channel for sending messages to this client
+    let outbound = state.outbound();
+    let (tx, mut rx) = mpsc::channel::<String>(outbound.queue_depth);
 
     // Register client
-    state.add_client(user_id.clone(), tx);
+    let channel = state.add_connection(user_id.clone(), device_id, session.protocol.clone(), tx);
 
     // Send auth success response
     let auth_response = WsMessage::AuthResponse {
         success: true,
         message: "Connected to server".to_string(),
+        code: None,
+        protocol_version: Some(session.protocol.version),
+        capabilities: session.protocol.capabilities.clone(),
     };
     match serde_json::to_string(&auth_response) {
         Ok(json) => {
-            if let Err(e) = ws_sender.send(Message::Text(json.into())).await {
+            if let Err(e) = ws_sender.send(Message::Text(json)).await {
                 error!("Failed to send auth response to {}: {}", user_id, e);
             }
         }
@@ -46,203 +103,657 @@ pub async fn handle_connection(ws_stream: WebSocketStream<TcpStream>, state: Arc
         }
     }
 
-    // Broadcast presence to all other clients
+    // Announce to subscribers; the client learns its contacts' presence by subscribing
     let presence = WsMessage::Presence {
         user_id: user_id.clone(),
         is_online: true,
         last_seen: None,
     };
     match serde_json::to_string(&presence) {
-        Ok(json) => state.broadcast(&json, Some(&user_id)),
-        Err(e) => error!("Failed to serialize presence for {}: {}", user_id, e),
-    }
-
-    // Send existing online users to the newly connected client
-    for online_user_id in state.online_users() {
-        if online_user_id != user_id {
-            let existing_presence = WsMessage::Presence {
-                user_id: online_user_id,
-                is_online: true,
-                last_seen: None,
-            };
-            if let Ok(json) = serde_json::to_string(&existing_presence) {
-                state.send_to_user(&user_id, &json);
-            }
+        Ok(json) => {
+            state.send_to_subscribers(&user_id, &json);
         }
+        Err(e) => error!("Failed to serialize presence for {}: {}", user_id, e),
     }
 
-    // Flush pending messages for this user (messages queued while offline)
-    let pending = state.take_pending_messages(&user_id);
-    if !pending.is_empty() {
+    // Flush pending messages for this device (messages queued while it was offline);
+    // whatever does not fit in the queue follows as the queue drains
+    let flushed = state.flush_pending(&user_id, device_id, outbound.queue_depth);
+    if flushed > 0 {
         info!(
-            "Delivering {} pending messages to {}",
-            pending.len(),
-            user_id
+            "Delivering {} pending messages to {} ({})",
+            flushed, user_id, device_id
         );
-        for msg in pending {
-            state.send_to_user(&user_id, &msg);
-        }
     }
 
-    // Spawn task to forward messages from channel to WebSocket
-    let mut send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if ws_sender.send(Message::Text(msg.into())).await.is_err() {
-                break;
-            }
-        }
-    });
-
-    // Process incoming messages
-    // Process incoming messages and monitor send task
-    let user_id_clone = user_id.clone();
-    let state_clone = state.clone();
+    // Forward queued frames to the socket and process incoming frames on one task,
+    // so replies to this connection (e.g. rate-limit errors) go out in order
+    let heartbeat = state.heartbeat();
+    let mut liveness = Liveness::new();
+    let mut ping_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + heartbeat.interval,
+        heartbeat.interval,
+    );
+    ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut shutdown_signal = state.shutdown_signal();
+    let shutting_down = async move {
+        // Err only if the state is gone, which cannot happen while it is borrowed here
+        let _ = shutdown_signal.wait_for(|down| *down).await;
+    };
+    tokio::pin!(shutting_down);
 
     loop {
         tokio::select! {
             // Branch 1: Read from WebSocket
             res = ws_receiver.next() => {
-                match res {
-                    Some(Ok(Message::Text(text))) => {
-                        handle_message(&text, &user_id_clone, &state_clone);
-                    }
+                if let Some(Ok(_)) = &res {
+                    liveness.touch();
+                }
+                let outcome = match res {
+                    Some(Ok(Message::Text(text))) => handle_message(&text, &session, &state),
+                    Some(Ok(Message::Binary(data))) => handle_binary(&data, &session, &state),
                     Some(Ok(Message::Close(_))) => {
-                        info!("User {} sent close frame", user_id_clone);
+                        info!("User {} sent close frame", user_id);
                         break;
                     }
-                    Some(Ok(Message::Ping(data))) => {
-                        let _ = data;
+                    Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {
+                        // tungstenite answers pings itself; both only count as activity
+                        HandleOutcome::Done
+                    }
+                    Some(Err(WsError::Capacity(e))) => {
+                        warn!("Oversized frame from {}: {}", user_id, e);
+                        HandleOutcome::Disconnect(oversized_frame(&e.to_string(), &state))
                     }
                     Some(Err(e)) => {
-                        error!("WebSocket error for user {}: {}", user_id_clone, e);
+                        error!("WebSocket error for user {}: {}", user_id, e);
                         break;
                     }
                     None => {
-                        info!("WebSocket stream ended for user {}", user_id_clone);
+                        info!("WebSocket stream ended for user {}", user_id);
+                        break;
+                    }
+                    _ => HandleOutcome::Done,
+                };
+                match outcome {
+                    HandleOutcome::Done => {}
+                    HandleOutcome::Reply(frame) => {
+                        let frame = session
+                            .protocol
+                            .allows_frame(&frame)
+                            .then(|| encode_frame(frame, encoding))
+                            .flatten();
+                        if let Some(frame) = frame {
+                            if !send_frame(&mut ws_sender, frame, heartbeat.timeout).await {
+                                break;
+                            }
+                        }
+                    }
+                    HandleOutcome::Disconnect(frame) => {
+                        warn!("Disconnecting {} after an error reply", user_id);
+                        if let Some(frame) = encode_frame(frame, encoding) {
+                            send_frame(&mut ws_sender, frame, heartbeat.timeout).await;
+                        }
+                        send_frame(&mut ws_sender, Message::Close(None), heartbeat.timeout).await;
                         break;
                     }
-                    _ => {}
                 }
             }
-            // Branch 2: Monitor Send Task (Write errors)
-            _ = &mut send_task => {
-                info!("Send task finished for user {} (likely connection lost)", user_id_clone);
+            // Branch 2: Forward frames routed to this user (write errors end the connection).
+            // A client that keeps overflowing its queue is dropped, even mid-write.
+            // Frames needing a capability this connection did not negotiate are skipped.
+            Some(msg) = rx.recv() => {
+                let frame = session
+                    .protocol
+                    .allows_frame(&msg)
+                    .then(|| encode_frame(msg, encoding))
+                    .flatten();
+                if let Some(frame) = frame {
+                    tokio::select! {
+                        sent = send_frame(&mut ws_sender, frame, heartbeat.timeout) => {
+                            if !sent {
+                                info!("Failed to write to user {} (likely connection lost)", user_id);
+                                break;
+                            }
+                        }
+                        _ = channel.kicked() => {
+                            warn!("Disconnecting {}: outbound queue persistently full", user_id);
+                            state.record_slow_disconnect(&user_id);
+                            break;
+                        }
+                    }
+                }
+                // Caught up: reset the overflow count and pull in anything spilled meanwhile
+                if rx.is_empty() {
+                    channel.mark_drained();
+                    state.flush_pending(&user_id, device_id, outbound.queue_depth);
+                }
+            }
+            // Branch 3: The server operator closed this connection (kick or ban)
+            _ = channel.disconnected() => {
+                info!("Disconnecting {}: closed by the server operator", user_id);
+                let notice =
+                    error_frame(ErrorCode::Kicked, "Disconnected by the server operator", None);
+                if let Some(frame) = encode_frame(notice, encoding) {
+                    send_frame(&mut ws_sender, frame, heartbeat.timeout).await;
+                }
+                send_frame(&mut ws_sender, Message::Close(None), heartbeat.timeout).await;
+                break;
+            }
+            // Branch 4: Server shutting down - say when to come back, then close
+            _ = &mut shutting_down => {
+                info!("Closing connection of {}: server shutting down", user_id);
+                if let Some(frame) = server_shutdown(&state)
+                    .filter(|json| session.protocol.allows_frame(json))
+                    .and_then(|json| encode_frame(json, encoding))
+                {
+                    send_frame(&mut ws_sender, frame, heartbeat.timeout).await;
+                }
+                send_frame(&mut ws_sender, Message::Close(None), heartbeat.timeout).await;
                 break;
             }
+            // Branch 5: Heartbeat - reap silent connections, otherwise ping
+            _ = ping_interval.tick() => {
+                if liveness.is_stale(heartbeat.timeout) {
+                    warn!("No response from {} in {:?}, closing connection", user_id, heartbeat.timeout);
+                    send_frame(&mut ws_sender, Message::Close(None), Duration::from_secs(1)).await;
+                    break;
+                }
+                if !send_frame(&mut ws_sender, Message::Ping(Vec::new()), heartbeat.timeout).await {
+                    info!("Failed to ping user {} (likely connection lost)", user_id);
+                    break;
+                }
+            }
         }
     }
 
-    // Cleanup
-    send_task.abort();
+    // Cleanup: closing the receiver marks this connection's channel as closed
+    rx.close();
     state.remove_client(&user_id);
+    state.hang_up_calls_of(&user_id, session.connection_id);
 
-    // Broadcast offline presence
-    let offline_presence = WsMessage::Presence {
-        user_id: user_id.clone(),
-        is_online: false,
-        last_seen: Some(chrono::Utc::now().timestamp_millis()),
-    };
-    if let Ok(json) = serde_json::to_string(&offline_presence) {
-        state.broadcast(&json, None);
+    // Frames still buffered were never written; keep the ones that must survive a disconnect
+    if !state.is_device_online(&user_id, device_id) {
+        let mut requeued = 0;
+        while let Ok(msg) = rx.try_recv() {
+            if is_queueable(&msg) {
+                state.queue_for_device(&user_id, device_id, msg, false);
+                requeued += 1;
+            }
+        }
+        if requeued > 0 {
+            info!(
+                "Requeued {} undelivered messages for {} ({})",
+                requeued, user_id, device_id
+            );
+        }
+    }
+
+    // Tell subscribers the user went offline, once its last device has gone
+    if !state.is_online(&user_id) {
+        let offline_presence = WsMessage::Presence {
+            user_id: user_id.clone(),
+            is_online: false,
+            last_seen: Some(chrono::Utc::now().timestamp_millis()),
+        };
+        if let Ok(json) = serde_json::to_string(&offline_presence) {
+            state.send_to_subscribers(&user_id, &json);
+        }
     }
 
     info!("User disconnected: {}", user_id);
 }
 
-/// Wait for the Connect message from a new connection
-async fn wait_for_connect(
-    receiver: &mut futures_util::stream::SplitStream<WebSocketStream<TcpStream>>,
-) -> Option<String> {
-    // Give client 10 seconds to authenticate
-    let timeout = tokio::time::timeout(std::time::Duration::from_secs(10), async {
-        while let Some(result) = receiver.next().await {
-            if let Ok(Message::Text(text)) = result {
-                match serde_json::from_str::<WsMessage>(&text) {
-                    Ok(msg) => {
-                        if let WsMessage::Connect { user_id, token } = msg {
-                            // Basic auth check using environment variable
-                            if let Ok(expected_token) = std::env::var("PULSE_ACCESS_TOKEN") {
-                                if !expected_token.is_empty() {
-                                    if let Some(received_token) = token {
-                                        if received_token != expected_token {
-                                            warn!(
-                                                "Authentication failed for {}: Invalid token",
-                                                user_id
-                                            );
-                                            return None;
-                                        }
-                                    } else {
-                                        warn!(
-                                            "Authentication failed for {}: No token provided",
-                                            user_id
-                                        );
-                                        return None;
-                                    }
-                                }
-                            }
-                            return Some(user_id);
-                        }
-                    }
-                    Err(e) => {
-                        warn!("Failed to parse Connect message: {}", e);
-                    }
-                }
+/// Whether a routed frame belongs in the offline queue
+fn is_queueable(frame: &str) -> bool {
+    serde_json::from_str::<WsMessage>(frame).is_ok_and(|msg| msg.is_queueable())
+}
+
+/// Sending half of a client WebSocket
+type WsSender<S> = futures_util::stream::SplitSink<WebSocketStream<S>, Message>;
+
+/// Receiving half of a client WebSocket
+type WsReceiver<S> = futures_util::stream::SplitStream<WebSocketStream<S>>;
+
+/// Write a frame, giving up if the peer stops reading for `timeout`
+/// (a half-open connection would otherwise block the write forever)
+async fn send_frame<S>(sender: &mut WsSender<S>, msg: Message, timeout: Duration) -> bool
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    matches!(
+        tokio::time::timeout(timeout, sender.send(msg)).await,
+        Ok(Ok(()))
+    )
+}
+
+/// The `server_shutdown` notice telling a client when to reconnect
+fn server_shutdown(state: &ServerState) -> Option<String> {
+    let notice = WsMessage::ServerShutdown {
+        reconnect_after_secs: state.shutdown().reconnect_delay.as_secs(),
+    };
+    serde_json::to_string(&notice).ok()
+}
+
+/// Authenticate a new connection: version check, token check, identity
+/// challenge-response, then device registration. Rejections are reported to the
+/// client with a structured AuthResponse. A server shutdown ends the handshake.
+async fn authenticate<S>(
+    sender: &mut WsSender<S>,
+    receiver: &mut WsReceiver<S>,
+    state: &ServerState,
+) -> Option<Session>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let auth = state.auth();
+    let started = Instant::now();
+    let mut shutdown_signal = state.shutdown_signal();
+    let shutting_down = async move {
+        let _ = shutdown_signal.wait_for(|down| *down).await;
+    };
+    // Version from Connect, for deciding whether a shutdown mid-handshake is announced
+    let version = AtomicU32::new(LEGACY_PROTOCOL_VERSION);
+    let handshake = tokio::time::timeout(auth.timeout, async {
+        let connect = wait_for_connect(sender, receiver, state).await?;
+        let user_id = connect.user_id;
+
+        let Some(protocol) = Negotiated::new(connect.protocol_version, &connect.capabilities)
+        else {
+            warn!(
+                "Authentication failed for {}: protocol version {:?} is too old",
+                user_id, connect.protocol_version
+            );
+            return Some(Err((user_id, AuthErrorCode::UnsupportedVersion)));
+        };
+        version.store(protocol.version, Ordering::Relaxed);
+
+        let device_id = match connect.device_id {
+            Some(device_id) if is_valid_device_id(&device_id) => device_id,
+            Some(_) => {
+                warn!("Authentication failed for {}: invalid device_id", user_id);
+                return Some(Err((user_id, AuthErrorCode::InvalidDevice)));
             }
+            None => DEFAULT_DEVICE_ID.to_string(),
+        };
+
+        if let Err(code) =
+            check_access_token(auth.access_token.as_deref(), connect.token.as_deref())
+        {
+            warn!("Authentication failed for {}: {:?}", user_id, code);
+            return Some(Err((user_id, code)));
+        }
+
+        let offered_key = match connect.public_key.as_deref().map(decode_key) {
+            Some(Some(key)) => Some(key),
+            Some(None) => return Some(Err((user_id, AuthErrorCode::InvalidKey))),
+            None => None,
+        };
+
+        // A bound user_id must always prove its key; an unbound one binds the key it offers
+        let expected_key = match (state.identity_key(&user_id), offered_key) {
+            (Some(bound), Some(offered)) if bound != offered => {
+                warn!(
+                    "Authentication failed for {}: identity key mismatch",
+                    user_id
+                );
+                return Some(Err((user_id, AuthErrorCode::KeyMismatch)));
+            }
+            (Some(bound), _) => Some(bound),
+            (None, Some(offered)) => Some(offered),
+            (None, None) if auth.require_identity => {
+                warn!("Authentication failed for {}: no identity key", user_id);
+                return Some(Err((user_id, AuthErrorCode::IdentityRequired)));
+            }
+            (None, None) => None,
+        };
+
+        if let Some(expected_key) = expected_key {
+            let challenge = Challenge::new();
+            let json = serde_json::to_string(&challenge.to_message()).ok()?;
+            sender.send(Message::Text(json)).await.ok()?;
+
+            let proof = wait_for_proof(sender, receiver, state).await?;
+            if !challenge.verify(&user_id, &expected_key, &proof) {
+                warn!("Authentication failed for {}: invalid proof", user_id);
+                return Some(Err((user_id, AuthErrorCode::InvalidProof)));
+            }
+
+            if !state.register_identity(&user_id, expected_key) {
+                // Another connection bound a different key while we were verifying
+                return Some(Err((user_id, AuthErrorCode::KeyMismatch)));
+            }
+        }
+
+        if state.is_banned(&user_id) {
+            warn!("Authentication failed for {}: banned", user_id);
+            return Some(Err((user_id, AuthErrorCode::Banned)));
+        }
+
+        // Only a proven user may add devices to its account
+        if !state.register_device(&user_id, &device_id) {
+            return Some(Err((user_id, AuthErrorCode::TooManyDevices)));
         }
-        None
+
+        Some(Ok(Session::new(user_id, protocol).with_device(device_id)))
     });
 
-    match timeout.await {
-        Ok(result) => result,
+    let result = tokio::select! {
+        result = handshake => result,
+        _ = shutting_down => {
+            info!("Closing connection during authentication: server shutting down");
+            let announce = version.load(Ordering::Relaxed) >= min_version_for("server_shutdown");
+            if let Some(notice) = server_shutdown(state).filter(|_| announce) {
+                let _ = sender.send(Message::Text(notice)).await;
+            }
+            let _ = sender.send(Message::Close(None)).await;
+            return None;
+        }
+    };
+
+    match result {
+        Ok(Some(Ok(session))) => {
+            state.metrics().record_auth("success", started.elapsed());
+            state.metrics().record_connection();
+            Some(session)
+        }
+        Ok(Some(Err((user_id, code)))) => {
+            state
+                .metrics()
+                .record_auth(&auth_code_label(code), started.elapsed());
+            let message = match code {
+                AuthErrorCode::UnsupportedVersion => format!(
+                    "Unsupported protocol version, this server accepts versions {} to {}",
+                    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ),
+                AuthErrorCode::TooManyDevices => format!(
+                    "{} is already in use on the maximum number of devices",
+                    user_id
+                ),
+                AuthErrorCode::Banned => format!("{} is banned from this server", user_id),
+                _ => format!("Authentication failed for {}", user_id),
+            };
+            let response = WsMessage::AuthResponse {
+                success: false,
+                message,
+                code: Some(code),
+                protocol_version: Some(PROTOCOL_VERSION),
+                capabilities: Vec::new(),
+            };
+            if let Ok(json) = serde_json::to_string(&response) {
+                let _ = sender.send(Message::Text(json)).await;
+            }
+            let _ = sender.send(Message::Close(None)).await;
+            None
+        }
+        Ok(None) => None,
         Err(_) => {
             warn!("Authentication timeout");
+            state.metrics().record_auth("timeout", started.elapsed());
+            let notice = error_frame(ErrorCode::AuthTimeout, "Authentication timed out", None);
+            let _ = sender.send(Message::Text(notice)).await;
+            let _ = sender.send(Message::Close(None)).await;
             None
         }
     }
 }
 
-/// Handle an incoming message from a connected client
-pub fn handle_message(text: &str, sender_id: &str, state: &ServerState) {
-    let mut msg: WsMessage = match serde_json::from_str(text) {
-        Ok(m) => m,
+/// Check the client's token against the configured access token (if any)
+fn check_access_token(expected: Option<&str>, token: Option<&str>) -> Result<(), AuthErrorCode> {
+    match expected {
+        Some(expected) => match token {
+            Some(received) if received == expected => Ok(()),
+            Some(_) => Err(AuthErrorCode::InvalidToken),
+            None => Err(AuthErrorCode::MissingToken),
+        },
+        None => Ok(()),
+    }
+}
+
+/// The fields of a client's Connect frame
+struct ConnectRequest {
+    user_id: String,
+    token: Option<String>,
+    public_key: Option<String>,
+    protocol_version: Option<u32>,
+    capabilities: Vec<Capability>,
+    device_id: Option<String>,
+}
+
+/// Wait for the Connect message from a new connection, answering any other frame
+/// with an error
+async fn wait_for_connect<S>(
+    sender: &mut WsSender<S>,
+    receiver: &mut WsReceiver<S>,
+    state: &ServerState,
+) -> Option<ConnectRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let reply = match next_auth_frame(sender, receiver, state).await? {
+            WsMessage::Connect {
+                user_id,
+                token,
+                public_key,
+                protocol_version,
+                capabilities,
+                device_id,
+            } => {
+                return Some(ConnectRequest {
+                    user_id,
+                    token,
+                    public_key,
+                    protocol_version,
+                    capabilities,
+                    device_id,
+                })
+            }
+            msg => not_authenticated(&msg),
+        };
+        sender.send(Message::Text(reply)).await.ok()?;
+    }
+}
+
+/// Wait for the client's answer to an AuthChallenge, answering any other frame
+/// with an error
+async fn wait_for_proof<S>(
+    sender: &mut WsSender<S>,
+    receiver: &mut WsReceiver<S>,
+    state: &ServerState,
+) -> Option<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let reply = match next_auth_frame(sender, receiver, state).await? {
+            WsMessage::AuthProof { proof } => return Some(proof),
+            msg => not_authenticated(&msg),
+        };
+        sender.send(Message::Text(reply)).await.ok()?;
+    }
+}
+
+/// Read the next text frame of the handshake, answering ones that do not parse.
+/// None once the connection ends, or after an oversized frame (which ends it too).
+async fn next_auth_frame<S>(
+    sender: &mut WsSender<S>,
+    receiver: &mut WsReceiver<S>,
+    state: &ServerState,
+) -> Option<WsMessage>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    while let Some(result) = receiver.next().await {
+        match result {
+            Ok(Message::Text(text)) => match serde_json::from_str::<WsMessage>(&text) {
+                Ok(msg) => return Some(msg),
+                Err(e) => {
+                    warn!("Failed to parse handshake message: {}", e);
+                    sender
+                        .send(Message::Text(parse_error(&text, &e)))
+                        .await
+                        .ok()?;
+                }
+            },
+            Err(WsError::Capacity(e)) => {
+                warn!("Oversized frame during handshake: {}", e);
+                let notice = oversized_frame(&e.to_string(), state);
+                let _ = sender.send(Message::Text(notice)).await;
+                let _ = sender.send(Message::Close(None)).await;
+                return None;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Error reply for a frame sent before authentication finished
+fn not_authenticated(msg: &WsMessage) -> String {
+    let message = format!("{} is not allowed before authentication", msg.frame_type());
+    error_frame(ErrorCode::NotAuthenticated, &message, msg.ref_id())
+}
+
+/// What the connection should do after a client frame has been handled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandleOutcome {
+    /// Nothing to send back to the sender
+    Done,
+    /// Send this frame back to the sending connection only
+    Reply(String),
+    /// Send this frame back, then close the connection
+    Disconnect(String),
+}
+
+/// Handle an incoming JSON text frame from a connected client
+pub fn handle_message(text: &str, session: &Session, state: &ServerState) -> HandleOutcome {
+    match serde_json::from_str(text) {
+        Ok(msg) => dispatch(msg, session, state),
         Err(e) => {
-            warn!("Failed to parse message from {}: {}", sender_id, e);
-            return;
+            warn!("Failed to parse message from {}: {}", session.user_id, e);
+            state.metrics().record_invalid_frame();
+            HandleOutcome::Reply(parse_error(text, &e))
         }
-    };
+    }
+}
 
-    // Enforce sender identity to prevent spoofing
-    match &mut msg {
-        WsMessage::ChatMessage {
-            sender_id: sid,
-            sender_name: _,
-            ..
-        } => *sid = sender_id.to_string(),
-        WsMessage::Typing { user_id, .. } => *user_id = sender_id.to_string(),
-        WsMessage::Presence { user_id, .. } => *user_id = sender_id.to_string(),
+/// Handle an incoming binary frame, in the encoding the connection negotiated
+pub fn handle_binary(data: &[u8], session: &Session, state: &ServerState) -> HandleOutcome {
+    let encoding = session.protocol.encoding();
+    if !encoding.is_binary() {
+        warn!(
+            "Binary frame from {} without a negotiated binary encoding",
+            session.user_id
+        );
+        return HandleOutcome::Reply(error_frame(
+            ErrorCode::UnsupportedEncoding,
+            "Binary frames need a negotiated binary encoding",
+            None,
+        ));
+    }
+    match encoding.decode(data) {
+        Ok(msg) => dispatch(msg, session, state),
+        Err(e) => {
+            warn!(
+                "Failed to decode {:?} frame from {}: {}",
+                encoding, session.user_id, e
+            );
+            state.metrics().record_invalid_frame();
+            let message = format!("Invalid {:?} frame: {}", encoding, e);
+            HandleOutcome::Reply(error_frame(ErrorCode::InvalidFrame, &message, None))
+        }
+    }
+}
 
-        // CORRECTION: In receipts, 'sender_id' is the DESTINATION (original sender of the Msg).
-        // We must enforce that the 'delivered_to' / 'user_id' matches the current connection.
-        WsMessage::DeliveryReceipt {
-            delivered_to: dt, ..
-        } => *dt = sender_id.to_string(),
+/// Route a parsed client frame. Frames are relayed and queued as JSON text and
+/// only re-encoded for connections that negotiated a binary encoding.
+fn dispatch(mut msg: WsMessage, session: &Session, state: &ServerState) -> HandleOutcome {
+    let sender_id = session.user_id.as_str();
+    state.metrics().record_frame(msg.frame_type());
+
+    // Drop frames over the sender's rate limit before they reach other users
+    if let Some(class) = MessageClass::of(&msg) {
+        match state.check_rate(sender_id, class) {
+            RateDecision::Allow => {}
+            RateDecision::Limited => {
+                warn!("Rate limited {} frame from {}", class.as_str(), sender_id);
+                state.metrics().record_rate_limited(class.as_str());
+                return HandleOutcome::Reply(rate_limit_error(&msg, class, &session.protocol));
+            }
+            RateDecision::Disconnect => {
+                state.metrics().record_rate_limited(class.as_str());
+                return HandleOutcome::Disconnect(rate_limit_error(&msg, class, &session.protocol));
+            }
+        }
+    }
 
-        WsMessage::ReadReceipt { user_id: uid, .. } => *uid = sender_id.to_string(),
+    // Refuse to relay fields over their size limits (e.g. a huge avatar broadcast to everyone)
+    if let Some((field, limit)) = state.limits().oversized_field(&msg) {
+        let frame_type = msg.frame_type();
+        warn!(
+            "Rejected {} frame from {}: {} over {} bytes",
+            frame_type, sender_id, field, limit
+        );
+        state.metrics().record_oversized(field);
+        let reason = format!("{} is larger than {} bytes", field, limit);
+        let reply = rejection(&msg, ErrorCode::PayloadTooLarge, reason, &session.protocol);
+        return HandleOutcome::Reply(reply);
+    }
+
+    // Enforce sender identity to prevent spoofing
+    msg.enforce_sender(sender_id);
 
-        WsMessage::ProfileUpdate { user_id, .. } => *user_id = sender_id.to_string(),
+    // Subscriptions are consumed here, not relayed
+    if let WsMessage::SubscribePresence { user_ids, chats } = msg {
+        for target in state.subscribe(sender_id, user_ids, chats) {
+            if state.is_online(&target) && !state.is_blocked_between(sender_id, &target) {
+                let presence = WsMessage::Presence {
+                    user_id: target,
+                    is_online: true,
+                    last_seen: None,
+                };
+                if let Ok(json) = serde_json::to_string(&presence) {
+                    state.send_to_user(sender_id, &json);
+                }
+            }
+        }
+        return HandleOutcome::Done;
+    }
 
-        // Video Call & WebRTC - Sender Enforcement
-        WsMessage::CallInvite { from_user_id, .. } => *from_user_id = sender_id.to_string(),
-        WsMessage::CallRinging { from_user_id, .. } => *from_user_id = sender_id.to_string(),
-        WsMessage::CallAccept { from_user_id, .. } => *from_user_id = sender_id.to_string(),
-        WsMessage::CallReject { from_user_id, .. } => *from_user_id = sender_id.to_string(),
-        WsMessage::CallHangup { from_user_id, .. } => *from_user_id = sender_id.to_string(),
-        WsMessage::RtcOffer { from_user_id, .. } => *from_user_id = sender_id.to_string(),
-        WsMessage::RtcAnswer { from_user_id, .. } => *from_user_id = sender_id.to_string(),
-        WsMessage::RtcIceCandidate { from_user_id, .. } => *from_user_id = sender_id.to_string(),
+    // Key directory and block list frames are handled here, not relayed
+    match &msg {
+        WsMessage::KeyRequest { user_id } => {
+            return HandleOutcome::Reply(key_response(user_id, state.identity_key(user_id)));
+        }
+        WsMessage::PublishKey { public_key } => {
+            return HandleOutcome::Reply(publish_key(sender_id, public_key, state));
+        }
+        WsMessage::BlockUser { user_id } => return set_blocked(sender_id, user_id, true, state),
+        WsMessage::UnblockUser { user_id } => return set_blocked(sender_id, user_id, false, state),
+        _ => {}
+    }
 
-        // Messages that shouldn't be sent by client or don't generally carry spoofable sender_id in this context
-        WsMessage::Connect { .. } | WsMessage::AuthResponse { .. } | WsMessage::Error { .. } => {}
+    // Nothing is relayed between blocked users. The sender of a chat message is not told
+    // that its recipient blocked it; the ack looks like the recipient is offline.
+    if let Some(recipient) = msg.recipient() {
+        if state.is_blocked_between(sender_id, recipient) {
+            debug!("Dropped frame from {} to {}: blocked", sender_id, recipient);
+            let acks = session.protocol.supports(Capability::Acks);
+            return match &msg {
+                WsMessage::ChatMessage { id, .. } if acks => {
+                    let (status, reason) = if state.has_blocked(sender_id, recipient) {
+                        (
+                            AckStatus::Error,
+                            Some("You have blocked this user".to_string()),
+                        )
+                    } else {
+                        (AckStatus::Queued, None)
+                    };
+                    HandleOutcome::Reply(server_ack(id, status, reason))
+                }
+                _ => HandleOutcome::Done,
+            };
+        }
     }
 
     // Re-serialize the secure message
@@ -250,22 +761,35 @@ pub fn handle_message(text: &str, sender_id: &str, state: &ServerState) {
         Ok(s) => s,
         Err(e) => {
             error!("Failed to re-serialize message from {}: {}", sender_id, e);
-            return;
+            return HandleOutcome::Done;
         }
     };
 
     match &msg {
-        WsMessage::ChatMessage { recipient_id, .. } => {
-            // Route to specific recipient (queues if offline)
-            state.send_or_queue(recipient_id, &safe_text);
+        WsMessage::ChatMessage {
+            id, recipient_id, ..
+        } => {
+            // Route to specific recipient (queues if offline), then tell the sender which it was
+            let status = if state.send_or_queue(recipient_id, &safe_text) {
+                AckStatus::Delivered
+            } else {
+                AckStatus::Queued
+            };
+            // Keep the sender's other devices in sync (the ack only reflects the recipient)
+            if recipient_id != sender_id {
+                state.sync_to_other_devices(sender_id, &session.device_id, &safe_text);
+            }
+            if session.protocol.supports(Capability::Acks) {
+                return HandleOutcome::Reply(server_ack(id, status, None));
+            }
         }
-        WsMessage::Typing { .. } => {
-            // Typing indicators are ephemeral - don't queue, just broadcast
-            state.broadcast(&safe_text, Some(sender_id));
+        WsMessage::Typing { chat_id, .. } => {
+            // Typing indicators are ephemeral - don't queue, only send to the chat's participants
+            state.send_to_chat_participants(sender_id, chat_id, &safe_text);
         }
         WsMessage::Presence { .. } => {
-            // Presence is broadcast to all online users
-            state.broadcast(&safe_text, Some(sender_id));
+            // Presence only goes to users subscribed to the sender
+            state.send_to_subscribers(sender_id, &safe_text);
         }
         WsMessage::DeliveryReceipt {
             sender_id: original_sender,
@@ -282,26 +806,285 @@ pub fn handle_message(text: &str, sender_id: &str, state: &ServerState) {
             state.send_or_queue(original_sender, &safe_text);
         }
         WsMessage::ProfileUpdate { .. } => {
-            // Profile updates broadcast to all online users
-            state.broadcast(&safe_text, Some(sender_id));
+            // Profile updates only go to users subscribed to the sender
+            state.send_to_subscribers(sender_id, &safe_text);
         }
         // === Video Call Control - route directly to recipient (no queue, time-sensitive) ===
-        WsMessage::CallInvite { to_user_id, .. }
-        | WsMessage::CallRinging { to_user_id, .. }
-        | WsMessage::CallAccept { to_user_id, .. }
-        | WsMessage::CallReject { to_user_id, .. }
-        | WsMessage::CallHangup { to_user_id, .. }
-        | WsMessage::RtcOffer { to_user_id, .. }
-        | WsMessage::RtcAnswer { to_user_id, .. }
-        | WsMessage::RtcIceCandidate { to_user_id, .. } => {
-            // Call signaling is time-sensitive - send directly, don't queue
-            state.send_to_user(to_user_id, &safe_text);
-        }
-        WsMessage::Connect { .. } => {
+        WsMessage::CallInvite { .. }
+        | WsMessage::CallRinging { .. }
+        | WsMessage::CallAccept { .. }
+        | WsMessage::CallReject { .. }
+        | WsMessage::CallHangup { .. }
+        | WsMessage::RtcOffer { .. }
+        | WsMessage::RtcAnswer { .. }
+        | WsMessage::RtcIceCandidate { .. } => {
+            return relay_call_frame(&msg, &safe_text, session, state);
+        }
+        WsMessage::Connect { .. } | WsMessage::AuthProof { .. } => {
             // Already authenticated, ignore
         }
-        WsMessage::AuthResponse { .. } | WsMessage::Error { .. } => {
+        WsMessage::SubscribePresence { .. }
+        | WsMessage::PublishKey { .. }
+        | WsMessage::KeyRequest { .. }
+        | WsMessage::BlockUser { .. }
+        | WsMessage::UnblockUser { .. } => {
+            // Handled above
+        }
+        WsMessage::AuthChallenge { .. }
+        | WsMessage::AuthResponse { .. }
+        | WsMessage::Error { .. }
+        | WsMessage::ServerAck { .. }
+        | WsMessage::DeliveryFailed { .. }
+        | WsMessage::Announcement { .. }
+        | WsMessage::ServerShutdown { .. }
+        | WsMessage::KeyResponse { .. }
+        | WsMessage::BlockList { .. }
+        | WsMessage::MissedCall { .. } => {
             // Server-only messages, ignore from client
         }
     }
+
+    HandleOutcome::Done
+}
+
+/// Check call signaling against the call registry, then send it directly to the other
+/// participant (time-sensitive, never queued). The caller is answered for the callee
+/// when the callee is busy or has no connection to ring, and the callee is left a
+/// `missed_call` instead of the invite.
+fn relay_call_frame(
+    msg: &WsMessage,
+    safe_text: &str,
+    session: &Session,
+    state: &ServerState,
+) -> HandleOutcome {
+    let Some(recipient) = msg.recipient() else {
+        return HandleOutcome::Done;
+    };
+    match state.track_call(&session.user_id, session.connection_id, msg) {
+        CallDecision::Relay => {
+            if state.send_to_user(recipient, safe_text) {
+                return HandleOutcome::Done;
+            }
+            match msg {
+                WsMessage::CallInvite { call_id, .. } => {
+                    state.miss_call(call_id);
+                    HandleOutcome::Reply(call_reject(msg, "offline"))
+                }
+                _ => HandleOutcome::Done,
+            }
+        }
+        CallDecision::Busy => {
+            if let WsMessage::CallInvite {
+                call_id,
+                from_user_id,
+                to_user_id,
+                kind,
+            } = msg
+            {
+                let now = chrono::Utc::now().timestamp_millis();
+                let missed = missed_call(call_id, from_user_id, to_user_id, kind, now);
+                state.queue_missed_call(&missed);
+            }
+            HandleOutcome::Reply(call_reject(msg, "busy"))
+        }
+        CallDecision::Stale => {
+            debug!(
+                "Dropped call frame from {}: call already ended",
+                session.user_id
+            );
+            HandleOutcome::Done
+        }
+        CallDecision::Invalid(reason) => {
+            warn!("Rejected call frame from {}: {}", session.user_id, reason);
+            HandleOutcome::Reply(error_frame(ErrorCode::InvalidCall, reason, msg.ref_id()))
+        }
+    }
+}
+
+/// Reject for a call invite, written as if the invited user had sent it
+fn call_reject(invite: &WsMessage, reason: &str) -> String {
+    let WsMessage::CallInvite {
+        call_id,
+        from_user_id,
+        to_user_id,
+        ..
+    } = invite
+    else {
+        return String::new();
+    };
+    let reject = WsMessage::CallReject {
+        call_id: call_id.clone(),
+        from_user_id: to_user_id.clone(),
+        to_user_id: from_user_id.clone(),
+        reason: reason.to_string(),
+    };
+    serde_json::to_string(&reject).unwrap_or_default()
+}
+
+/// Frame sent when a frame is dropped by the rate limiter
+fn rate_limit_error(msg: &WsMessage, class: MessageClass, protocol: &Negotiated) -> String {
+    let reason = format!("Rate limit exceeded for {} messages", class.as_str());
+    rejection(msg, ErrorCode::RateLimited, reason, protocol)
+}
+
+/// Frame sent when a frame is dropped before routing:
+/// a failed ack for chat messages, a plain error otherwise
+fn rejection(msg: &WsMessage, code: ErrorCode, reason: String, protocol: &Negotiated) -> String {
+    if let WsMessage::ChatMessage { id, .. } = msg {
+        if protocol.supports(Capability::Acks) {
+            return server_ack(id, AckStatus::Error, Some(reason));
+        }
+    }
+    error_frame(code, &reason, msg.ref_id())
+}
+
+/// Error reply for a frame over the frame or message size limit, counted in metrics
+fn oversized_frame(reason: &str, state: &ServerState) -> String {
+    state.metrics().record_oversized("frame");
+    error_frame(ErrorCode::PayloadTooLarge, reason, None)
+}
+
+/// Bind a published key to an account that has none yet (trust on first use, as
+/// in `Connect`) and answer with the key on file, or an error frame if it differs
+fn publish_key(user_id: &str, public_key: &str, state: &ServerState) -> String {
+    match decode_key(public_key) {
+        None => error_frame(ErrorCode::InvalidKey, "Invalid public key", None),
+        Some(key) if state.register_identity(user_id, key) => key_response(user_id, Some(key)),
+        Some(_) => {
+            warn!(
+                "{} published a key that differs from its bound identity",
+                user_id
+            );
+            error_frame(
+                ErrorCode::KeyMismatch,
+                "Public key does not match the bound identity key",
+                None,
+            )
+        }
+    }
+}
+
+/// Add or remove a block, then show each user as offline to the other (blocked) or
+/// as it really is (unblocked) if it was subscribed to the other. The sender is answered
+/// with its block list.
+fn set_blocked(user_id: &str, target: &str, blocked: bool, state: &ServerState) -> HandleOutcome {
+    if target.is_empty() || target == user_id {
+        return HandleOutcome::Reply(error_frame(
+            ErrorCode::InvalidTarget,
+            "Invalid user to block",
+            None,
+        ));
+    }
+    if blocked {
+        if !state.block_user(user_id, target) {
+            return HandleOutcome::Reply(error_frame(
+                ErrorCode::BlockListFull,
+                "Block list is full",
+                None,
+            ));
+        }
+    } else {
+        state.unblock_user(user_id, target);
+    }
+
+    let visible = !state.is_blocked_between(user_id, target);
+    for (subject, viewer) in [(user_id, target), (target, user_id)] {
+        let subscribed = state.subscribers_of(subject).iter().any(|s| s == viewer);
+        if subscribed && (!visible || state.is_online(subject)) {
+            let presence = WsMessage::Presence {
+                user_id: subject.to_string(),
+                is_online: visible,
+                last_seen: None,
+            };
+            if let Ok(json) = serde_json::to_string(&presence) {
+                state.send_to_user(viewer, &json);
+            }
+        }
+    }
+
+    // Confirm the change, so the client knows the server has it
+    let block_list = WsMessage::BlockList {
+        user_ids: state.block_list(user_id),
+    };
+    match serde_json::to_string(&block_list) {
+        Ok(json) => HandleOutcome::Reply(json),
+        Err(_) => HandleOutcome::Done,
+    }
+}
+
+/// Key directory answer for `user_id`
+fn key_response(user_id: &str, key: Option<[u8; 32]>) -> String {
+    let response = WsMessage::KeyResponse {
+        user_id: user_id.to_string(),
+        public_key: key.map(hex::encode),
+    };
+    serde_json::to_string(&response).unwrap_or_default()
+}
+
+/// Turn a JSON frame into the WebSocket message for a connection's encoding,
+/// or None (logged) if it cannot be re-encoded
+fn encode_frame(frame: String, encoding: Encoding) -> Option<Message> {
+    if !encoding.is_binary() {
+        return Some(Message::Text(frame));
+    }
+    let encoded = serde_json::from_str::<WsMessage>(&frame)
+        .map_err(|e| e.to_string())
+        .and_then(|msg| encoding.encode(&msg));
+    match encoded {
+        Ok(bytes) => Some(Message::Binary(bytes)),
+        Err(e) => {
+            error!("Failed to encode frame as {:?}: {}", encoding, e);
+            None
+        }
+    }
+}
+
+/// The snake_case wire name of an auth error code, for metrics labels
+fn auth_code_label(code: AuthErrorCode) -> String {
+    serde_json::to_value(code)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Error reply for a rejected frame, with the id of that frame if it had one
+fn error_frame(code: ErrorCode, message: &str, ref_id: Option<&str>) -> String {
+    let error = WsMessage::Error {
+        message: message.to_string(),
+        code: Some(code),
+        ref_id: ref_id.map(str::to_string),
+    };
+    serde_json::to_string(&error).unwrap_or_default()
+}
+
+/// Error reply for a text frame that did not parse: unknown if its `type` is not one
+/// this server knows, invalid otherwise. Its `id` or `call_id`, if any, is the ref_id.
+fn parse_error(text: &str, error: &serde_json::Error) -> String {
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(text) else {
+        return error_frame(ErrorCode::InvalidFrame, "Frame is not valid JSON", None);
+    };
+    let ref_id = ["id", "message_id", "call_id"]
+        .iter()
+        .find_map(|field| value.get(field).and_then(|id| id.as_str()));
+    match value.get("type").and_then(|tag| tag.as_str()) {
+        Some(tag) if !WsMessage::FRAME_TYPES.contains(&tag) => {
+            let message = format!("Unknown frame type: {}", tag);
+            error_frame(ErrorCode::UnknownType, &message, ref_id)
+        }
+        Some(tag) => {
+            let message = format!("Invalid {} frame: {}", tag, error);
+            error_frame(ErrorCode::InvalidFrame, &message, ref_id)
+        }
+        None => error_frame(ErrorCode::InvalidFrame, "Frame has no type", ref_id),
+    }
+}
+
+/// Acknowledgement frame for a chat message, sent back to its sender
+fn server_ack(message_id: &str, status: AckStatus, reason: Option<String>) -> String {
+    let ack = WsMessage::ServerAck {
+        message_id: message_id.to_string(),
+        status,
+        reason,
+    };
+    serde_json::to_string(&ack).unwrap_or_default()
 }
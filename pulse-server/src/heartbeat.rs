@@ -0,0 +1,80 @@
+use std::time::{Duration, Instant};
+
+/// Server-driven ping/pong settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    /// How often the server pings each connection
+    pub interval: Duration,
+    /// Close a connection that has sent nothing (not even a pong) for this long
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+/// Tracks when a connection was last heard from
+pub struct Liveness {
+    last_seen: Instant,
+}
+
+impl Liveness {
+    pub fn new() -> Self {
+        Self {
+            last_seen: Instant::now(),
+        }
+    }
+
+    /// Record that a frame (of any kind) arrived from the peer
+    pub fn touch(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    /// Whether the peer has been silent for longer than `timeout`
+    pub fn is_stale(&self, timeout: Duration) -> bool {
+        self.is_stale_at(timeout, Instant::now())
+    }
+
+    fn is_stale_at(&self, timeout: Duration, now: Instant) -> bool {
+        now.duration_since(self.last_seen) > timeout
+    }
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fresh_connection_is_not_stale() {
+        let liveness = Liveness::new();
+        assert!(!liveness.is_stale(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_silent_connection_becomes_stale() {
+        let liveness = Liveness::new();
+        let later = liveness.last_seen + Duration::from_secs(91);
+        assert!(liveness.is_stale_at(Duration::from_secs(90), later));
+        assert!(!liveness.is_stale_at(Duration::from_secs(120), later));
+    }
+
+    #[test]
+    fn test_touch_resets_silence() {
+        let mut liveness = Liveness::new();
+        let before = liveness.last_seen;
+        std::thread::sleep(Duration::from_millis(5));
+        liveness.touch();
+        assert!(liveness.last_seen > before);
+    }
+}
@@ -0,0 +1,303 @@
+//! Plain-HTTP listeners, each on its own port next to the WebSocket one.
+//!
+//! The monitoring listener serves `GET /metrics` (Prometheus text format),
+//! `GET /healthz` (the process is up) and `GET /readyz` (the relay can take
+//! traffic). The admin listener, on loopback, serves only the operator API under
+//! `/admin`. Each request gets one response and the connection is closed, which
+//! is all scrapers, probes and the admin CLI need.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use crate::admin;
+use crate::state::ServerState;
+
+/// Longest request head accepted (request line plus headers)
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// Longest request body accepted (admin announcements)
+const MAX_BODY_BYTES: usize = 8 * 1024;
+
+/// Time a client has to send its request and read the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TEXT: &str = "text/plain; charset=utf-8";
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+const JSON: &str = "application/json";
+
+/// One parsed HTTP request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Request<'a> {
+    pub method: &'a str,
+    /// Path without the query string
+    pub path: &'a str,
+    /// Value of the `Authorization` header
+    pub authorization: Option<&'a str>,
+    /// Declared `Content-Length` (the body may have been cut short)
+    pub content_length: usize,
+    pub body: &'a [u8],
+}
+
+/// One HTTP response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub(crate) fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: TEXT,
+            body: body.into(),
+        }
+    }
+
+    pub(crate) fn json(status: u16, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: JSON,
+            body: format!("{}\n", body),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+/// Accept monitoring requests until the listener fails
+pub async fn serve_http(listener: TcpListener, state: Arc<ServerState>) {
+    serve(listener, state, None).await
+}
+
+/// Accept `/admin` requests bearing `admin_token` until the listener fails
+pub async fn serve_admin(listener: TcpListener, state: Arc<ServerState>, admin_token: String) {
+    serve(listener, state, Some(admin_token.into())).await
+}
+
+async fn serve(listener: TcpListener, state: Arc<ServerState>, admin_token: Option<Arc<str>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let state = state.clone();
+                let admin_token = admin_token.clone();
+                tokio::spawn(async move {
+                    let handled = handle_request(stream, &state, admin_token.as_deref());
+                    if tokio::time::timeout(REQUEST_TIMEOUT, handled)
+                        .await
+                        .is_err()
+                    {
+                        debug!("HTTP request timed out");
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept HTTP connection: {}", e),
+        }
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, state: &ServerState, admin_token: Option<&str>) {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let complete = match head_end(&data) {
+            Some(end) => {
+                let head = String::from_utf8_lossy(&data[..end]);
+                data.len() >= end + 4 + content_length(&head).min(MAX_BODY_BYTES)
+            }
+            None => data.len() > MAX_REQUEST_BYTES,
+        };
+        if complete {
+            break;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let response = match parse_request(&data) {
+        Some(request) if request.content_length > MAX_BODY_BYTES => {
+            Response::text(413, "Payload too large\n")
+        }
+        Some(request) if request.body.len() < request.content_length => {
+            Response::text(400, "Bad request\n")
+        }
+        Some(request) => route(&request, state, admin_token),
+        None => Response::text(400, "Bad request\n"),
+    };
+    let include_body = !data.starts_with(b"HEAD ");
+    let _ = stream.write_all(&encode(&response, include_body)).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Offset of the blank line ending the request head
+fn head_end(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+/// `Content-Length` from a request head (0 when absent or malformed)
+fn content_length(head: &str) -> usize {
+    header(head, "content-length")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Request line, the headers this listener uses and the body
+fn parse_request(data: &[u8]) -> Option<Request<'_>> {
+    let (head, body) = match head_end(data) {
+        Some(end) => (&data[..end], &data[end + 4..]),
+        None => (data, &[][..]),
+    };
+    let head = std::str::from_utf8(head).ok()?;
+    let line = head.split('\n').next()?.trim_end();
+    let mut parts = line.split(' ');
+    let method = parts.next().filter(|m| !m.is_empty())?;
+    let target = parts.next()?;
+    parts
+        .next()
+        .filter(|version| version.starts_with("HTTP/"))?;
+    let path = target.split('?').next().unwrap_or(target);
+
+    let content_length = content_length(head);
+    Some(Request {
+        method,
+        path,
+        authorization: header(head, "authorization"),
+        content_length,
+        body: &body[..body.len().min(content_length)],
+    })
+}
+
+/// Answer one request. With `admin_token` this is the admin listener, which serves
+/// only `/admin`; without it, the monitoring listener, which never does.
+pub(crate) fn route(request: &Request, state: &ServerState, admin_token: Option<&str>) -> Response {
+    let path = request.path;
+    if admin_token.is_some() {
+        if path == "/admin" || path.starts_with("/admin/") {
+            return admin::route(request, state, admin_token);
+        }
+        return Response::text(404, "Not found\n");
+    }
+    if !matches!(path, "/metrics" | "/healthz" | "/readyz") {
+        return Response::text(404, "Not found\n");
+    }
+    if request.method != "GET" && request.method != "HEAD" {
+        return Response::text(405, "Method not allowed\n");
+    }
+
+    match path {
+        "/metrics" => Response {
+            status: 200,
+            content_type: PROMETHEUS_TEXT,
+            body: state.render_metrics(),
+        },
+        "/readyz" => match state.readiness() {
+            Ok(()) => Response::text(200, "ready\n"),
+            Err(reason) => Response::text(503, format!("not ready: {}\n", reason)),
+        },
+        _ => Response::text(200, "ok\n"),
+    }
+}
+
+fn encode(response: &Response, include_body: bool) -> Vec<u8> {
+    let mut bytes = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    )
+    .into_bytes();
+    if include_body {
+        bytes.extend_from_slice(response.body.as_bytes());
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request<'a>(method: &'a str, path: &'a str) -> Request<'a> {
+        Request {
+            method,
+            path,
+            authorization: None,
+            content_length: 0,
+            body: b"",
+        }
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(b"GET /metrics?x=1 HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert_eq!((request.method, request.path), ("GET", "/metrics"));
+        assert_eq!(parse_request(b"GET /metrics\r\n\r\n"), None);
+        assert_eq!(parse_request(b"\r\n\r\n"), None);
+
+        let request = parse_request(
+            b"POST /admin/announce HTTP/1.1\r\nauthorization: Bearer t\r\n\
+              Content-Length: 5\r\n\r\nhello, extra",
+        )
+        .unwrap();
+        assert_eq!(request.authorization, Some("Bearer t"));
+        assert_eq!(request.content_length, 5);
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn test_routes() {
+        let state = ServerState::new();
+        assert_eq!(route(&request("GET", "/healthz"), &state, None).status, 200);
+        assert_eq!(route(&request("GET", "/readyz"), &state, None).status, 200);
+        assert_eq!(
+            route(&request("POST", "/metrics"), &state, None).status,
+            405
+        );
+        assert_eq!(route(&request("GET", "/admin"), &state, None).status, 404);
+        assert_eq!(
+            route(&request("GET", "/admin/users"), &state, Some("t")).status,
+            401
+        );
+        // The admin listener serves nothing else
+        assert_eq!(
+            route(&request("GET", "/metrics"), &state, Some("t")).status,
+            404
+        );
+
+        let metrics = route(&request("GET", "/metrics"), &state, None);
+        assert_eq!(metrics.content_type, PROMETHEUS_TEXT);
+        assert!(metrics.body.contains("pulse_users_online 0\n"));
+    }
+
+    #[test]
+    fn test_head_omits_body() {
+        let response = Response::text(200, "ok\n");
+        let head = String::from_utf8(encode(&response, false)).unwrap();
+        assert!(head.ends_with("Content-Length: 3\r\nConnection: close\r\n\r\n"));
+    }
+}
@@ -2,11 +2,43 @@
 //!
 //! This module exposes the server components for use in integration tests.
 
+mod admin;
+mod auth;
+mod calls;
+mod config;
 mod connection;
-mod messages;
+mod heartbeat;
+mod http;
+mod limits;
+mod metrics;
+mod outbound;
+mod protocol;
+mod queue;
+mod rate_limit;
+mod shutdown;
 mod state;
+mod store;
+mod tls;
 
+pub use admin::AdminCommand;
+pub use auth::{compute_proof, AuthConfig};
+pub use calls::CallConfig;
+pub use config::{LogFormat, RuntimeConfig, ServerConfig};
 pub use connection::handle_connection;
-pub use connection::handle_message;
-pub use messages::WsMessage;
-pub use state::ServerState;
+pub use connection::{handle_binary, handle_message, HandleOutcome, Session};
+pub use heartbeat::HeartbeatConfig;
+pub use http::{serve_admin, serve_http};
+pub use limits::LimitsConfig;
+pub use metrics::Metrics;
+pub use outbound::{ClientChannel, DeliveryStats, OutboundConfig};
+pub use protocol::Negotiated;
+pub use pulse_protocol::{
+    AckStatus, AuthErrorCode, Capability, DeliveryFailureReason, Encoding, ErrorCode,
+    WsChatParticipants, WsMessage, LEGACY_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use queue::QueueConfig;
+pub use rate_limit::{BucketConfig, MessageClass, RateDecision, RateLimitConfig};
+pub use shutdown::{shut_down, ShutdownConfig};
+pub use state::{ServerState, DEFAULT_DEVICE_ID};
+pub use store::Store;
+pub use tls::TlsTerminator;
@@ -0,0 +1,124 @@
+//! Size limits on what clients may send: whole frames, enforced by tungstenite
+//! while reading, and the large fields of parsed frames, checked before relaying.
+
+use pulse_protocol::WsMessage;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+/// Frame and field size limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitsConfig {
+    /// Largest single WebSocket frame payload
+    pub max_frame_bytes: usize,
+    /// Largest message, after reassembling fragmented frames
+    pub max_message_bytes: usize,
+    /// Largest chat message `content` (encrypted payloads included)
+    pub max_content_bytes: usize,
+    /// Largest `sdp` of an `rtc_offer` or `rtc_answer`
+    pub max_sdp_bytes: usize,
+    /// Largest `candidate` of an `rtc_ice_candidate`
+    pub max_candidate_bytes: usize,
+    /// Largest base64 `avatar_data` of a `profile_update`
+    pub max_avatar_bytes: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_bytes: 1024 * 1024,
+            max_message_bytes: 1024 * 1024,
+            max_content_bytes: 128 * 1024,
+            max_sdp_bytes: 32 * 1024,
+            max_candidate_bytes: 2 * 1024,
+            // A 512 KiB avatar (the client's limit) is about 683 KiB in base64
+            max_avatar_bytes: 768 * 1024,
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// tungstenite settings for accepting a connection under these limits
+    pub fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_frame_size: Some(self.max_frame_bytes),
+            max_message_size: Some(self.max_message_bytes),
+            ..WebSocketConfig::default()
+        }
+    }
+
+    /// The first field of `msg` over its limit, with that limit
+    pub fn oversized_field(&self, msg: &WsMessage) -> Option<(&'static str, usize)> {
+        let (field, size, limit) = match msg {
+            WsMessage::ChatMessage { content, .. } => {
+                ("content", content.len(), self.max_content_bytes)
+            }
+            WsMessage::RtcOffer { sdp, .. } | WsMessage::RtcAnswer { sdp, .. } => {
+                ("sdp", sdp.len(), self.max_sdp_bytes)
+            }
+            WsMessage::RtcIceCandidate { candidate, .. } => {
+                ("candidate", candidate.len(), self.max_candidate_bytes)
+            }
+            WsMessage::ProfileUpdate {
+                avatar_data: Some(avatar_data),
+                ..
+            } => ("avatar_data", avatar_data.len(), self.max_avatar_bytes),
+            _ => return None,
+        };
+        (size > limit).then_some((field, limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(size: usize) -> WsMessage {
+        WsMessage::RtcIceCandidate {
+            call_id: "call1".to_string(),
+            from_user_id: "alice".to_string(),
+            to_user_id: "bob".to_string(),
+            candidate: "a".repeat(size),
+        }
+    }
+
+    #[test]
+    fn test_oversized_field_reports_field_and_limit() {
+        let limits = LimitsConfig {
+            max_candidate_bytes: 10,
+            ..LimitsConfig::default()
+        };
+        assert_eq!(limits.oversized_field(&candidate(10)), None);
+        assert_eq!(
+            limits.oversized_field(&candidate(11)),
+            Some(("candidate", 10))
+        );
+
+        // Frames without a limited field always pass
+        let typing = WsMessage::Typing {
+            chat_id: "c1".to_string(),
+            user_id: "alice".to_string(),
+            is_typing: true,
+        };
+        assert_eq!(limits.oversized_field(&typing), None);
+    }
+
+    #[test]
+    fn test_profile_update_without_avatar_passes() {
+        let limits = LimitsConfig {
+            max_avatar_bytes: 4,
+            ..LimitsConfig::default()
+        };
+        let update = |avatar_data: Option<&str>| WsMessage::ProfileUpdate {
+            user_id: "alice".to_string(),
+            name: "Alice".to_string(),
+            phone: None,
+            avatar_url: None,
+            about: None,
+            avatar_data: avatar_data.map(str::to_string),
+        };
+        assert_eq!(limits.oversized_field(&update(None)), None);
+        assert_eq!(
+            limits.oversized_field(&update(Some("aGVsbG8="))),
+            Some(("avatar_data", 4))
+        );
+    }
+}
@@ -1,38 +1,173 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use pulse_server::{handle_connection, ServerState};
+use pulse_server::{
+    handle_connection, serve_admin, serve_http, shut_down, AdminCommand, LogFormat, ServerConfig,
+    ServerState, Store, TlsTerminator,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tokio_tungstenite::accept_async;
-use tracing::{error, info};
-use tracing_subscriber::EnvFilter;
+use tokio_tungstenite::accept_async_with_config;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
 
-const DEFAULT_PORT: u16 = 9001;
+/// How often queued messages are checked against the queue TTL
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often ringing calls are checked against the ring timeout
+const CALL_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often offline users' rate limiters are checked for eviction
+const RATE_LIMIT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+const USAGE: &str = "Usage: pulse-server [--config <path>]
+       pulse-server admin [--config <path>] [--addr <host:port>] <command>
+
+Admin commands (need server.admin_token, or PULSE_ADMIN_TOKEN):
+  users               online users with connection and queued message counts
+  user <user_id>      one user's connections, devices, queue and ban status
+  kick <user_id>      close the user's connections
+  ban <user_id>       refuse the user's connections until unbanned (and kick it)
+  unban <user_id>     lift a ban
+  bans                list banned users
+  purge <user_id>     drop every message queued for the user
+  announce <message>  send a notice to every online user";
+
+/// Handle for changing the log filter after startup
+#[cfg(unix)]
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
 
 #[tokio::main]
 async fn main() {
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("admin") {
+        args.next();
+        std::process::exit(run_admin(args).await);
+    }
+
+    // Load configuration before logging, which it configures
+    let config_path = match config_path_from_args(args) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let config = match ServerConfig::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     // Initialize logging
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
-        )
-        .init();
-
-    // Get bind address: PULSE_SERVER_ADDR takes priority, then PORT (Railway), then default
-    let addr = if let Ok(addr) = std::env::var("PULSE_SERVER_ADDR") {
-        addr
-    } else {
-        let port = std::env::var("PORT")
-            .ok()
-            .and_then(|p| p.parse::<u16>().ok())
-            .unwrap_or(DEFAULT_PORT);
-        format!("0.0.0.0:{}", port)
+    let (filter, filter_handle) = reload::Layer::new(EnvFilter::new(&config.log.level));
+    let registry = tracing_subscriber::registry().with(filter);
+    match config.log.format {
+        LogFormat::Text => registry.with(fmt::layer()).init(),
+        LogFormat::Json => registry.with(fmt::layer().json()).init(),
+    }
+
+    if let Some(path) = &config_path {
+        info!("Loaded configuration from {}", path.display());
+    }
+
+    // Create server state, backed by a persistent store when a data directory is configured
+    let state = match &config.server.data_dir {
+        Some(dir) => {
+            let state = Store::open(dir).and_then(ServerState::with_store);
+            match state {
+                Ok(state) => {
+                    info!("Persisting relay state in {}", dir.display());
+                    state
+                }
+                Err(e) => {
+                    error!("Failed to open data directory: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => {
+            warn!("No data directory configured, offline queue and identities will not survive restarts");
+            ServerState::new()
+        }
+    };
+
+    let state = Arc::new(state.with_runtime_config(config.runtime()));
+
+    // Terminate TLS in-process when a certificate is configured
+    let tls = match &config.tls {
+        Some(tls) => match TlsTerminator::new(&tls.cert_path, &tls.key_path) {
+            Ok(terminator) => {
+                info!("TLS enabled with certificate {}", tls.cert_path.display());
+                Some(Arc::new(terminator))
+            }
+            Err(e) => {
+                error!("Failed to load TLS certificate: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
     };
 
-    // Create server state
-    let state = Arc::new(ServerState::new());
+    #[cfg(unix)]
+    spawn_reload_on_sighup(
+        config_path.clone(),
+        config.clone(),
+        state.clone(),
+        tls.clone(),
+        filter_handle,
+    );
+    #[cfg(not(unix))]
+    drop(filter_handle);
+
+    // Expire queued messages past their TTL, telling their senders. The TTL can change
+    // on reload, so the wait is worked out again before every sweep.
+    let sweeper = state.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(EXPIRY_SWEEP_INTERVAL.min(sweeper.queue().ttl)).await;
+            let expired = sweeper.expire_pending();
+            if expired > 0 {
+                info!("Expired {} queued messages", expired);
+            }
+        }
+    });
+
+    // End call invites nobody answered
+    let call_sweeper = state.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(CALL_SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let expired = call_sweeper.expire_calls();
+            if expired > 0 {
+                info!("Ended {} unanswered calls", expired);
+            }
+        }
+    });
+
+    // Forget rate limits of users who went offline and have since cooled down
+    let limiter_sweeper = state.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RATE_LIMIT_SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let expired = limiter_sweeper.expire_rate_limiters();
+            if expired > 0 {
+                debug!("Dropped {} idle rate limiters", expired);
+            }
+        }
+    });
 
     // Bind TCP listener
-    let listener = match TcpListener::bind(&addr).await {
+    let addr = config.server.bind;
+    let listener = match TcpListener::bind(addr).await {
         Ok(l) => l,
         Err(e) => {
             error!("Failed to bind to {}: {}", addr, e);
@@ -42,10 +177,46 @@ async fn main() {
 
     info!("Pulse server listening on {}", addr);
 
-    // Accept connections with graceful shutdown
+    // Metrics and health probes on their own port, so they are never exposed by accident
+    if let Some(metrics_addr) = config.server.metrics_bind {
+        match TcpListener::bind(metrics_addr).await {
+            Ok(metrics_listener) => {
+                info!(
+                    "Serving metrics and health checks on http://{}",
+                    metrics_addr
+                );
+                tokio::spawn(serve_http(metrics_listener, state.clone()));
+            }
+            Err(e) => {
+                error!("Failed to bind metrics listener to {}: {}", metrics_addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    // The admin API on a loopback port of its own, apart from what probes can reach
+    if let Some(admin_token) = config.server.admin_token.clone() {
+        let admin_addr = config.server.admin_bind;
+        match TcpListener::bind(admin_addr).await {
+            Ok(admin_listener) => {
+                info!("Admin API enabled on http://{}/admin", admin_addr);
+                tokio::spawn(serve_admin(admin_listener, state.clone(), admin_token));
+            }
+            Err(e) => {
+                error!("Failed to bind admin listener to {}: {}", admin_addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if state.requires_identity() {
+        info!("Identity keys are required for every user");
+    }
+
+    // Accept connections until ctrl_c or SIGTERM
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
         tokio::select! {
-            _ = tokio::signal::ctrl_c() => {
+            _ = &mut shutdown => {
                 info!("Received shutdown signal, closing server...");
                 break;
             }
@@ -55,14 +226,20 @@ async fn main() {
                         info!("New connection from {}", peer_addr);
 
                         let state = state.clone();
+                        let tls = tls.clone();
                         tokio::spawn(async move {
-                            match accept_async(stream).await {
-                                Ok(ws_stream) => {
-                                    handle_connection(ws_stream, state).await;
-                                }
-                                Err(e) => {
-                                    error!("WebSocket handshake failed for {}: {}", peer_addr, e);
+                            let Some(tls) = tls else {
+                                return serve(stream, peer_addr, state).await;
+                            };
+                            // Peers that stall the handshake get as long as they would to
+                            // authenticate
+                            let handshake = tls.accept(stream);
+                            match tokio::time::timeout(state.auth().timeout, handshake).await {
+                                Ok(Ok(stream)) => serve(stream, peer_addr, state).await,
+                                Ok(Err(e)) => {
+                                    warn!("TLS handshake failed for {}: {}", peer_addr, e);
                                 }
+                                Err(_) => warn!("TLS handshake timed out for {}", peer_addr),
                             }
                         });
                     }
@@ -74,5 +251,190 @@ async fn main() {
         }
     }
 
+    // Stop accepting, then let every connection say goodbye; a second signal skips the wait
+    drop(listener);
+    tokio::select! {
+        _ = shut_down(&state) => {}
+        _ = shutdown_signal() => warn!("Received second shutdown signal, exiting now"),
+    }
+
     info!("Server shutdown complete");
 }
+
+/// Resolves on ctrl_c, or on SIGTERM (sent by `docker stop`, systemd and most hosts) on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("SIGTERM handling unavailable: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Run the WebSocket handshake under the configured size limits and auth timeout, and hand
+/// the connection to the relay
+async fn serve<S>(stream: S, peer_addr: SocketAddr, state: Arc<ServerState>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let config = state.limits().websocket_config();
+    let handshake = accept_async_with_config(stream, Some(config));
+    match tokio::time::timeout(state.auth().timeout, handshake).await {
+        Ok(Ok(ws_stream)) => {
+            handle_connection(ws_stream, state).await;
+        }
+        Ok(Err(e)) => {
+            error!("WebSocket handshake failed for {}: {}", peer_addr, e);
+        }
+        Err(_) => {
+            warn!("WebSocket handshake timed out for {}", peer_addr);
+        }
+    }
+}
+
+/// Parse `--config <path>` (or `--config=<path>`) from the command line
+fn config_path_from_args(
+    mut args: impl Iterator<Item = String>,
+) -> Result<Option<PathBuf>, String> {
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" | "-c" => {
+                let value = args.next().ok_or("--config requires a path")?;
+                path = Some(PathBuf::from(value));
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            other => match other.strip_prefix("--config=") {
+                Some(value) => path = Some(PathBuf::from(value)),
+                None => return Err(format!("Unknown argument: {}", other)),
+            },
+        }
+    }
+    Ok(path)
+}
+
+/// Run `pulse-server admin ...` against a running server's admin API: the address and
+/// token come from the same config file and environment the server reads, unless
+/// `--addr` is given. Returns the process exit code.
+async fn run_admin(mut args: impl Iterator<Item = String>) -> i32 {
+    let mut config_path = None;
+    let mut addr = None;
+    let mut command = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" | "-c" => match args.next() {
+                Some(value) => config_path = Some(PathBuf::from(value)),
+                None => return usage_error("--config requires a path"),
+            },
+            "--addr" => match args.next().map(|value| value.parse::<SocketAddr>()) {
+                Some(Ok(value)) => addr = Some(value),
+                Some(Err(e)) => return usage_error(&format!("Invalid --addr: {}", e)),
+                None => return usage_error("--addr requires host:port"),
+            },
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return 0;
+            }
+            _ => {
+                command.push(arg);
+                command.extend(args.by_ref());
+            }
+        }
+    }
+    let command = match AdminCommand::parse(&command) {
+        Ok(command) => command,
+        Err(e) => return usage_error(&e),
+    };
+
+    let config = match ServerConfig::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let Some(token) = config.server.admin_token else {
+        eprintln!("No admin token configured (set server.admin_token or PULSE_ADMIN_TOKEN)");
+        return 1;
+    };
+    let addr = addr.unwrap_or(config.server.admin_bind);
+
+    match command.send(addr, &token).await {
+        Ok(body) => {
+            print!("{}", body);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn usage_error(message: &str) -> i32 {
+    eprintln!("{}\n{}", message, USAGE);
+    2
+}
+
+/// Re-read the TLS certificate, config file and environment on SIGHUP and apply the
+/// reloadable settings. Anything that fails to load is logged and the running copy is kept.
+#[cfg(unix)]
+fn spawn_reload_on_sighup(
+    path: Option<PathBuf>,
+    running: ServerConfig,
+    state: Arc<ServerState>,
+    tls: Option<Arc<TlsTerminator>>,
+    filter_handle: FilterHandle,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Config reload on SIGHUP unavailable: {}", e);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            // Open connections keep their session; new handshakes get the new certificate
+            if let Some(tls) = &tls {
+                match tls.reload() {
+                    Ok(()) => info!("TLS certificate reloaded"),
+                    Err(e) => error!("TLS reload failed, keeping current certificate: {}", e),
+                }
+            }
+
+            let config = match ServerConfig::load(path.as_deref()) {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("Config reload failed, keeping current settings: {}", e);
+                    continue;
+                }
+            };
+
+            for key in config.restart_required(&running) {
+                warn!("{} changed; restart the server to apply it", key);
+            }
+            if let Err(e) = filter_handle.reload(EnvFilter::new(&config.log.level)) {
+                error!("Failed to apply log.level: {}", e);
+            }
+            state.reload(config.runtime());
+            info!("Configuration reloaded");
+        }
+    });
+}
@@ -0,0 +1,391 @@
+//! Relay metrics in the Prometheus text exposition format.
+//!
+//! Counters are bumped as frames and handshakes are handled; gauges (users
+//! online, queue depth, calls) are read from `ServerState` when scraped.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use dashmap::DashMap;
+
+use crate::outbound::DeliveryStats;
+
+/// Upper bounds (seconds) of the authentication time histogram buckets
+const AUTH_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Counters shared by every connection
+#[derive(Default)]
+pub struct Metrics {
+    /// WebSocket connections that completed authentication
+    connections: AtomicU64,
+    /// Handshake outcome (`success`, an auth error code or `timeout`) -> count
+    auth_results: DashMap<String, u64>,
+    auth_duration: Histogram,
+    /// Frame `type` -> frames received from clients
+    frames: DashMap<&'static str, u64>,
+    /// Frames from clients that could not be parsed or decoded
+    invalid_frames: AtomicU64,
+    /// Message class -> frames dropped by the rate limiter
+    rate_limited: DashMap<&'static str, u64>,
+    /// Oversized field (`frame` for the whole frame) -> frames rejected for their size
+    oversized: DashMap<&'static str, u64>,
+}
+
+/// Values read from `ServerState` at scrape time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Gauges {
+    pub users_online: usize,
+    pub connections: usize,
+    pub queued_messages: usize,
+    pub active_calls: usize,
+    /// Summed over every user
+    pub delivery: DeliveryStats,
+}
+
+impl Metrics {
+    pub fn record_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a finished handshake and how long it took
+    pub fn record_auth(&self, result: &str, duration: Duration) {
+        *self.auth_results.entry(result.to_string()).or_default() += 1;
+        self.auth_duration.observe(duration);
+    }
+
+    pub fn record_frame(&self, frame_type: &'static str) {
+        *self.frames.entry(frame_type).or_default() += 1;
+    }
+
+    pub fn record_invalid_frame(&self) {
+        self.invalid_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limited(&self, class: &'static str) {
+        *self.rate_limited.entry(class).or_default() += 1;
+    }
+
+    pub fn record_oversized(&self, field: &'static str) {
+        *self.oversized.entry(field).or_default() += 1;
+    }
+
+    /// Frames of one type received so far
+    pub fn frames_received(&self, frame_type: &str) -> u64 {
+        self.frames.get(frame_type).map(|count| *count).unwrap_or(0)
+    }
+
+    /// Frames rejected so far because `field` (or the whole `frame`) was too large
+    pub fn oversized_frames(&self, field: &str) -> u64 {
+        self.oversized.get(field).map(|count| *count).unwrap_or(0)
+    }
+
+    /// Render every metric in the Prometheus text format
+    pub(crate) fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+
+        gauge(
+            &mut out,
+            "pulse_users_online",
+            "Users with at least one open connection",
+            gauges.users_online,
+        );
+        gauge(
+            &mut out,
+            "pulse_connections_open",
+            "Open client connections",
+            gauges.connections,
+        );
+        gauge(
+            &mut out,
+            "pulse_queued_messages",
+            "Messages waiting in offline queues",
+            gauges.queued_messages,
+        );
+        gauge(
+            &mut out,
+            "pulse_active_calls",
+            "Ringing and active calls",
+            gauges.active_calls,
+        );
+
+        counter(
+            &mut out,
+            "pulse_connections_total",
+            "Connections that completed authentication",
+            self.connections.load(Ordering::Relaxed),
+        );
+        labeled_counter(
+            &mut out,
+            "pulse_auth_total",
+            "Authentication handshakes by outcome",
+            "result",
+            sorted(
+                self.auth_results
+                    .iter()
+                    .map(|e| (e.key().clone(), *e.value())),
+            ),
+        );
+        self.auth_duration.render(
+            &mut out,
+            "pulse_auth_duration_seconds",
+            "Time from accepting a connection to the end of its handshake",
+        );
+        labeled_counter(
+            &mut out,
+            "pulse_frames_received_total",
+            "Frames received from clients by type",
+            "type",
+            sorted(
+                self.frames
+                    .iter()
+                    .map(|e| (e.key().to_string(), *e.value())),
+            ),
+        );
+        counter(
+            &mut out,
+            "pulse_invalid_frames_total",
+            "Frames from clients that could not be parsed",
+            self.invalid_frames.load(Ordering::Relaxed),
+        );
+        labeled_counter(
+            &mut out,
+            "pulse_rate_limited_total",
+            "Frames dropped by the rate limiter by message class",
+            "class",
+            sorted(
+                self.rate_limited
+                    .iter()
+                    .map(|e| (e.key().to_string(), *e.value())),
+            ),
+        );
+        labeled_counter(
+            &mut out,
+            "pulse_oversized_frames_total",
+            "Frames rejected for their size, by the field over its limit",
+            "field",
+            sorted(
+                self.oversized
+                    .iter()
+                    .map(|e| (e.key().to_string(), *e.value())),
+            ),
+        );
+
+        let delivery = gauges.delivery;
+        labeled_counter(
+            &mut out,
+            "pulse_undelivered_total",
+            "Frames that did not reach a connection directly, by what happened to them",
+            "outcome",
+            vec![
+                ("dropped".to_string(), delivery.dropped),
+                ("spilled".to_string(), delivery.spilled),
+                ("expired".to_string(), delivery.expired),
+                ("evicted".to_string(), delivery.evicted),
+                ("purged".to_string(), delivery.purged),
+            ],
+        );
+        counter(
+            &mut out,
+            "pulse_slow_disconnects_total",
+            "Connections closed for staying too slow to keep up",
+            delivery.slow_disconnects,
+        );
+        out
+    }
+}
+
+/// Cumulative histogram over `AUTH_BUCKETS`
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; AUTH_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, bucket) in AUTH_BUCKETS.iter().zip(&self.buckets) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let count = self.count.load(Ordering::Relaxed);
+        header(out, name, help, "histogram");
+        for (bound, bucket) in AUTH_BUCKETS.iter().zip(&self.buckets) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn labeled_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: Vec<(String, u64)>,
+) {
+    header(out, name, help, "counter");
+    for (value, count) in values {
+        let _ = writeln!(
+            out,
+            "{}{{{}=\"{}\"}} {}",
+            name,
+            label,
+            escape_label(&value),
+            count
+        );
+    }
+}
+
+/// Stable output order, so consecutive scrapes are easy to diff
+fn sorted(values: impl Iterator<Item = (String, u64)>) -> Vec<(String, u64)> {
+    let mut values: Vec<_> = values.collect();
+    values.sort();
+    values
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_rendered_with_labels() {
+        let metrics = Metrics::default();
+        metrics.record_connection();
+        metrics.record_frame("message");
+        metrics.record_frame("message");
+        metrics.record_frame("typing");
+        metrics.record_rate_limited("chat");
+        metrics.record_oversized("sdp");
+        metrics.record_auth("invalid_token", Duration::from_millis(3));
+
+        let text = metrics.render(&Gauges {
+            users_online: 2,
+            queued_messages: 7,
+            ..Gauges::default()
+        });
+        assert!(text.contains("pulse_users_online 2\n"), "{}", text);
+        assert!(text.contains("pulse_queued_messages 7\n"), "{}", text);
+        assert!(text.contains("pulse_connections_total 1\n"), "{}", text);
+        assert!(
+            text.contains("pulse_frames_received_total{type=\"message\"} 2\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("pulse_frames_received_total{type=\"typing\"} 1\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("pulse_rate_limited_total{class=\"chat\"} 1\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("pulse_oversized_frames_total{field=\"sdp\"} 1\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("pulse_auth_total{result=\"invalid_token\"} 1\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("# TYPE pulse_auth_duration_seconds histogram\n"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.record_auth("success", Duration::from_millis(20));
+        metrics.record_auth("success", Duration::from_millis(300));
+        metrics.record_auth("timeout", Duration::from_secs(10));
+
+        let text = metrics.render(&Gauges::default());
+        assert!(
+            text.contains("pulse_auth_duration_seconds_bucket{le=\"0.01\"} 0\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("pulse_auth_duration_seconds_bucket{le=\"0.025\"} 1\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("pulse_auth_duration_seconds_bucket{le=\"0.5\"} 2\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("pulse_auth_duration_seconds_bucket{le=\"5\"} 2\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("pulse_auth_duration_seconds_bucket{le=\"+Inf\"} 3\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("pulse_auth_duration_seconds_count 3\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("pulse_auth_duration_seconds_sum 10.32\n"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn test_label_values_escaped() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
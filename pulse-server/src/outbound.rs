@@ -0,0 +1,188 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+
+use crate::protocol::Negotiated;
+
+/// Outbound queue settings applied to every connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboundConfig {
+    /// Frames buffered per connection before the overflow policy applies
+    pub queue_depth: usize,
+    /// Consecutive overflows (without the queue draining) before a slow client is disconnected
+    pub max_overflows: u32,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            queue_depth: 256,
+            max_overflows: 64,
+        }
+    }
+}
+
+/// Per-user delivery counters (exposed for tests/metrics)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeliveryStats {
+    /// Ephemeral frames (typing, presence, profile, call signaling) dropped on overflow
+    pub dropped: u64,
+    /// Chat messages and receipts moved to the pending queue on overflow
+    pub spilled: u64,
+    /// Connections closed for staying too slow to keep up
+    pub slow_disconnects: u64,
+    /// Queued messages dropped because they outlived the queue TTL
+    pub expired: u64,
+    /// Queued messages evicted (or refused) to stay within the queue quotas
+    pub evicted: u64,
+    /// Queued messages dropped by an operator purging the queue
+    pub purged: u64,
+}
+
+/// Result of offering a frame to one connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Offer {
+    Sent,
+    Full,
+    Closed,
+}
+
+/// One live connection's bounded outbound queue
+pub struct ClientChannel {
+    /// Device of the user this connection belongs to
+    device_id: String,
+    /// What the connection negotiated, which decides the frames it may be sent
+    protocol: Negotiated,
+    tx: mpsc::Sender<String>,
+    /// Overflows since the connection last drained its queue
+    overflows: AtomicU32,
+    /// Signalled once the connection has overflowed `max_overflows` times in a row
+    kick: Notify,
+    /// Signalled when the server operator closes the connection
+    disconnect: Notify,
+}
+
+impl ClientChannel {
+    pub fn new(
+        device_id: impl Into<String>,
+        protocol: Negotiated,
+        tx: mpsc::Sender<String>,
+    ) -> Self {
+        Self {
+            device_id: device_id.into(),
+            protocol,
+            tx,
+            overflows: AtomicU32::new(0),
+            kick: Notify::new(),
+            disconnect: Notify::new(),
+        }
+    }
+
+    /// Queue a frame without waiting, counting overflows toward a disconnect
+    pub(crate) fn offer(&self, message: String, max_overflows: u32) -> Offer {
+        match self.tx.try_send(message) {
+            Ok(()) => Offer::Sent,
+            Err(TrySendError::Full(_)) => {
+                if self.overflows.fetch_add(1, Ordering::Relaxed) + 1 == max_overflows {
+                    self.kick.notify_one();
+                }
+                Offer::Full
+            }
+            Err(TrySendError::Closed(_)) => Offer::Closed,
+        }
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// Whether the connection negotiated what `frame` needs
+    pub fn allows_frame(&self, frame: &str) -> bool {
+        self.protocol.allows_frame(frame)
+    }
+
+    /// Whether the connection has gone away
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Called by the connection once it has caught up and its queue is empty
+    pub fn mark_drained(&self) {
+        self.overflows.store(0, Ordering::Relaxed);
+    }
+
+    /// Resolves once the connection should be dropped for being persistently slow
+    pub async fn kicked(&self) {
+        self.kick.notified().await;
+    }
+
+    /// Ask the connection to close (remembered until the connection next checks)
+    pub fn disconnect(&self) {
+        self.disconnect.notify_one();
+    }
+
+    /// Resolves once `disconnect` has been called
+    pub async fn disconnected(&self) {
+        self.disconnect.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offer_until_full() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let channel = ClientChannel::new("desk", Negotiated::current(), tx);
+
+        assert_eq!(channel.offer("a".to_string(), 10), Offer::Sent);
+        assert_eq!(channel.offer("b".to_string(), 10), Offer::Sent);
+        assert_eq!(channel.offer("c".to_string(), 10), Offer::Full);
+
+        assert_eq!(rx.try_recv().unwrap(), "a");
+        assert_eq!(channel.offer("d".to_string(), 10), Offer::Sent);
+    }
+
+    #[test]
+    fn test_offer_to_closed_channel() {
+        let (tx, rx) = mpsc::channel(2);
+        let channel = ClientChannel::new("desk", Negotiated::current(), tx);
+        drop(rx);
+
+        assert!(channel.is_closed());
+        assert_eq!(channel.offer("a".to_string(), 10), Offer::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_persistent_overflow_kicks() {
+        let (tx, _rx) = mpsc::channel(1);
+        let channel = ClientChannel::new("desk", Negotiated::current(), tx);
+
+        channel.offer("fill".to_string(), 3);
+        for _ in 0..3 {
+            assert_eq!(channel.offer("overflow".to_string(), 3), Offer::Full);
+        }
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), channel.kicked())
+            .await
+            .expect("Channel should have been kicked");
+    }
+
+    #[tokio::test]
+    async fn test_draining_resets_overflows() {
+        let (tx, _rx) = mpsc::channel(1);
+        let channel = ClientChannel::new("desk", Negotiated::current(), tx);
+
+        channel.offer("fill".to_string(), 3);
+        channel.offer("overflow".to_string(), 3);
+        channel.offer("overflow".to_string(), 3);
+        channel.mark_drained();
+        channel.offer("overflow".to_string(), 3);
+
+        let kicked =
+            tokio::time::timeout(std::time::Duration::from_millis(50), channel.kicked()).await;
+        assert!(kicked.is_err(), "Drained channel should not be kicked");
+    }
+}
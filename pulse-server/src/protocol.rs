@@ -0,0 +1,186 @@
+//! Protocol version and capability negotiation.
+//!
+//! The server settles on the version both sides speak and the capabilities both
+//! support, and only sends frames a connection has negotiated (or whose version
+//! introduced them). Clients older
+//! than [`MIN_PROTOCOL_VERSION`] are refused with `unsupported_version`.
+
+use pulse_protocol::{
+    min_version_for, Capability, Encoding, LEGACY_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use serde::Deserialize;
+
+/// Capabilities this server offers to clients
+pub const SERVER_CAPABILITIES: &[Capability] = &[
+    Capability::Acks,
+    Capability::Msgpack,
+    Capability::Cbor,
+    Capability::Keys,
+];
+
+/// Outcome of negotiating a client's `Connect`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    /// Version both sides speak
+    pub version: u32,
+    /// Capabilities both sides support
+    pub capabilities: Vec<Capability>,
+}
+
+impl Negotiated {
+    /// Settle on a version and capability set, or None if the client is too old
+    pub fn new(client_version: Option<u32>, client_capabilities: &[Capability]) -> Option<Self> {
+        let client_version = client_version.unwrap_or(LEGACY_PROTOCOL_VERSION);
+        if client_version < MIN_PROTOCOL_VERSION {
+            return None;
+        }
+
+        let capabilities = SERVER_CAPABILITIES
+            .iter()
+            .copied()
+            .filter(|cap| client_capabilities.contains(cap))
+            .collect();
+
+        Some(Self {
+            version: client_version.min(PROTOCOL_VERSION),
+            capabilities,
+        })
+    }
+
+    /// Everything this server offers (what tests and current clients get)
+    pub fn current() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: SERVER_CAPABILITIES.to_vec(),
+        }
+    }
+
+    /// What a client that sent no version or capabilities gets
+    pub fn legacy() -> Self {
+        Self {
+            version: LEGACY_PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        }
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// How frames are encoded on this connection once authenticated
+    pub fn encoding(&self) -> Encoding {
+        Encoding::negotiated(&self.capabilities)
+    }
+
+    /// Whether a routed frame or reply may be sent on this connection
+    pub fn allows_frame(&self, frame: &str) -> bool {
+        // Acks and keys are the only capabilities that gate frames; current clients with
+        // both get everything, so skip the parse
+        let current = self.version >= PROTOCOL_VERSION;
+        if current && self.supports(Capability::Acks) && self.supports(Capability::Keys) {
+            return true;
+        }
+        let Ok(frame) = serde_json::from_str::<FrameType>(frame) else {
+            return true;
+        };
+        let capable = Capability::required_for(frame.kind).is_none_or(|cap| self.supports(cap));
+        capable && self.version >= min_version_for(frame.kind)
+    }
+}
+
+/// The `type` tag of a frame, read without parsing the rest
+#[derive(Deserialize)]
+struct FrameType<'a> {
+    #[serde(rename = "type", borrow)]
+    kind: &'a str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_client_gets_no_capabilities() {
+        let negotiated = Negotiated::new(None, &[]).unwrap();
+        assert_eq!(negotiated, Negotiated::legacy());
+        assert!(!negotiated.supports(Capability::Acks));
+    }
+
+    #[test]
+    fn test_negotiates_down_and_intersects() {
+        let negotiated = Negotiated::new(
+            Some(PROTOCOL_VERSION + 5),
+            &[Capability::Unknown, Capability::Acks],
+        )
+        .unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.capabilities, vec![Capability::Acks]);
+    }
+
+    #[test]
+    fn test_too_old_rejected() {
+        assert!(Negotiated::new(Some(MIN_PROTOCOL_VERSION - 1), &[Capability::Acks]).is_none());
+    }
+
+    #[test]
+    fn test_frames_gated_on_capability() {
+        let legacy = Negotiated::legacy();
+        let ack = r#"{"type":"server_ack","message_id":"m1","status":"delivered"}"#;
+        let failed = r#"{"type":"delivery_failed","message_id":"m1","chat_id":"c","recipient_id":"r","reason":"expired"}"#;
+        let typing = r#"{"type":"typing","chat_id":"c","user_id":"u","is_typing":true}"#;
+
+        assert!(!legacy.allows_frame(ack));
+        assert!(!legacy.allows_frame(failed));
+        assert!(legacy.allows_frame(typing));
+        assert!(Negotiated::current().allows_frame(ack));
+    }
+
+    #[test]
+    fn test_key_response_needs_keys() {
+        let key_response = r#"{"type":"key_response","user_id":"alice"}"#;
+        let acks_only = Negotiated::new(Some(PROTOCOL_VERSION), &[Capability::Acks]).unwrap();
+
+        assert!(!Negotiated::legacy().allows_frame(key_response));
+        assert!(!acks_only.allows_frame(key_response));
+        assert!(Negotiated::current().allows_frame(key_response));
+    }
+
+    #[test]
+    fn test_missed_call_needs_version_2() {
+        let missed = r#"{"type":"missed_call","call_id":"c1","from_user_id":"alice"}"#;
+        let v1 = Negotiated::new(Some(1), &[Capability::Acks, Capability::Keys]).unwrap();
+
+        assert!(!Negotiated::legacy().allows_frame(missed));
+        assert!(!v1.allows_frame(missed));
+        assert!(Negotiated::current().allows_frame(missed));
+        // Version only gates the frames it introduced
+        let ack = r#"{"type":"server_ack","message_id":"m1","status":"delivered"}"#;
+        assert!(v1.allows_frame(ack));
+    }
+
+    #[test]
+    fn test_announcement_needs_version_2() {
+        let announcement = r#"{"type":"announcement","message":"Maintenance","timestamp":1}"#;
+        let v1 = Negotiated::new(Some(1), &[Capability::Acks]).unwrap();
+
+        assert!(!v1.allows_frame(announcement));
+        assert!(Negotiated::current().allows_frame(announcement));
+    }
+
+    #[test]
+    fn test_server_shutdown_needs_version_2() {
+        let shutdown = r#"{"type":"server_shutdown","reconnect_after_secs":5}"#;
+
+        assert!(!Negotiated::legacy().allows_frame(shutdown));
+        assert!(Negotiated::current().allows_frame(shutdown));
+    }
+
+    #[test]
+    fn test_binary_encoding_negotiated() {
+        assert_eq!(Negotiated::legacy().encoding(), Encoding::Json);
+        let cbor = Negotiated::new(Some(PROTOCOL_VERSION), &[Capability::Cbor]).unwrap();
+        assert_eq!(cbor.encoding(), Encoding::Cbor);
+        assert_eq!(Negotiated::current().encoding(), Encoding::MessagePack);
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Limits applied to every offline device's pending queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// How long a message may wait for its recipient before it expires
+    pub ttl: Duration,
+    /// Messages kept per device; the oldest is evicted beyond this
+    pub max_messages: usize,
+    /// Payload bytes kept per device; the oldest messages are evicted beyond this
+    pub max_bytes: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            max_messages: 1000,
+            max_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// A message waiting in an offline device's queue
+pub(crate) struct PendingMessage {
+    /// Row id in the persistent store (None when running without one)
+    pub seq: Option<i64>,
+    pub payload: String,
+    /// Unix millis when the message was queued
+    pub queued_at: i64,
+    /// Copy for a device of a user who already had the message elsewhere,
+    /// so losing it is not reported to the sender
+    pub sync_copy: bool,
+}
+
+/// One device's pending messages, oldest first, with their total size
+#[derive(Default)]
+pub(crate) struct PendingQueue {
+    messages: VecDeque<PendingMessage>,
+    bytes: usize,
+}
+
+impl PendingQueue {
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn front(&self) -> Option<&PendingMessage> {
+        self.messages.front()
+    }
+
+    pub fn push(&mut self, message: PendingMessage) {
+        self.bytes += message.payload.len();
+        self.messages.push_back(message);
+    }
+
+    pub fn pop_front(&mut self) -> Option<PendingMessage> {
+        let message = self.messages.pop_front()?;
+        self.bytes -= message.payload.len();
+        Some(message)
+    }
+
+    /// Evict the oldest messages until one of `incoming` bytes fits within the limits
+    pub fn make_room(&mut self, incoming: usize, config: &QueueConfig) -> Vec<PendingMessage> {
+        let mut evicted = Vec::new();
+        while !self.messages.is_empty()
+            && (self.messages.len() >= config.max_messages
+                || self.bytes + incoming > config.max_bytes)
+        {
+            evicted.extend(self.pop_front());
+        }
+        evicted
+    }
+
+    /// Remove messages queued before `cutoff` (unix millis)
+    pub fn take_expired(&mut self, cutoff: i64) -> Vec<PendingMessage> {
+        let mut expired = Vec::new();
+        while self.front().is_some_and(|m| m.queued_at < cutoff) {
+            expired.extend(self.pop_front());
+        }
+        expired
+    }
+
+    pub fn into_messages(self) -> impl Iterator<Item = PendingMessage> {
+        self.messages.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(payload: &str, queued_at: i64) -> PendingMessage {
+        PendingMessage {
+            seq: None,
+            payload: payload.to_string(),
+            queued_at,
+            sync_copy: false,
+        }
+    }
+
+    #[test]
+    fn test_tracks_bytes() {
+        let mut queue = PendingQueue::default();
+        queue.push(message("abc", 1));
+        queue.push(message("de", 2));
+        assert_eq!(queue.bytes, 5);
+
+        queue.pop_front();
+        assert_eq!(queue.bytes, 2);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_make_room_by_count() {
+        let config = QueueConfig {
+            max_messages: 2,
+            ..Default::default()
+        };
+        let mut queue = PendingQueue::default();
+        queue.push(message("a", 1));
+        queue.push(message("b", 2));
+
+        let evicted = queue.make_room(1, &config);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].payload, "a");
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_make_room_by_bytes() {
+        let config = QueueConfig {
+            max_bytes: 10,
+            ..Default::default()
+        };
+        let mut queue = PendingQueue::default();
+        queue.push(message("aaaa", 1));
+        queue.push(message("bbbb", 2));
+
+        assert!(queue.make_room(2, &config).is_empty());
+        let evicted = queue.make_room(6, &config);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(queue.front().unwrap().payload, "bbbb");
+
+        // A message larger than the whole quota empties the queue and still does not fit
+        assert_eq!(queue.make_room(11, &config).len(), 1);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_take_expired_stops_at_first_fresh_message() {
+        let mut queue = PendingQueue::default();
+        queue.push(message("old", 100));
+        queue.push(message("older than cutoff", 150));
+        queue.push(message("fresh", 300));
+
+        let expired = queue.take_expired(200);
+        assert_eq!(expired.len(), 2);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.front().unwrap().payload, "fresh");
+    }
+}
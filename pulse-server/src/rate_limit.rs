@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use pulse_protocol::WsMessage;
+
+/// Message classes that are rate-limited independently
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageClass {
+    /// Chat messages and delivery/read receipts
+    Chat,
+    /// Typing indicators
+    Typing,
+    /// Presence announcements, subscription changes and block list edits
+    Presence,
+    /// Call control and WebRTC signaling
+    Call,
+    /// Profile updates (may carry avatar bytes)
+    Profile,
+    /// Key directory lookups and publishes
+    Keys,
+}
+
+impl MessageClass {
+    /// Classify a client frame; None for frames that are not rate-limited
+    pub fn of(msg: &WsMessage) -> Option<Self> {
+        match msg {
+            WsMessage::ChatMessage { .. }
+            | WsMessage::DeliveryReceipt { .. }
+            | WsMessage::ReadReceipt { .. } => Some(Self::Chat),
+            WsMessage::Typing { .. } => Some(Self::Typing),
+            WsMessage::Presence { .. }
+            | WsMessage::SubscribePresence { .. }
+            | WsMessage::BlockUser { .. }
+            | WsMessage::UnblockUser { .. } => Some(Self::Presence),
+            WsMessage::CallInvite { .. }
+            | WsMessage::CallRinging { .. }
+            | WsMessage::CallAccept { .. }
+            | WsMessage::CallReject { .. }
+            | WsMessage::CallHangup { .. }
+            | WsMessage::RtcOffer { .. }
+            | WsMessage::RtcAnswer { .. }
+            | WsMessage::RtcIceCandidate { .. } => Some(Self::Call),
+            WsMessage::ProfileUpdate { .. } => Some(Self::Profile),
+            WsMessage::PublishKey { .. } | WsMessage::KeyRequest { .. } => Some(Self::Keys),
+            WsMessage::Connect { .. }
+            | WsMessage::AuthChallenge { .. }
+            | WsMessage::AuthProof { .. }
+            | WsMessage::AuthResponse { .. }
+            | WsMessage::Error { .. }
+            | WsMessage::ServerAck { .. }
+            | WsMessage::DeliveryFailed { .. }
+            | WsMessage::Announcement { .. }
+            | WsMessage::ServerShutdown { .. }
+            | WsMessage::KeyResponse { .. }
+            | WsMessage::BlockList { .. }
+            | WsMessage::MissedCall { .. } => None,
+        }
+    }
+
+    /// Name used in error frames and logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Chat => "chat",
+            Self::Typing => "typing",
+            Self::Presence => "presence",
+            Self::Call => "call",
+            Self::Profile => "profile",
+            Self::Keys => "keys",
+        }
+    }
+}
+
+/// Token bucket parameters for one message class
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BucketConfig {
+    /// Maximum burst size
+    pub capacity: u32,
+    /// Tokens added per second
+    pub refill_per_sec: f64,
+}
+
+impl BucketConfig {
+    pub const fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        Self {
+            capacity,
+            refill_per_sec,
+        }
+    }
+}
+
+/// Rate limits applied to every user
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitConfig {
+    pub chat: BucketConfig,
+    pub typing: BucketConfig,
+    pub presence: BucketConfig,
+    pub call: BucketConfig,
+    pub profile: BucketConfig,
+    pub keys: BucketConfig,
+    /// Limited frames tolerated within `violation_window` before disconnecting
+    pub max_violations: u32,
+    pub violation_window: Duration,
+}
+
+impl RateLimitConfig {
+    pub fn bucket(&self, class: MessageClass) -> BucketConfig {
+        match class {
+            MessageClass::Chat => self.chat,
+            MessageClass::Typing => self.typing,
+            MessageClass::Presence => self.presence,
+            MessageClass::Call => self.call,
+            MessageClass::Profile => self.profile,
+            MessageClass::Keys => self.keys,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            chat: BucketConfig::new(30, 5.0),
+            typing: BucketConfig::new(10, 2.0),
+            presence: BucketConfig::new(5, 1.0),
+            // ICE candidates arrive in bursts when a call starts
+            call: BucketConfig::new(200, 50.0),
+            profile: BucketConfig::new(5, 0.1),
+            // Opening a long contact list looks up many keys at once
+            keys: BucketConfig::new(50, 2.0),
+            max_violations: 20,
+            violation_window: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Result of checking a frame against the limiter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateDecision {
+    /// Within limits, process the frame
+    Allow,
+    /// Over the limit, drop the frame and tell the client
+    Limited,
+    /// Over the limit too often, disconnect the client
+    Disconnect,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: BucketConfig, now: Instant) -> Self {
+        Self {
+            tokens: config.capacity as f64,
+            last_refill: now,
+        }
+    }
+
+    fn try_take(&mut self, config: BucketConfig, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.refill_per_sec).min(config.capacity as f64);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&self, config: BucketConfig, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens + elapsed * config.refill_per_sec >= config.capacity as f64
+    }
+}
+
+/// Per-user token buckets plus violation tracking
+pub struct RateLimiter {
+    buckets: HashMap<MessageClass, TokenBucket>,
+    /// Total limited frames per class (exposed for tests/metrics)
+    limited: HashMap<MessageClass, u64>,
+    window_start: Instant,
+    window_violations: u32,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            limited: HashMap::new(),
+            window_start: Instant::now(),
+            window_violations: 0,
+        }
+    }
+
+    /// Check a frame of the given class, consuming a token if allowed
+    pub fn check(&mut self, class: MessageClass, config: &RateLimitConfig) -> RateDecision {
+        self.check_at(class, config, Instant::now())
+    }
+
+    fn check_at(
+        &mut self,
+        class: MessageClass,
+        config: &RateLimitConfig,
+        now: Instant,
+    ) -> RateDecision {
+        let bucket_config = config.bucket(class);
+        let bucket = self
+            .buckets
+            .entry(class)
+            .or_insert_with(|| TokenBucket::new(bucket_config, now));
+
+        if bucket.try_take(bucket_config, now) {
+            return RateDecision::Allow;
+        }
+
+        *self.limited.entry(class).or_insert(0) += 1;
+
+        if now.duration_since(self.window_start) > config.violation_window {
+            self.window_start = now;
+            self.window_violations = 0;
+        }
+        self.window_violations += 1;
+
+        if self.window_violations > config.max_violations {
+            RateDecision::Disconnect
+        } else {
+            RateDecision::Limited
+        }
+    }
+
+    /// Whether the limiter remembers nothing a fresh one would not: every bucket
+    /// has refilled and no violations are left in the current window
+    pub fn is_idle(&self, config: &RateLimitConfig) -> bool {
+        self.is_idle_at(config, Instant::now())
+    }
+
+    fn is_idle_at(&self, config: &RateLimitConfig, now: Instant) -> bool {
+        let window_clear = self.window_violations == 0
+            || now.duration_since(self.window_start) > config.violation_window;
+        window_clear
+            && self
+                .buckets
+                .iter()
+                .all(|(class, bucket)| bucket.is_full(config.bucket(*class), now))
+    }
+
+    /// Number of frames limited for a class so far
+    pub fn limited_count(&self, class: MessageClass) -> u64 {
+        self.limited.get(&class).copied().unwrap_or(0)
+    }
+
+    /// Number of frames limited across all classes so far
+    pub fn total_limited(&self) -> u64 {
+        self.limited.values().sum()
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(capacity: u32, refill_per_sec: f64, max_violations: u32) -> RateLimitConfig {
+        let bucket = BucketConfig::new(capacity, refill_per_sec);
+        RateLimitConfig {
+            chat: bucket,
+            typing: bucket,
+            presence: bucket,
+            call: bucket,
+            profile: bucket,
+            keys: bucket,
+            max_violations,
+            violation_window: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn test_burst_then_limited() {
+        let config = config(3, 0.0, 10);
+        let mut limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.check_at(MessageClass::Chat, &config, now),
+                RateDecision::Allow
+            );
+        }
+        assert_eq!(
+            limiter.check_at(MessageClass::Chat, &config, now),
+            RateDecision::Limited
+        );
+        assert_eq!(limiter.limited_count(MessageClass::Chat), 1);
+    }
+
+    #[test]
+    fn test_refill_over_time() {
+        let config = config(1, 2.0, 10);
+        let mut limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.check_at(MessageClass::Typing, &config, now),
+            RateDecision::Allow
+        );
+        assert_eq!(
+            limiter.check_at(MessageClass::Typing, &config, now),
+            RateDecision::Limited
+        );
+
+        // Half a second at 2 tokens/sec refills one token
+        let later = now + Duration::from_millis(500);
+        assert_eq!(
+            limiter.check_at(MessageClass::Typing, &config, later),
+            RateDecision::Allow
+        );
+    }
+
+    #[test]
+    fn test_classes_are_independent() {
+        let config = config(1, 0.0, 10);
+        let mut limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.check_at(MessageClass::Typing, &config, now),
+            RateDecision::Allow
+        );
+        assert_eq!(
+            limiter.check_at(MessageClass::Typing, &config, now),
+            RateDecision::Limited
+        );
+        assert_eq!(
+            limiter.check_at(MessageClass::Chat, &config, now),
+            RateDecision::Allow
+        );
+        assert_eq!(limiter.limited_count(MessageClass::Chat), 0);
+    }
+
+    #[test]
+    fn test_repeat_offender_disconnected() {
+        let config = config(0, 0.0, 2);
+        let mut limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.check_at(MessageClass::Call, &config, now),
+            RateDecision::Limited
+        );
+        assert_eq!(
+            limiter.check_at(MessageClass::Call, &config, now),
+            RateDecision::Limited
+        );
+        assert_eq!(
+            limiter.check_at(MessageClass::Call, &config, now),
+            RateDecision::Disconnect
+        );
+        assert_eq!(limiter.total_limited(), 3);
+    }
+
+    #[test]
+    fn test_violation_window_resets() {
+        let config = config(0, 0.0, 1);
+        let mut limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.check_at(MessageClass::Call, &config, now),
+            RateDecision::Limited
+        );
+        let later = now + Duration::from_secs(11);
+        assert_eq!(
+            limiter.check_at(MessageClass::Call, &config, later),
+            RateDecision::Limited
+        );
+    }
+
+    #[test]
+    fn test_idle_once_refilled_and_window_lapsed() {
+        let config = config(1, 1.0, 10);
+        let mut limiter = RateLimiter::new();
+        let now = Instant::now();
+        assert!(limiter.is_idle_at(&config, now));
+
+        limiter.check_at(MessageClass::Chat, &config, now);
+        limiter.check_at(MessageClass::Chat, &config, now);
+        assert!(!limiter.is_idle_at(&config, now));
+
+        // The bucket refills after a second, but the violation is remembered
+        // for the rest of its window
+        assert!(!limiter.is_idle_at(&config, now + Duration::from_secs(2)));
+        assert!(limiter.is_idle_at(&config, now + Duration::from_secs(11)));
+    }
+}
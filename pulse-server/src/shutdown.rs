@@ -0,0 +1,94 @@
+//! Coordinated shutdown: stop taking traffic, tell every client when to come
+//! back, wait for the connections to close, then flush the store.
+
+use std::time::{Duration, Instant};
+
+use tracing::{error, info, warn};
+
+use crate::state::ServerState;
+
+/// How often the drain checks whether connections are still open
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Shutdown settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownConfig {
+    /// Longest the server waits for connections to close before exiting anyway
+    pub grace_period: Duration,
+    /// Delay suggested to clients in `server_shutdown` before they reconnect
+    pub reconnect_delay: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: Duration::from_secs(10),
+            reconnect_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Send every connection `server_shutdown` and close it, wait up to the grace period
+/// for them to finish (undelivered frames go back to the offline queue), then flush
+/// the store. Returns how many connections were still open when the wait ended.
+pub async fn shut_down(state: &ServerState) -> usize {
+    let config = state.shutdown();
+    let open = state.connections_open();
+    info!(
+        "Shutting down: closing {} connections (grace period {:?})",
+        open, config.grace_period
+    );
+    state.begin_shutdown();
+
+    let deadline = Instant::now() + config.grace_period;
+    let mut open = state.connections_open();
+    while open > 0 && Instant::now() < deadline {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        open = state.connections_open();
+    }
+    if open > 0 {
+        warn!(
+            "{} connections still open after {:?}, exiting anyway",
+            open, config.grace_period
+        );
+    }
+
+    match state.flush() {
+        Ok(()) => info!("Relay state flushed"),
+        Err(e) => error!("Failed to flush relay state: {}", e),
+    }
+    open
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_shut_down_waits_for_connections_up_to_grace_period() {
+        let state = ServerState::new().with_shutdown(ShutdownConfig {
+            grace_period: Duration::from_millis(200),
+            reconnect_delay: Duration::from_secs(1),
+        });
+        let (tx, rx) = mpsc::channel(4);
+        state.add_client("alice".to_string(), tx);
+        let mut signal = state.shutdown_signal();
+
+        // The connection closes as soon as it is told to
+        let closer = tokio::spawn(async move {
+            signal.wait_for(|down| *down).await.unwrap();
+            drop(rx);
+        });
+        assert_eq!(shut_down(&state).await, 0);
+        closer.await.unwrap();
+        assert_eq!(state.readiness(), Err("shutting down".to_string()));
+
+        // One that never closes is given up on after the grace period
+        let (tx, _rx) = mpsc::channel(4);
+        state.add_client("bob".to_string(), tx);
+        let started = Instant::now();
+        assert_eq!(shut_down(&state).await, 1);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -1,16 +1,74 @@
-use dashmap::DashMap;
-use tokio::sync::mpsc;
-use tracing::info;
+use std::collections::{HashMap, HashSet};
 
-/// Maximum pending messages per user to prevent unbounded memory growth
-const MAX_PENDING_MESSAGES_PER_USER: usize = 1000;
+use dashmap::mapref::entry::Entry;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use dashmap::{DashMap, DashSet};
+use pulse_protocol::{DeliveryFailureReason, WsChatParticipants, WsMessage};
+use tokio::sync::{mpsc, watch};
+use tracing::{error, info, warn};
+
+use crate::auth::AuthConfig;
+use crate::calls::{CallConfig, CallDecision, CallRegistry, CallState};
+use crate::config::RuntimeConfig;
+use crate::heartbeat::HeartbeatConfig;
+use crate::limits::LimitsConfig;
+use crate::metrics::{Gauges, Metrics};
+use crate::outbound::{ClientChannel, DeliveryStats, Offer, OutboundConfig};
+use crate::protocol::Negotiated;
+use crate::queue::{PendingMessage, PendingQueue, QueueConfig};
+use crate::rate_limit::{MessageClass, RateDecision, RateLimitConfig, RateLimiter};
+use crate::shutdown::ShutdownConfig;
+use crate::store::Store;
+
+/// Maximum users (and chats, and participants per chat) one subscription may name
+const MAX_SUBSCRIPTIONS_PER_USER: usize = 5000;
+
+/// Maximum users one account may block
+const MAX_BLOCKS_PER_USER: usize = 5000;
+
+/// Maximum devices one account may connect from
+const MAX_DEVICES_PER_USER: usize = 16;
+
+/// Device of clients that do not send a device_id in Connect
+pub const DEFAULT_DEVICE_ID: &str = "default";
 
 /// Server state managing connected clients and pending messages
 pub struct ServerState {
-    /// user_id -> list of sender channels (supports multiple connections per user)
-    pub clients: DashMap<String, Vec<mpsc::UnboundedSender<String>>>,
-    /// user_id -> list of pending messages (for offline users)
-    pending_messages: DashMap<String, Vec<String>>,
+    /// user_id -> bounded outbound queue per connection (supports multiple connections per user)
+    pub clients: DashMap<String, Vec<Arc<ClientChannel>>>,
+    /// user_id -> device_id -> pending messages, oldest first (for offline devices)
+    pending_messages: DashMap<String, HashMap<String, PendingQueue>>,
+    /// user_id -> X25519 identity public key the user_id is bound to
+    identities: DashMap<String, [u8; 32]>,
+    /// user_id -> devices the user has connected from (each gets its own queue)
+    devices: DashMap<String, HashSet<String>>,
+    /// user_id -> users it has blocked (no frames are relayed between a blocked pair)
+    blocks: DashMap<String, HashSet<String>>,
+    /// Users the server operator has banned (their handshakes are refused)
+    bans: DashSet<String>,
+    /// Durable copy of `pending_messages`, `identities`, `devices`, `blocks` and `bans`
+    /// (survives restarts)
+    store: Option<Store>,
+    /// Auth, rate limit, heartbeat, outbound, queue, call and size limit settings
+    /// (swapped on reload)
+    settings: RwLock<RuntimeConfig>,
+    /// user_id -> frames dropped/spilled because the user's queues were full
+    delivery_stats: DashMap<String, DeliveryStats>,
+    /// user_id -> rate limiter shared by all of the user's connections
+    rate_limiters: DashMap<String, RateLimiter>,
+    /// subscriber -> users whose presence/profile updates it receives
+    subscriptions: DashMap<String, HashSet<String>>,
+    /// user_id -> subscribers (reverse index of `subscriptions`)
+    subscribers: DashMap<String, HashSet<String>>,
+    /// user_id -> chat_id -> other participants its typing indicators are routed to
+    chat_participants: DashMap<String, HashMap<String, Vec<String>>>,
+    /// Ringing and active calls, so signaling can be checked and calls torn down
+    active_calls: CallRegistry,
+    /// Counters exported on the monitoring port
+    metrics: Metrics,
+    /// Set once the server starts shutting down; every connection watches it
+    shutting_down: watch::Sender<bool>,
 }
 
 impl ServerState {
@@ -18,54 +76,913 @@ impl ServerState {
         Self {
             clients: DashMap::new(),
             pending_messages: DashMap::new(),
+            identities: DashMap::new(),
+            devices: DashMap::new(),
+            blocks: DashMap::new(),
+            bans: DashSet::new(),
+            store: None,
+            settings: RwLock::new(RuntimeConfig::default()),
+            delivery_stats: DashMap::new(),
+            rate_limiters: DashMap::new(),
+            subscriptions: DashMap::new(),
+            subscribers: DashMap::new(),
+            chat_participants: DashMap::new(),
+            active_calls: CallRegistry::default(),
+            metrics: Metrics::default(),
+            shutting_down: watch::Sender::new(false),
+        }
+    }
+
+    /// Create server state backed by a persistent store, replaying any queued messages
+    pub fn with_store(store: Store) -> Result<Self, String> {
+        let stored = store
+            .load_pending()
+            .map_err(|e| format!("Failed to replay pending messages: {}", e))?;
+
+        let pending_messages: DashMap<String, HashMap<String, PendingQueue>> = DashMap::new();
+        let replayed = stored.len();
+        for msg in stored {
+            pending_messages
+                .entry(msg.user_id)
+                .or_default()
+                .entry(msg.device_id)
+                .or_default()
+                .push(PendingMessage {
+                    seq: Some(msg.seq),
+                    payload: msg.payload,
+                    queued_at: msg.queued_at,
+                    sync_copy: msg.sync_copy,
+                });
+        }
+
+        if replayed > 0 {
+            info!(
+                "Replayed {} pending messages for {} users",
+                replayed,
+                pending_messages.len()
+            );
+        }
+
+        let identities: DashMap<String, [u8; 32]> = store
+            .load_identities()
+            .map_err(|e| format!("Failed to load identities: {}", e))?
+            .into_iter()
+            .collect();
+
+        let devices: DashMap<String, HashSet<String>> = DashMap::new();
+        for (user_id, device_id) in store
+            .load_devices()
+            .map_err(|e| format!("Failed to load devices: {}", e))?
+        {
+            devices.entry(user_id).or_default().insert(device_id);
+        }
+
+        let blocks: DashMap<String, HashSet<String>> = DashMap::new();
+        for (user_id, blocked_id) in store
+            .load_blocks()
+            .map_err(|e| format!("Failed to load blocks: {}", e))?
+        {
+            blocks.entry(user_id).or_default().insert(blocked_id);
+        }
+
+        let bans: DashSet<String> = store
+            .load_bans()
+            .map_err(|e| format!("Failed to load bans: {}", e))?
+            .into_iter()
+            .collect();
+
+        Ok(Self {
+            clients: DashMap::new(),
+            pending_messages,
+            identities,
+            devices,
+            blocks,
+            bans,
+            store: Some(store),
+            settings: RwLock::new(RuntimeConfig::default()),
+            delivery_stats: DashMap::new(),
+            rate_limiters: DashMap::new(),
+            subscriptions: DashMap::new(),
+            subscribers: DashMap::new(),
+            chat_participants: DashMap::new(),
+            active_calls: CallRegistry::default(),
+            metrics: Metrics::default(),
+            shutting_down: watch::Sender::new(false),
+        })
+    }
+
+    /// Replace every runtime setting at once (e.g. from a config file)
+    pub fn with_runtime_config(mut self, runtime: RuntimeConfig) -> Self {
+        *self.settings.get_mut().unwrap() = runtime;
+        self
+    }
+
+    /// Swap the runtime settings on a live server.
+    /// Connections already open keep the heartbeat timing and queue depth they started with.
+    pub fn reload(&self, runtime: RuntimeConfig) {
+        *self.settings.write().unwrap() = runtime;
+    }
+
+    fn settings(&self) -> RwLockReadGuard<'_, RuntimeConfig> {
+        self.settings.read().unwrap()
+    }
+
+    /// Override the default access token, identity requirement and auth timeout
+    pub fn with_auth(mut self, auth: AuthConfig) -> Self {
+        self.settings.get_mut().unwrap().auth = auth;
+        self
+    }
+
+    /// Authentication settings applied to new connections
+    pub fn auth(&self) -> AuthConfig {
+        self.settings().auth.clone()
+    }
+
+    /// Require every user to authenticate with an identity key
+    pub fn with_require_identity(mut self, require_identity: bool) -> Self {
+        self.settings.get_mut().unwrap().auth.require_identity = require_identity;
+        self
+    }
+
+    /// Override the default per-class rate limits
+    pub fn with_rate_limits(mut self, rate_limits: RateLimitConfig) -> Self {
+        self.settings.get_mut().unwrap().rate_limits = rate_limits;
+        self
+    }
+
+    /// Override the default ping interval and idle timeout
+    pub fn with_heartbeat(mut self, heartbeat: HeartbeatConfig) -> Self {
+        self.settings.get_mut().unwrap().heartbeat = heartbeat;
+        self
+    }
+
+    /// Ping interval and idle timeout applied to connections
+    pub fn heartbeat(&self) -> HeartbeatConfig {
+        self.settings().heartbeat
+    }
+
+    /// Override the default outbound queue depth and slow-client policy
+    pub fn with_outbound(mut self, outbound: OutboundConfig) -> Self {
+        self.settings.get_mut().unwrap().outbound = outbound;
+        self
+    }
+
+    /// Outbound queue settings applied to connections
+    pub fn outbound(&self) -> OutboundConfig {
+        self.settings().outbound
+    }
+
+    /// Override the default pending queue TTL and quotas
+    pub fn with_queue(mut self, queue: QueueConfig) -> Self {
+        self.settings.get_mut().unwrap().queue = queue;
+        self
+    }
+
+    /// Pending queue settings applied to offline users
+    pub fn queue(&self) -> QueueConfig {
+        self.settings().queue
+    }
+
+    /// Override the default call ring timeout
+    pub fn with_calls(mut self, calls: CallConfig) -> Self {
+        self.settings.get_mut().unwrap().calls = calls;
+        self
+    }
+
+    /// Override the default shutdown grace period and reconnect delay
+    pub fn with_shutdown(mut self, shutdown: ShutdownConfig) -> Self {
+        self.settings.get_mut().unwrap().shutdown = shutdown;
+        self
+    }
+
+    /// Shutdown settings
+    pub fn shutdown(&self) -> ShutdownConfig {
+        self.settings().shutdown
+    }
+
+    /// Override the default frame and field size limits
+    pub fn with_limits(mut self, limits: LimitsConfig) -> Self {
+        self.settings.get_mut().unwrap().limits = limits;
+        self
+    }
+
+    /// Frame and field size limits (frame limits apply to new connections)
+    pub fn limits(&self) -> LimitsConfig {
+        self.settings().limits
+    }
+
+    /// Call signaling settings
+    pub fn calls(&self) -> CallConfig {
+        self.settings().calls
+    }
+
+    /// Whether users without an identity key are rejected
+    pub fn requires_identity(&self) -> bool {
+        self.settings().auth.require_identity
+    }
+
+    /// Get the identity key a user_id is bound to, if any
+    pub fn identity_key(&self, user_id: &str) -> Option<[u8; 32]> {
+        self.identities.get(user_id).map(|key| *key)
+    }
+
+    /// Bind a user_id to an identity key on first use
+    /// Returns false if the user_id is already bound to a different key
+    pub fn register_identity(&self, user_id: &str, public_key: [u8; 32]) -> bool {
+        match self.identities.entry(user_id.to_string()) {
+            Entry::Occupied(existing) => *existing.get() == public_key,
+            Entry::Vacant(slot) => {
+                if let Some(store) = &self.store {
+                    if let Err(e) = store.insert_identity(user_id, &public_key) {
+                        error!("Failed to persist identity for {}: {}", user_id, e);
+                    }
+                }
+                slot.insert(public_key);
+                info!("Registered identity key for {}", user_id);
+                true
+            }
+        }
+    }
+
+    /// Remember a device of a user so messages are queued for it while it is offline.
+    /// Returns false if the user already has the maximum number of devices.
+    pub fn register_device(&self, user_id: &str, device_id: &str) -> bool {
+        let mut devices = self.devices.entry(user_id.to_string()).or_default();
+        if devices.contains(device_id) {
+            return true;
+        }
+        if devices.len() >= MAX_DEVICES_PER_USER {
+            warn!(
+                "Refused device {} for {}: too many devices",
+                device_id, user_id
+            );
+            return false;
+        }
+        if let Some(store) = &self.store {
+            if let Err(e) = store.insert_device(user_id, device_id) {
+                error!(
+                    "Failed to persist device {} for {}: {}",
+                    device_id, user_id, e
+                );
+            }
         }
+        devices.insert(device_id.to_string());
+        info!("Registered device {} for {}", device_id, user_id);
+        true
     }
 
-    /// Register a new client connection (supports multiple connections per user)
-    pub fn add_client(&self, user_id: String, tx: mpsc::UnboundedSender<String>) {
+    /// Devices a user has connected from, or just the default device if none is known yet
+    pub fn devices_of(&self, user_id: &str) -> Vec<String> {
+        match self.devices.get(user_id) {
+            Some(devices) if !devices.is_empty() => devices.iter().cloned().collect(),
+            _ => vec![DEFAULT_DEVICE_ID.to_string()],
+        }
+    }
+
+    fn has_device(&self, user_id: &str, device_id: &str) -> bool {
+        self.devices
+            .get(user_id)
+            .is_some_and(|devices| devices.contains(device_id))
+    }
+
+    /// Register a new client connection on the user's default device
+    pub fn add_client(&self, user_id: String, tx: mpsc::Sender<String>) -> Arc<ClientChannel> {
+        self.add_device_client(user_id, DEFAULT_DEVICE_ID, tx)
+    }
+
+    /// Register a new client connection from one of a user's devices
+    /// (supports multiple devices per user and multiple connections per device)
+    pub fn add_device_client(
+        &self,
+        user_id: String,
+        device_id: &str,
+        tx: mpsc::Sender<String>,
+    ) -> Arc<ClientChannel> {
+        self.add_connection(user_id, device_id, Negotiated::current(), tx)
+    }
+
+    /// Register a connection from one of a user's devices with the protocol it negotiated
+    pub fn add_connection(
+        &self,
+        user_id: String,
+        device_id: &str,
+        protocol: Negotiated,
+        tx: mpsc::Sender<String>,
+    ) -> Arc<ClientChannel> {
+        self.register_device(&user_id, device_id);
+        // Messages queued before any device was known go to the first one that connects
+        if !self.has_device(&user_id, DEFAULT_DEVICE_ID) {
+            self.claim_unassigned(&user_id, device_id);
+        }
+
+        let channel = Arc::new(ClientChannel::new(device_id, protocol, tx));
         self.clients
             .entry(user_id)
-            .or_insert_with(Vec::new)
-            .push(tx);
+            .or_default()
+            .push(channel.clone());
+        channel
+    }
+
+    /// Move a user's messages queued under the default device to `device_id`
+    fn claim_unassigned(&self, user_id: &str, device_id: &str) {
+        let Some(mut queues) = self.pending_messages.get_mut(user_id) else {
+            return;
+        };
+        let Some(unassigned) = queues.remove(DEFAULT_DEVICE_ID) else {
+            return;
+        };
+        let queue = queues.entry(device_id.to_string()).or_default();
+        for msg in unassigned.into_messages() {
+            queue.push(msg);
+        }
+        drop(queues);
+
+        if let Some(store) = &self.store {
+            if let Err(e) = store.reassign_pending(user_id, DEFAULT_DEVICE_ID, device_id) {
+                error!("Failed to reassign pending messages for {}: {}", user_id, e);
+            }
+        }
     }
 
     /// Remove a specific client connection by checking if the channel is closed
     pub fn remove_client(&self, user_id: &str) {
         if let Some(mut entry) = self.clients.get_mut(user_id) {
             // Remove closed channels
-            entry.retain(|tx| !tx.is_closed());
+            entry.retain(|channel| !channel.is_closed());
             // If no channels left, remove the user entry
             if entry.is_empty() {
                 drop(entry);
                 self.clients.remove(user_id);
+                self.clear_subscriptions(user_id);
+            }
+        }
+    }
+
+    /// Charge a frame of the given class against the user's rate limits
+    pub fn check_rate(&self, user_id: &str, class: MessageClass) -> RateDecision {
+        let settings = self.settings();
+        self.rate_limiters
+            .entry(user_id.to_string())
+            .or_default()
+            .check(class, &settings.rate_limits)
+    }
+
+    /// Forget the rate limits of offline users whose limiters have returned to
+    /// a fresh state. Limiters outlive connections so that reconnecting does
+    /// not reset a flooder's budget or violation count.
+    pub fn expire_rate_limiters(&self) -> usize {
+        let settings = self.settings();
+        let before = self.rate_limiters.len();
+        self.rate_limiters.retain(|user_id, limiter| {
+            self.clients.contains_key(user_id) || !limiter.is_idle(&settings.rate_limits)
+        });
+        before.saturating_sub(self.rate_limiters.len())
+    }
+
+    /// Number of frames from a user dropped by the rate limiter (all classes)
+    pub fn rate_limited_count(&self, user_id: &str) -> u64 {
+        self.rate_limiters
+            .get(user_id)
+            .map(|limiter| limiter.total_limited())
+            .unwrap_or(0)
+    }
+
+    /// Number of frames of one class from a user dropped by the rate limiter
+    pub fn rate_limited_count_for(&self, user_id: &str, class: MessageClass) -> u64 {
+        self.rate_limiters
+            .get(user_id)
+            .map(|limiter| limiter.limited_count(class))
+            .unwrap_or(0)
+    }
+
+    /// Replace a user's subscriptions and declared chat participants.
+    /// Returns the users it is now subscribed to.
+    pub fn subscribe(
+        &self,
+        subscriber: &str,
+        user_ids: Vec<String>,
+        chats: Vec<WsChatParticipants>,
+    ) -> Vec<String> {
+        let targets: HashSet<String> = user_ids
+            .into_iter()
+            .filter(|id| id != subscriber)
+            .take(MAX_SUBSCRIPTIONS_PER_USER)
+            .collect();
+
+        // Hold the subscriber's entry while the reverse index is updated
+        let mut current = self
+            .subscriptions
+            .entry(subscriber.to_string())
+            .or_default();
+        for removed in current.difference(&targets) {
+            self.remove_subscriber(removed, subscriber);
+        }
+        for added in targets.difference(&current) {
+            self.subscribers
+                .entry(added.clone())
+                .or_default()
+                .insert(subscriber.to_string());
+        }
+        *current = targets.clone();
+        drop(current);
+
+        let chats: HashMap<String, Vec<String>> = chats
+            .into_iter()
+            .take(MAX_SUBSCRIPTIONS_PER_USER)
+            .map(|chat| {
+                let participants = chat
+                    .participant_ids
+                    .into_iter()
+                    .filter(|id| id != subscriber)
+                    .take(MAX_SUBSCRIPTIONS_PER_USER)
+                    .collect();
+                (chat.chat_id, participants)
+            })
+            .collect();
+        self.chat_participants.insert(subscriber.to_string(), chats);
+
+        targets.into_iter().collect()
+    }
+
+    /// Drop everything a user subscribed to (called once their last connection closes)
+    fn clear_subscriptions(&self, user_id: &str) {
+        if let Some((_, targets)) = self.subscriptions.remove(user_id) {
+            for target in &targets {
+                self.remove_subscriber(target, user_id);
+            }
+        }
+        self.chat_participants.remove(user_id);
+    }
+
+    fn remove_subscriber(&self, target: &str, subscriber: &str) {
+        if let Entry::Occupied(mut entry) = self.subscribers.entry(target.to_string()) {
+            entry.get_mut().remove(subscriber);
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+    }
+
+    /// Users currently subscribed to a user's presence/profile updates
+    pub fn subscribers_of(&self, user_id: &str) -> Vec<String> {
+        self.subscribers
+            .get(user_id)
+            .map(|subs| subs.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Send a presence/profile update about `user_id` to its subscribers (except blocked ones)
+    /// Returns the number of subscribers it was sent to
+    pub fn send_to_subscribers(&self, user_id: &str, message: &str) -> usize {
+        self.subscribers_of(user_id)
+            .iter()
+            .filter(|subscriber| !self.is_blocked_between(user_id, subscriber))
+            .filter(|subscriber| self.send_to_user(subscriber, message))
+            .count()
+    }
+
+    /// Send a typing indicator to the participants the sender declared for `chat_id`
+    /// Returns the number of participants it was sent to
+    pub fn send_to_chat_participants(
+        &self,
+        sender_id: &str,
+        chat_id: &str,
+        message: &str,
+    ) -> usize {
+        let participants = self
+            .chat_participants
+            .get(sender_id)
+            .and_then(|chats| chats.get(chat_id).cloned())
+            .unwrap_or_default();
+
+        participants
+            .iter()
+            .filter(|participant| !self.is_blocked_between(sender_id, participant))
+            .filter(|participant| self.send_to_user(participant, message))
+            .count()
+    }
+
+    /// Add `blocked_id` to a user's block list.
+    /// Returns false if the user already blocks the maximum number of users.
+    pub fn block_user(&self, user_id: &str, blocked_id: &str) -> bool {
+        let mut blocked = self.blocks.entry(user_id.to_string()).or_default();
+        if blocked.contains(blocked_id) {
+            return true;
+        }
+        if blocked.len() >= MAX_BLOCKS_PER_USER {
+            warn!("Refused block by {}: block list full", user_id);
+            return false;
+        }
+        blocked.insert(blocked_id.to_string());
+        drop(blocked);
+
+        if let Some(store) = &self.store {
+            if let Err(e) = store.insert_block(user_id, blocked_id) {
+                error!("Failed to persist block by {}: {}", user_id, e);
+            }
+        }
+        true
+    }
+
+    /// Remove `blocked_id` from a user's block list
+    pub fn unblock_user(&self, user_id: &str, blocked_id: &str) {
+        if let Entry::Occupied(mut entry) = self.blocks.entry(user_id.to_string()) {
+            entry.get_mut().remove(blocked_id);
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+
+        if let Some(store) = &self.store {
+            if let Err(e) = store.remove_block(user_id, blocked_id) {
+                error!("Failed to remove block by {}: {}", user_id, e);
             }
         }
     }
 
-    /// Broadcast message to all clients except the sender
+    /// The users `user_id` has blocked, sorted
+    pub fn block_list(&self, user_id: &str) -> Vec<String> {
+        let mut blocked: Vec<String> = self
+            .blocks
+            .get(user_id)
+            .map(|blocked| blocked.iter().cloned().collect())
+            .unwrap_or_default();
+        blocked.sort();
+        blocked
+    }
+
+    /// Whether `user_id` has blocked `other`
+    pub fn has_blocked(&self, user_id: &str, other: &str) -> bool {
+        self.blocks
+            .get(user_id)
+            .is_some_and(|blocked| blocked.contains(other))
+    }
+
+    /// Whether either user has blocked the other
+    pub fn is_blocked_between(&self, a: &str, b: &str) -> bool {
+        self.has_blocked(a, b) || self.has_blocked(b, a)
+    }
+
+    /// Check a call signaling frame against the call it belongs to and record the transition
+    pub(crate) fn track_call(
+        &self,
+        user_id: &str,
+        connection_id: u64,
+        msg: &WsMessage,
+    ) -> CallDecision {
+        self.active_calls.apply(user_id, connection_id, msg)
+    }
+
+    /// End a call whose invite reached none of the callee's connections,
+    /// leaving the callee a `missed_call` for when it is back
+    pub(crate) fn miss_call(&self, call_id: &str) {
+        if let Some(call) = self.active_calls.end(call_id) {
+            self.queue_missed_call(&call.missed_call());
+        }
+    }
+
+    /// Send a `missed_call` to its callee, or queue it until one of the callee's devices connects
+    pub(crate) fn queue_missed_call(&self, missed: &WsMessage) {
+        let WsMessage::MissedCall { to_user_id, .. } = missed else {
+            return;
+        };
+        match serde_json::to_string(missed) {
+            Ok(json) => {
+                self.send_or_queue(to_user_id, &json);
+            }
+            Err(e) => error!("Failed to serialize missed call: {}", e),
+        }
+    }
+
+    /// Whether a user has a ringing or active call
+    pub fn in_call(&self, user_id: &str) -> bool {
+        self.active_calls.in_call(user_id)
+    }
+
+    /// End the call a closed connection was carrying and hang up on the other participant.
+    /// A callee that went away while its phone rang also gets a `missed_call`.
+    /// Call after `remove_client`, so a ringing callee with no connections left counts as gone.
+    pub fn hang_up_calls_of(&self, user_id: &str, connection_id: u64) {
+        let still_online = self.is_online(user_id);
+        let ended = self
+            .active_calls
+            .drop_connection(user_id, connection_id, still_online);
+        if let Some(call) = ended {
+            info!("Call {} ended: {} disconnected", call.call_id, user_id);
+            let peer = call.peer_of(user_id);
+            self.send_call_frame(peer, &hangup_frame(&call.call_id, user_id, peer));
+            if call.state == CallState::Ringing && call.callee == user_id {
+                self.queue_missed_call(&call.missed_call());
+            }
+        }
+    }
+
+    /// End invites that rang past the ring timeout: the caller gets a `timeout` reject,
+    /// the callee's ringing is stopped with a hangup and followed by a `missed_call`.
+    /// Returns the number of calls ended.
+    pub fn expire_calls(&self) -> usize {
+        let expired = self.active_calls.expire(self.calls().ring_timeout);
+        for call in &expired {
+            info!("Call {} to {} was not answered", call.call_id, call.callee);
+            let reject = WsMessage::CallReject {
+                call_id: call.call_id.clone(),
+                from_user_id: call.callee.clone(),
+                to_user_id: call.caller.clone(),
+                reason: "timeout".to_string(),
+            };
+            self.send_call_frame(&call.caller, &reject);
+            let hangup = hangup_frame(&call.call_id, &call.caller, &call.callee);
+            self.send_call_frame(&call.callee, &hangup);
+            self.queue_missed_call(&call.missed_call());
+        }
+        expired.len()
+    }
+
+    fn send_call_frame(&self, user_id: &str, frame: &WsMessage) {
+        match serde_json::to_string(frame) {
+            Ok(json) => {
+                self.send_to_user(user_id, &json);
+            }
+            Err(e) => error!("Failed to serialize call frame: {}", e),
+        }
+    }
+
+    /// Broadcast message to all clients except the sender (dropped for full queues)
     pub fn broadcast(&self, message: &str, exclude_user_id: Option<&str>) {
+        let recipients: Vec<String> = self
+            .clients
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|user_id| Some(user_id.as_str()) != exclude_user_id)
+            .collect();
+
+        for user_id in recipients {
+            self.send_to_user(&user_id, message);
+        }
+    }
+
+    /// Send an ephemeral frame to a specific user (sends to all their connections).
+    /// Dropped, and counted, if every connection's queue is full.
+    pub fn send_to_user(&self, user_id: &str, message: &str) -> bool {
+        let offer = self.offer_to_user(user_id, None, message);
+        self.settle_ephemeral(user_id, offer)
+    }
+
+    /// Whether an ephemeral frame got through; counts it as dropped if it hit full queues
+    fn settle_ephemeral(&self, user_id: &str, offer: Offer) -> bool {
+        match offer {
+            Offer::Sent => true,
+            Offer::Full => {
+                self.record_delivery(user_id, |stats| stats.dropped += 1);
+                warn!("Outbound queue full for {}, dropped frame", user_id);
+                false
+            }
+            Offer::Closed => false,
+        }
+    }
+
+    /// Offer a frame to every connection of a user (or of one of its devices): Sent if any
+    /// accepted it, Full if some were full, Closed if there is no live connection
+    fn offer_to_user(&self, user_id: &str, device_id: Option<&str>, message: &str) -> Offer {
+        self.offer_to_channels(user_id, message, |channel| {
+            device_id.is_none_or(|device_id| channel.device_id() == device_id)
+        })
+    }
+
+    /// Offer a frame to the connections of a user that `accepts` picks, with the same
+    /// result as [`Self::offer_to_user`]
+    fn offer_to_channels(
+        &self,
+        user_id: &str,
+        message: &str,
+        accepts: impl Fn(&ClientChannel) -> bool,
+    ) -> Offer {
+        let Some(channels) = self.clients.get(user_id) else {
+            return Offer::Closed;
+        };
+
+        let max_overflows = self.outbound().max_overflows;
+        let mut result = Offer::Closed;
+        let targets = channels.iter().filter(|channel| accepts(channel));
+        for channel in targets {
+            match channel.offer(message.to_string(), max_overflows) {
+                Offer::Sent => result = Offer::Sent,
+                Offer::Full if result == Offer::Closed => result = Offer::Full,
+                _ => {}
+            }
+        }
+        result
+    }
+
+    fn record_delivery(&self, user_id: &str, update: impl FnOnce(&mut DeliveryStats)) {
+        update(&mut self.delivery_stats.entry(user_id.to_string()).or_default());
+    }
+
+    /// Count a connection closed for being persistently slow
+    pub fn record_slow_disconnect(&self, user_id: &str) {
+        self.record_delivery(user_id, |stats| stats.slow_disconnects += 1);
+    }
+
+    /// Frames dropped, spilled, expired, evicted or purged on the way to a user
+    pub fn delivery_stats(&self, user_id: &str) -> DeliveryStats {
+        self.delivery_stats
+            .get(user_id)
+            .map(|stats| *stats)
+            .unwrap_or_default()
+    }
+
+    /// Counters bumped by connections as they authenticate and send frames
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Every metric, with gauges read from the current state, in the Prometheus text format
+    pub fn render_metrics(&self) -> String {
+        let mut gauges = Gauges {
+            active_calls: self.active_calls.len(),
+            ..Gauges::default()
+        };
         for entry in self.clients.iter() {
-            if Some(entry.key().as_str()) != exclude_user_id {
-                for tx in entry.value().iter() {
-                    let _ = tx.send(message.to_string());
+            if !entry.value().is_empty() {
+                gauges.users_online += 1;
+                gauges.connections += entry.value().len();
+            }
+        }
+        gauges.queued_messages = self.queued_messages();
+        for entry in self.delivery_stats.iter() {
+            let stats = entry.value();
+            gauges.delivery.dropped += stats.dropped;
+            gauges.delivery.spilled += stats.spilled;
+            gauges.delivery.slow_disconnects += stats.slow_disconnects;
+            gauges.delivery.expired += stats.expired;
+            gauges.delivery.evicted += stats.evicted;
+            gauges.delivery.purged += stats.purged;
+        }
+        self.metrics.render(&gauges)
+    }
+
+    /// Whether the relay can take traffic: Err says why not
+    pub fn readiness(&self) -> Result<(), String> {
+        if self.is_shutting_down() {
+            return Err("shutting down".to_string());
+        }
+        if let Some(store) = &self.store {
+            store
+                .ping()
+                .map_err(|e| format!("store unavailable: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Tell every connection to send `server_shutdown` and close
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.send_replace(true);
+    }
+
+    /// Whether `begin_shutdown` has been called
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutting_down.borrow()
+    }
+
+    /// Changes to true once the server starts shutting down
+    pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutting_down.subscribe()
+    }
+
+    /// Open connections across all users
+    pub fn connections_open(&self) -> usize {
+        self.clients
+            .iter()
+            .map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .filter(|channel| !channel.is_closed())
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Write everything the store holds to its database file
+    pub fn flush(&self) -> Result<(), String> {
+        match &self.store {
+            Some(store) => store
+                .checkpoint()
+                .map_err(|e| format!("checkpoint failed: {}", e)),
+            None => {
+                let queued = self.queued_messages();
+                if queued > 0 {
+                    warn!(
+                        "No data directory configured, {} queued messages are lost",
+                        queued
+                    );
                 }
+                Ok(())
             }
         }
     }
 
-    /// Send message to a specific user (sends to all their connections)
-    pub fn send_to_user(&self, user_id: &str, message: &str) -> bool {
-        if let Some(channels) = self.clients.get(user_id) {
-            let mut sent = false;
-            for tx in channels.iter() {
-                if tx.send(message.to_string()).is_ok() {
-                    sent = true;
+    /// Open connections of a user (0 when offline)
+    pub fn connection_count(&self, user_id: &str) -> usize {
+        self.clients
+            .get(user_id)
+            .map(|channels| {
+                channels
+                    .iter()
+                    .filter(|channel| !channel.is_closed())
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Close every connection of a user; returns how many were told to close
+    pub fn kick_user(&self, user_id: &str) -> usize {
+        let Some(channels) = self.clients.get(user_id) else {
+            return 0;
+        };
+        let mut kicked = 0;
+        for channel in channels.iter().filter(|channel| !channel.is_closed()) {
+            channel.disconnect();
+            kicked += 1;
+        }
+        kicked
+    }
+
+    /// Refuse the user's future handshakes and close its open connections;
+    /// returns how many connections were closed
+    pub fn ban_user(&self, user_id: &str) -> usize {
+        if self.bans.insert(user_id.to_string()) {
+            if let Some(store) = &self.store {
+                if let Err(e) = store.insert_ban(user_id) {
+                    error!("Failed to persist ban of {}: {}", user_id, e);
+                }
+            }
+        }
+        self.kick_user(user_id)
+    }
+
+    /// Lift a ban; returns whether the user was banned
+    pub fn unban_user(&self, user_id: &str) -> bool {
+        let removed = self.bans.remove(user_id).is_some();
+        if removed {
+            if let Some(store) = &self.store {
+                if let Err(e) = store.remove_ban(user_id) {
+                    error!("Failed to remove ban of {}: {}", user_id, e);
                 }
             }
-            sent
-        } else {
-            false
         }
+        removed
+    }
+
+    /// Whether the server operator has banned a user
+    pub fn is_banned(&self, user_id: &str) -> bool {
+        self.bans.contains(user_id)
+    }
+
+    /// Every banned user, sorted
+    pub fn banned_users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self.bans.iter().map(|user| user.key().clone()).collect();
+        users.sort();
+        users
+    }
+
+    /// Drop every message queued for a user without delivering it, reporting each chat
+    /// message to its sender as `delivery_failed`; returns how many were dropped
+    pub fn purge_pending(&self, user_id: &str) -> usize {
+        let Some((_, queues)) = self.pending_messages.remove(user_id) else {
+            return 0;
+        };
+        let messages: Vec<PendingMessage> = queues
+            .into_values()
+            .flat_map(|queue| queue.into_messages())
+            .collect();
+        let purged = messages.len();
+        self.fail_pending(user_id, messages, DeliveryFailureReason::Purged);
+        purged
+    }
+
+    /// Send an operator notice to every online user; returns how many users it reached.
+    /// Users whose connections are too old for `announcement` or all backed up do not count.
+    pub fn announce(&self, message: &str) -> usize {
+        let announcement = WsMessage::Announcement {
+            message: message.to_string(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+        };
+        let json = match serde_json::to_string(&announcement) {
+            Ok(json) => json,
+            Err(e) => {
+                error!("Failed to serialize announcement: {}", e);
+                return 0;
+            }
+        };
+        self.online_users()
+            .iter()
+            .filter(|user_id| {
+                let offer =
+                    self.offer_to_channels(user_id, &json, |channel| channel.allows_frame(&json));
+                self.settle_ephemeral(user_id, offer)
+            })
+            .count()
     }
 
     /// Get list of online user IDs
@@ -85,49 +1002,320 @@ impl ServerState {
             .unwrap_or(false)
     }
 
-    /// Queue a message for an offline user
+    /// Check if one of a user's devices has a live connection
+    pub fn is_device_online(&self, user_id: &str, device_id: &str) -> bool {
+        self.clients.get(user_id).is_some_and(|channels| {
+            channels
+                .iter()
+                .any(|channel| channel.device_id() == device_id && !channel.is_closed())
+        })
+    }
+
+    /// Queue a message for every device of an offline user
     pub fn queue_message(&self, user_id: &str, message: String) {
-        let mut entry = self
+        for device_id in self.devices_of(user_id) {
+            self.queue_for_device(user_id, &device_id, message.clone(), false);
+        }
+    }
+
+    /// Queue a message for one offline device of a user. Older messages evicted to stay
+    /// within the quotas are reported to their senders, unless they were sync copies.
+    pub fn queue_for_device(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        message: String,
+        sync_copy: bool,
+    ) {
+        let limits = self.queue();
+
+        // A message larger than the whole byte quota can never be queued
+        if message.len() > limits.max_bytes {
+            warn!(
+                "Message of {} bytes exceeds the queue quota for {} ({}), dropped",
+                message.len(),
+                user_id,
+                device_id
+            );
+            self.record_delivery(user_id, |stats| stats.evicted += 1);
+            if !sync_copy {
+                self.report_failed_delivery(user_id, &message, DeliveryFailureReason::QueueFull);
+            }
+            return;
+        }
+
+        let queued_at = chrono::Utc::now().timestamp_millis();
+
+        // Persist first so the message survives a crash right after queueing
+        let seq = self.store.as_ref().and_then(|store| {
+            store
+                .insert_pending(user_id, device_id, &message, queued_at, sync_copy)
+                .map_err(|e| error!("Failed to persist pending message for {}: {}", user_id, e))
+                .ok()
+        });
+
+        let mut queues = self
             .pending_messages
             .entry(user_id.to_string())
-            .or_insert_with(Vec::new);
-
-        // Enforce queue limit - drop oldest if at capacity
-        if entry.len() >= MAX_PENDING_MESSAGES_PER_USER {
-            entry.remove(0);
+            .or_default();
+        let queue = queues.entry(device_id.to_string()).or_default();
+
+        // Enforce queue limits - evict oldest until the new message fits
+        let evicted = queue.make_room(message.len(), &limits);
+        queue.push(PendingMessage {
+            seq,
+            payload: message,
+            queued_at,
+            sync_copy,
+        });
+        drop(queues);
+
+        if !evicted.is_empty() {
             info!(
-                "Queue limit reached for {}, dropped oldest message",
-                user_id
+                "Queue limit reached for {} ({}), evicted {} oldest messages",
+                user_id,
+                device_id,
+                evicted.len()
             );
+            self.fail_pending(user_id, evicted, DeliveryFailureReason::QueueFull);
+        }
+    }
+
+    /// Drop queued messages older than the TTL and tell their senders.
+    /// Returns the number of messages expired.
+    pub fn expire_pending(&self) -> usize {
+        let cutoff = self.expiry_cutoff();
+
+        let mut expired = Vec::new();
+        for mut entry in self.pending_messages.iter_mut() {
+            let messages: Vec<PendingMessage> = entry
+                .values_mut()
+                .flat_map(|queue| queue.take_expired(cutoff))
+                .collect();
+            entry.retain(|_, queue| !queue.is_empty());
+            if !messages.is_empty() {
+                expired.push((entry.key().clone(), messages));
+            }
+        }
+        self.pending_messages.retain(|_, queues| !queues.is_empty());
+
+        // Report after every shard lock is released: failures may be queued for their senders
+        let mut count = 0;
+        for (user_id, messages) in expired {
+            count += messages.len();
+            self.fail_pending(&user_id, messages, DeliveryFailureReason::Expired);
+        }
+        count
+    }
+
+    /// Unix millis before which a queued message has expired
+    fn expiry_cutoff(&self) -> i64 {
+        let ttl = i64::try_from(self.queue().ttl.as_millis()).unwrap_or(i64::MAX);
+        chrono::Utc::now().timestamp_millis().saturating_sub(ttl)
+    }
+
+    /// Forget messages removed from a user's queues without delivery and report them
+    fn fail_pending(
+        &self,
+        user_id: &str,
+        messages: Vec<PendingMessage>,
+        reason: DeliveryFailureReason,
+    ) {
+        for msg in messages {
+            self.forget_persisted(msg.seq);
+            self.record_delivery(user_id, |stats| match reason {
+                DeliveryFailureReason::Expired => stats.expired += 1,
+                DeliveryFailureReason::QueueFull => stats.evicted += 1,
+                DeliveryFailureReason::Purged => stats.purged += 1,
+            });
+            if !msg.sync_copy {
+                self.report_failed_delivery(user_id, &msg.payload, reason);
+            }
         }
-        entry.push(message);
     }
 
-    /// Take all pending messages for a user (clears the queue)
+    /// Send a `delivery_failed` frame to the sender of an undeliverable chat message.
+    /// Receipts, other queued frames and a user's own messages synced to its devices
+    /// are dropped silently.
+    fn report_failed_delivery(
+        &self,
+        recipient_id: &str,
+        payload: &str,
+        reason: DeliveryFailureReason,
+    ) {
+        let Ok(WsMessage::ChatMessage {
+            id,
+            chat_id,
+            sender_id,
+            ..
+        }) = serde_json::from_str(payload)
+        else {
+            return;
+        };
+        if sender_id == recipient_id {
+            return;
+        }
+
+        let failed = WsMessage::DeliveryFailed {
+            message_id: id,
+            chat_id,
+            recipient_id: recipient_id.to_string(),
+            reason,
+        };
+        match serde_json::to_string(&failed) {
+            Ok(json) => {
+                self.send_or_queue(&sender_id, &json);
+            }
+            Err(e) => error!("Failed to serialize delivery failure: {}", e),
+        }
+    }
+
+    /// Take all pending messages for a user, device by device (clears the queues)
     pub fn take_pending_messages(&self, user_id: &str) -> Vec<String> {
-        self.pending_messages
-            .remove(user_id)
-            .map(|(_, msgs)| msgs)
-            .unwrap_or_default()
+        let Some((_, queues)) = self.pending_messages.remove(user_id) else {
+            return Vec::new();
+        };
+
+        // Compact the on-disk queue now that the messages are handed off
+        if let Some(store) = &self.store {
+            if let Err(e) = store.remove_user_pending(user_id) {
+                error!("Failed to compact pending messages for {}: {}", user_id, e);
+            }
+        }
+
+        queues
+            .into_values()
+            .flat_map(|queue| queue.into_messages().map(|m| m.payload))
+            .collect()
     }
 
-    /// Send to user if online, otherwise queue the message
-    /// Returns true if sent immediately, false if queued
+    /// Delete a message from the persistent store, if it was persisted
+    fn forget_persisted(&self, seq: Option<i64>) {
+        if let (Some(store), Some(seq)) = (&self.store, seq) {
+            if let Err(e) = store.remove_pending(seq) {
+                error!("Failed to remove persisted message {}: {}", seq, e);
+            }
+        }
+    }
+
+    /// Send to each of the user's devices that is online, otherwise queue the message for it.
+    /// Messages that do not fit in a full outbound queue are spilled to the pending queue.
+    /// Returns true if some device got it immediately, false if it was only queued
     pub fn send_or_queue(&self, user_id: &str, message: &str) -> bool {
-        if self.send_to_user(user_id, message) {
-            true
-        } else {
-            self.queue_message(user_id, message.to_string());
-            info!("Queued message for offline user {}", user_id);
-            false
+        self.send_or_queue_devices(user_id, None, message)
+    }
+
+    /// Mirror a message a user sent from one device to the user's other devices,
+    /// queueing it for those that are offline
+    pub fn sync_to_other_devices(&self, user_id: &str, from_device: &str, message: &str) {
+        self.send_or_queue_devices(user_id, Some(from_device), message);
+    }
+
+    fn send_or_queue_devices(
+        &self,
+        user_id: &str,
+        skip_device: Option<&str>,
+        message: &str,
+    ) -> bool {
+        let mut delivered = false;
+        let mut missed = Vec::new();
+        for device_id in self.devices_of(user_id) {
+            if Some(device_id.as_str()) == skip_device {
+                continue;
+            }
+            match self.offer_to_user(user_id, Some(&device_id), message) {
+                Offer::Sent => delivered = true,
+                Offer::Full => missed.push((device_id, true)),
+                Offer::Closed => missed.push((device_id, false)),
+            }
+        }
+
+        // Once the user has the message somewhere, the other devices' copies are only for sync
+        let sync_copy = delivered || skip_device.is_some();
+        for (device_id, spilled) in missed {
+            self.queue_for_device(user_id, &device_id, message.to_string(), sync_copy);
+            if spilled {
+                self.record_delivery(user_id, |stats| stats.spilled += 1);
+                info!(
+                    "Outbound queue full for {} ({}), spilled message to pending queue",
+                    user_id, device_id
+                );
+            } else {
+                info!(
+                    "Queued message for offline device {} of {}",
+                    device_id, user_id
+                );
+            }
+        }
+        delivered
+    }
+
+    /// Move up to `max` pending messages, oldest first, into a device's connection queues.
+    /// Stops at the first message no connection can take. Returns the number moved.
+    pub fn flush_pending(&self, user_id: &str, device_id: &str, max: usize) -> usize {
+        // Never deliver what has already outlived the TTL
+        let cutoff = self.expiry_cutoff();
+        let expired = self
+            .pending_messages
+            .get_mut(user_id)
+            .and_then(|mut queues| queues.get_mut(device_id).map(|q| q.take_expired(cutoff)))
+            .unwrap_or_default();
+        self.fail_pending(user_id, expired, DeliveryFailureReason::Expired);
+
+        let Some(mut queues) = self.pending_messages.get_mut(user_id) else {
+            return 0;
+        };
+        let Some(queue) = queues.get_mut(device_id) else {
+            return 0;
+        };
+
+        let mut flushed = 0;
+        while flushed < max {
+            let Some(front) = queue.front() else {
+                break;
+            };
+            if self.offer_to_user(user_id, Some(device_id), &front.payload) != Offer::Sent {
+                break;
+            }
+            if let Some(msg) = queue.pop_front() {
+                self.forget_persisted(msg.seq);
+            }
+            flushed += 1;
+        }
+
+        if queue.is_empty() {
+            queues.remove(device_id);
         }
+        let emptied = queues.is_empty();
+        drop(queues);
+        if emptied {
+            self.pending_messages
+                .remove_if(user_id, |_, queues| queues.is_empty());
+        }
+        flushed
     }
 
-    /// Get the number of pending messages for a user
+    /// Messages waiting in every user's offline queues
+    fn queued_messages(&self) -> usize {
+        self.pending_messages
+            .iter()
+            .map(|entry| entry.values().map(|queue| queue.len()).sum::<usize>())
+            .sum()
+    }
+
+    /// Get the number of pending messages for a user, across all of its devices
     pub fn pending_count(&self, user_id: &str) -> usize {
         self.pending_messages
             .get(user_id)
-            .map(|msgs| msgs.len())
+            .map(|queues| queues.values().map(|queue| queue.len()).sum())
+            .unwrap_or(0)
+    }
+
+    /// Get the number of pending messages for one of a user's devices
+    pub fn pending_count_for(&self, user_id: &str, device_id: &str) -> usize {
+        self.pending_messages
+            .get(user_id)
+            .and_then(|queues| queues.get(device_id).map(|queue| queue.len()))
             .unwrap_or(0)
     }
 }
@@ -138,6 +1326,15 @@ impl Default for ServerState {
     }
 }
 
+/// Hangup sent on behalf of `from_user_id`, who is gone or did not answer
+fn hangup_frame(call_id: &str, from_user_id: &str, to_user_id: &str) -> WsMessage {
+    WsMessage::CallHangup {
+        call_id: call_id.to_string(),
+        from_user_id: from_user_id.to_string(),
+        to_user_id: to_user_id.to_string(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -152,7 +1349,7 @@ mod tests {
     #[test]
     fn test_add_and_remove_client() {
         let state = ServerState::new();
-        let (tx, rx) = mpsc::unbounded_channel();
+        let (tx, rx) = mpsc::channel(16);
 
         state.add_client("user1".to_string(), tx);
         assert!(state.is_online("user1"));
@@ -168,9 +1365,9 @@ mod tests {
     #[test]
     fn test_multiple_clients() {
         let state = ServerState::new();
-        let (tx1, _rx1) = mpsc::unbounded_channel();
-        let (tx2, _rx2) = mpsc::unbounded_channel();
-        let (tx3, _rx3) = mpsc::unbounded_channel();
+        let (tx1, _rx1) = mpsc::channel(16);
+        let (tx2, _rx2) = mpsc::channel(16);
+        let (tx3, _rx3) = mpsc::channel(16);
 
         state.add_client("user1".to_string(), tx1);
         state.add_client("user2".to_string(), tx2);
@@ -186,7 +1383,7 @@ mod tests {
     #[test]
     fn test_send_to_user() {
         let state = ServerState::new();
-        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (tx, mut rx) = mpsc::channel(16);
 
         state.add_client("user1".to_string(), tx);
 
@@ -204,9 +1401,9 @@ mod tests {
     #[test]
     fn test_broadcast_excludes_sender() {
         let state = ServerState::new();
-        let (tx1, mut rx1) = mpsc::unbounded_channel();
-        let (tx2, mut rx2) = mpsc::unbounded_channel();
-        let (tx3, mut rx3) = mpsc::unbounded_channel();
+        let (tx1, mut rx1) = mpsc::channel(16);
+        let (tx2, mut rx2) = mpsc::channel(16);
+        let (tx3, mut rx3) = mpsc::channel(16);
 
         state.add_client("user1".to_string(), tx1);
         state.add_client("user2".to_string(), tx2);
@@ -226,8 +1423,8 @@ mod tests {
     #[test]
     fn test_broadcast_to_all() {
         let state = ServerState::new();
-        let (tx1, mut rx1) = mpsc::unbounded_channel();
-        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        let (tx1, mut rx1) = mpsc::channel(16);
+        let (tx2, mut rx2) = mpsc::channel(16);
 
         state.add_client("user1".to_string(), tx1);
         state.add_client("user2".to_string(), tx2);
@@ -243,8 +1440,8 @@ mod tests {
     #[test]
     fn test_multiple_connections_per_user() {
         let state = ServerState::new();
-        let (tx1, mut rx1) = mpsc::unbounded_channel();
-        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        let (tx1, mut rx1) = mpsc::channel(16);
+        let (tx2, mut rx2) = mpsc::channel(16);
 
         // Add same user with two connections (frontend + backend scenario)
         state.add_client("user1".to_string(), tx1);
@@ -263,8 +1460,8 @@ mod tests {
     #[test]
     fn test_partial_disconnect() {
         let state = ServerState::new();
-        let (tx1, rx1) = mpsc::unbounded_channel();
-        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        let (tx1, rx1) = mpsc::channel(16);
+        let (tx2, mut rx2) = mpsc::channel(16);
 
         // Add same user with two connections
         state.add_client("user1".to_string(), tx1);
@@ -321,7 +1518,7 @@ mod tests {
     #[test]
     fn test_send_or_queue_routes_correctly() {
         let state = ServerState::new();
-        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (tx, mut rx) = mpsc::channel(16);
 
         // User is offline - should queue
         assert!(!state.send_or_queue("offline_user", "queued msg"));
@@ -341,14 +1538,20 @@ mod tests {
         let state = ServerState::new();
 
         // Fill queue to limit
-        for i in 0..MAX_PENDING_MESSAGES_PER_USER {
+        for i in 0..QueueConfig::default().max_messages {
             state.queue_message("user1", format!("msg{}", i));
         }
-        assert_eq!(state.pending_count("user1"), MAX_PENDING_MESSAGES_PER_USER);
+        assert_eq!(
+            state.pending_count("user1"),
+            QueueConfig::default().max_messages
+        );
 
         // Add one more - should drop oldest
         state.queue_message("user1", "new_msg".to_string());
-        assert_eq!(state.pending_count("user1"), MAX_PENDING_MESSAGES_PER_USER);
+        assert_eq!(
+            state.pending_count("user1"),
+            QueueConfig::default().max_messages
+        );
 
         // Verify oldest was dropped and newest is present
         let messages = state.take_pending_messages("user1");
@@ -356,6 +1559,85 @@ mod tests {
         assert_eq!(messages[messages.len() - 1], "new_msg");
     }
 
+    #[test]
+    fn test_store_replays_pending_messages() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let state = ServerState::with_store(Store::open(dir.path()).unwrap()).unwrap();
+            state.queue_message("user1", "msg1".to_string());
+            state.queue_message("user1", "msg2".to_string());
+            state.queue_message("user2", "msg3".to_string());
+        }
+
+        // Simulate a restart
+        let state = ServerState::with_store(Store::open(dir.path()).unwrap()).unwrap();
+        assert_eq!(state.pending_count("user1"), 2);
+        assert_eq!(state.pending_count("user2"), 1);
+        assert_eq!(state.take_pending_messages("user1"), vec!["msg1", "msg2"]);
+    }
+
+    #[test]
+    fn test_store_compacted_after_take() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let state = ServerState::with_store(Store::open(dir.path()).unwrap()).unwrap();
+            state.queue_message("user1", "delivered".to_string());
+            state.queue_message("user2", "still queued".to_string());
+            state.take_pending_messages("user1");
+        }
+
+        let store = Store::open(dir.path()).unwrap();
+        let rows = store.load_pending().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].user_id, "user2");
+    }
+
+    #[test]
+    fn test_store_drops_evicted_messages() {
+        let store = Store::open_in_memory().unwrap();
+        let state = ServerState::with_store(store).unwrap();
+
+        for i in 0..=QueueConfig::default().max_messages {
+            state.queue_message("user1", format!("msg{}", i));
+        }
+
+        let store = state.store.as_ref().unwrap();
+        assert_eq!(
+            store.pending_len().unwrap(),
+            QueueConfig::default().max_messages
+        );
+        assert_eq!(store.load_pending().unwrap()[0].payload, "msg1");
+    }
+
+    #[test]
+    fn test_register_identity_first_use_wins() {
+        let state = ServerState::new();
+
+        assert!(state.identity_key("user1").is_none());
+        assert!(state.register_identity("user1", [1u8; 32]));
+        assert_eq!(state.identity_key("user1"), Some([1u8; 32]));
+
+        // Re-registering the same key is fine, a different key is not
+        assert!(state.register_identity("user1", [1u8; 32]));
+        assert!(!state.register_identity("user1", [2u8; 32]));
+        assert_eq!(state.identity_key("user1"), Some([1u8; 32]));
+    }
+
+    #[test]
+    fn test_identities_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let state = ServerState::with_store(Store::open(dir.path()).unwrap()).unwrap();
+            state.register_identity("user1", [9u8; 32]);
+        }
+
+        let state = ServerState::with_store(Store::open(dir.path()).unwrap()).unwrap();
+        assert_eq!(state.identity_key("user1"), Some([9u8; 32]));
+    }
+
     #[test]
     fn test_pending_messages_per_user_isolation() {
         let state = ServerState::new();
@@ -373,4 +1655,561 @@ mod tests {
         // user2's messages should be unaffected
         assert_eq!(state.pending_count("user2"), 1);
     }
+
+    fn chat(chat_id: &str, participants: &[&str]) -> WsChatParticipants {
+        WsChatParticipants {
+            chat_id: chat_id.to_string(),
+            participant_ids: participants.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_send_to_subscribers_only() {
+        let state = ServerState::new();
+        let (tx_sub, mut rx_sub) = mpsc::channel(16);
+        let (tx_other, mut rx_other) = mpsc::channel(16);
+        state.add_client("subscriber".to_string(), tx_sub);
+        state.add_client("stranger".to_string(), tx_other);
+
+        state.subscribe("subscriber", vec!["alice".to_string()], vec![]);
+
+        assert_eq!(state.send_to_subscribers("alice", "alice update"), 1);
+        assert_eq!(rx_sub.try_recv().unwrap(), "alice update");
+        assert!(rx_other.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_subscribe_replaces_previous_set() {
+        let state = ServerState::new();
+
+        state.subscribe("sub", vec!["alice".to_string(), "bob".to_string()], vec![]);
+        state.subscribe("sub", vec!["bob".to_string(), "carol".to_string()], vec![]);
+
+        assert!(state.subscribers_of("alice").is_empty());
+        assert_eq!(state.subscribers_of("bob"), vec!["sub"]);
+        assert_eq!(state.subscribers_of("carol"), vec!["sub"]);
+    }
+
+    #[test]
+    fn test_subscriptions_cleared_when_last_connection_closes() {
+        let state = ServerState::new();
+        let (tx, rx) = mpsc::channel(16);
+        state.add_client("sub".to_string(), tx);
+        state.subscribe(
+            "sub",
+            vec!["alice".to_string()],
+            vec![chat("c1", &["alice"])],
+        );
+
+        drop(rx);
+        state.remove_client("sub");
+
+        assert!(state.subscribers_of("alice").is_empty());
+        assert_eq!(state.send_to_chat_participants("sub", "c1", "typing"), 0);
+    }
+
+    #[test]
+    fn test_typing_routed_to_declared_participants() {
+        let state = ServerState::new();
+        let (tx_bob, mut rx_bob) = mpsc::channel(16);
+        let (tx_carol, mut rx_carol) = mpsc::channel(16);
+        state.add_client("bob".to_string(), tx_bob);
+        state.add_client("carol".to_string(), tx_carol);
+
+        state.subscribe(
+            "alice",
+            vec![],
+            vec![
+                chat("alice-bob", &["alice", "bob"]),
+                chat("alice-carol", &["carol"]),
+            ],
+        );
+
+        assert_eq!(
+            state.send_to_chat_participants("alice", "alice-bob", "typing"),
+            1
+        );
+        assert_eq!(rx_bob.try_recv().unwrap(), "typing");
+        assert!(rx_carol.try_recv().is_err());
+
+        // Unknown chats reach nobody
+        assert_eq!(
+            state.send_to_chat_participants("alice", "unknown", "typing"),
+            0
+        );
+    }
+
+    #[test]
+    fn test_full_queue_drops_ephemeral_and_spills_chat() {
+        let state = ServerState::new();
+        let (tx, _rx) = mpsc::channel(1);
+        state.add_client("slow".to_string(), tx);
+
+        assert!(state.send_to_user("slow", "typing 1"));
+        assert!(!state.send_to_user("slow", "typing 2"));
+        assert!(!state.send_or_queue("slow", "chat 1"));
+
+        assert_eq!(state.pending_count("slow"), 1);
+        let stats = state.delivery_stats("slow");
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(stats.spilled, 1);
+        assert_eq!(stats.slow_disconnects, 0);
+    }
+
+    #[test]
+    fn test_flush_pending_fills_queue_in_order() {
+        let state = ServerState::new();
+        for i in 0..5 {
+            state.queue_message("bob", format!("msg {}", i));
+        }
+
+        let (tx, mut rx) = mpsc::channel(3);
+        state.add_client("bob".to_string(), tx);
+
+        // Only as many as fit in the queue are moved
+        assert_eq!(state.flush_pending("bob", DEFAULT_DEVICE_ID, 10), 3);
+        assert_eq!(state.pending_count("bob"), 2);
+
+        for i in 0..3 {
+            assert_eq!(rx.try_recv().unwrap(), format!("msg {}", i));
+        }
+
+        // The rest follow once the connection drains
+        assert_eq!(state.flush_pending("bob", DEFAULT_DEVICE_ID, 10), 2);
+        assert_eq!(state.pending_count("bob"), 0);
+        assert_eq!(rx.try_recv().unwrap(), "msg 3");
+        assert_eq!(rx.try_recv().unwrap(), "msg 4");
+    }
+
+    #[test]
+    fn test_flush_pending_respects_max() {
+        let state = ServerState::new();
+        for i in 0..5 {
+            state.queue_message("bob", format!("msg {}", i));
+        }
+        let (tx, _rx) = mpsc::channel(16);
+        state.add_client("bob".to_string(), tx);
+
+        assert_eq!(state.flush_pending("bob", DEFAULT_DEVICE_ID, 2), 2);
+        assert_eq!(state.pending_count("bob"), 3);
+        assert_eq!(state.flush_pending("nobody", DEFAULT_DEVICE_ID, 2), 0);
+    }
+
+    fn chat_json(id: &str, sender: &str, recipient: &str) -> String {
+        serde_json::to_string(&WsMessage::ChatMessage {
+            id: id.to_string(),
+            chat_id: "c1".to_string(),
+            sender_id: sender.to_string(),
+            sender_name: sender.to_string(),
+            recipient_id: recipient.to_string(),
+            content: "hello".to_string(),
+            timestamp: 1,
+            reply_to_id: None,
+            url_preview: None,
+        })
+        .unwrap()
+    }
+
+    fn expect_failure(
+        rx: &mut mpsc::Receiver<String>,
+        message_id: &str,
+        reason: DeliveryFailureReason,
+    ) {
+        let frame: WsMessage = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        match frame {
+            WsMessage::DeliveryFailed {
+                message_id: id,
+                recipient_id,
+                reason: r,
+                ..
+            } => {
+                assert_eq!(id, message_id);
+                assert_eq!(recipient_id, "bob");
+                assert_eq!(r, reason);
+            }
+            other => panic!("Expected DeliveryFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_evicted_message_reported_to_sender() {
+        let state = ServerState::new().with_queue(QueueConfig {
+            max_messages: 1,
+            ..Default::default()
+        });
+        let (tx, mut rx) = mpsc::channel(16);
+        state.add_client("alice".to_string(), tx);
+
+        state.queue_message("bob", chat_json("m1", "alice", "bob"));
+        state.queue_message("bob", chat_json("m2", "alice", "bob"));
+
+        assert_eq!(state.pending_count("bob"), 1);
+        expect_failure(&mut rx, "m1", DeliveryFailureReason::QueueFull);
+        assert_eq!(state.delivery_stats("bob").evicted, 1);
+    }
+
+    #[test]
+    fn test_byte_quota_evicts_oldest() {
+        let one = chat_json("m1", "alice", "bob");
+        let state = ServerState::new().with_queue(QueueConfig {
+            max_bytes: one.len() * 2,
+            ..Default::default()
+        });
+        let (tx, mut rx) = mpsc::channel(16);
+        state.add_client("alice".to_string(), tx);
+
+        state.queue_message("bob", one);
+        state.queue_message("bob", chat_json("m2", "alice", "bob"));
+        assert!(rx.try_recv().is_err());
+
+        state.queue_message("bob", chat_json("m3", "alice", "bob"));
+        assert_eq!(state.pending_count("bob"), 2);
+        expect_failure(&mut rx, "m1", DeliveryFailureReason::QueueFull);
+
+        // Larger than the whole quota: refused outright, the queue is left alone
+        let huge = chat_json("m4", "alice", "bob") + &" ".repeat(state.queue().max_bytes);
+        state.queue_message("bob", huge);
+        assert_eq!(state.pending_count("bob"), 2);
+        expect_failure(&mut rx, "m4", DeliveryFailureReason::QueueFull);
+    }
+
+    #[test]
+    fn test_expired_messages_reported_to_offline_sender() {
+        let store = Store::open_in_memory().unwrap();
+        let state = ServerState::with_store(store)
+            .unwrap()
+            .with_queue(QueueConfig {
+                ttl: std::time::Duration::from_millis(1),
+                ..Default::default()
+            });
+
+        state.queue_message("bob", chat_json("m1", "alice", "bob"));
+        state.queue_message("bob", "receipt".to_string());
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        assert_eq!(state.expire_pending(), 2);
+        assert_eq!(state.pending_count("bob"), 0);
+        assert_eq!(state.delivery_stats("bob").expired, 2);
+
+        // Only the chat message produces a failure, queued until alice is back
+        let failures = state.take_pending_messages("alice");
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].contains("\"type\":\"delivery_failed\""));
+        assert!(failures[0].contains("\"reason\":\"expired\""));
+        assert_eq!(state.store.as_ref().unwrap().pending_len().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_purged_messages_reported_to_sender() {
+        let store = Store::open_in_memory().unwrap();
+        let state = ServerState::with_store(store).unwrap();
+        let (tx, mut rx) = mpsc::channel(16);
+        state.add_client("alice".to_string(), tx);
+
+        state.queue_message("bob", chat_json("m1", "alice", "bob"));
+        state.queue_message("bob", "receipt".to_string());
+
+        assert_eq!(state.purge_pending("bob"), 2);
+        assert_eq!(state.pending_count("bob"), 0);
+        assert_eq!(state.delivery_stats("bob").purged, 2);
+        expect_failure(&mut rx, "m1", DeliveryFailureReason::Purged);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(state.store.as_ref().unwrap().pending_len().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_flush_skips_expired_messages() {
+        let state = ServerState::new().with_queue(QueueConfig {
+            ttl: std::time::Duration::from_millis(1),
+            ..Default::default()
+        });
+        state.queue_message("bob", "stale".to_string());
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        let (tx, mut rx) = mpsc::channel(16);
+        state.add_client("bob".to_string(), tx);
+
+        assert_eq!(state.flush_pending("bob", DEFAULT_DEVICE_ID, 10), 0);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(state.pending_count("bob"), 0);
+    }
+
+    #[test]
+    fn test_cannot_subscribe_to_self() {
+        let state = ServerState::new();
+        assert!(state
+            .subscribe("alice", vec!["alice".to_string()], vec![])
+            .is_empty());
+        assert!(state.subscribers_of("alice").is_empty());
+    }
+
+    #[test]
+    fn test_devices_have_separate_queues() {
+        let state = ServerState::new();
+        for device in ["laptop", "desk"] {
+            let (tx, rx) = mpsc::channel(16);
+            state.add_device_client("bob".to_string(), device, tx);
+            drop(rx);
+        }
+        state.remove_client("bob");
+
+        assert!(!state.send_or_queue("bob", "msg"));
+        assert_eq!(state.pending_count_for("bob", "laptop"), 1);
+        assert_eq!(state.pending_count_for("bob", "desk"), 1);
+
+        // The first device back does not drain the other's queue
+        let (tx, mut rx) = mpsc::channel(16);
+        state.add_device_client("bob".to_string(), "laptop", tx);
+        assert_eq!(state.flush_pending("bob", "laptop", 10), 1);
+        assert_eq!(rx.try_recv().unwrap(), "msg");
+        assert_eq!(state.pending_count_for("bob", "desk"), 1);
+    }
+
+    #[test]
+    fn test_online_device_gets_message_offline_device_queues_it() {
+        let state = ServerState::new();
+        let (tx_desk, rx_desk) = mpsc::channel(16);
+        state.add_device_client("bob".to_string(), "desk", tx_desk);
+        drop(rx_desk);
+        state.remove_client("bob");
+        let (tx_laptop, mut rx_laptop) = mpsc::channel(16);
+        state.add_device_client("bob".to_string(), "laptop", tx_laptop);
+
+        assert!(state.send_or_queue("bob", "msg"));
+        assert_eq!(rx_laptop.try_recv().unwrap(), "msg");
+        assert_eq!(state.pending_count_for("bob", "laptop"), 0);
+        assert_eq!(state.pending_count_for("bob", "desk"), 1);
+    }
+
+    #[test]
+    fn test_first_device_claims_messages_queued_before_any_device() {
+        let store = Store::open_in_memory().unwrap();
+        let state = ServerState::with_store(store).unwrap();
+        state.queue_message("bob", "early".to_string());
+        assert_eq!(state.pending_count_for("bob", DEFAULT_DEVICE_ID), 1);
+
+        let (tx, mut rx) = mpsc::channel(16);
+        state.add_device_client("bob".to_string(), "laptop", tx);
+        assert_eq!(state.pending_count_for("bob", DEFAULT_DEVICE_ID), 0);
+        assert_eq!(state.flush_pending("bob", "laptop", 10), 1);
+        assert_eq!(rx.try_recv().unwrap(), "early");
+        assert_eq!(state.store.as_ref().unwrap().pending_len().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sync_skips_origin_device_and_queues_for_offline_ones() {
+        let state = ServerState::new();
+        let (tx_c, rx_c) = mpsc::channel(16);
+        state.add_device_client("alice".to_string(), "c", tx_c);
+        drop(rx_c);
+        state.remove_client("alice");
+        let (tx_a, mut rx_a) = mpsc::channel(16);
+        let (tx_b, mut rx_b) = mpsc::channel(16);
+        state.add_device_client("alice".to_string(), "a", tx_a);
+        state.add_device_client("alice".to_string(), "b", tx_b);
+
+        state.sync_to_other_devices("alice", "a", "sent from a");
+        assert!(rx_a.try_recv().is_err());
+        assert_eq!(rx_b.try_recv().unwrap(), "sent from a");
+        assert_eq!(state.pending_count_for("alice", "c"), 1);
+        assert_eq!(state.pending_count_for("alice", "a"), 0);
+    }
+
+    #[test]
+    fn test_losing_a_sync_copy_is_not_reported() {
+        let state = ServerState::new().with_queue(QueueConfig {
+            max_messages: 1,
+            ..Default::default()
+        });
+        let (tx_alice, mut rx_alice) = mpsc::channel(16);
+        state.add_client("alice".to_string(), tx_alice);
+        let (tx_desk, rx_desk) = mpsc::channel(16);
+        state.add_device_client("bob".to_string(), "desk", tx_desk);
+        drop(rx_desk);
+        state.remove_client("bob");
+        let (tx_laptop, _rx_laptop) = mpsc::channel(16);
+        state.add_device_client("bob".to_string(), "laptop", tx_laptop);
+
+        // Delivered to the laptop, so the desk's copies are only for sync
+        assert!(state.send_or_queue("bob", &chat_json("m1", "alice", "bob")));
+        assert!(state.send_or_queue("bob", &chat_json("m2", "alice", "bob")));
+        assert_eq!(state.pending_count_for("bob", "desk"), 1);
+        assert_eq!(state.delivery_stats("bob").evicted, 1);
+        assert!(rx_alice.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_device_limit() {
+        let state = ServerState::new();
+        for i in 0..MAX_DEVICES_PER_USER {
+            assert!(state.register_device("bob", &format!("device-{}", i)));
+        }
+        assert!(!state.register_device("bob", "one-too-many"));
+        assert!(state.register_device("bob", "device-0"));
+        assert_eq!(state.devices_of("bob").len(), MAX_DEVICES_PER_USER);
+    }
+
+    #[test]
+    fn test_block_hides_presence_and_typing_both_ways() {
+        let state = ServerState::new();
+        let (tx_bob, mut rx_bob) = mpsc::channel(16);
+        let (tx_alice, mut rx_alice) = mpsc::channel(16);
+        state.add_client("bob".to_string(), tx_bob);
+        state.add_client("alice".to_string(), tx_alice);
+        state.subscribe(
+            "bob",
+            vec!["alice".to_string()],
+            vec![chat("c1", &["alice"])],
+        );
+        state.subscribe("alice", vec!["bob".to_string()], vec![chat("c1", &["bob"])]);
+
+        assert!(state.block_user("alice", "bob"));
+        assert!(state.has_blocked("alice", "bob"));
+        assert!(!state.has_blocked("bob", "alice"));
+        assert_eq!(state.block_list("alice"), vec!["bob".to_string()]);
+        assert!(state.block_list("bob").is_empty());
+        assert!(state.is_blocked_between("bob", "alice"));
+
+        assert_eq!(state.send_to_subscribers("alice", "alice online"), 0);
+        assert_eq!(state.send_to_subscribers("bob", "bob online"), 0);
+        assert_eq!(state.send_to_chat_participants("bob", "c1", "typing"), 0);
+        assert_eq!(state.send_to_chat_participants("alice", "c1", "typing"), 0);
+        assert!(rx_bob.try_recv().is_err());
+        assert!(rx_alice.try_recv().is_err());
+
+        state.unblock_user("alice", "bob");
+        assert!(!state.is_blocked_between("alice", "bob"));
+        assert_eq!(state.send_to_subscribers("alice", "alice online"), 1);
+        assert_eq!(rx_bob.try_recv().unwrap(), "alice online");
+    }
+
+    #[test]
+    fn test_block_limit() {
+        let state = ServerState::new();
+        for i in 0..MAX_BLOCKS_PER_USER {
+            assert!(state.block_user("alice", &format!("user-{}", i)));
+        }
+        assert!(!state.block_user("alice", "one-too-many"));
+        assert!(state.block_user("alice", "user-0"));
+    }
+
+    #[test]
+    fn test_blocks_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let state = ServerState::with_store(Store::open(dir.path()).unwrap()).unwrap();
+            state.block_user("alice", "bob");
+            state.block_user("alice", "carol");
+            state.unblock_user("alice", "carol");
+        }
+
+        let state = ServerState::with_store(Store::open(dir.path()).unwrap()).unwrap();
+        assert!(state.has_blocked("alice", "bob"));
+        assert!(!state.has_blocked("alice", "carol"));
+    }
+
+    #[test]
+    fn test_bans_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let state = ServerState::with_store(Store::open(dir.path()).unwrap()).unwrap();
+            state.ban_user("mallory");
+            state.ban_user("eve");
+            assert!(state.unban_user("eve"));
+            assert!(!state.unban_user("eve"));
+        }
+
+        let state = ServerState::with_store(Store::open(dir.path()).unwrap()).unwrap();
+        assert!(state.is_banned("mallory"));
+        assert!(!state.is_banned("eve"));
+        assert_eq!(state.banned_users(), vec!["mallory".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_kick_and_ban_close_connections() {
+        let state = ServerState::new();
+        let (tx1, _rx1) = mpsc::channel(16);
+        let (tx2, _rx2) = mpsc::channel(16);
+        let first = state.add_device_client("alice".to_string(), "laptop", tx1);
+        let second = state.add_device_client("alice".to_string(), "phone", tx2);
+        assert_eq!(state.connection_count("alice"), 2);
+
+        assert_eq!(state.kick_user("alice"), 2);
+        // The signal is remembered until each connection checks for it
+        tokio::time::timeout(std::time::Duration::from_secs(1), first.disconnected())
+            .await
+            .unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(1), second.disconnected())
+            .await
+            .unwrap();
+
+        assert_eq!(state.ban_user("alice"), 2);
+        assert!(state.is_banned("alice"));
+        assert_eq!(state.kick_user("bob"), 0);
+    }
+
+    #[test]
+    fn test_purge_and_announce() {
+        let state = ServerState::new();
+        state.queue_message("bob", "one".to_string());
+        state.queue_message("bob", "two".to_string());
+        assert_eq!(state.purge_pending("bob"), 2);
+        assert_eq!(state.pending_count("bob"), 0);
+
+        let (tx, mut rx) = mpsc::channel(16);
+        state.add_client("alice".to_string(), tx);
+        assert_eq!(state.announce("Maintenance at 22:00"), 1);
+        let frame: WsMessage = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert!(matches!(
+            frame,
+            WsMessage::Announcement { message, .. } if message == "Maintenance at 22:00"
+        ));
+    }
+
+    #[test]
+    fn test_announce_counts_only_users_it_reached() {
+        let state = ServerState::new();
+        let (tx_alice, _rx_alice) = mpsc::channel(16);
+        state.add_client("alice".to_string(), tx_alice);
+        // Bob's client predates announcements; carol's queue is full
+        let (tx_bob, mut rx_bob) = mpsc::channel(16);
+        state.add_connection("bob".to_string(), "phone", Negotiated::legacy(), tx_bob);
+        let (tx_carol, _rx_carol) = mpsc::channel(1);
+        state.add_client("carol".to_string(), tx_carol);
+        state.send_to_user("carol", "backlog");
+
+        assert_eq!(state.announce("Maintenance at 22:00"), 1);
+        assert!(rx_bob.try_recv().is_err());
+        assert_eq!(state.delivery_stats("carol").dropped, 1);
+    }
+
+    #[test]
+    fn test_callee_leaving_while_ringing_gets_missed_call() {
+        let state = ServerState::new();
+        let (tx_alice, mut rx_alice) = mpsc::channel(16);
+        let (tx_bob, rx_bob) = mpsc::channel(16);
+        state.add_client("alice".to_string(), tx_alice);
+        state.add_client("bob".to_string(), tx_bob);
+
+        let invite = WsMessage::CallInvite {
+            call_id: "call1".to_string(),
+            from_user_id: "alice".to_string(),
+            to_user_id: "bob".to_string(),
+            kind: "video".to_string(),
+        };
+        assert_eq!(state.track_call("alice", 1, &invite), CallDecision::Relay);
+
+        drop(rx_bob);
+        state.remove_client("bob");
+        state.hang_up_calls_of("bob", 2);
+
+        let hangup: WsMessage = serde_json::from_str(&rx_alice.try_recv().unwrap()).unwrap();
+        assert!(matches!(hangup, WsMessage::CallHangup { .. }));
+        assert!(!state.in_call("alice"));
+        let missed = state.take_pending_messages("bob");
+        assert_eq!(missed.len(), 1);
+        assert!(missed[0].contains(r#""type":"missed_call""#));
+    }
 }
@@ -0,0 +1,504 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, Result};
+
+use crate::state::DEFAULT_DEVICE_ID;
+
+/// File name of the relay database inside the data directory
+const DB_FILE_NAME: &str = "pulse-server.db";
+
+/// A queued message as stored on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredMessage {
+    pub seq: i64,
+    pub user_id: String,
+    pub device_id: String,
+    pub payload: String,
+    pub queued_at: i64,
+    /// Copy for a device of a user who already had the message elsewhere
+    pub sync_copy: bool,
+}
+
+/// Durable relay state (SQLite): the offline message queues, registered identities, known devices,
+/// block lists and bans
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+impl Store {
+    /// Open (or create) the store inside `data_dir`
+    pub fn open(data_dir: &Path) -> std::result::Result<Self, String> {
+        std::fs::create_dir_all(data_dir)
+            .map_err(|e| format!("Failed to create {}: {}", data_dir.display(), e))?;
+
+        let db_path = data_dir.join(DB_FILE_NAME);
+        Connection::open(&db_path)
+            .and_then(Self::init)
+            .map_err(|e| format!("Failed to open {}: {}", db_path.display(), e))
+    }
+
+    /// Open a throwaway in-memory store (used by tests)
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    /// Check that the database still answers queries
+    pub fn ping(&self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT 1", [], |_| Ok(()))
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        // auto_vacuum must be set before the first table is created to take effect
+        conn.execute_batch(
+            "
+            PRAGMA auto_vacuum = INCREMENTAL;
+            PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = NORMAL;
+
+            CREATE TABLE IF NOT EXISTS pending_messages (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                device_id TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                queued_at INTEGER NOT NULL,
+                sync_copy INTEGER NOT NULL DEFAULT 0
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_pending_messages_user_id ON pending_messages(user_id);
+
+            CREATE TABLE IF NOT EXISTS identities (
+                user_id TEXT PRIMARY KEY,
+                public_key BLOB NOT NULL,
+                registered_at INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS devices (
+                user_id TEXT NOT NULL,
+                device_id TEXT NOT NULL,
+                registered_at INTEGER NOT NULL,
+                PRIMARY KEY (user_id, device_id)
+            );
+
+            CREATE TABLE IF NOT EXISTS blocks (
+                user_id TEXT NOT NULL,
+                blocked_id TEXT NOT NULL,
+                blocked_at INTEGER NOT NULL,
+                PRIMARY KEY (user_id, blocked_id)
+            );
+
+            CREATE TABLE IF NOT EXISTS bans (
+                user_id TEXT PRIMARY KEY,
+                banned_at INTEGER NOT NULL
+            );
+            ",
+        )?;
+
+        // Migration: queues from before multi-device accounts belong to the default device
+        if !Self::has_column(&conn, "pending_messages", "device_id")? {
+            conn.execute_batch(&format!(
+                "ALTER TABLE pending_messages ADD COLUMN device_id TEXT NOT NULL DEFAULT '{}';
+                 ALTER TABLE pending_messages ADD COLUMN sync_copy INTEGER NOT NULL DEFAULT 0;",
+                DEFAULT_DEVICE_ID
+            ))?;
+        }
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+        conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+            [table, column],
+            |row| row.get::<_, i64>(0),
+        )
+        .map(|count| count > 0)
+    }
+
+    /// Append a message to one of a user's device queues, returning its sequence number
+    pub fn insert_pending(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        payload: &str,
+        queued_at: i64,
+        sync_copy: bool,
+    ) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO pending_messages (user_id, device_id, payload, queued_at, sync_copy)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![user_id, device_id, payload, queued_at, sync_copy],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Hand every message queued for one of a user's devices to another of its devices
+    pub fn reassign_pending(
+        &self,
+        user_id: &str,
+        from_device: &str,
+        to_device: &str,
+    ) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE pending_messages SET device_id = ?3 WHERE user_id = ?1 AND device_id = ?2",
+            params![user_id, from_device, to_device],
+        )
+    }
+
+    /// Remove a single queued message
+    pub fn remove_pending(&self, seq: i64) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM pending_messages WHERE seq = ?1", [seq])?;
+        Ok(())
+    }
+
+    /// Remove every queued message for a user and reclaim the freed pages
+    pub fn remove_user_pending(&self, user_id: &str) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM pending_messages WHERE user_id = ?1", [user_id])?;
+        if removed > 0 {
+            conn.execute_batch("PRAGMA incremental_vacuum;")?;
+        }
+        Ok(removed)
+    }
+
+    /// Load every queued message in insertion order (used to replay on startup)
+    pub fn load_pending(&self) -> Result<Vec<StoredMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT seq, user_id, device_id, payload, queued_at, sync_copy
+             FROM pending_messages ORDER BY seq ASC",
+        )?;
+
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(StoredMessage {
+                    seq: row.get(0)?,
+                    user_id: row.get(1)?,
+                    device_id: row.get(2)?,
+                    payload: row.get(3)?,
+                    queued_at: row.get(4)?,
+                    sync_copy: row.get(5)?,
+                })
+            })?
+            .collect();
+
+        rows
+    }
+
+    /// Total number of queued messages on disk
+    pub fn pending_len(&self) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT COUNT(*) FROM pending_messages", [], |row| {
+            row.get::<_, i64>(0)
+        })
+        .map(|count| count as usize)
+    }
+
+    /// Record the identity public key a user_id is bound to
+    pub fn insert_identity(&self, user_id: &str, public_key: &[u8; 32]) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO identities (user_id, public_key, registered_at) VALUES (?1, ?2, ?3)",
+            params![
+                user_id,
+                public_key.as_slice(),
+                chrono::Utc::now().timestamp_millis()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Load every registered identity (used to populate the registry on startup)
+    pub fn load_identities(&self) -> Result<Vec<(String, [u8; 32])>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT user_id, public_key FROM identities")?;
+
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?
+            .filter_map(|r| r.ok())
+            .filter_map(|(user_id, key)| key.try_into().ok().map(|key| (user_id, key)))
+            .collect();
+
+        Ok(rows)
+    }
+
+    /// Record a device of a user (no-op if it is already known)
+    pub fn insert_device(&self, user_id: &str, device_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO devices (user_id, device_id, registered_at) VALUES (?1, ?2, ?3)",
+            params![user_id, device_id, chrono::Utc::now().timestamp_millis()],
+        )?;
+        Ok(())
+    }
+
+    /// Load every known device (used to populate the device registry on startup)
+    pub fn load_devices(&self) -> Result<Vec<(String, String)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT user_id, device_id FROM devices")?;
+
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+
+        rows
+    }
+
+    /// Record that `user_id` blocked `blocked_id` (no-op if it already did)
+    pub fn insert_block(&self, user_id: &str, blocked_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO blocks (user_id, blocked_id, blocked_at) VALUES (?1, ?2, ?3)",
+            params![user_id, blocked_id, chrono::Utc::now().timestamp_millis()],
+        )?;
+        Ok(())
+    }
+
+    /// Forget a block; returns whether there was one
+    pub fn remove_block(&self, user_id: &str, blocked_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute(
+            "DELETE FROM blocks WHERE user_id = ?1 AND blocked_id = ?2",
+            params![user_id, blocked_id],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Load every block as (user_id, blocked_id) (used to populate the block lists on startup)
+    pub fn load_blocks(&self) -> Result<Vec<(String, String)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT user_id, blocked_id FROM blocks")?;
+
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+
+        rows
+    }
+
+    /// Move everything in the write-ahead log into the database file (e.g. before exiting)
+    pub fn checkpoint(&self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+    }
+
+    /// Record that an operator banned `user_id` (no-op if it already is)
+    pub fn insert_ban(&self, user_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO bans (user_id, banned_at) VALUES (?1, ?2)",
+            params![user_id, chrono::Utc::now().timestamp_millis()],
+        )?;
+        Ok(())
+    }
+
+    /// Lift a ban; returns whether there was one
+    pub fn remove_ban(&self, user_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM bans WHERE user_id = ?1", params![user_id])?;
+        Ok(removed > 0)
+    }
+
+    /// Load every banned user_id (used to populate the ban list on startup)
+    pub fn load_bans(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT user_id FROM bans ORDER BY user_id")?;
+
+        let rows = stmt.query_map([], |row| row.get(0))?.collect();
+
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_load_in_order() {
+        let store = Store::open_in_memory().unwrap();
+
+        store
+            .insert_pending("user1", DEFAULT_DEVICE_ID, "a", 1, false)
+            .unwrap();
+        store
+            .insert_pending("user2", DEFAULT_DEVICE_ID, "b", 2, false)
+            .unwrap();
+        store
+            .insert_pending("user1", DEFAULT_DEVICE_ID, "c", 3, false)
+            .unwrap();
+
+        let rows = store.load_pending().unwrap();
+        let payloads: Vec<&str> = rows.iter().map(|r| r.payload.as_str()).collect();
+        assert_eq!(payloads, vec!["a", "b", "c"]);
+        assert_eq!(rows[1].user_id, "user2");
+        assert_eq!(rows[2].queued_at, 3);
+    }
+
+    #[test]
+    fn test_remove_user_only_touches_that_user() {
+        let store = Store::open_in_memory().unwrap();
+
+        store
+            .insert_pending("user1", DEFAULT_DEVICE_ID, "a", 1, false)
+            .unwrap();
+        store
+            .insert_pending("user2", DEFAULT_DEVICE_ID, "b", 2, false)
+            .unwrap();
+        store
+            .insert_pending("user1", DEFAULT_DEVICE_ID, "c", 3, false)
+            .unwrap();
+
+        assert_eq!(store.remove_user_pending("user1").unwrap(), 2);
+        assert_eq!(store.pending_len().unwrap(), 1);
+        assert_eq!(store.load_pending().unwrap()[0].user_id, "user2");
+    }
+
+    #[test]
+    fn test_remove_single_message() {
+        let store = Store::open_in_memory().unwrap();
+
+        let seq = store
+            .insert_pending("user1", DEFAULT_DEVICE_ID, "a", 1, false)
+            .unwrap();
+        store
+            .insert_pending("user1", DEFAULT_DEVICE_ID, "b", 2, false)
+            .unwrap();
+        store.remove_pending(seq).unwrap();
+
+        let rows = store.load_pending().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].payload, "b");
+    }
+
+    #[test]
+    fn test_identity_roundtrip() {
+        let store = Store::open_in_memory().unwrap();
+
+        store.insert_identity("user1", &[7u8; 32]).unwrap();
+        assert!(store.insert_identity("user1", &[8u8; 32]).is_err());
+
+        let identities = store.load_identities().unwrap();
+        assert_eq!(identities, vec![("user1".to_string(), [7u8; 32])]);
+    }
+
+    #[test]
+    fn test_reopen_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let store = Store::open(dir.path()).unwrap();
+            store
+                .insert_pending("user1", DEFAULT_DEVICE_ID, "survives restart", 1, false)
+                .unwrap();
+        }
+
+        let store = Store::open(dir.path()).unwrap();
+        let rows = store.load_pending().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].payload, "survives restart");
+    }
+
+    #[test]
+    fn test_devices_and_reassigned_queue() {
+        let store = Store::open_in_memory().unwrap();
+
+        store.insert_device("user1", "laptop").unwrap();
+        store.insert_device("user1", "laptop").unwrap();
+        store.insert_device("user1", "desktop").unwrap();
+        let mut devices = store.load_devices().unwrap();
+        devices.sort();
+        assert_eq!(
+            devices,
+            vec![
+                ("user1".to_string(), "desktop".to_string()),
+                ("user1".to_string(), "laptop".to_string())
+            ]
+        );
+
+        store
+            .insert_pending("user1", DEFAULT_DEVICE_ID, "a", 1, false)
+            .unwrap();
+        store
+            .insert_pending("user1", "desktop", "b", 2, true)
+            .unwrap();
+        assert_eq!(
+            store
+                .reassign_pending("user1", DEFAULT_DEVICE_ID, "laptop")
+                .unwrap(),
+            1
+        );
+
+        let rows = store.load_pending().unwrap();
+        assert_eq!(rows[0].device_id, "laptop");
+        assert!(!rows[0].sync_copy);
+        assert_eq!(rows[1].device_id, "desktop");
+        assert!(rows[1].sync_copy);
+    }
+
+    #[test]
+    fn test_block_roundtrip() {
+        let store = Store::open_in_memory().unwrap();
+
+        store.insert_block("user1", "user2").unwrap();
+        store.insert_block("user1", "user2").unwrap();
+        store.insert_block("user1", "user3").unwrap();
+        assert!(store.remove_block("user1", "user3").unwrap());
+        assert!(!store.remove_block("user1", "user3").unwrap());
+
+        assert_eq!(
+            store.load_blocks().unwrap(),
+            vec![("user1".to_string(), "user2".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_ban_roundtrip() {
+        let store = Store::open_in_memory().unwrap();
+
+        store.insert_ban("user2").unwrap();
+        store.insert_ban("user2").unwrap();
+        store.insert_ban("user1").unwrap();
+        assert!(store.remove_ban("user1").unwrap());
+        assert!(!store.remove_ban("user1").unwrap());
+
+        assert_eq!(store.load_bans().unwrap(), vec!["user2".to_string()]);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path()).unwrap();
+        store.insert_ban("user1").unwrap();
+        store.checkpoint().unwrap();
+        Store::open_in_memory().unwrap().checkpoint().unwrap();
+    }
+
+    #[test]
+    fn test_migrates_queue_from_before_devices() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE pending_messages (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                queued_at INTEGER NOT NULL
+            );
+            INSERT INTO pending_messages (user_id, payload, queued_at) VALUES ('user1', 'old', 1);",
+        )
+        .unwrap();
+
+        let store = Store::init(conn).unwrap();
+        let rows = store.load_pending().unwrap();
+        assert_eq!(rows[0].device_id, DEFAULT_DEVICE_ID);
+        assert!(!rows[0].sync_copy);
+    }
+}
@@ -0,0 +1,179 @@
+//! Optional `wss://` termination with rustls.
+//!
+//! The certificate lives behind a resolver rather than in the rustls config,
+//! so [`TlsTerminator::reload`] swaps it for new handshakes while connections
+//! that already completed theirs keep running untouched.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Accepts TLS on plain TCP streams using a certificate that can be reloaded
+pub struct TlsTerminator {
+    acceptor: TlsAcceptor,
+    certs: Arc<CertResolver>,
+}
+
+impl TlsTerminator {
+    /// Load the PEM certificate chain and private key, failing if either is unusable
+    pub fn new(cert_path: &Path, key_path: &Path) -> Result<Self, String> {
+        let provider = Arc::new(ring::default_provider());
+        let key = load_certified_key(cert_path, key_path, &provider)?;
+        let certs = Arc::new(CertResolver {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            provider: provider.clone(),
+            current: RwLock::new(Arc::new(key)),
+        });
+
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("Failed to configure TLS: {}", e))?
+            .with_no_client_auth()
+            .with_cert_resolver(certs.clone());
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            certs,
+        })
+    }
+
+    /// Re-read the certificate and key from disk for future handshakes.
+    /// On error the current certificate stays in use.
+    pub fn reload(&self) -> Result<(), String> {
+        self.certs.reload()
+    }
+
+    /// Run the TLS handshake on an accepted TCP stream
+    pub async fn accept(&self, stream: TcpStream) -> std::io::Result<TlsStream<TcpStream>> {
+        self.acceptor.accept(stream).await
+    }
+}
+
+/// Hands every handshake the most recently loaded certificate
+#[derive(Debug)]
+struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    fn reload(&self) -> Result<(), String> {
+        let key = load_certified_key(&self.cert_path, &self.key_path, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, String> {
+    let certs = rustls_pemfile::certs(&mut open_pem(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: invalid PEM: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", cert_path.display()));
+    }
+
+    let key = rustls_pemfile::private_key(&mut open_pem(key_path)?)
+        .map_err(|e| format!("{}: invalid PEM: {}", key_path.display(), e))?
+        .ok_or_else(|| format!("{}: no private key found", key_path.display()))?;
+
+    CertifiedKey::from_der(certs, key, provider).map_err(|e| {
+        format!(
+            "{} / {}: unusable certificate or key: {}",
+            cert_path.display(),
+            key_path.display(),
+            e
+        )
+    })
+}
+
+fn open_pem(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_pair(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.join(format!("{}.crt", name));
+        let key_path = dir.join(format!("{}.key", name));
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn current_cert(terminator: &TlsTerminator) -> Vec<u8> {
+        let key = terminator.certs.current.read().unwrap().clone();
+        key.cert[0].to_vec()
+    }
+
+    #[test]
+    fn test_loads_self_signed_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_pair(dir.path(), "server");
+        assert!(TlsTerminator::new(&cert, &key).is_ok());
+    }
+
+    #[test]
+    fn test_mismatched_key_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = write_pair(dir.path(), "one");
+        let (_, other_key) = write_pair(dir.path(), "two");
+
+        let err = TlsTerminator::new(&cert, &other_key).err().unwrap();
+        assert!(err.contains("unusable certificate or key"), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_key_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = write_pair(dir.path(), "server");
+
+        // A certificate file holds no private key
+        let err = TlsTerminator::new(&cert, &cert).err().unwrap();
+        assert!(err.contains("no private key found"), "{}", err);
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_current_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_pair(dir.path(), "server");
+        let terminator = TlsTerminator::new(&cert, &key).unwrap();
+        let before = current_cert(&terminator);
+
+        std::fs::write(&key, "not a key").unwrap();
+        assert!(terminator.reload().is_err());
+        assert_eq!(current_cert(&terminator), before);
+
+        // A fresh pair at the same paths is picked up
+        let (new_cert, new_key) = write_pair(dir.path(), "fresh");
+        std::fs::rename(new_cert, &cert).unwrap();
+        std::fs::rename(new_key, &key).unwrap();
+        terminator.reload().unwrap();
+        assert_ne!(current_cert(&terminator), before);
+    }
+}
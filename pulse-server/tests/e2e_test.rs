@@ -81,6 +81,7 @@ impl Drop for ServerProcess {
 }
 
 /// Helper to connect and authenticate a client
+#[allow(clippy::useless_conversion)]
 async fn connect_and_auth(
     url: &str,
     user_id: &str,
@@ -97,7 +98,9 @@ async fn connect_and_auth(
     // Send connect message
     let connect_msg = json!({
         "type": "connect",
-        "user_id": user_id
+        "user_id": user_id,
+        "protocol_version": 2,
+        "capabilities": ["acks"]
     });
     write
         .send(Message::Text(connect_msg.to_string().into()))
@@ -125,6 +128,26 @@ async fn connect_and_auth(
     Ok(write.reunite(read).unwrap())
 }
 
+/// Declare the users (and chat participants) a client follows, and let the server apply it
+async fn subscribe(
+    client: &mut tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >,
+    user_ids: &[&str],
+    chats: serde_json::Value,
+) {
+    let msg = json!({
+        "type": "subscribe_presence",
+        "user_ids": user_ids,
+        "chats": chats
+    });
+    client
+        .send(Message::Text(msg.to_string()))
+        .await
+        .expect("Send failed");
+    sleep(Duration::from_millis(50)).await;
+}
+
 /// Read next message with timeout, skipping presence notifications if needed
 async fn read_message_of_type(
     read: &mut futures_util::stream::SplitStream<
@@ -192,10 +215,11 @@ async fn e2e_presence_broadcast_on_connect() {
     let server = ServerProcess::start(port).expect("Failed to start server");
     server.wait_until_ready().await.expect("Server not ready");
 
-    // Connect first client
-    let client1 = connect_and_auth(&server.url(), "user1")
+    // Connect first client, following user2
+    let mut client1 = connect_and_auth(&server.url(), "user1")
         .await
         .expect("Failed to connect user1");
+    subscribe(&mut client1, &["user2"], json!([])).await;
     let (_, mut read1) = client1.split();
 
     // Connect second client
@@ -213,6 +237,7 @@ async fn e2e_presence_broadcast_on_connect() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_message_delivery_between_clients() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -263,14 +288,21 @@ async fn e2e_message_delivery_between_clients() {
 }
 
 #[tokio::test]
-async fn e2e_typing_indicator_broadcast() {
+#[allow(clippy::useless_conversion)]
+async fn e2e_typing_indicator_routed_to_participants() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
     server.wait_until_ready().await.expect("Server not ready");
 
-    let client1 = connect_and_auth(&server.url(), "typer")
+    let mut client1 = connect_and_auth(&server.url(), "typer")
         .await
         .expect("Failed to connect");
+    subscribe(
+        &mut client1,
+        &[],
+        json!([{ "chat_id": "chat1", "participant_ids": ["watcher"] }]),
+    )
+    .await;
     let client2 = connect_and_auth(&server.url(), "watcher")
         .await
         .expect("Failed to connect");
@@ -307,6 +339,7 @@ async fn e2e_typing_indicator_broadcast() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_delivery_receipt_flow() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -352,6 +385,7 @@ async fn e2e_delivery_receipt_flow() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_read_receipt_flow() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -398,7 +432,8 @@ async fn e2e_read_receipt_flow() {
 }
 
 #[tokio::test]
-async fn e2e_profile_update_broadcast() {
+#[allow(clippy::useless_conversion)]
+async fn e2e_profile_update_routed_to_subscribers() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
     server.wait_until_ready().await.expect("Server not ready");
@@ -406,9 +441,10 @@ async fn e2e_profile_update_broadcast() {
     let client1 = connect_and_auth(&server.url(), "updater")
         .await
         .expect("Failed to connect");
-    let client2 = connect_and_auth(&server.url(), "observer")
+    let mut client2 = connect_and_auth(&server.url(), "observer")
         .await
         .expect("Failed to connect");
+    subscribe(&mut client2, &["updater"], json!([])).await;
 
     let (mut write1, _) = client1.split();
     let (_, mut read2) = client2.split();
@@ -452,9 +488,10 @@ async fn e2e_offline_presence_on_disconnect() {
     server.wait_until_ready().await.expect("Server not ready");
 
     // Connect first client
-    let client1 = connect_and_auth(&server.url(), "stayer")
+    let mut client1 = connect_and_auth(&server.url(), "stayer")
         .await
         .expect("Failed to connect");
+    subscribe(&mut client1, &["leaver"], json!([])).await;
     let (_, mut read1) = client1.split();
 
     // Connect and then disconnect second client
@@ -481,6 +518,7 @@ async fn e2e_offline_presence_on_disconnect() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_message_routed_to_specific_recipient() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -543,6 +581,7 @@ async fn e2e_message_routed_to_specific_recipient() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_sender_does_not_receive_own_message() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -586,6 +625,7 @@ async fn e2e_sender_does_not_receive_own_message() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_server_handles_invalid_json() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -635,6 +675,7 @@ async fn e2e_server_handles_invalid_json() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_reconnection_replaces_old_session() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -706,10 +747,11 @@ async fn e2e_new_client_receives_existing_online_users() {
     // Small delay to ensure alice is registered
     sleep(Duration::from_millis(100)).await;
 
-    // Connect second client (Bob) - should receive alice's online presence
-    let client2 = connect_and_auth(&server.url(), "bob")
+    // Connect second client (Bob) - subscribing reports alice's online presence
+    let mut client2 = connect_and_auth(&server.url(), "bob")
         .await
         .expect("Failed to connect bob");
+    subscribe(&mut client2, &["alice"], json!([])).await;
     let (_, mut read2) = client2.split();
 
     // Bob should receive alice's presence (existing online user)
@@ -727,16 +769,18 @@ async fn e2e_bidirectional_presence_sync() {
     let server = ServerProcess::start(port).expect("Failed to start server");
     server.wait_until_ready().await.expect("Server not ready");
 
-    // Connect first client (Alice)
-    let client1 = connect_and_auth(&server.url(), "alice")
+    // Connect first client (Alice), following bob
+    let mut client1 = connect_and_auth(&server.url(), "alice")
         .await
         .expect("Failed to connect alice");
+    subscribe(&mut client1, &["bob"], json!([])).await;
     let (_, mut read1) = client1.split();
 
-    // Connect second client (Bob)
-    let client2 = connect_and_auth(&server.url(), "bob")
+    // Connect second client (Bob), following alice
+    let mut client2 = connect_and_auth(&server.url(), "bob")
         .await
         .expect("Failed to connect bob");
+    subscribe(&mut client2, &["alice"], json!([])).await;
     let (_, mut read2) = client2.split();
 
     // Alice should receive bob's online presence (bob just connected)
@@ -746,7 +790,7 @@ async fn e2e_bidirectional_presence_sync() {
     assert_eq!(alice_sees_bob["user_id"], "bob");
     assert_eq!(alice_sees_bob["is_online"], true);
 
-    // Bob should receive alice's presence (existing online user sent on connect)
+    // Bob should receive alice's presence (existing online user sent on subscribe)
     let bob_sees_alice = read_message_of_type(&mut read2, "presence", 5)
         .await
         .expect("Bob should receive alice's presence");
@@ -759,6 +803,7 @@ async fn e2e_bidirectional_presence_sync() {
 // ============================================================================
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_offline_message_queued_and_delivered() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -806,6 +851,7 @@ async fn e2e_offline_message_queued_and_delivered() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion, clippy::expect_fun_call)]
 async fn e2e_multiple_offline_messages_delivered_in_order() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -855,6 +901,7 @@ async fn e2e_multiple_offline_messages_delivered_in_order() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_offline_delivery_receipt_queued() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -897,6 +944,7 @@ async fn e2e_offline_delivery_receipt_queued() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_offline_read_receipt_queued() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -940,15 +988,22 @@ async fn e2e_offline_read_receipt_queued() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_typing_not_queued() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
     server.wait_until_ready().await.expect("Server not ready");
 
     // Alice connects
-    let client_alice = connect_and_auth(&server.url(), "alice")
+    let mut client_alice = connect_and_auth(&server.url(), "alice")
         .await
         .expect("Failed to connect alice");
+    subscribe(
+        &mut client_alice,
+        &[],
+        json!([{ "chat_id": "chat-alice-bob", "participant_ids": ["bob"] }]),
+    )
+    .await;
     let (mut write_alice, _) = client_alice.split();
 
     // Bob is offline - Alice sends typing indicator (should NOT be queued)
@@ -987,6 +1042,7 @@ async fn e2e_typing_not_queued() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_message_to_online_user_immediate() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -1036,6 +1092,7 @@ async fn e2e_message_to_online_user_immediate() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_concurrent_senders_to_offline_user() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
@@ -1105,6 +1162,7 @@ async fn e2e_concurrent_senders_to_offline_user() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn e2e_rapid_reconnect_delivers_all() {
     let port = get_unique_port();
     let server = ServerProcess::start(port).expect("Failed to start server");
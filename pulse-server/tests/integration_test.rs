@@ -12,14 +12,29 @@ use tokio_tungstenite::{connect_async, tungstenite::Message};
 
 /// Start a test server on a random available port
 async fn start_test_server() -> (u16, tokio::task::JoinHandle<()>) {
+    start_test_server_with_state(pulse_server::ServerState::new()).await
+}
+
+/// Start a test server with preconfigured state
+async fn start_test_server_with_state(
+    state: pulse_server::ServerState,
+) -> (u16, tokio::task::JoinHandle<()>) {
+    start_test_server_with_shared_state(std::sync::Arc::new(state)).await
+}
+
+/// Start a test server whose state the test can inspect while it runs
+async fn start_test_server_with_shared_state(
+    state: std::sync::Arc<pulse_server::ServerState>,
+) -> (u16, tokio::task::JoinHandle<()>) {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let port = listener.local_addr().unwrap().port();
 
-    let state = std::sync::Arc::new(pulse_server::ServerState::new());
-
     let handle = tokio::spawn(async move {
         while let Ok((stream, _)) = listener.accept().await {
-            let ws_stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let config = state.limits().websocket_config();
+            let ws_stream = tokio_tungstenite::accept_async_with_config(stream, Some(config))
+                .await
+                .unwrap();
             let state = state.clone();
             tokio::spawn(async move {
                 pulse_server::handle_connection(ws_stream, state).await;
@@ -34,6 +49,7 @@ async fn start_test_server() -> (u16, tokio::task::JoinHandle<()>) {
 }
 
 /// Connect a client to the server and authenticate
+#[allow(clippy::useless_conversion)]
 async fn connect_client(
     port: u16,
     user_id: &str,
@@ -48,7 +64,9 @@ async fn connect_client(
     // Send connect message
     let connect_msg = json!({
         "type": "connect",
-        "user_id": user_id
+        "user_id": user_id,
+        "protocol_version": 2,
+        "capabilities": ["acks"]
     });
     write
         .send(Message::Text(connect_msg.to_string().into()))
@@ -74,6 +92,23 @@ async fn connect_client(
     write.reunite(read).unwrap()
 }
 
+/// Declare the users (and chat participants) a client follows, and let the server apply it
+async fn subscribe(
+    client: &mut tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >,
+    user_ids: &[&str],
+    chats: serde_json::Value,
+) {
+    let msg = json!({
+        "type": "subscribe_presence",
+        "user_ids": user_ids,
+        "chats": chats
+    });
+    client.send(Message::Text(msg.to_string())).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+}
+
 #[tokio::test]
 async fn test_client_connects_and_authenticates() {
     let (port, server_handle) = start_test_server().await;
@@ -87,8 +122,9 @@ async fn test_client_connects_and_authenticates() {
 async fn test_presence_broadcast_on_connect() {
     let (port, server_handle) = start_test_server().await;
 
-    // Connect first client
-    let client1 = connect_client(port, "user1").await;
+    // Connect first client, following user2
+    let mut client1 = connect_client(port, "user1").await;
+    subscribe(&mut client1, &["user2"], json!([])).await;
     let (_, mut read1) = client1.split();
 
     // Connect second client
@@ -114,6 +150,7 @@ async fn test_presence_broadcast_on_connect() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn test_message_broadcast() {
     let (port, server_handle) = start_test_server().await;
 
@@ -163,6 +200,7 @@ async fn test_message_broadcast() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn test_sender_does_not_receive_own_message() {
     let (port, server_handle) = start_test_server().await;
 
@@ -185,7 +223,11 @@ async fn test_sender_does_not_receive_own_message() {
         .await
         .unwrap();
 
-    // User1 should NOT receive their own message back
+    // User1 only gets the relay's acknowledgement, not their own message back
+    let ack = next_json(&mut read1).await;
+    assert_eq!(ack["type"], "server_ack");
+    assert_eq!(ack["message_id"], "msg1");
+
     let result = timeout(Duration::from_millis(500), read1.next()).await;
 
     // Should timeout because no message should be received
@@ -195,11 +237,18 @@ async fn test_sender_does_not_receive_own_message() {
 }
 
 #[tokio::test]
-async fn test_typing_indicator_broadcast() {
+#[allow(clippy::useless_conversion)]
+async fn test_typing_indicator_routed_to_participants() {
     let (port, server_handle) = start_test_server().await;
 
-    let client1 = connect_client(port, "user1").await;
+    let mut client1 = connect_client(port, "user1").await;
     let client2 = connect_client(port, "user2").await;
+    subscribe(
+        &mut client1,
+        &[],
+        json!([{ "chat_id": "chat1", "participant_ids": ["user1", "user2"] }]),
+    )
+    .await;
 
     let (mut write1, _) = client1.split();
     let (_, mut read2) = client2.split();
@@ -239,6 +288,7 @@ async fn test_typing_indicator_broadcast() {
 }
 
 #[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn test_delivery_receipt_routed_to_sender() {
     let (port, server_handle) = start_test_server().await;
 
@@ -287,8 +337,9 @@ async fn test_delivery_receipt_routed_to_sender() {
 async fn test_offline_presence_on_disconnect() {
     let (port, server_handle) = start_test_server().await;
 
-    // Connect first client
-    let client1 = connect_client(port, "user1").await;
+    // Connect first client, following user2
+    let mut client1 = connect_client(port, "user1").await;
+    subscribe(&mut client1, &["user2"], json!([])).await;
     let (_, mut read1) = client1.split();
 
     // Connect and then disconnect second client
@@ -321,6 +372,53 @@ async fn test_offline_presence_on_disconnect() {
 }
 
 #[tokio::test]
+async fn test_server_ack_reports_delivery() {
+    let limits = pulse_server::RateLimitConfig {
+        chat: pulse_server::BucketConfig::new(2, 0.0),
+        ..Default::default()
+    };
+    let state = pulse_server::ServerState::new().with_rate_limits(limits);
+    let (port, server_handle) = start_test_server_with_state(state).await;
+
+    let _online = connect_client(port, "online").await;
+    let sender = connect_client(port, "sender").await;
+    let (mut write, mut read) = sender.split();
+
+    for (id, recipient) in [("m1", "online"), ("m2", "offline"), ("m3", "online")] {
+        let msg = json!({
+            "type": "message",
+            "id": id,
+            "chat_id": "chat1",
+            "sender_id": "sender",
+            "sender_name": "Sender",
+            "recipient_id": recipient,
+            "content": "hello",
+            "timestamp": 1
+        });
+        write.send(Message::Text(msg.to_string())).await.unwrap();
+    }
+
+    let delivered = next_json(&mut read).await;
+    assert_eq!(delivered["type"], "server_ack");
+    assert_eq!(delivered["message_id"], "m1");
+    assert_eq!(delivered["status"], "delivered");
+
+    let queued = next_json(&mut read).await;
+    assert_eq!(queued["message_id"], "m2");
+    assert_eq!(queued["status"], "queued");
+
+    // Over the chat limit: the ack carries the reason instead of a bare error frame
+    let rejected = next_json(&mut read).await;
+    assert_eq!(rejected["type"], "server_ack");
+    assert_eq!(rejected["message_id"], "m3");
+    assert_eq!(rejected["status"], "error");
+    assert!(rejected["reason"].as_str().unwrap().contains("chat"));
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+#[allow(clippy::useless_conversion)]
 async fn test_message_routed_to_specific_recipient() {
     let (port, server_handle) = start_test_server().await;
 
@@ -380,11 +478,13 @@ async fn test_message_routed_to_specific_recipient() {
 }
 
 #[tokio::test]
-async fn test_profile_update_broadcast() {
+#[allow(clippy::useless_conversion)]
+async fn test_profile_update_routed_to_subscribers() {
     let (port, server_handle) = start_test_server().await;
 
     let client1 = connect_client(port, "user1").await;
-    let client2 = connect_client(port, "user2").await;
+    let mut client2 = connect_client(port, "user2").await;
+    subscribe(&mut client2, &["user1"], json!([])).await;
 
     let (mut write1, _) = client1.split();
     let (_, mut read2) = client2.split();
@@ -425,3 +525,1546 @@ async fn test_profile_update_broadcast() {
 
     server_handle.abort();
 }
+
+/// Read the next text frame as JSON
+async fn next_json(
+    read: &mut futures_util::stream::SplitStream<
+        tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >,
+    >,
+) -> serde_json::Value {
+    loop {
+        let msg = timeout(Duration::from_secs(5), read.next())
+            .await
+            .expect("Timeout")
+            .expect("Closed")
+            .expect("Error");
+
+        match msg {
+            Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+            // Heartbeat frames are answered by tungstenite
+            Message::Ping(_) | Message::Pong(_) => continue,
+            other => panic!("Expected text message, got {:?}", other),
+        }
+    }
+}
+
+/// Connect with an identity key, answering the challenge with `prover`, and return the auth response
+async fn connect_with_identity(
+    port: u16,
+    user_id: &str,
+    offered_key: Option<&x25519_dalek::StaticSecret>,
+    prover: &x25519_dalek::StaticSecret,
+) -> serde_json::Value {
+    let url = format!("ws://127.0.0.1:{}", port);
+    let (ws_stream, _) = connect_async(&url).await.expect("Failed to connect");
+    let (mut write, mut read) = ws_stream.split();
+
+    let public_key =
+        offered_key.map(|secret| hex::encode(x25519_dalek::PublicKey::from(secret).as_bytes()));
+    let connect_msg = json!({
+        "type": "connect",
+        "user_id": user_id,
+        "public_key": public_key
+    });
+    write
+        .send(Message::Text(connect_msg.to_string()))
+        .await
+        .unwrap();
+
+    let challenge = next_json(&mut read).await;
+    if challenge["type"] != "auth_challenge" {
+        return challenge;
+    }
+
+    let server_key = pulse_protocol::decode_key(challenge["server_key"].as_str().unwrap()).unwrap();
+    let nonce = hex::decode(challenge["nonce"].as_str().unwrap()).unwrap();
+    let shared = prover.diffie_hellman(&x25519_dalek::PublicKey::from(server_key));
+    let proof = pulse_server::compute_proof(shared.as_bytes(), &nonce, user_id);
+
+    let proof_msg = json!({ "type": "auth_proof", "proof": hex::encode(proof) });
+    write
+        .send(Message::Text(proof_msg.to_string()))
+        .await
+        .unwrap();
+
+    next_json(&mut read).await
+}
+
+#[tokio::test]
+async fn test_identity_key_bound_on_first_connect() {
+    let (port, server_handle) = start_test_server().await;
+    let identity = x25519_dalek::StaticSecret::random_from_rng(rand::rngs::OsRng);
+
+    // First connect registers the key
+    let response = connect_with_identity(port, "alice", Some(&identity), &identity).await;
+    assert_eq!(response["type"], "auth_response");
+    assert_eq!(response["success"], true);
+
+    // Later connects are challenged even without offering the key again
+    let response = connect_with_identity(port, "alice", None, &identity).await;
+    assert_eq!(response["success"], true);
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_identity_key_mismatch_rejected() {
+    let (port, server_handle) = start_test_server().await;
+    let identity = x25519_dalek::StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let impostor = x25519_dalek::StaticSecret::random_from_rng(rand::rngs::OsRng);
+
+    let response = connect_with_identity(port, "alice", Some(&identity), &identity).await;
+    assert_eq!(response["success"], true);
+
+    // Offering a different key for a bound user_id is rejected outright
+    let response = connect_with_identity(port, "alice", Some(&impostor), &impostor).await;
+    assert_eq!(response["type"], "auth_response");
+    assert_eq!(response["success"], false);
+    assert_eq!(response["code"], "key_mismatch");
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_identity_invalid_proof_rejected() {
+    let (port, server_handle) = start_test_server().await;
+    let identity = x25519_dalek::StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let impostor = x25519_dalek::StaticSecret::random_from_rng(rand::rngs::OsRng);
+
+    let response = connect_with_identity(port, "alice", Some(&identity), &identity).await;
+    assert_eq!(response["success"], true);
+
+    // Knowing only the user_id is not enough to answer the challenge
+    let response = connect_with_identity(port, "alice", None, &impostor).await;
+    assert_eq!(response["success"], false);
+    assert_eq!(response["code"], "invalid_proof");
+
+    server_handle.abort();
+}
+
+/// Connect fields of a client that negotiates the key directory
+fn key_directory_protocol() -> serde_json::Value {
+    json!({ "protocol_version": 2, "capabilities": ["acks", "keys"] })
+}
+
+#[tokio::test]
+async fn test_key_request_answered_from_directory() {
+    let (port, server_handle) = start_test_server().await;
+    let identity = x25519_dalek::StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let alice_key = hex::encode(x25519_dalek::PublicKey::from(&identity).as_bytes());
+
+    let response = connect_with_identity(port, "alice", Some(&identity), &identity).await;
+    assert_eq!(response["success"], true);
+
+    // Bob can fetch Alice's key without ever having heard from her
+    let (bob, _) = connect_with_protocol(port, "bob", key_directory_protocol()).await;
+    let (mut write, mut read) = bob.split();
+    let request = json!({ "type": "key_request", "user_id": "alice" });
+    write
+        .send(Message::Text(request.to_string()))
+        .await
+        .unwrap();
+    let response = next_json(&mut read).await;
+    assert_eq!(response["type"], "key_response");
+    assert_eq!(response["user_id"], "alice");
+    assert_eq!(response["public_key"], alice_key);
+
+    // Users without a key are answered without one
+    let request = json!({ "type": "key_request", "user_id": "carol" });
+    write
+        .send(Message::Text(request.to_string()))
+        .await
+        .unwrap();
+    let response = next_json(&mut read).await;
+    assert_eq!(response["user_id"], "carol");
+    assert!(response.get("public_key").is_none());
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_publish_key_binds_unbound_account() {
+    let state = std::sync::Arc::new(pulse_server::ServerState::new());
+    let (port, server_handle) = start_test_server_with_shared_state(state.clone()).await;
+    let key = [7u8; 32];
+
+    let (bob, _) = connect_with_protocol(port, "bob", key_directory_protocol()).await;
+    let (mut write, mut read) = bob.split();
+    let publish = json!({ "type": "publish_key", "public_key": hex::encode(key) });
+    write
+        .send(Message::Text(publish.to_string()))
+        .await
+        .unwrap();
+    let response = next_json(&mut read).await;
+    assert_eq!(response["type"], "key_response");
+    assert_eq!(response["public_key"], hex::encode(key));
+    assert_eq!(state.identity_key("bob"), Some(key));
+
+    // A bound key is not replaced by publishing a different one
+    let publish = json!({ "type": "publish_key", "public_key": hex::encode([8u8; 32]) });
+    write
+        .send(Message::Text(publish.to_string()))
+        .await
+        .unwrap();
+    let response = next_json(&mut read).await;
+    assert_eq!(response["type"], "error");
+    assert_eq!(state.identity_key("bob"), Some(key));
+
+    let publish = json!({ "type": "publish_key", "public_key": "not hex" });
+    write
+        .send(Message::Text(publish.to_string()))
+        .await
+        .unwrap();
+    let response = next_json(&mut read).await;
+    assert_eq!(response["type"], "error");
+
+    server_handle.abort();
+}
+
+/// Connect with an optional access token and return the auth response
+async fn connect_with_token(port: u16, user_id: &str, token: Option<&str>) -> serde_json::Value {
+    let url = format!("ws://127.0.0.1:{}", port);
+    let (ws_stream, _) = connect_async(&url).await.expect("Failed to connect");
+    let (mut write, mut read) = ws_stream.split();
+
+    let connect_msg = json!({
+        "type": "connect",
+        "user_id": user_id,
+        "token": token
+    });
+    write
+        .send(Message::Text(connect_msg.to_string()))
+        .await
+        .unwrap();
+
+    next_json(&mut read).await
+}
+
+#[tokio::test]
+async fn test_access_token_follows_reloaded_config() {
+    let mut config =
+        pulse_server::ServerConfig::parse("[auth]\naccess_token = \"first\"\n").unwrap();
+    let state =
+        std::sync::Arc::new(pulse_server::ServerState::new().with_runtime_config(config.runtime()));
+    let (port, server_handle) = start_test_server_with_shared_state(state.clone()).await;
+
+    let response = connect_with_token(port, "alice", None).await;
+    assert_eq!(response["code"], "missing_token");
+    let response = connect_with_token(port, "alice", Some("first")).await;
+    assert_eq!(response["success"], true);
+
+    // New connections use the reloaded token
+    config.auth.access_token = Some("second".to_string());
+    state.reload(config.runtime());
+
+    let response = connect_with_token(port, "bob", Some("first")).await;
+    assert_eq!(response["code"], "invalid_token");
+    let response = connect_with_token(port, "bob", Some("second")).await;
+    assert_eq!(response["success"], true);
+
+    server_handle.abort();
+}
+
+/// Connect with the given protocol fields merged into Connect and return the auth response
+async fn connect_with_protocol(
+    port: u16,
+    user_id: &str,
+    protocol: serde_json::Value,
+) -> (
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+    serde_json::Value,
+) {
+    let url = format!("ws://127.0.0.1:{}", port);
+    let (ws_stream, _) = connect_async(&url).await.expect("Failed to connect");
+    let (mut write, mut read) = ws_stream.split();
+
+    let mut connect_msg = json!({ "type": "connect", "user_id": user_id });
+    for (key, value) in protocol.as_object().unwrap() {
+        connect_msg[key] = value.clone();
+    }
+    write
+        .send(Message::Text(connect_msg.to_string()))
+        .await
+        .unwrap();
+
+    let response = next_json(&mut read).await;
+    (write.reunite(read).unwrap(), response)
+}
+
+#[tokio::test]
+async fn test_auth_response_reports_negotiated_protocol() {
+    let (port, server_handle) = start_test_server().await;
+
+    // A newer client is negotiated down, and unknown capabilities are dropped
+    let (_client, response) = connect_with_protocol(
+        port,
+        "alice",
+        json!({ "protocol_version": 99, "capabilities": ["acks", "teleport"] }),
+    )
+    .await;
+    assert_eq!(response["success"], true);
+    assert_eq!(response["protocol_version"], pulse_server::PROTOCOL_VERSION);
+    assert_eq!(response["capabilities"], json!(["acks"]));
+
+    // A client that names no version is treated as legacy
+    let (_client, response) = connect_with_protocol(port, "bob", json!({})).await;
+    assert_eq!(response["success"], true);
+    assert_eq!(
+        response["protocol_version"],
+        pulse_server::LEGACY_PROTOCOL_VERSION
+    );
+    assert!(response.get("capabilities").is_none());
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_unsupported_protocol_version_rejected() {
+    let (port, server_handle) = start_test_server().await;
+
+    let (_client, response) = connect_with_protocol(
+        port,
+        "alice",
+        json!({ "protocol_version": pulse_server::MIN_PROTOCOL_VERSION - 1 }),
+    )
+    .await;
+    assert_eq!(response["success"], false);
+    assert_eq!(response["code"], "unsupported_version");
+    assert_eq!(response["protocol_version"], pulse_server::PROTOCOL_VERSION);
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_legacy_client_gets_no_server_ack() {
+    let limits = pulse_server::RateLimitConfig {
+        chat: pulse_server::BucketConfig::new(1, 0.0),
+        ..Default::default()
+    };
+    let state = pulse_server::ServerState::new().with_rate_limits(limits);
+    let (port, server_handle) = start_test_server_with_state(state).await;
+
+    let bob = connect_client(port, "bob").await;
+    let (legacy, _) = connect_with_protocol(port, "legacy", json!({})).await;
+    let (mut write, mut read) = legacy.split();
+    let (_bob_write, mut bob_read) = bob.split();
+
+    for id in ["m1", "m2"] {
+        let msg = json!({
+            "type": "message",
+            "id": id,
+            "chat_id": "chat1",
+            "sender_id": "legacy",
+            "sender_name": "Legacy",
+            "recipient_id": "bob",
+            "content": "hello",
+            "timestamp": 1
+        });
+        write.send(Message::Text(msg.to_string())).await.unwrap();
+    }
+
+    let received = next_json(&mut bob_read).await;
+    assert_eq!(received["id"], "m1");
+
+    // No ack for m1; the rate-limited m2 is reported with a plain error frame
+    let reply = next_json(&mut read).await;
+    assert_eq!(reply["type"], "error");
+    assert!(reply["message"].as_str().unwrap().contains("chat"));
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_msgpack_client_interoperates_with_json_client() {
+    use pulse_server::{Encoding, WsMessage};
+
+    let (port, server_handle) = start_test_server().await;
+    let mut bob = connect_client(port, "bob").await;
+    let (mut alice, response) = connect_with_protocol(
+        port,
+        "alice",
+        json!({ "protocol_version": 2, "capabilities": ["acks", "msgpack"] }),
+    )
+    .await;
+    assert_eq!(response["capabilities"], json!(["acks", "msgpack"]));
+
+    // Binary clients may still send JSON text frames
+    let chats = |peer: &str| json!([{ "chat_id": "chat1", "participant_ids": [peer] }]);
+    subscribe(&mut alice, &[], chats("bob")).await;
+    subscribe(&mut bob, &[], chats("alice")).await;
+
+    let (mut alice_write, mut alice_read) = alice.split();
+    let (mut bob_write, mut bob_read) = bob.split();
+
+    let content = format!(
+        "enc:{}",
+        json!({ "ciphertext": [1, 2, 3, 250], "nonce": vec![7; 12], "sender_public_key": vec![9; 32] })
+    );
+    let msg = WsMessage::ChatMessage {
+        id: "m1".to_string(),
+        chat_id: "chat1".to_string(),
+        sender_id: "alice".to_string(),
+        sender_name: "Alice".to_string(),
+        recipient_id: "bob".to_string(),
+        content: content.clone(),
+        timestamp: 1,
+        reply_to_id: None,
+        url_preview: None,
+    };
+    let frame = Encoding::MessagePack.encode(&msg).unwrap();
+    alice_write.send(Message::Binary(frame)).await.unwrap();
+
+    // Bob speaks JSON and gets the content exactly as Alice wrote it
+    let received = next_json(&mut bob_read).await;
+    assert_eq!(received["id"], "m1");
+    assert_eq!(received["content"], content.as_str());
+
+    // Everything Alice receives is MessagePack, including her ack and Bob's JSON reply
+    let reply = json!({
+        "type": "typing",
+        "chat_id": "chat1",
+        "user_id": "bob",
+        "is_typing": true
+    });
+    bob_write
+        .send(Message::Text(reply.to_string()))
+        .await
+        .unwrap();
+
+    let mut frames = Vec::new();
+    while frames.len() < 2 {
+        let msg = timeout(Duration::from_secs(5), alice_read.next())
+            .await
+            .expect("Timeout")
+            .expect("Closed")
+            .expect("Error");
+        match msg {
+            Message::Binary(data) => frames.push(Encoding::MessagePack.decode(&data).unwrap()),
+            Message::Ping(_) | Message::Pong(_) => continue,
+            other => panic!("Expected binary frame, got {:?}", other),
+        }
+    }
+    assert!(matches!(&frames[0], WsMessage::ServerAck { message_id, .. } if message_id == "m1"));
+    assert!(matches!(&frames[1], WsMessage::Typing { user_id, .. } if user_id == "bob"));
+
+    server_handle.abort();
+}
+
+/// Connect and authenticate one device of a user
+async fn connect_device(
+    port: u16,
+    user_id: &str,
+    device_id: &str,
+) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>> {
+    let (client, response) = connect_with_protocol(
+        port,
+        user_id,
+        json!({ "protocol_version": 2, "capabilities": ["acks"], "device_id": device_id }),
+    )
+    .await;
+    assert_eq!(response["success"], true);
+    client
+}
+
+fn chat_message(id: &str, sender: &str, recipient: &str) -> Message {
+    let msg = json!({
+        "type": "message",
+        "id": id,
+        "chat_id": "chat1",
+        "sender_id": sender,
+        "sender_name": sender,
+        "recipient_id": recipient,
+        "content": "hello",
+        "timestamp": 1
+    });
+    Message::Text(msg.to_string())
+}
+
+#[tokio::test]
+async fn test_chat_message_synced_to_senders_other_devices() {
+    let (port, server_handle) = start_test_server().await;
+    let desk = connect_device(port, "alice", "desk").await;
+    let laptop = connect_device(port, "alice", "laptop").await;
+    let bob = connect_client(port, "bob").await;
+
+    let (mut desk_write, mut desk_read) = desk.split();
+    let (_, mut laptop_read) = laptop.split();
+    let (_, mut bob_read) = bob.split();
+
+    desk_write
+        .send(chat_message("m1", "alice", "bob"))
+        .await
+        .unwrap();
+
+    let received = next_json(&mut bob_read).await;
+    assert_eq!(received["id"], "m1");
+
+    let synced = next_json(&mut laptop_read).await;
+    assert_eq!(synced["type"], "message");
+    assert_eq!(synced["id"], "m1");
+    assert_eq!(synced["recipient_id"], "bob");
+
+    // The sending device only gets its ack, not an echo
+    let ack = next_json(&mut desk_read).await;
+    assert_eq!(ack["type"], "server_ack");
+    assert_eq!(ack["status"], "delivered");
+    assert!(timeout(Duration::from_millis(200), desk_read.next())
+        .await
+        .is_err());
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_each_offline_device_gets_its_own_queue() {
+    let (port, server_handle) = start_test_server().await;
+    drop(connect_device(port, "bob", "desk").await);
+    drop(connect_device(port, "bob", "laptop").await);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let alice = connect_client(port, "alice").await;
+    let (mut alice_write, mut alice_read) = alice.split();
+    alice_write
+        .send(chat_message("m1", "alice", "bob"))
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut alice_read).await["status"], "queued");
+
+    // The first device back does not take the message away from the other
+    for device in ["laptop", "desk"] {
+        let client = connect_device(port, "bob", device).await;
+        let (_, mut read) = client.split();
+        let queued = next_json(&mut read).await;
+        assert_eq!(queued["id"], "m1", "{}", device);
+    }
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_user_stays_online_until_last_device_disconnects() {
+    let (port, server_handle) = start_test_server().await;
+    let mut watcher = connect_client(port, "watcher").await;
+    subscribe(&mut watcher, &["bob"], json!([])).await;
+    let (_, mut watcher_read) = watcher.split();
+
+    let desk = connect_device(port, "bob", "desk").await;
+    let laptop = connect_device(port, "bob", "laptop").await;
+    assert_eq!(next_json(&mut watcher_read).await["is_online"], true);
+    assert_eq!(next_json(&mut watcher_read).await["is_online"], true);
+
+    drop(desk);
+    assert!(timeout(Duration::from_millis(300), watcher_read.next())
+        .await
+        .is_err());
+
+    drop(laptop);
+    let offline = next_json(&mut watcher_read).await;
+    assert_eq!(offline["user_id"], "bob");
+    assert_eq!(offline["is_online"], false);
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_invalid_device_id_rejected() {
+    let (port, server_handle) = start_test_server().await;
+
+    let (_client, response) = connect_with_protocol(
+        port,
+        "alice",
+        json!({ "protocol_version": 2, "device_id": "not a device id" }),
+    )
+    .await;
+    assert_eq!(response["success"], false);
+    assert_eq!(response["code"], "invalid_device");
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_blocked_pair_exchanges_nothing() {
+    let (port, server_handle) = start_test_server().await;
+    let (mut alice_write, mut alice_read) = connect_client(port, "alice").await.split();
+    let (mut bob_write, mut bob_read) = connect_client(port, "bob").await.split();
+
+    let block = json!({ "type": "block_user", "user_id": "bob" });
+    alice_write
+        .send(Message::Text(block.to_string()))
+        .await
+        .unwrap();
+    let confirmed = next_json(&mut alice_read).await;
+    assert_eq!(confirmed["type"], "block_list");
+    assert_eq!(confirmed["user_ids"], json!(["bob"]));
+
+    // Bob is not told he is blocked: his message looks queued for an offline recipient
+    bob_write
+        .send(chat_message("m1", "bob", "alice"))
+        .await
+        .unwrap();
+    let ack = next_json(&mut bob_read).await;
+    assert_eq!(ack["type"], "server_ack");
+    assert_eq!(ack["status"], "queued");
+
+    let invite = json!({
+        "type": "call_invite",
+        "call_id": "call1",
+        "from_user_id": "bob",
+        "to_user_id": "alice",
+        "kind": "audio"
+    });
+    bob_write
+        .send(Message::Text(invite.to_string()))
+        .await
+        .unwrap();
+    assert!(timeout(Duration::from_millis(200), alice_read.next())
+        .await
+        .is_err());
+
+    // Alice learns her message was refused
+    alice_write
+        .send(chat_message("m2", "alice", "bob"))
+        .await
+        .unwrap();
+    let ack = next_json(&mut alice_read).await;
+    assert_eq!(ack["status"], "error");
+    assert!(timeout(Duration::from_millis(200), bob_read.next())
+        .await
+        .is_err());
+
+    let unblock = json!({ "type": "unblock_user", "user_id": "bob" });
+    alice_write
+        .send(Message::Text(unblock.to_string()))
+        .await
+        .unwrap();
+    let confirmed = next_json(&mut alice_read).await;
+    assert_eq!(confirmed["user_ids"], json!([]));
+
+    bob_write
+        .send(chat_message("m3", "bob", "alice"))
+        .await
+        .unwrap();
+    let received = next_json(&mut alice_read).await;
+    assert_eq!(received["id"], "m3");
+
+    server_handle.abort();
+}
+
+/// A call signaling frame without extra fields (invites are audio calls)
+fn call_frame(kind: &str, call_id: &str, from: &str, to: &str) -> Message {
+    let mut frame = json!({
+        "type": kind,
+        "call_id": call_id,
+        "from_user_id": from,
+        "to_user_id": to
+    });
+    match kind {
+        "call_invite" => frame["kind"] = json!("audio"),
+        "call_reject" => frame["reason"] = json!("rejected"),
+        _ => {}
+    }
+    Message::Text(frame.to_string())
+}
+
+#[tokio::test]
+async fn test_second_caller_gets_busy() {
+    let (port, server_handle) = start_test_server().await;
+    let (mut alice_write, _alice_read) = connect_client(port, "alice").await.split();
+    let (_bob_write, mut bob_read) = connect_client(port, "bob").await.split();
+    let (mut carol_write, mut carol_read) = connect_client(port, "carol").await.split();
+
+    alice_write
+        .send(call_frame("call_invite", "call1", "alice", "bob"))
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut bob_read).await["call_id"], "call1");
+
+    carol_write
+        .send(call_frame("call_invite", "call2", "carol", "bob"))
+        .await
+        .unwrap();
+    let reject = next_json(&mut carol_read).await;
+    assert_eq!(reject["type"], "call_reject");
+    assert_eq!(reject["call_id"], "call2");
+    assert_eq!(reject["from_user_id"], "bob");
+    assert_eq!(reject["reason"], "busy");
+    // Bob is not rung a second time, only told he missed Carol's call
+    let missed = next_json(&mut bob_read).await;
+    assert_eq!(missed["type"], "missed_call");
+    assert_eq!(missed["call_id"], "call2");
+    assert_eq!(missed["from_user_id"], "carol");
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_invite_to_offline_user_rejected() {
+    let (port, server_handle) = start_test_server().await;
+    let (mut alice_write, mut alice_read) = connect_client(port, "alice").await.split();
+
+    alice_write
+        .send(call_frame("call_invite", "call1", "alice", "bob"))
+        .await
+        .unwrap();
+    let reject = next_json(&mut alice_read).await;
+    assert_eq!(reject["type"], "call_reject");
+    assert_eq!(reject["reason"], "offline");
+
+    // Bob learns about the call when he comes online
+    let (_bob_write, mut bob_read) = connect_client(port, "bob").await.split();
+    let missed = next_json(&mut bob_read).await;
+    assert_eq!(missed["type"], "missed_call");
+    assert_eq!(missed["call_id"], "call1");
+    assert_eq!(missed["from_user_id"], "alice");
+    assert_eq!(missed["kind"], "audio");
+
+    // The failed invite does not leave Alice stuck in a call
+    alice_write
+        .send(call_frame("call_invite", "call2", "alice", "bob"))
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut bob_read).await["call_id"], "call2");
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_call_hung_up_when_participant_disconnects() {
+    let state = std::sync::Arc::new(pulse_server::ServerState::new());
+    let (port, server_handle) = start_test_server_with_shared_state(state.clone()).await;
+    let (mut alice_write, mut alice_read) = connect_client(port, "alice").await.split();
+    let (mut bob_write, mut bob_read) = connect_client(port, "bob").await.split();
+
+    alice_write
+        .send(call_frame("call_invite", "call1", "alice", "bob"))
+        .await
+        .unwrap();
+    next_json(&mut bob_read).await;
+    bob_write
+        .send(call_frame("call_accept", "call1", "bob", "alice"))
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut alice_read).await["type"], "call_accept");
+
+    // Bob's other connection coming and going leaves the call up
+    drop(connect_client(port, "bob").await);
+    assert!(timeout(Duration::from_millis(200), alice_read.next())
+        .await
+        .is_err());
+    assert!(state.in_call("alice"));
+
+    bob_write.send(Message::Close(None)).await.unwrap();
+    drop(bob_read);
+    let hangup = next_json(&mut alice_read).await;
+    assert_eq!(hangup["type"], "call_hangup");
+    assert_eq!(hangup["call_id"], "call1");
+    assert_eq!(hangup["from_user_id"], "bob");
+    assert!(!state.in_call("alice"));
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_unanswered_invite_expires() {
+    let calls = pulse_server::CallConfig {
+        ring_timeout: Duration::from_millis(50),
+    };
+    let state = std::sync::Arc::new(pulse_server::ServerState::new().with_calls(calls));
+    let (port, server_handle) = start_test_server_with_shared_state(state.clone()).await;
+    let (mut alice_write, mut alice_read) = connect_client(port, "alice").await.split();
+    let (_bob_write, mut bob_read) = connect_client(port, "bob").await.split();
+
+    alice_write
+        .send(call_frame("call_invite", "call1", "alice", "bob"))
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut bob_read).await["type"], "call_invite");
+    assert_eq!(state.expire_calls(), 0);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(state.expire_calls(), 1);
+    let reject = next_json(&mut alice_read).await;
+    assert_eq!(reject["type"], "call_reject");
+    assert_eq!(reject["reason"], "timeout");
+    assert_eq!(next_json(&mut bob_read).await["type"], "call_hangup");
+    assert_eq!(next_json(&mut bob_read).await["type"], "missed_call");
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_invalid_call_transition_rejected() {
+    let (port, server_handle) = start_test_server().await;
+    let (mut alice_write, mut alice_read) = connect_client(port, "alice").await.split();
+    let (_bob_write, mut bob_read) = connect_client(port, "bob").await.split();
+
+    alice_write
+        .send(call_frame("call_invite", "call1", "alice", "bob"))
+        .await
+        .unwrap();
+    next_json(&mut bob_read).await;
+
+    // Only Bob can answer the call Alice placed
+    alice_write
+        .send(call_frame("call_accept", "call1", "alice", "bob"))
+        .await
+        .unwrap();
+    let error = next_json(&mut alice_read).await;
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["code"], "invalid_call");
+    assert_eq!(error["ref_id"], "call1");
+    assert!(timeout(Duration::from_millis(200), bob_read.next())
+        .await
+        .is_err());
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_rejected_frames_are_answered_with_error_codes() {
+    let state = pulse_server::ServerState::new().with_auth(pulse_server::AuthConfig {
+        timeout: Duration::from_millis(300),
+        ..Default::default()
+    });
+    let (port, server_handle) = start_test_server_with_state(state).await;
+    let url = format!("ws://127.0.0.1:{}", port);
+
+    // Frames before Connect are refused, and a client that never authenticates is told why
+    let (ws_stream, _) = connect_async(&url).await.unwrap();
+    let (mut write, mut read) = ws_stream.split();
+    write
+        .send(chat_message("m1", "mallory", "bob"))
+        .await
+        .unwrap();
+    let error = next_json(&mut read).await;
+    assert_eq!(error["code"], "not_authenticated");
+    assert_eq!(error["ref_id"], "m1");
+    assert_eq!(next_json(&mut read).await["code"], "auth_timeout");
+
+    // Once connected, malformed and unknown frames get a reply instead of silence
+    let (mut write, mut read) = connect_client(port, "alice").await.split();
+    write
+        .send(Message::Text("{not json".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut read).await["code"], "invalid_frame");
+    let unknown = json!({"type": "teleport", "id": "t1"});
+    write
+        .send(Message::Text(unknown.to_string()))
+        .await
+        .unwrap();
+    let error = next_json(&mut read).await;
+    assert_eq!(error["code"], "unknown_type");
+    assert_eq!(error["ref_id"], "t1");
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_oversized_frames_and_fields_rejected() {
+    let limits = pulse_server::LimitsConfig {
+        max_frame_bytes: 4096,
+        max_message_bytes: 4096,
+        max_sdp_bytes: 100,
+        ..Default::default()
+    };
+    let state = std::sync::Arc::new(pulse_server::ServerState::new().with_limits(limits));
+    let (port, server_handle) = start_test_server_with_shared_state(state.clone()).await;
+    let (mut alice_write, mut alice_read) = connect_client(port, "alice").await.split();
+    let (_bob_write, mut bob_read) = connect_client(port, "bob").await.split();
+
+    // A field over its limit is rejected and never relayed
+    let offer = json!({
+        "type": "rtc_offer",
+        "call_id": "call1",
+        "from_user_id": "alice",
+        "to_user_id": "bob",
+        "sdp": "v".repeat(200)
+    });
+    alice_write
+        .send(Message::Text(offer.to_string()))
+        .await
+        .unwrap();
+    let error = next_json(&mut alice_read).await;
+    assert_eq!(error["code"], "payload_too_large");
+    assert_eq!(error["ref_id"], "call1");
+    assert!(error["message"].as_str().unwrap().contains("sdp"));
+    assert!(timeout(Duration::from_millis(200), bob_read.next())
+        .await
+        .is_err());
+
+    // A frame over the frame limit is answered, then the connection is closed
+    alice_write
+        .send(Message::Text("x".repeat(5000)))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_json(&mut alice_read).await["code"],
+        "payload_too_large"
+    );
+    let closed = timeout(Duration::from_secs(5), async {
+        loop {
+            match alice_read.next().await {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            }
+        }
+    })
+    .await;
+    assert!(
+        closed.is_ok(),
+        "Oversized frame should close the connection"
+    );
+
+    assert_eq!(state.metrics().oversized_frames("sdp"), 1);
+    assert_eq!(state.metrics().oversized_frames("frame"), 1);
+
+    server_handle.abort();
+}
+
+/// Rate limits that allow a burst of two typing frames and never refill
+fn strict_typing_limits(max_violations: u32) -> pulse_server::RateLimitConfig {
+    pulse_server::RateLimitConfig {
+        typing: pulse_server::BucketConfig::new(2, 0.0),
+        max_violations,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_typing_flood_is_rate_limited() {
+    let state = pulse_server::ServerState::new().with_rate_limits(strict_typing_limits(100));
+    let (port, server_handle) = start_test_server_with_state(state).await;
+
+    let flooder = connect_client(port, "flooder").await;
+    let (mut flooder_write, mut flooder_read) = flooder.split();
+
+    let typing = json!({
+        "type": "typing",
+        "user_id": "flooder",
+        "chat_id": "chat1",
+        "is_typing": true
+    });
+    for _ in 0..3 {
+        flooder_write
+            .send(Message::Text(typing.to_string()))
+            .await
+            .unwrap();
+    }
+
+    // The third frame exceeds the burst and is answered with an error
+    let error = next_json(&mut flooder_read).await;
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["code"], "rate_limited");
+    assert!(error["message"].as_str().unwrap().contains("typing"));
+
+    // Other classes keep their own budget
+    let msg = json!({
+        "type": "message",
+        "id": "m1",
+        "chat_id": "chat1",
+        "sender_id": "flooder",
+        "sender_name": "Flooder",
+        "recipient_id": "flooder",
+        "content": "still allowed",
+        "timestamp": 1
+    });
+    flooder_write
+        .send(Message::Text(msg.to_string()))
+        .await
+        .unwrap();
+    let ack = next_json(&mut flooder_read).await;
+    assert_eq!(ack["type"], "server_ack");
+    assert_eq!(ack["status"], "delivered");
+    let echoed = next_json(&mut flooder_read).await;
+    assert_eq!(echoed["type"], "message");
+    assert_eq!(echoed["content"], "still allowed");
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_repeat_offender_is_disconnected() {
+    let state = pulse_server::ServerState::new().with_rate_limits(strict_typing_limits(1));
+    let (port, server_handle) = start_test_server_with_state(state).await;
+
+    let flooder = connect_client(port, "flooder").await;
+    let (mut flooder_write, mut flooder_read) = flooder.split();
+
+    let typing = json!({
+        "type": "typing",
+        "user_id": "flooder",
+        "chat_id": "chat1",
+        "is_typing": true
+    });
+    for _ in 0..4 {
+        let _ = flooder_write.send(Message::Text(typing.to_string())).await;
+    }
+
+    assert_eq!(next_json(&mut flooder_read).await["type"], "error");
+    assert_eq!(next_json(&mut flooder_read).await["type"], "error");
+
+    // The server closes the connection after the second violation
+    let closed = timeout(Duration::from_secs(5), async {
+        loop {
+            match flooder_read.next().await {
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return true,
+                Some(Ok(_)) => continue,
+            }
+        }
+    })
+    .await
+    .expect("Server did not disconnect the flooder");
+    assert!(closed);
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_rate_limits_survive_reconnect() {
+    let state = std::sync::Arc::new(
+        pulse_server::ServerState::new().with_rate_limits(strict_typing_limits(1)),
+    );
+    let (port, server_handle) = start_test_server_with_shared_state(state.clone()).await;
+
+    let typing = json!({
+        "type": "typing",
+        "user_id": "flooder",
+        "chat_id": "chat1",
+        "is_typing": true
+    });
+
+    let flooder = connect_client(port, "flooder").await;
+    let (mut flooder_write, mut flooder_read) = flooder.split();
+    for _ in 0..4 {
+        let _ = flooder_write.send(Message::Text(typing.to_string())).await;
+    }
+    let closed = timeout(Duration::from_secs(5), async {
+        while let Some(Ok(_)) = flooder_read.next().await {}
+    })
+    .await;
+    assert!(closed.is_ok(), "Server did not disconnect the flooder");
+
+    timeout(Duration::from_secs(5), async {
+        while state.is_online("flooder") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Flooder still online");
+
+    // Going offline does not reset the limiter, and the sweep keeps it
+    assert_eq!(state.expire_rate_limiters(), 0);
+
+    let flooder = connect_client(port, "flooder").await;
+    let (mut flooder_write, mut flooder_read) = flooder.split();
+    flooder_write
+        .send(Message::Text(typing.to_string()))
+        .await
+        .unwrap();
+    let error = next_json(&mut flooder_read).await;
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["code"], "rate_limited");
+
+    // Its violations are remembered too, so it is disconnected straight away
+    let closed = timeout(Duration::from_secs(5), async {
+        while let Some(Ok(_)) = flooder_read.next().await {}
+    })
+    .await;
+    assert!(closed.is_ok(), "Reconnected flooder was not disconnected");
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_subscribe_returns_online_contacts() {
+    let (port, server_handle) = start_test_server().await;
+
+    let _client1 = connect_client(port, "user1").await;
+    let mut client2 = connect_client(port, "user2").await;
+
+    subscribe(&mut client2, &["user1", "offline_user"], json!([])).await;
+    let (_, mut read2) = client2.split();
+
+    // Only online contacts are reported
+    let presence = next_json(&mut read2).await;
+    assert_eq!(presence["type"], "presence");
+    assert_eq!(presence["user_id"], "user1");
+    assert_eq!(presence["is_online"], true);
+    assert!(timeout(Duration::from_millis(300), read2.next())
+        .await
+        .is_err());
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_presence_and_typing_not_leaked_to_strangers() {
+    let (port, server_handle) = start_test_server().await;
+
+    let stranger = connect_client(port, "stranger").await;
+    let (_, mut stranger_read) = stranger.split();
+
+    let mut client1 = connect_client(port, "user1").await;
+    subscribe(
+        &mut client1,
+        &[],
+        json!([{ "chat_id": "chat1", "participant_ids": ["user2"] }]),
+    )
+    .await;
+    let (mut write1, _) = client1.split();
+
+    let typing = json!({
+        "type": "typing",
+        "chat_id": "chat1",
+        "user_id": "user1",
+        "is_typing": true
+    });
+    write1
+        .send(Message::Text(typing.to_string()))
+        .await
+        .unwrap();
+
+    let profile_update = json!({
+        "type": "profile_update",
+        "user_id": "user1",
+        "name": "Alice",
+        "phone": null,
+        "avatar_url": null,
+        "about": null,
+        "avatar_data": null
+    });
+    write1
+        .send(Message::Text(profile_update.to_string()))
+        .await
+        .unwrap();
+
+    // The stranger sees neither user1 coming online nor its typing/profile frames
+    let result = timeout(Duration::from_millis(500), stranger_read.next()).await;
+    assert!(result.is_err(), "Stranger received {:?}", result);
+
+    server_handle.abort();
+}
+
+/// Heartbeat settings short enough to exercise reaping in a test
+fn fast_heartbeat() -> pulse_server::HeartbeatConfig {
+    pulse_server::HeartbeatConfig {
+        interval: Duration::from_millis(100),
+        timeout: Duration::from_millis(400),
+    }
+}
+
+#[tokio::test]
+async fn test_silent_connection_is_reaped() {
+    let state = pulse_server::ServerState::new().with_heartbeat(fast_heartbeat());
+    let (port, server_handle) = start_test_server_with_state(state).await;
+
+    let mut observer = connect_client(port, "observer").await;
+    subscribe(&mut observer, &["silent"], json!([])).await;
+    let (_, mut observer_read) = observer.split();
+
+    // Never polled again, so the client never answers the server's pings
+    let _silent = connect_client(port, "silent").await;
+
+    let online = next_json(&mut observer_read).await;
+    assert_eq!(online["type"], "presence");
+    assert_eq!(online["is_online"], true);
+
+    let offline = next_json(&mut observer_read).await;
+    assert_eq!(offline["type"], "presence");
+    assert_eq!(offline["user_id"], "silent");
+    assert_eq!(offline["is_online"], false);
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_responsive_connection_survives_heartbeat() {
+    let state = pulse_server::ServerState::new().with_heartbeat(fast_heartbeat());
+    let (port, server_handle) = start_test_server_with_state(state).await;
+
+    let mut observer = connect_client(port, "observer").await;
+    subscribe(&mut observer, &["alive"], json!([])).await;
+    let (_, mut observer_read) = observer.split();
+
+    // Keep reading so tungstenite answers every ping with a pong
+    let alive = connect_client(port, "alive").await;
+    let (_alive_write, mut alive_read) = alive.split();
+    let pings = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let reader_pings = pings.clone();
+    let reader = tokio::spawn(async move {
+        while let Some(Ok(msg)) = alive_read.next().await {
+            if let Message::Ping(_) = msg {
+                reader_pings.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+    });
+
+    let online = next_json(&mut observer_read).await;
+    assert_eq!(online["is_online"], true);
+
+    // Well past the timeout, the observer must not see "alive" go offline
+    let result = timeout(Duration::from_millis(1000), next_json(&mut observer_read)).await;
+    assert!(
+        result.is_err(),
+        "Responsive client was reaped: {:?}",
+        result
+    );
+
+    assert!(
+        pings.load(std::sync::atomic::Ordering::SeqCst) > 0,
+        "Server never pinged the client"
+    );
+
+    reader.abort();
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_slow_consumer_is_disconnected() {
+    let limits = pulse_server::RateLimitConfig {
+        chat: pulse_server::BucketConfig::new(10_000, 10_000.0),
+        typing: pulse_server::BucketConfig::new(10_000, 10_000.0),
+        ..Default::default()
+    };
+    let outbound = pulse_server::OutboundConfig {
+        queue_depth: 4,
+        max_overflows: 8,
+    };
+    let state = std::sync::Arc::new(
+        pulse_server::ServerState::new()
+            .with_rate_limits(limits)
+            .with_outbound(outbound),
+    );
+    let (port, server_handle) = start_test_server_with_shared_state(state.clone()).await;
+
+    let mut observer = connect_client(port, "observer").await;
+    subscribe(&mut observer, &["slow"], json!([])).await;
+    let (_, mut observer_read) = observer.split();
+
+    // Never polled again, so the socket buffers fill and the server queue backs up
+    let _slow = connect_client(port, "slow").await;
+    let online = next_json(&mut observer_read).await;
+    assert_eq!(online["is_online"], true);
+
+    let mut sender = connect_client(port, "sender").await;
+    subscribe(
+        &mut sender,
+        &[],
+        json!([{"chat_id": "c1", "participant_ids": ["slow"]}]),
+    )
+    .await;
+
+    let content = "x".repeat(64 * 1024);
+    for i in 0..200 {
+        let msg = json!({
+            "type": "message",
+            "id": format!("msg-{}", i),
+            "chat_id": "c1",
+            "sender_id": "sender",
+            "sender_name": "Sender",
+            "recipient_id": "slow",
+            "content": content,
+            "timestamp": i
+        });
+        sender.send(Message::Text(msg.to_string())).await.unwrap();
+        let typing =
+            json!({"type": "typing", "user_id": "sender", "chat_id": "c1", "is_typing": true});
+        sender
+            .send(Message::Text(typing.to_string()))
+            .await
+            .unwrap();
+    }
+
+    // The server gives up on the slow client rather than buffering without bound
+    let offline = timeout(Duration::from_secs(10), next_json(&mut observer_read))
+        .await
+        .expect("Slow client was never disconnected");
+    assert_eq!(offline["user_id"], "slow");
+    assert_eq!(offline["is_online"], false);
+
+    let stats = state.delivery_stats("slow");
+    assert_eq!(stats.slow_disconnects, 1);
+    assert!(
+        stats.spilled > 0,
+        "No chat messages were spilled: {:?}",
+        stats
+    );
+    assert!(
+        stats.dropped > 0,
+        "No typing frames were dropped: {:?}",
+        stats
+    );
+
+    // Chat messages that could not be written wait in the offline queue
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(state.pending_count("slow") > 0);
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_evicted_message_reported_to_sender() {
+    let queue = pulse_server::QueueConfig {
+        max_messages: 1,
+        ..Default::default()
+    };
+    let state = pulse_server::ServerState::new().with_queue(queue);
+    let (port, server_handle) = start_test_server_with_state(state).await;
+
+    let sender = connect_client(port, "sender").await;
+    let (mut write, mut read) = sender.split();
+
+    for id in ["m1", "m2"] {
+        let msg = json!({
+            "type": "message",
+            "id": id,
+            "chat_id": "chat1",
+            "sender_id": "sender",
+            "sender_name": "Sender",
+            "recipient_id": "offline",
+            "content": "hello",
+            "timestamp": 1
+        });
+        write.send(Message::Text(msg.to_string())).await.unwrap();
+    }
+
+    let mut frames = Vec::new();
+    for _ in 0..3 {
+        frames.push(next_json(&mut read).await);
+    }
+
+    // Both are acknowledged as queued; queueing m2 evicts m1, which is reported as failed
+    let failed = frames
+        .iter()
+        .find(|f| f["type"] == "delivery_failed")
+        .expect("No delivery_failed frame");
+    assert_eq!(failed["message_id"], "m1");
+    assert_eq!(failed["recipient_id"], "offline");
+    assert_eq!(failed["reason"], "queue_full");
+    assert_eq!(
+        frames
+            .iter()
+            .filter(|f| f["type"] == "server_ack" && f["status"] == "queued")
+            .count(),
+        2
+    );
+
+    server_handle.abort();
+}
+
+/// Send one plain-HTTP GET and return the raw response
+async fn http_get(port: u16, path: &str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+        .await
+        .expect("Timeout waiting for HTTP response")
+        .unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_metrics_and_health_endpoints() {
+    let state = std::sync::Arc::new(pulse_server::ServerState::new());
+    let (port, server_handle) = start_test_server_with_shared_state(state.clone()).await;
+
+    let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let metrics_port = metrics_listener.local_addr().unwrap().port();
+    let metrics_handle = tokio::spawn(pulse_server::serve_http(metrics_listener, state.clone()));
+
+    let alice = connect_client(port, "alice").await;
+    let (mut write, _read) = alice.split();
+    let msg = json!({
+        "type": "message",
+        "id": "m1",
+        "chat_id": "chat1",
+        "sender_id": "alice",
+        "sender_name": "Alice",
+        "recipient_id": "bob",
+        "content": "hello",
+        "timestamp": 1
+    });
+    write.send(Message::Text(msg.to_string())).await.unwrap();
+    for _ in 0..50 {
+        if state.metrics().frames_received("message") > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let metrics = http_get(metrics_port, "/metrics").await;
+    assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"), "{}", metrics);
+    assert!(metrics.contains("pulse_users_online 1\n"), "{}", metrics);
+    assert!(
+        metrics.contains("pulse_auth_total{result=\"success\"} 1\n"),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains("pulse_frames_received_total{type=\"message\"} 1\n"),
+        "{}",
+        metrics
+    );
+    // Bob is offline, so the message waits in his queue
+    assert!(metrics.contains("pulse_queued_messages 1\n"), "{}", metrics);
+
+    assert!(http_get(metrics_port, "/healthz")
+        .await
+        .starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(http_get(metrics_port, "/readyz")
+        .await
+        .ends_with("\r\n\r\nready\n"));
+    assert!(http_get(metrics_port, "/nope")
+        .await
+        .starts_with("HTTP/1.1 404 Not Found\r\n"));
+    // The admin API is never on the monitoring port
+    assert!(http_get(metrics_port, "/admin/users")
+        .await
+        .starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    metrics_handle.abort();
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_admin_api_kicks_bans_and_announces() {
+    use pulse_server::AdminCommand;
+
+    let state = std::sync::Arc::new(pulse_server::ServerState::new());
+    let (port, server_handle) = start_test_server_with_shared_state(state.clone()).await;
+
+    let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_addr = admin_listener.local_addr().unwrap();
+    let admin_handle = tokio::spawn(pulse_server::serve_admin(
+        admin_listener,
+        state.clone(),
+        "s3cret".to_string(),
+    ));
+
+    let alice = connect_client(port, "alice").await;
+    let (_write, mut read) = alice.split();
+
+    let users = AdminCommand::Users
+        .send(admin_addr, "s3cret")
+        .await
+        .unwrap();
+    let users: serde_json::Value = serde_json::from_str(&users).unwrap();
+    assert_eq!(users["users"][0]["user_id"], "alice");
+    assert_eq!(users["users"][0]["connections"], 1);
+
+    let err = AdminCommand::Users
+        .send(admin_addr, "wrong")
+        .await
+        .unwrap_err();
+    assert!(err.contains("401"), "{}", err);
+
+    AdminCommand::Announce("Restarting soon".to_string())
+        .send(admin_addr, "s3cret")
+        .await
+        .unwrap();
+    let announcement = next_json(&mut read).await;
+    assert_eq!(announcement["type"], "announcement");
+    assert_eq!(announcement["message"], "Restarting soon");
+
+    // Banning closes the open connection and refuses new ones
+    AdminCommand::Ban("alice".to_string())
+        .send(admin_addr, "s3cret")
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut read).await["type"], "error");
+    let response = connect_with_token(port, "alice", None).await;
+    assert_eq!(response["success"], false);
+    assert_eq!(response["code"], "banned");
+
+    AdminCommand::Unban("alice".to_string())
+        .send(admin_addr, "s3cret")
+        .await
+        .unwrap();
+    connect_client(port, "alice").await;
+
+    admin_handle.abort();
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_shutdown_notifies_and_closes_clients() {
+    let state = std::sync::Arc::new(pulse_server::ServerState::new().with_shutdown(
+        pulse_server::ShutdownConfig {
+            grace_period: Duration::from_secs(5),
+            reconnect_delay: Duration::from_secs(7),
+        },
+    ));
+    let (port, server_handle) = start_test_server_with_shared_state(state.clone()).await;
+
+    let alice = connect_client(port, "alice").await;
+    let (_write, mut read) = alice.split();
+
+    assert_eq!(pulse_server::shut_down(&state).await, 0);
+    assert!(!state.is_online("alice"));
+
+    let notice = next_json(&mut read).await;
+    assert_eq!(notice["type"], "server_shutdown");
+    assert_eq!(notice["reconnect_after_secs"], 7);
+    let closed = timeout(Duration::from_secs(5), read.next())
+        .await
+        .expect("Timeout waiting for close");
+    assert!(
+        matches!(closed, Some(Ok(Message::Close(_))) | None),
+        "{:?}",
+        closed
+    );
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_shutdown_closes_connections_still_authenticating() {
+    let state = std::sync::Arc::new(pulse_server::ServerState::new());
+    let (port, server_handle) = start_test_server_with_shared_state(state.clone()).await;
+    let url = format!("ws://127.0.0.1:{}", port);
+
+    // One connection has not sent Connect yet; the other waits on its identity challenge
+    let (silent, _) = connect_async(&url).await.expect("Failed to connect");
+    let (_silent_write, mut silent_read) = silent.split();
+    let (challenged, _) = connect_async(&url).await.expect("Failed to connect");
+    let (mut write, mut read) = challenged.split();
+    let connect_msg = json!({
+        "type": "connect",
+        "user_id": "alice",
+        "public_key": hex::encode([7u8; 32]),
+        "protocol_version": 2
+    });
+    write
+        .send(Message::Text(connect_msg.to_string()))
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut read).await["type"], "auth_challenge");
+
+    let started = std::time::Instant::now();
+    pulse_server::shut_down(&state).await;
+
+    // The client that named its version is told when to come back; both are closed
+    assert_eq!(next_json(&mut read).await["type"], "server_shutdown");
+    for read in [&mut read, &mut silent_read] {
+        let closed = timeout(Duration::from_secs(2), read.next())
+            .await
+            .expect("Timeout waiting for close");
+        assert!(
+            matches!(closed, Some(Ok(Message::Close(_))) | None),
+            "{:?}",
+            closed
+        );
+    }
+    assert!(started.elapsed() < state.auth().timeout);
+
+    server_handle.abort();
+}
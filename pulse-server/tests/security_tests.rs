@@ -1,11 +1,14 @@
-use pulse_server::{ServerState, WsMessage, handle_message};
+use pulse_server::{
+    handle_binary, handle_message, BucketConfig, Encoding, ErrorCode, HandleOutcome, MessageClass,
+    Negotiated, RateLimitConfig, ServerState, Session, WsMessage,
+};
 use tokio::sync::mpsc;
 use std::sync::Arc;
 
 #[tokio::test]
 async fn test_sender_spoofing_protection() {
     let state = Arc::new(ServerState::new());
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (tx, mut rx) = mpsc::channel(16);
     state.add_client("victim".to_string(), tx);
 
     // Attacker "attacker" tries to send a message as "admin"
@@ -21,7 +24,8 @@ async fn test_sender_spoofing_protection() {
     }"#;
 
     // "attacker" is the authenticated connection
-    handle_message(spoofed_json, "attacker", &state);
+    let attacker = Session::new("attacker", Negotiated::current());
+    handle_message(spoofed_json, &attacker, &state);
 
     // Check what "victim" received
     if let Some(msg_str) = rx.recv().await {
@@ -40,7 +44,7 @@ async fn test_sender_spoofing_protection() {
 #[tokio::test]
 async fn test_receipt_routing_protection() {
      let state = Arc::new(ServerState::new());
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (tx, mut rx) = mpsc::channel(16);
     // The original sender of the message who expects a receipt
     state.add_client("user_origin".to_string(), tx);
 
@@ -56,7 +60,8 @@ async fn test_receipt_routing_protection() {
     }"#;
     
     // "reader" is the authenticated connection
-    handle_message(spoofed_receipt, "reader", &state);
+    let reader = Session::new("reader", Negotiated::current());
+    handle_message(spoofed_receipt, &reader, &state);
     
     // Check what "user_origin" received
     if let Some(msg_str) = rx.recv().await {
@@ -71,3 +76,141 @@ async fn test_receipt_routing_protection() {
          panic!("Origin received nothing");
     }
 }
+
+#[tokio::test]
+async fn test_typing_flood_does_not_reach_other_users() {
+    let limits = RateLimitConfig {
+        typing: BucketConfig::new(5, 0.0),
+        max_violations: 100,
+        ..Default::default()
+    };
+    let state = Arc::new(ServerState::new().with_rate_limits(limits));
+    let (tx, mut rx) = mpsc::channel(16);
+    state.add_client("bystander".to_string(), tx);
+    let subscribe = r#"{"type": "subscribe_presence", "user_ids": [], "chats": [{"chat_id": "c1", "participant_ids": ["bystander"]}]}"#;
+    let spammer = Session::new("spammer", Negotiated::current());
+    handle_message(subscribe, &spammer, &state);
+
+    let typing = r#"{"type": "typing", "user_id": "spammer", "chat_id": "c1", "is_typing": true}"#;
+    let mut errors = 0;
+    for _ in 0..50 {
+        if let HandleOutcome::Reply(frame) = handle_message(typing, &spammer, &state) {
+            assert_eq!(error_code(&frame), (Some(ErrorCode::RateLimited), None));
+            errors += 1;
+        }
+    }
+
+    // Only the burst is delivered; everything after it is dropped
+    let mut delivered = 0;
+    while rx.try_recv().is_ok() {
+        delivered += 1;
+    }
+    assert_eq!(delivered, 5);
+    assert_eq!(errors, 45);
+    assert_eq!(
+        state.rate_limited_count_for("spammer", MessageClass::Typing),
+        45
+    );
+    assert_eq!(state.rate_limited_count("spammer"), 45);
+}
+
+#[tokio::test]
+async fn test_chat_flood_cannot_fill_offline_queue() {
+    let limits = RateLimitConfig {
+        chat: BucketConfig::new(10, 0.0),
+        max_violations: 5,
+        ..Default::default()
+    };
+    let state = Arc::new(ServerState::new().with_rate_limits(limits));
+
+    let msg = r#"{
+        "type": "message",
+        "id": "1",
+        "chat_id": "c1",
+        "sender_id": "spammer",
+        "sender_name": "Spammer",
+        "recipient_id": "offline_victim",
+        "content": "spam",
+        "timestamp": 123
+    }"#;
+
+    let spammer = Session::new("spammer", Negotiated::current());
+    let mut outcomes = Vec::new();
+    for _ in 0..16 {
+        outcomes.push(handle_message(msg, &spammer, &state));
+    }
+
+    assert_eq!(state.pending_count("offline_victim"), 10);
+    assert!(matches!(outcomes[10], HandleOutcome::Reply(_)));
+    // The sixth violation crosses max_violations and asks for a disconnect
+    assert!(matches!(outcomes[15], HandleOutcome::Disconnect(_)));
+    assert_eq!(
+        state.rate_limited_count_for("spammer", MessageClass::Chat),
+        6
+    );
+}
+
+#[tokio::test]
+async fn test_binary_frames_need_negotiated_encoding() {
+    let state = Arc::new(ServerState::new());
+    let (tx, mut rx) = mpsc::channel(16);
+    state.add_client("victim".to_string(), tx);
+
+    let msg = WsMessage::Typing {
+        chat_id: "c1".to_string(),
+        user_id: "victim".to_string(),
+        is_typing: true,
+    };
+    let frame = Encoding::MessagePack.encode(&msg).unwrap();
+
+    // A JSON-only connection cannot slip frames past the text parser
+    let json_only = Session::new("attacker", Negotiated::legacy());
+    let HandleOutcome::Reply(reply) = handle_binary(&frame, &json_only, &state) else {
+        panic!("Expected an error reply");
+    };
+    assert_eq!(
+        error_code(&reply),
+        (Some(ErrorCode::UnsupportedEncoding), None)
+    );
+
+    // Garbage in the negotiated encoding is rejected, not relayed
+    let binary = Session::new("attacker", Negotiated::current());
+    let HandleOutcome::Reply(reply) = handle_binary(&[0xc1, 0xff], &binary, &state) else {
+        panic!("Expected an error reply");
+    };
+    assert_eq!(error_code(&reply), (Some(ErrorCode::InvalidFrame), None));
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_unparsable_frames_are_answered_with_error_codes() {
+    let state = Arc::new(ServerState::new());
+    let sender = Session::new("alice", Negotiated::current());
+    let reply = |text: &str| match handle_message(text, &sender, &state) {
+        HandleOutcome::Reply(frame) => error_code(&frame),
+        outcome => panic!("Expected an error reply, got {:?}", outcome),
+    };
+
+    assert_eq!(reply("not json"), (Some(ErrorCode::InvalidFrame), None));
+    assert_eq!(
+        reply(r#"{"type": "teleport", "id": "t1"}"#),
+        (Some(ErrorCode::UnknownType), Some("t1".to_string()))
+    );
+    // A known type with a missing field refers back to the message it rejected
+    assert_eq!(
+        reply(r#"{"type": "message", "id": "m1", "chat_id": "c1"}"#),
+        (Some(ErrorCode::InvalidFrame), Some("m1".to_string()))
+    );
+    assert_eq!(
+        reply(r#"{"id": "m2"}"#),
+        (Some(ErrorCode::InvalidFrame), Some("m2".to_string()))
+    );
+}
+
+/// The code and ref_id of an error reply
+fn error_code(frame: &str) -> (Option<ErrorCode>, Option<String>) {
+    match serde_json::from_str(frame).unwrap() {
+        WsMessage::Error { code, ref_id, .. } => (code, ref_id),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
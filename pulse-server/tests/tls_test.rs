@@ -0,0 +1,208 @@
+//! TLS tests for the Pulse WebSocket server
+//!
+//! These tests terminate wss:// in-process with self-signed certificates on
+//! loopback, the same way `main.rs` does when `[tls]` is configured.
+
+use futures_util::{SinkExt, StreamExt};
+use rustls::pki_types::{CertificateDer, ServerName};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::{client_async, tungstenite::Message, WebSocketStream};
+
+type TlsClient = WebSocketStream<TlsStream<TcpStream>>;
+
+/// Write a fresh self-signed certificate for `localhost` and return it for clients to trust
+fn write_self_signed(cert_path: &Path, key_path: &Path) -> CertificateDer<'static> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(cert_path, cert.cert.pem()).unwrap();
+    std::fs::write(key_path, cert.key_pair.serialize_pem()).unwrap();
+    cert.cert.der().clone()
+}
+
+/// Start a wss:// test server on a random available port
+async fn start_tls_server(
+    tls: Arc<pulse_server::TlsTerminator>,
+) -> (u16, tokio::task::JoinHandle<()>) {
+    let state = Arc::new(pulse_server::ServerState::new());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let handle = tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let state = state.clone();
+            let tls = tls.clone();
+            tokio::spawn(async move {
+                // Clients that reject the certificate abort the handshake
+                let Ok(stream) = tls.accept(stream).await else {
+                    return;
+                };
+                let config = state.limits().websocket_config();
+                let ws_stream = tokio_tungstenite::accept_async_with_config(stream, Some(config))
+                    .await
+                    .unwrap();
+                pulse_server::handle_connection(ws_stream, state).await;
+            });
+        }
+    });
+
+    (port, handle)
+}
+
+/// Open a wss:// connection that trusts only `trusted`
+async fn connect_tls(
+    port: u16,
+    trusted: &CertificateDer<'static>,
+) -> Result<TlsClient, std::io::Error> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(trusted.clone()).unwrap();
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    let tcp = TcpStream::connect(("127.0.0.1", port)).await?;
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, tcp)
+        .await?;
+
+    let url = format!("wss://localhost:{}", port);
+    let (ws_stream, _) = client_async(url, stream)
+        .await
+        .expect("WebSocket handshake");
+    Ok(ws_stream)
+}
+
+/// Read the next text frame as JSON
+async fn next_json(client: &mut TlsClient) -> serde_json::Value {
+    loop {
+        let msg = timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("Timeout")
+            .expect("Closed")
+            .expect("Error");
+
+        match msg {
+            Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+            Message::Ping(_) | Message::Pong(_) => continue,
+            other => panic!("Expected text message, got {:?}", other),
+        }
+    }
+}
+
+/// Connect over TLS and authenticate as `user_id`
+async fn connect_user(port: u16, trusted: &CertificateDer<'static>, user_id: &str) -> TlsClient {
+    let mut client = connect_tls(port, trusted).await.expect("TLS handshake");
+    let connect_msg = json!({
+        "type": "connect",
+        "user_id": user_id,
+        "protocol_version": 2,
+        "capabilities": ["acks"]
+    });
+    client
+        .send(Message::Text(connect_msg.to_string()))
+        .await
+        .unwrap();
+
+    let response = next_json(&mut client).await;
+    assert_eq!(response["type"], "auth_response");
+    assert_eq!(response["success"], true);
+    client
+}
+
+/// Send a chat message and wait for it to arrive at the recipient
+async fn assert_relayed(sender: &mut TlsClient, recipient: &mut TlsClient, from: &str, to: &str) {
+    let msg = json!({
+        "type": "message",
+        "id": format!("{}-to-{}", from, to),
+        "chat_id": "chat1",
+        "sender_id": from,
+        "sender_name": from,
+        "recipient_id": to,
+        "content": "hello over tls",
+        "timestamp": 1
+    });
+    sender.send(Message::Text(msg.to_string())).await.unwrap();
+
+    let ack = next_json(sender).await;
+    assert_eq!(ack["type"], "server_ack");
+    assert_eq!(ack["status"], "delivered");
+
+    let received = next_json(recipient).await;
+    assert_eq!(received["type"], "message");
+    assert_eq!(received["sender_id"], from);
+    assert_eq!(received["content"], "hello over tls");
+}
+
+fn cert_paths(dir: &tempfile::TempDir) -> (PathBuf, PathBuf) {
+    (dir.path().join("cert.pem"), dir.path().join("key.pem"))
+}
+
+#[tokio::test]
+async fn test_wss_connection_relays_messages() {
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = cert_paths(&dir);
+    let cert = write_self_signed(&cert_path, &key_path);
+    let tls = Arc::new(pulse_server::TlsTerminator::new(&cert_path, &key_path).unwrap());
+    let (port, server_handle) = start_tls_server(tls).await;
+
+    let mut alice = connect_user(port, &cert, "alice").await;
+    let mut bob = connect_user(port, &cert, "bob").await;
+    assert_relayed(&mut alice, &mut bob, "alice", "bob").await;
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_plain_websocket_rejected_by_tls_listener() {
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = cert_paths(&dir);
+    write_self_signed(&cert_path, &key_path);
+    let tls = Arc::new(pulse_server::TlsTerminator::new(&cert_path, &key_path).unwrap());
+    let (port, server_handle) = start_tls_server(tls).await;
+
+    let url = format!("ws://127.0.0.1:{}", port);
+    let result = timeout(
+        Duration::from_secs(5),
+        tokio_tungstenite::connect_async(url),
+    )
+    .await
+    .expect("Timeout");
+    assert!(result.is_err());
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_certificate_reload_keeps_open_connections() {
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = cert_paths(&dir);
+    let old_cert = write_self_signed(&cert_path, &key_path);
+    let tls = Arc::new(pulse_server::TlsTerminator::new(&cert_path, &key_path).unwrap());
+    let (port, server_handle) = start_tls_server(tls.clone()).await;
+
+    let mut alice = connect_user(port, &old_cert, "alice").await;
+
+    // Rotate the certificate on disk and reload it
+    let new_cert = write_self_signed(&cert_path, &key_path);
+    tls.reload().unwrap();
+
+    // New handshakes present the new certificate
+    assert!(connect_tls(port, &old_cert).await.is_err());
+    let mut bob = connect_user(port, &new_cert, "bob").await;
+
+    // The connection made under the old certificate still works both ways
+    assert_relayed(&mut bob, &mut alice, "bob", "alice").await;
+    assert_relayed(&mut alice, &mut bob, "alice", "bob").await;
+
+    server_handle.abort();
+}
@@ -0,0 +1,376 @@
+use crate::db::Database;
+use crate::models::CallLog;
+use crate::utils::is_blocked;
+use crate::utils::validation::validate_phone_id;
+use rusqlite::Connection;
+use tauri::State;
+
+/// Call kinds the app places
+const CALL_KINDS: [&str; 2] = ["video", "audio"];
+const CALL_DIRECTIONS: [&str; 2] = ["incoming", "outgoing"];
+const CALL_OUTCOMES: [&str; 4] = ["answered", "rejected", "missed", "failed"];
+
+/// Longest call id accepted (the app uses UUIDs)
+const MAX_CALL_ID_LENGTH: usize = 64;
+
+/// Entries returned by `get_call_history` when no limit is given, and the most it returns
+const DEFAULT_HISTORY_LIMIT: u32 = 100;
+const MAX_HISTORY_LIMIT: u32 = 1000;
+
+fn validate_call_id(call_id: &str) -> Result<(), String> {
+    if call_id.is_empty() {
+        return Err("Call ID cannot be empty".to_string());
+    }
+    if call_id.len() > MAX_CALL_ID_LENGTH {
+        return Err(format!(
+            "Call ID too long (max {} characters)",
+            MAX_CALL_ID_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+fn validate_one_of(field: &str, value: &str, allowed: &[&str]) -> Result<(), String> {
+    if allowed.contains(&value) {
+        Ok(())
+    } else {
+        Err(format!("Unknown call {}: {}", field, value))
+    }
+}
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Save a call the server reported as missed (`missed_call`) in the call history.
+/// A call already logged as in progress is closed as missed; a finished one is kept.
+/// Returns false if nothing changed or the caller is blocked.
+pub(crate) fn save_missed_call(
+    conn: &Connection,
+    call_id: &str,
+    from_user_id: &str,
+    kind: &str,
+    timestamp: i64,
+) -> Result<bool, String> {
+    validate_call_id(call_id)?;
+    let peer_id = validate_phone_id(from_user_id)?;
+    validate_one_of("kind", kind, &CALL_KINDS)?;
+    if is_blocked(conn, &peer_id) {
+        return Ok(false);
+    }
+
+    let changed = conn
+        .execute(
+            "INSERT INTO calls
+                 (call_id, peer_id, kind, direction, started_at, ended_at, outcome)
+             VALUES (?1, ?2, ?3, 'incoming', ?4, ?4, 'missed')
+             ON CONFLICT(call_id) DO UPDATE SET ended_at = excluded.ended_at, outcome = 'missed'
+             WHERE calls.ended_at IS NULL",
+            (call_id, &peer_id, kind, timestamp),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(changed > 0)
+}
+
+/// Log a call being placed or ringing. Returns false if the call is already logged.
+pub(crate) fn insert_call(
+    conn: &Connection,
+    call_id: &str,
+    peer_id: &str,
+    kind: &str,
+    direction: &str,
+    started_at: i64,
+) -> Result<bool, String> {
+    validate_call_id(call_id)?;
+    let peer_id = validate_phone_id(peer_id)?;
+    validate_one_of("kind", kind, &CALL_KINDS)?;
+    validate_one_of("direction", direction, &CALL_DIRECTIONS)?;
+
+    let inserted = conn
+        .execute(
+            "INSERT OR IGNORE INTO calls (call_id, peer_id, kind, direction, started_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            (call_id, &peer_id, kind, direction, started_at),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(inserted > 0)
+}
+
+/// Record when a call was answered. Returns false if the call is unknown, already
+/// answered or already over.
+pub(crate) fn mark_call_answered(
+    conn: &Connection,
+    call_id: &str,
+    answered_at: i64,
+) -> Result<bool, String> {
+    validate_call_id(call_id)?;
+    let updated = conn
+        .execute(
+            "UPDATE calls SET answered_at = ?2
+             WHERE call_id = ?1 AND answered_at IS NULL AND ended_at IS NULL",
+            (call_id, answered_at),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(updated > 0)
+}
+
+/// Record how a call ended. Only the first end counts: returns false if the call is
+/// unknown or already over.
+pub(crate) fn mark_call_ended(
+    conn: &Connection,
+    call_id: &str,
+    outcome: &str,
+    ended_at: i64,
+) -> Result<bool, String> {
+    validate_call_id(call_id)?;
+    validate_one_of("outcome", outcome, &CALL_OUTCOMES)?;
+    let updated = conn
+        .execute(
+            "UPDATE calls SET ended_at = ?2, outcome = ?3
+             WHERE call_id = ?1 AND ended_at IS NULL",
+            (call_id, ended_at, outcome),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(updated > 0)
+}
+
+/// Most recent calls first
+pub(crate) fn load_call_history(conn: &Connection, limit: u32) -> Result<Vec<CallLog>, String> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT c.call_id, c.peer_id, COALESCE(u.display_name, u.name), c.kind, c.direction,
+                    c.started_at, c.answered_at, c.ended_at, c.outcome
+             FROM calls c
+             LEFT JOIN users u ON c.peer_id = u.id
+             ORDER BY c.started_at DESC
+             LIMIT ?1",
+        )
+        .map_err(|e| e.to_string())?;
+
+    let calls = stmt
+        .query_map([limit], |row| {
+            Ok(CallLog {
+                call_id: row.get(0)?,
+                peer_id: row.get(1)?,
+                peer_name: row.get(2)?,
+                kind: row.get(3)?,
+                direction: row.get(4)?,
+                started_at: row.get(5)?,
+                answered_at: row.get(6)?,
+                ended_at: row.get(7)?,
+                outcome: row.get(8)?,
+            })
+        })
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    Ok(calls)
+}
+
+/// Returns false if the call was not in the history
+pub(crate) fn remove_call(conn: &Connection, call_id: &str) -> Result<bool, String> {
+    validate_call_id(call_id)?;
+    let deleted = conn
+        .execute("DELETE FROM calls WHERE call_id = ?1", [call_id])
+        .map_err(|e| e.to_string())?;
+    Ok(deleted > 0)
+}
+
+/// Record a `missed_call` frame received on the frontend's connection
+#[tauri::command]
+pub fn record_missed_call(
+    db: State<'_, Database>,
+    call_id: String,
+    from_user_id: String,
+    kind: String,
+    timestamp: i64,
+) -> Result<bool, String> {
+    let conn = db.0.lock().map_err(|e| e.to_string())?;
+    save_missed_call(&conn, &call_id, &from_user_id, &kind, timestamp)
+}
+
+/// Log an outgoing call once its invite is sent, or an incoming one once it rings
+#[tauri::command]
+pub fn log_call_start(
+    db: State<'_, Database>,
+    call_id: String,
+    peer_id: String,
+    kind: String,
+    direction: String,
+) -> Result<bool, String> {
+    let conn = db.0.lock().map_err(|e| e.to_string())?;
+    insert_call(&conn, &call_id, &peer_id, &kind, &direction, now_millis())
+}
+
+/// Log that a call was accepted (by us or by the peer)
+#[tauri::command]
+pub fn log_call_answer(db: State<'_, Database>, call_id: String) -> Result<bool, String> {
+    let conn = db.0.lock().map_err(|e| e.to_string())?;
+    mark_call_answered(&conn, &call_id, now_millis())
+}
+
+/// Log how a call ended: answered, rejected, missed or failed
+#[tauri::command]
+pub fn log_call_end(
+    db: State<'_, Database>,
+    call_id: String,
+    outcome: String,
+) -> Result<bool, String> {
+    let conn = db.0.lock().map_err(|e| e.to_string())?;
+    mark_call_ended(&conn, &call_id, &outcome, now_millis())
+}
+
+/// Call history, most recent first (100 entries unless `limit` is given, at most 1000)
+#[tauri::command]
+pub fn get_call_history(
+    db: State<'_, Database>,
+    limit: Option<u32>,
+) -> Result<Vec<CallLog>, String> {
+    let limit = limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+    let conn = db.0.lock().map_err(|e| e.to_string())?;
+    load_call_history(&conn, limit)
+}
+
+/// Remove one call from the history. Returns false if it was not there.
+#[tauri::command]
+pub fn delete_call_log(db: State<'_, Database>, call_id: String) -> Result<bool, String> {
+    let conn = db.0.lock().map_err(|e| e.to_string())?;
+    remove_call(&conn, &call_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::create_tables;
+
+    const PEER: &str = "+14155552671";
+
+    fn test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        conn
+    }
+
+    fn history(conn: &Connection) -> Vec<CallLog> {
+        load_call_history(conn, DEFAULT_HISTORY_LIMIT).unwrap()
+    }
+
+    #[test]
+    fn test_answered_call_lifecycle() {
+        let conn = test_db();
+        assert!(insert_call(&conn, "call-1", PEER, "video", "outgoing", 1_000).unwrap());
+        assert!(mark_call_answered(&conn, "call-1", 2_000).unwrap());
+        assert!(mark_call_ended(&conn, "call-1", "answered", 3_000).unwrap());
+
+        let calls = history(&conn);
+        assert_eq!(calls.len(), 1);
+        let call = &calls[0];
+        assert_eq!(call.peer_id, PEER);
+        assert_eq!(call.direction, "outgoing");
+        assert_eq!(call.started_at, 1_000);
+        assert_eq!(call.answered_at, Some(2_000));
+        assert_eq!(call.ended_at, Some(3_000));
+        assert_eq!(call.outcome.as_deref(), Some("answered"));
+    }
+
+    #[test]
+    fn test_only_first_end_counts() {
+        let conn = test_db();
+        insert_call(&conn, "call-1", PEER, "audio", "incoming", 1_000).unwrap();
+        assert!(mark_call_ended(&conn, "call-1", "rejected", 2_000).unwrap());
+        assert!(!mark_call_ended(&conn, "call-1", "failed", 3_000).unwrap());
+        assert!(!mark_call_answered(&conn, "call-1", 4_000).unwrap());
+
+        let call = &history(&conn)[0];
+        assert_eq!(call.outcome.as_deref(), Some("rejected"));
+        assert_eq!(call.ended_at, Some(2_000));
+        assert_eq!(call.answered_at, None);
+    }
+
+    #[test]
+    fn test_duplicate_start_ignored() {
+        let conn = test_db();
+        assert!(insert_call(&conn, "call-1", PEER, "video", "incoming", 1_000).unwrap());
+        assert!(!insert_call(&conn, "call-1", PEER, "video", "incoming", 5_000).unwrap());
+        assert_eq!(history(&conn)[0].started_at, 1_000);
+    }
+
+    #[test]
+    fn test_invalid_values_rejected() {
+        let conn = test_db();
+        assert!(insert_call(&conn, "", PEER, "video", "incoming", 1).is_err());
+        assert!(insert_call(&conn, "call-1", "not a phone", "video", "incoming", 1).is_err());
+        assert!(insert_call(&conn, "call-1", PEER, "hologram", "incoming", 1).is_err());
+        assert!(insert_call(&conn, "call-1", PEER, "video", "sideways", 1).is_err());
+        insert_call(&conn, "call-1", PEER, "video", "incoming", 1).unwrap();
+        assert!(mark_call_ended(&conn, "call-1", "exploded", 2).is_err());
+        assert!(history(&conn)[0].outcome.is_none());
+    }
+
+    #[test]
+    fn test_missed_call_from_server() {
+        let conn = test_db();
+        assert!(save_missed_call(&conn, "call-1", PEER, "video", 1_000).unwrap());
+        // The same frame delivered to the frontend and the backend is logged once
+        assert!(!save_missed_call(&conn, "call-1", PEER, "video", 1_000).unwrap());
+
+        let calls = history(&conn);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].direction, "incoming");
+        assert_eq!(calls[0].outcome.as_deref(), Some("missed"));
+    }
+
+    #[test]
+    fn test_missed_call_closes_ringing_call_only() {
+        let conn = test_db();
+        insert_call(&conn, "ringing", PEER, "video", "incoming", 1_000).unwrap();
+        assert!(save_missed_call(&conn, "ringing", PEER, "video", 1_000).unwrap());
+        assert_eq!(history(&conn)[0].outcome.as_deref(), Some("missed"));
+
+        insert_call(&conn, "rejected", PEER, "video", "incoming", 2_000).unwrap();
+        mark_call_ended(&conn, "rejected", "rejected", 2_500).unwrap();
+        assert!(!save_missed_call(&conn, "rejected", PEER, "video", 2_000).unwrap());
+        assert_eq!(history(&conn)[0].outcome.as_deref(), Some("rejected"));
+    }
+
+    #[test]
+    fn test_missed_call_from_blocked_user_dropped() {
+        let conn = test_db();
+        conn.execute(
+            "INSERT INTO blocked_users (user_id, blocked_at) VALUES (?1, 0)",
+            [PEER],
+        )
+        .unwrap();
+        assert!(!save_missed_call(&conn, "call-1", PEER, "video", 1_000).unwrap());
+        assert!(history(&conn).is_empty());
+    }
+
+    #[test]
+    fn test_history_newest_first_with_peer_name() {
+        let conn = test_db();
+        conn.execute(
+            "INSERT INTO users (id, name, display_name) VALUES (?1, 'Alice', 'Ali')",
+            [PEER],
+        )
+        .unwrap();
+        insert_call(&conn, "old", PEER, "video", "outgoing", 1_000).unwrap();
+        insert_call(&conn, "new", "+442071234567", "audio", "incoming", 2_000).unwrap();
+
+        let calls = history(&conn);
+        assert_eq!(calls[0].call_id, "new");
+        assert_eq!(calls[0].peer_name, None);
+        assert_eq!(calls[1].peer_name.as_deref(), Some("Ali"));
+        assert_eq!(load_call_history(&conn, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_delete_call_log() {
+        let conn = test_db();
+        insert_call(&conn, "call-1", PEER, "video", "outgoing", 1_000).unwrap();
+        assert!(remove_call(&conn, "call-1").unwrap());
+        assert!(!remove_call(&conn, "call-1").unwrap());
+        assert!(history(&conn).is_empty());
+    }
+}
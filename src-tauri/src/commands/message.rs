@@ -7,8 +7,8 @@ use crate::models::input::{
 };
 use crate::models::{Message, UrlPreview, User};
 use crate::utils::validation::validate_phone_id;
-use crate::utils::{generate_deterministic_chat_id, get_self_id};
-use crate::websocket::{get_ws_client, WsMessage};
+use crate::utils::{generate_deterministic_chat_id, get_self_id, is_blocked};
+use crate::websocket::{fetch_missing_peer_key, get_ws_client, WsMessage};
 use tauri::State;
 
 /// Helper to get the peer user ID from a chat (for 1-on-1 chats)
@@ -170,6 +170,22 @@ pub async fn send_message(db: State<'_, Database>, input: SendMessageInput) -> R
     let now = chrono::Utc::now().timestamp_millis();
     let msg_id = uuid::Uuid::new_v4().to_string();
 
+    // Phase 0: On first contact, fetch the peer's key from the server (no lock held while waiting)
+    let peer_id = {
+        let conn = db.0.lock().map_err(|e| e.to_string())?;
+        let peer_id = get_peer_user_id(&conn, &chat_id, &get_self_id(&conn)?);
+        if peer_id
+            .as_deref()
+            .is_some_and(|peer_id| is_blocked(&conn, peer_id))
+        {
+            return Err("You have blocked this user".to_string());
+        }
+        peer_id
+    };
+    if let Some(peer_id) = peer_id {
+        fetch_missing_peer_key(&db, &peer_id).await;
+    }
+
     // Phase 1: Gather data and prepare (with lock)
     let (self_id, _previews_enabled, encrypted_content, cached_preview, url_to_fetch) = {
         let conn = db.0.lock().map_err(|e| e.to_string())?;
@@ -222,7 +238,7 @@ pub async fn send_message(db: State<'_, Database>, input: SendMessageInput) -> R
 
         conn.execute(
             "INSERT INTO messages (id, chat_id, sender_id, content, message_type, reply_to_id, preview_url, status, created_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 'sent', ?8)",
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 'pending', ?8)",
             (&msg_id, &chat_id, &self_id, &encrypted_content, &input.message_type, &input.reply_to_id, &preview_url, now),
         )
         .map_err(|e| e.to_string())?;
@@ -265,7 +281,8 @@ pub async fn send_message(db: State<'_, Database>, input: SendMessageInput) -> R
         media_url: None,
         reply_to_id: input.reply_to_id,
         url_preview,
-        status: "sent".to_string(),
+        // Becomes "sent" once the relay acknowledges it
+        status: "pending".to_string(),
         created_at: now,
         edited_at: None,
     })
@@ -335,6 +352,35 @@ pub fn update_message_status(
     Ok(true)
 }
 
+/// Move an outgoing message from "pending" to "sent" once the relay has acknowledged it.
+/// Returns false if the message is unknown or already further along.
+pub fn mark_message_sent(conn: &rusqlite::Connection, message_id: &str) -> Result<bool, String> {
+    let updated = conn
+        .execute(
+            "UPDATE messages SET status = 'sent' WHERE id = ?1 AND status = 'pending'",
+            [message_id],
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(updated > 0)
+}
+
+/// Move an outgoing message from "pending" to "failed", keeping why it could not be sent.
+/// Returns false if the message is unknown or already further along.
+pub fn mark_message_failed(
+    conn: &rusqlite::Connection,
+    message_id: &str,
+    reason: Option<&str>,
+) -> Result<bool, String> {
+    let updated = conn
+        .execute(
+            "UPDATE messages SET status = 'failed', failure_reason = ?2
+             WHERE id = ?1 AND status = 'pending'",
+            rusqlite::params![message_id, reason],
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(updated > 0)
+}
+
 /// Search messages by content
 /// Note: With E2E encryption, searching within encrypted content has limitations.
 /// Only unencrypted messages or messages that match the encrypted pattern will be found.
@@ -411,6 +457,7 @@ pub fn search_messages(db: State<'_, Database>, input: SearchMessagesInput) -> R
 
 /// Receive an incoming message from WebSocket and save it to local database
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub fn receive_message(
     db: State<'_, Database>,
     id: String,
@@ -421,6 +468,7 @@ pub fn receive_message(
     timestamp: i64,
     reply_to_id: Option<String>,
     url_preview: Option<UrlPreview>,
+    recipient_id: Option<String>,
 ) -> Result<Message, String> {
     // Validate and normalize sender ID (accepts phone numbers with + prefix)
     let sender_id = validate_phone_id(&sender_id)?;
@@ -431,11 +479,23 @@ pub fn receive_message(
 
     let self_id = get_self_id(&conn)?;
 
-    // Don't save messages from ourselves (we already have them)
-    if sender_id == self_id {
+    // A message from ourselves was sent from another of our devices: file it under its recipient
+    let from_self = sender_id == self_id;
+    let peer_id = match recipient_id {
+        Some(recipient_id) if from_self => validate_phone_id(&recipient_id)?,
+        _ => sender_id.clone(),
+    };
+
+    // Don't save our own messages to ourselves (we already have them)
+    if peer_id == self_id {
         return Err("Message from self, skipping".to_string());
     }
 
+    // The server drops messages between blocked users; check again in case it did not
+    if is_blocked(&conn, &peer_id) {
+        return Err("Message from blocked user, skipping".to_string());
+    }
+
     // Check if message already exists
     let exists: bool = conn
         .query_row("SELECT 1 FROM messages WHERE id = ?1", [&id], |_| Ok(true))
@@ -446,23 +506,25 @@ pub fn receive_message(
     }
 
     // Generate deterministic chat ID
-    let chat_id = generate_deterministic_chat_id(&self_id, &sender_id);
+    let chat_id = generate_deterministic_chat_id(&self_id, &peer_id);
 
-    // Check if sender exists as a user, if not create them
-    let sender_exists: bool = conn
-        .query_row("SELECT 1 FROM users WHERE id = ?1", [&sender_id], |_| {
+    // Check if the peer exists as a user, if not create them
+    let peer_exists: bool = conn
+        .query_row("SELECT 1 FROM users WHERE id = ?1", [&peer_id], |_| {
             Ok(true)
         })
         .unwrap_or(false);
 
-    if !sender_exists {
+    if !peer_exists {
+        // sender_name is our own name when the message came from another of our devices
         let name = sender_name
             .clone()
-            .unwrap_or_else(|| format!("User {}", &sender_id[..8.min(sender_id.len())]));
+            .filter(|_| !from_self)
+            .unwrap_or_else(|| format!("User {}", &peer_id[..8.min(peer_id.len())]));
         conn.execute(
             "INSERT INTO users (id, name, phone, avatar_url, about, last_seen, is_online, is_self)
              VALUES (?1, ?2, '', '', 'Hey there! I am using Pulse', ?3, 0, 0)",
-            (&sender_id, &name, timestamp),
+            (&peer_id, &name, timestamp),
         )
         .map_err(|e| e.to_string())?;
     }
@@ -489,7 +551,7 @@ pub fn receive_message(
 
         conn.execute(
             "INSERT INTO chat_participants (chat_id, user_id, joined_at) VALUES (?1, ?2, ?3)",
-            (&chat_id, &sender_id, timestamp),
+            (&chat_id, &peer_id, timestamp),
         )
         .map_err(|e| e.to_string())?;
     }
@@ -502,10 +564,11 @@ pub fn receive_message(
 
     // The content might be encrypted (prefixed with "enc:") from the sender
     // Store as-is in the database (preserving encryption)
+    let status = if from_self { "sent" } else { "received" };
     conn.execute(
         "INSERT INTO messages (id, chat_id, sender_id, content, message_type, reply_to_id, preview_url, status, created_at)
-         VALUES (?1, ?2, ?3, ?4, 'text', ?5, ?6, 'received', ?7)",
-        (&id, &chat_id, &sender_id, &content, &reply_to_id, &preview_url, timestamp),
+         VALUES (?1, ?2, ?3, ?4, 'text', ?5, ?6, ?7, ?8)",
+        (&id, &chat_id, &sender_id, &content, &reply_to_id, &preview_url, status, timestamp),
     )
     .map_err(|e| e.to_string())?;
 
@@ -537,14 +600,16 @@ pub fn receive_message(
         )
         .ok();
 
-    // Broadcast delivery receipt back to sender
-    let delivery_receipt = WsMessage::DeliveryReceipt {
-        message_id: id.clone(),
-        chat_id: chat_id.clone(),
-        sender_id: sender_id.clone(),
-        delivered_to: self_id.clone(),
-    };
-    let _ = get_ws_client().broadcast(delivery_receipt);
+    // Broadcast delivery receipt back to sender (a synced copy of our own message needs none)
+    if !from_self {
+        let delivery_receipt = WsMessage::DeliveryReceipt {
+            message_id: id.clone(),
+            chat_id: chat_id.clone(),
+            sender_id: sender_id.clone(),
+            delivered_to: self_id.clone(),
+        };
+        let _ = get_ws_client().broadcast(delivery_receipt);
+    }
 
     // Decrypt content for the returned message (so UI can display it)
     let decrypted_content = decrypt_content(&conn, &content, &chat_id, &self_id);
@@ -559,7 +624,7 @@ pub fn receive_message(
         media_url: None,
         reply_to_id,
         url_preview,
-        status: "received".to_string(),
+        status: status.to_string(),
         created_at: timestamp,
         edited_at: None,
     })
@@ -1,4 +1,5 @@
 // Make submodules public so Tauri can access the generated command macros
+pub mod call;
 pub mod chat;
 pub mod message;
 pub mod turn;
@@ -1,12 +1,26 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha1::Sha1;
 use std::env;
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 const METERED_DOMAIN: &str = "pulse-app.metered.live";
 const FETCH_TIMEOUT_SECS: u64 = 5;
 
+/// Metered does not report an expiry, so its credentials are refetched after this long
+const METERED_CREDENTIAL_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Default lifetime of locally minted coturn credentials (`PULSE_TURN_TTL_SECS`)
+const DEFAULT_COTURN_TTL_SECS: u64 = 24 * 60 * 60;
+/// Cached credentials are refreshed this long before they expire (or halfway, if sooner)
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// ICE servers handed out by the last successful lookup
+static ICE_CACHE: Mutex<Option<CachedIceServers>> = Mutex::new(None);
+
 /// ICE server configuration returned to frontend
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct IceServer {
     pub urls: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -23,6 +37,153 @@ struct MeteredIceServer {
     credential: Option<String>,
 }
 
+struct CachedIceServers {
+    servers: Vec<IceServer>,
+    /// None for servers that never expire
+    refresh_at: Option<Instant>,
+}
+
+/// Where ICE servers come from, picked with `PULSE_ICE_PROVIDER`
+#[derive(Debug, PartialEq)]
+enum IceProvider {
+    /// Metered.ca TURN credentials API (`metered`, the default)
+    Metered { api_key: String },
+    /// Fixed servers from `PULSE_ICE_SERVERS` (`static`)
+    Static(Vec<IceServer>),
+    /// A self-hosted coturn with `use-auth-secret` (`coturn`): time-limited credentials
+    /// are derived locally from the shared secret instead of fetched
+    Coturn {
+        urls: Vec<String>,
+        secret: String,
+        ttl: Duration,
+    },
+}
+
+impl IceProvider {
+    fn from_env() -> Result<Self, String> {
+        Self::from_lookup(|key| env::var(key).ok().filter(|value| !value.trim().is_empty()))
+    }
+
+    fn from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let provider = get("PULSE_ICE_PROVIDER").unwrap_or_else(|| "metered".to_string());
+        match provider.trim().to_ascii_lowercase().as_str() {
+            "metered" => {
+                let api_key = get("METERED_API_KEY").ok_or("METERED_API_KEY not set")?;
+                Ok(IceProvider::Metered { api_key })
+            }
+            "static" => {
+                let urls = server_urls(get("PULSE_ICE_SERVERS"))?;
+                let username = get("PULSE_ICE_USERNAME");
+                let credential = get("PULSE_ICE_CREDENTIAL");
+                let servers = urls
+                    .into_iter()
+                    .map(|urls| {
+                        // STUN servers take no credentials
+                        let is_turn = urls.starts_with("turn:") || urls.starts_with("turns:");
+                        IceServer {
+                            username: username.clone().filter(|_| is_turn),
+                            credential: credential.clone().filter(|_| is_turn),
+                            urls,
+                        }
+                    })
+                    .collect();
+                Ok(IceProvider::Static(servers))
+            }
+            "coturn" => {
+                let urls = server_urls(get("PULSE_ICE_SERVERS"))?;
+                let secret = get("PULSE_TURN_SECRET").ok_or("PULSE_TURN_SECRET not set")?;
+                let ttl_secs = match get("PULSE_TURN_TTL_SECS") {
+                    Some(value) => value
+                        .trim()
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|secs| *secs > 0)
+                        .ok_or(format!("Invalid PULSE_TURN_TTL_SECS: {}", value))?,
+                    None => DEFAULT_COTURN_TTL_SECS,
+                };
+                Ok(IceProvider::Coturn {
+                    urls,
+                    secret,
+                    ttl: Duration::from_secs(ttl_secs),
+                })
+            }
+            other => Err(format!("Unknown PULSE_ICE_PROVIDER: {}", other)),
+        }
+    }
+
+    /// The servers plus how long they stay valid (None: forever)
+    async fn ice_servers(&self) -> Result<(Vec<IceServer>, Option<Duration>), String> {
+        match self {
+            IceProvider::Metered { api_key } => {
+                let servers = fetch_metered(api_key).await?;
+                Ok((servers, Some(METERED_CREDENTIAL_TTL)))
+            }
+            IceProvider::Static(servers) => Ok((servers.clone(), None)),
+            IceProvider::Coturn { urls, secret, ttl } => {
+                let user = crate::db::device_id().unwrap_or("pulse");
+                let expires_at = chrono::Utc::now().timestamp() + ttl.as_secs() as i64;
+                let (username, credential) = coturn_credentials(secret, user, expires_at);
+                let servers = urls
+                    .iter()
+                    .map(|urls| IceServer {
+                        urls: urls.clone(),
+                        username: Some(username.clone()),
+                        credential: Some(credential.clone()),
+                    })
+                    .collect();
+                Ok((servers, Some(*ttl)))
+            }
+        }
+    }
+}
+
+/// Comma-separated server URLs
+fn server_urls(value: Option<String>) -> Result<Vec<String>, String> {
+    let urls: Vec<String> = value
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(str::to_string)
+        .collect();
+    if urls.is_empty() {
+        return Err("PULSE_ICE_SERVERS not set".to_string());
+    }
+    if let Some(bad) = urls.iter().find(|url| {
+        !["stun:", "stuns:", "turn:", "turns:"]
+            .iter()
+            .any(|s| url.starts_with(s))
+    }) {
+        return Err(format!("Not a STUN or TURN URL: {}", bad));
+    }
+    Ok(urls)
+}
+
+/// coturn REST API credentials: the username is `<expiry unix time>:<user>` and the
+/// password is base64(HMAC-SHA1(secret, username)), which coturn recomputes to verify
+fn coturn_credentials(secret: &str, user: &str, expires_at: i64) -> (String, String) {
+    let username = format!("{}:{}", expires_at, user);
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret.as_bytes())
+        .expect("HMAC can take key of any size");
+    mac.update(username.as_bytes());
+    let credential = BASE64.encode(mac.finalize().into_bytes());
+    (username, credential)
+}
+
+/// When cached credentials with this lifetime should be replaced
+fn refresh_after(lifetime: Duration) -> Duration {
+    lifetime.saturating_sub(REFRESH_MARGIN.min(lifetime / 2))
+}
+
+fn cached_servers(now: Instant) -> Option<Vec<IceServer>> {
+    let cache = ICE_CACHE.lock().ok()?;
+    let cached = cache.as_ref()?;
+    match cached.refresh_at {
+        Some(refresh_at) if now >= refresh_at => None,
+        _ => Some(cached.servers.clone()),
+    }
+}
+
 /// Fallback STUN servers (Google's public STUN)
 fn get_fallback_servers() -> Vec<IceServer> {
     vec![
@@ -40,21 +201,7 @@ fn get_fallback_servers() -> Vec<IceServer> {
 }
 
 /// Fetch TURN credentials from Metered.ca API
-#[tauri::command]
-pub async fn get_turn_credentials() -> Result<Vec<IceServer>, String> {
-    // Get API key from environment
-    let api_key = match env::var("METERED_API_KEY") {
-        Ok(key) if !key.is_empty() => key,
-        Ok(_) => {
-            tracing::warn!("METERED_API_KEY is empty, using fallback STUN servers");
-            return Ok(get_fallback_servers());
-        }
-        Err(_) => {
-            tracing::warn!("METERED_API_KEY not set, using fallback STUN servers");
-            return Ok(get_fallback_servers());
-        }
-    };
-
+async fn fetch_metered(api_key: &str) -> Result<Vec<IceServer>, String> {
     let url = format!(
         "https://{}/api/v1/turn/credentials?apiKey={}",
         METERED_DOMAIN, api_key
@@ -65,29 +212,20 @@ pub async fn get_turn_credentials() -> Result<Vec<IceServer>, String> {
         .build()
         .map_err(|e| e.to_string())?;
 
-    let response = match client.get(&url).send().await {
-        Ok(resp) => resp,
-        Err(e) => {
-            tracing::warn!("Failed to fetch TURN credentials: {}, using fallback", e);
-            return Ok(get_fallback_servers());
-        }
-    };
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch TURN credentials: {}", e))?;
 
     if !response.status().is_success() {
-        tracing::warn!(
-            "Metered API returned {}, using fallback",
-            response.status()
-        );
-        return Ok(get_fallback_servers());
+        return Err(format!("Metered API returned {}", response.status()));
     }
 
-    let servers: Vec<MeteredIceServer> = match response.json().await {
-        Ok(s) => s,
-        Err(e) => {
-            tracing::warn!("Failed to parse TURN credentials: {}, using fallback", e);
-            return Ok(get_fallback_servers());
-        }
-    };
+    let servers: Vec<MeteredIceServer> = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse TURN credentials: {}", e))?;
 
     // Convert to our IceServer type
     let ice_servers: Vec<IceServer> = servers
@@ -100,10 +238,145 @@ pub async fn get_turn_credentials() -> Result<Vec<IceServer>, String> {
         .collect();
 
     if ice_servers.is_empty() {
-        tracing::warn!("Metered returned empty servers, using fallback");
-        return Ok(get_fallback_servers());
+        return Err("Metered returned empty servers".to_string());
     }
 
     tracing::info!("Fetched {} ICE servers from Metered", ice_servers.len());
     Ok(ice_servers)
 }
+
+/// ICE servers (STUN + TURN) from the configured provider, cached until shortly before
+/// the credentials expire. Falls back to public STUN if the provider is unusable.
+#[tauri::command]
+pub async fn get_turn_credentials() -> Result<Vec<IceServer>, String> {
+    let now = Instant::now();
+    if let Some(servers) = cached_servers(now) {
+        return Ok(servers);
+    }
+
+    let provider = match IceProvider::from_env() {
+        Ok(provider) => provider,
+        Err(e) => {
+            tracing::warn!("{}, using fallback STUN servers", e);
+            return Ok(get_fallback_servers());
+        }
+    };
+
+    let (servers, lifetime) = match provider.ice_servers().await {
+        Ok(result) => result,
+        Err(e) => {
+            tracing::warn!("{}, using fallback STUN servers", e);
+            return Ok(get_fallback_servers());
+        }
+    };
+
+    if let Ok(mut cache) = ICE_CACHE.lock() {
+        *cache = Some(CachedIceServers {
+            servers: servers.clone(),
+            refresh_at: lifetime.map(|lifetime| now + refresh_after(lifetime)),
+        });
+    }
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn provider(vars: &[(&str, &str)]) -> Result<IceProvider, String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        IceProvider::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn test_metered_is_default() {
+        assert_eq!(
+            provider(&[("METERED_API_KEY", "key")]),
+            Ok(IceProvider::Metered {
+                api_key: "key".to_string()
+            })
+        );
+        assert!(provider(&[]).is_err());
+    }
+
+    #[test]
+    fn test_static_servers_only_turn_gets_credentials() {
+        let provider = provider(&[
+            ("PULSE_ICE_PROVIDER", "static"),
+            (
+                "PULSE_ICE_SERVERS",
+                "stun:stun.example.com:3478, turn:turn.example.com:3478",
+            ),
+            ("PULSE_ICE_USERNAME", "alice"),
+            ("PULSE_ICE_CREDENTIAL", "secret"),
+        ])
+        .unwrap();
+        let IceProvider::Static(servers) = provider else {
+            panic!("expected static provider");
+        };
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].urls, "stun:stun.example.com:3478");
+        assert_eq!(servers[0].username, None);
+        assert_eq!(servers[1].username.as_deref(), Some("alice"));
+        assert_eq!(servers[1].credential.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_coturn_requires_secret_and_urls() {
+        let coturn = [
+            ("PULSE_ICE_PROVIDER", "coturn"),
+            ("PULSE_ICE_SERVERS", "turn:turn.example.com:3478"),
+        ];
+        assert!(provider(&coturn).is_err());
+        assert_eq!(
+            provider(&[coturn[0], ("PULSE_TURN_SECRET", "north")]),
+            Err("PULSE_ICE_SERVERS not set".to_string())
+        );
+
+        let with_secret = [coturn[0], coturn[1], ("PULSE_TURN_SECRET", "north")];
+        let Ok(IceProvider::Coturn { ttl, .. }) = provider(&with_secret) else {
+            panic!("expected coturn provider");
+        };
+        assert_eq!(ttl, Duration::from_secs(DEFAULT_COTURN_TTL_SECS));
+        let zero_ttl = [
+            coturn[0],
+            coturn[1],
+            with_secret[2],
+            ("PULSE_TURN_TTL_SECS", "0"),
+        ];
+        assert!(provider(&zero_ttl).is_err());
+    }
+
+    #[test]
+    fn test_invalid_provider_config_rejected() {
+        assert!(provider(&[("PULSE_ICE_PROVIDER", "carrier-pigeon")]).is_err());
+        assert!(provider(&[
+            ("PULSE_ICE_PROVIDER", "static"),
+            ("PULSE_ICE_SERVERS", "https://turn.example.com")
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_coturn_credentials_match_rest_api_scheme() {
+        let (username, credential) = coturn_credentials("north", "device-1", 1_700_086_400);
+        assert_eq!(username, "1700086400:device-1");
+        // base64(HMAC-SHA1("north", username)), as coturn computes it
+        assert_eq!(credential, "4VlwvmlfkQyjyxDHWETdBRTFZpQ=");
+    }
+
+    #[test]
+    fn test_refresh_before_expiry() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(refresh_after(day), day - REFRESH_MARGIN);
+        // Short-lived credentials are refreshed halfway through
+        assert_eq!(
+            refresh_after(Duration::from_secs(60)),
+            Duration::from_secs(30)
+        );
+    }
+}
@@ -1,15 +1,17 @@
 use crate::db::Database;
 use crate::crypto::storage;
 use crate::models::User;
+use crate::utils::get_self_id;
 use crate::utils::validation::{
     validate_about, validate_phone, validate_phone_id, validate_url, validate_user_name,
 };
+use crate::websocket::{get_ws_client, WsMessage};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use rusqlite::OptionalExtension;
 use serde::Serialize;
 use std::fs;
 use std::path::Path;
-use tauri::{AppHandle, Manager, State};
+use tauri::{AppHandle, Emitter, Manager, State};
 
 #[derive(Serialize)]
 struct StoredIdentity {
@@ -506,3 +508,102 @@ fn detect_image_format(bytes: &[u8]) -> Option<&'static str> {
     }
     None
 }
+
+/// Block a user: the server stops relaying messages, typing, calls and presence between us,
+/// and their messages are also dropped locally. Saved even when offline and sent on reconnect.
+#[tauri::command]
+pub fn block_user(
+    app: AppHandle,
+    db: State<'_, Database>,
+    user_id: String,
+) -> Result<bool, String> {
+    let user_id = validate_phone_id(&user_id)?;
+
+    let blocked = {
+        let conn = db.0.lock().map_err(|e| e.to_string())?;
+        if user_id == get_self_id(&conn)? {
+            return Err("Cannot block yourself".to_string());
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO blocked_users (user_id, blocked_at, synced) VALUES (?1, ?2, 0)",
+            (&user_id, chrono::Utc::now().timestamp_millis()),
+        )
+        .map_err(|e| e.to_string())?;
+
+        // Marked synced once the server confirms it with its block list
+        let msg = WsMessage::BlockUser {
+            user_id: user_id.clone(),
+        };
+        let _ = get_ws_client().send(msg);
+        blocked_user_ids(&conn)?
+    };
+
+    let _ = app.emit("blocked-users-changed", &blocked);
+    Ok(true)
+}
+
+/// Unblock a user. Needs a server connection, so the server's block list never keeps
+/// a block the user believes is gone.
+#[tauri::command]
+pub fn unblock_user(
+    app: AppHandle,
+    db: State<'_, Database>,
+    user_id: String,
+) -> Result<bool, String> {
+    let user_id = validate_phone_id(&user_id)?;
+
+    get_ws_client().send(WsMessage::UnblockUser {
+        user_id: user_id.clone(),
+    })?;
+
+    let blocked = {
+        let conn = db.0.lock().map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM blocked_users WHERE user_id = ?1", [&user_id])
+            .map_err(|e| e.to_string())?;
+        blocked_user_ids(&conn)?
+    };
+
+    let _ = app.emit("blocked-users-changed", &blocked);
+    Ok(true)
+}
+
+/// Get the IDs of the users we have blocked
+#[tauri::command]
+pub fn get_blocked_users(db: State<'_, Database>) -> Result<Vec<String>, String> {
+    let conn = db.0.lock().map_err(|e| e.to_string())?;
+    blocked_user_ids(&conn)
+}
+
+fn blocked_user_ids(conn: &rusqlite::Connection) -> Result<Vec<String>, String> {
+    let mut stmt = conn
+        .prepare("SELECT user_id FROM blocked_users ORDER BY blocked_at")
+        .map_err(|e| e.to_string())?;
+    let ids = stmt
+        .query_map([], |row| row.get(0))
+        .map_err(|e| e.to_string())?
+        .filter_map(|r| r.ok())
+        .collect();
+    Ok(ids)
+}
+
+/// Blocks made while the server connection was down (resent after reconnecting)
+pub(crate) fn unsynced_blocks(conn: &rusqlite::Connection) -> Result<Vec<String>, String> {
+    let mut stmt = conn
+        .prepare("SELECT user_id FROM blocked_users WHERE synced = 0")
+        .map_err(|e| e.to_string())?;
+    let ids = stmt
+        .query_map([], |row| row.get(0))
+        .map_err(|e| e.to_string())?
+        .filter_map(|r| r.ok())
+        .collect();
+    Ok(ids)
+}
+
+pub(crate) fn mark_block_synced(conn: &rusqlite::Connection, user_id: &str) -> Result<(), String> {
+    conn.execute(
+        "UPDATE blocked_users SET synced = 1 WHERE user_id = ?1",
+        [user_id],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
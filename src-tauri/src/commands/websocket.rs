@@ -1,8 +1,9 @@
+use crate::commands::message::{mark_message_failed, mark_message_sent};
 use crate::crypto::get_crypto_manager;
 use crate::db::Database;
 use crate::models::UrlPreview;
 use crate::websocket::{get_ws_client, WsMessage, WsUrlPreview};
-use tauri::State;
+use tauri::{AppHandle, State};
 
 /// Helper to get the peer user ID from a chat (for 1-on-1 chats)
 fn get_peer_user_id(conn: &rusqlite::Connection, chat_id: &str, self_id: &str) -> Option<String> {
@@ -14,6 +15,8 @@ fn get_peer_user_id(conn: &rusqlite::Connection, chat_id: &str, self_id: &str) -
     .ok()
 }
 
+/// Send a chat message to the server. Returns true if it already counts as sent, false while
+/// it waits for a `server_ack`; if it cannot be sent at all it is marked failed.
 #[tauri::command]
 pub fn broadcast_message(
     db: State<'_, Database>,
@@ -73,7 +76,7 @@ pub fn broadcast_message(
     });
 
     let msg = WsMessage::ChatMessage {
-        id: message_id,
+        id: message_id.clone(),
         chat_id,
         sender_id,
         sender_name,
@@ -84,7 +87,16 @@ pub fn broadcast_message(
         url_preview: ws_preview,
     };
 
-    get_ws_client().broadcast(msg)?;
+    // Without acks nothing will confirm the message, so it counts as sent once handed over
+    let client = get_ws_client();
+    if let Err(e) = client.broadcast(msg) {
+        mark_message_failed(&conn, &message_id, Some(&e))?;
+        return Err(e);
+    }
+    if client.acks_granted() {
+        return Ok(false);
+    }
+    mark_message_sent(&conn, &message_id)?;
     Ok(true)
 }
 
@@ -94,6 +106,14 @@ pub async fn get_server_url() -> Result<String, String> {
     Ok(get_ws_client().get_server_url().await)
 }
 
+/// Get this installation's device id (the frontend sends it in its own Connect)
+#[tauri::command]
+pub fn get_device_id() -> Result<String, String> {
+    crate::db::device_id()
+        .map(String::from)
+        .ok_or_else(|| "Database not initialized".to_string())
+}
+
 /// Check if connected to the central server
 #[tauri::command]
 pub async fn is_connected() -> Result<bool, String> {
@@ -102,8 +122,8 @@ pub async fn is_connected() -> Result<bool, String> {
 
 /// Connect to the central WebSocket server
 #[tauri::command]
-pub async fn connect_websocket(user_id: String) -> Result<(), String> {
-    crate::websocket::init_websocket(&user_id).await
+pub async fn connect_websocket(app: AppHandle, user_id: String) -> Result<(), String> {
+    crate::websocket::init_websocket(&user_id, app).await
 }
 
 /// Gracefully disconnect from the central WebSocket server
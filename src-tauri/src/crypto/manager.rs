@@ -5,6 +5,7 @@ use aes_gcm::{
     Aes256Gcm, Nonce,
 };
 use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use rand::RngCore;
 use rusqlite::Connection;
 use sha2::Sha256;
@@ -68,6 +69,44 @@ impl CryptoManager {
         }
     }
 
+    /// Get the identity public key if one has been initialized (never generates a new one)
+    pub fn identity_public_key(&self) -> Option<[u8; 32]> {
+        self.identity_key
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|key| *key.public_key.as_bytes())
+    }
+
+    /// Answer a server authentication challenge, proving possession of the identity key
+    ///
+    /// The proof is HMAC-SHA256("pulse-auth-v1" || nonce || user_id) keyed by the
+    /// X25519 shared secret between our identity key and the server's ephemeral key.
+    pub fn prove_identity(
+        &self,
+        server_key: &[u8],
+        nonce: &[u8],
+        user_id: &str,
+    ) -> Result<Vec<u8>, String> {
+        let guard = self.identity_key.lock().unwrap();
+        let keypair = guard.as_ref().ok_or("No identity key")?;
+
+        let server_key: [u8; 32] = server_key
+            .try_into()
+            .map_err(|_| "Invalid server key length")?;
+        let shared_secret = keypair
+            .private_key
+            .diffie_hellman(&PublicKey::from(server_key));
+
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(shared_secret.as_bytes())
+            .map_err(|_| "Failed to create HMAC")?;
+        mac.update(b"pulse-auth-v1");
+        mac.update(nonce);
+        mac.update(user_id.as_bytes());
+
+        Ok(mac.finalize().into_bytes().to_vec())
+    }
+
     /// Derive a shared secret using X25519 and HKDF
     fn derive_session_key(
         &self,
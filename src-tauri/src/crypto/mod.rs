@@ -119,6 +119,20 @@ pub fn get_peer_key(
     }
 }
 
+/// Answer a server authentication challenge for the frontend WebSocket connection
+/// Returns the hex-encoded proof to send back in an `auth_proof` frame
+#[tauri::command]
+pub fn answer_auth_challenge(
+    user_id: String,
+    nonce: String,
+    server_key: String,
+) -> Result<String, String> {
+    let nonce = hex::decode(&nonce).map_err(|e| e.to_string())?;
+    let server_key = hex::decode(&server_key).map_err(|e| e.to_string())?;
+    let proof = get_crypto_manager().prove_identity(&server_key, &nonce, &user_id)?;
+    Ok(hex::encode(proof))
+}
+
 /// Ensure a session exists for a chat (auto-derives if peer key available)
 #[tauri::command]
 pub fn ensure_chat_session(
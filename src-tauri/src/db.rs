@@ -2,11 +2,14 @@ use rusqlite::{Connection, OptionalExtension, Result};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Mutex, OnceLock};
 use tauri::{AppHandle, Manager};
 
 pub struct Database(pub Mutex<Connection>);
 
+/// This installation's device id, sent in Connect so the server can tell an account's devices apart
+static DEVICE_ID: OnceLock<String> = OnceLock::new();
+
 #[derive(Debug, Serialize, Deserialize)]
 struct StoredIdentity {
     user_id: String,
@@ -36,6 +39,36 @@ fn save_stored_identity(path: &Path, user_id: &str) {
     fs::write(path, contents).expect("Failed to write identity file");
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredDevice {
+    device_id: String,
+}
+
+/// Load this installation's device id, generating one on first run.
+/// Kept out of identity.json so copying an identity to another machine does not clone the device.
+fn load_or_create_device_id(path: &Path) -> String {
+    let stored = fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<StoredDevice>(&contents).ok())
+        .map(|device| device.device_id)
+        .filter(|device_id| pulse_protocol::is_valid_device_id(device_id));
+    if let Some(device_id) = stored {
+        return device_id;
+    }
+
+    let device = StoredDevice {
+        device_id: uuid::Uuid::new_v4().to_string(),
+    };
+    let contents = serde_json::to_string_pretty(&device).expect("Failed to serialize device");
+    fs::write(path, contents).expect("Failed to write device file");
+    device.device_id
+}
+
+/// This installation's device id (set once the database is initialized)
+pub fn device_id() -> Option<&'static str> {
+    DEVICE_ID.get().map(String::as_str)
+}
+
 pub fn init_database(app: &AppHandle) -> Result<()> {
     let app_dir = app
         .path()
@@ -45,83 +78,10 @@ pub fn init_database(app: &AppHandle) -> Result<()> {
 
     let db_path = app_dir.join("pulse.db");
     let identity_path = app_dir.join("identity.json");
+    DEVICE_ID.get_or_init(|| load_or_create_device_id(&app_dir.join("device.json")));
     let conn = Connection::open(db_path)?;
 
-    // Create tables
-    conn.execute_batch(
-        "
-        -- Users table (contacts + self)
-        CREATE TABLE IF NOT EXISTS users (
-            id TEXT PRIMARY KEY,
-            name TEXT NOT NULL,
-            display_name TEXT,
-            phone TEXT,
-            avatar_url TEXT,
-            about TEXT DEFAULT 'Hey there! I am using Pulse',
-            last_seen INTEGER,
-            is_online INTEGER DEFAULT 0,
-            is_self INTEGER DEFAULT 0
-        );
-
-        -- Chats table (1-on-1 and groups)
-        CREATE TABLE IF NOT EXISTS chats (
-            id TEXT PRIMARY KEY,
-            type TEXT CHECK(type IN ('individual', 'group')) NOT NULL,
-            name TEXT,
-            avatar_url TEXT,
-            created_at INTEGER NOT NULL,
-            updated_at INTEGER NOT NULL
-        );
-
-        -- Chat participants
-        CREATE TABLE IF NOT EXISTS chat_participants (
-            chat_id TEXT REFERENCES chats(id),
-            user_id TEXT REFERENCES users(id),
-            role TEXT DEFAULT 'member',
-            joined_at INTEGER,
-            PRIMARY KEY (chat_id, user_id)
-        );
-
-        -- Messages table
-        CREATE TABLE IF NOT EXISTS messages (
-            id TEXT PRIMARY KEY,
-            chat_id TEXT REFERENCES chats(id),
-            sender_id TEXT REFERENCES users(id),
-            content TEXT,
-            message_type TEXT DEFAULT 'text',
-            media_url TEXT,
-            reply_to_id TEXT REFERENCES messages(id),
-            status TEXT DEFAULT 'sent',
-            created_at INTEGER NOT NULL,
-            edited_at INTEGER
-        );
-
-        -- Public keys table (identity + peers) for E2E encryption
-        CREATE TABLE IF NOT EXISTS public_keys (
-            user_id TEXT PRIMARY KEY REFERENCES users(id),
-            public_key BLOB NOT NULL,
-            key_type TEXT CHECK(key_type IN ('identity', 'peer')) NOT NULL,
-            created_at INTEGER NOT NULL,
-            updated_at INTEGER NOT NULL
-        );
-
-        -- URL previews cache table
-        CREATE TABLE IF NOT EXISTS url_previews (
-            url TEXT PRIMARY KEY,
-            title TEXT,
-            description TEXT,
-            image_url TEXT,
-            site_name TEXT,
-            fetched_at INTEGER NOT NULL
-        );
-
-        -- Create indexes for better performance
-        CREATE INDEX IF NOT EXISTS idx_messages_chat_id ON messages(chat_id);
-        CREATE INDEX IF NOT EXISTS idx_messages_created_at ON messages(created_at);
-        CREATE INDEX IF NOT EXISTS idx_chat_participants_user_id ON chat_participants(user_id);
-        CREATE INDEX IF NOT EXISTS idx_public_keys_type ON public_keys(key_type);
-        ",
-    )?;
+    create_tables(&conn)?;
 
     // Migration: Add display_name column if it doesn't exist (for existing databases)
     let has_display_name: bool = conn
@@ -168,6 +128,20 @@ pub fn init_database(app: &AppHandle) -> Result<()> {
         conn.execute("ALTER TABLE messages ADD COLUMN preview_url TEXT", [])?;
     }
 
+    // Migration: Add failure_reason column to messages table
+    let has_failure_reason: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('messages') WHERE name = 'failure_reason'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .map(|count| count > 0)
+        .unwrap_or(false);
+
+    if !has_failure_reason {
+        conn.execute("ALTER TABLE messages ADD COLUMN failure_reason TEXT", [])?;
+    }
+
     // Create or reuse current user with a stable identity ID
     let existing_self_id: Option<String> = conn
         .query_row(
@@ -206,3 +180,103 @@ pub fn init_database(app: &AppHandle) -> Result<()> {
     app.manage(Database(Mutex::new(conn)));
     Ok(())
 }
+
+/// Create any missing tables and indexes (migrations of older databases run separately)
+pub(crate) fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+        -- Users table (contacts + self)
+        CREATE TABLE IF NOT EXISTS users (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            display_name TEXT,
+            phone TEXT,
+            avatar_url TEXT,
+            about TEXT DEFAULT 'Hey there! I am using Pulse',
+            last_seen INTEGER,
+            is_online INTEGER DEFAULT 0,
+            is_self INTEGER DEFAULT 0
+        );
+
+        -- Chats table (1-on-1 and groups)
+        CREATE TABLE IF NOT EXISTS chats (
+            id TEXT PRIMARY KEY,
+            type TEXT CHECK(type IN ('individual', 'group')) NOT NULL,
+            name TEXT,
+            avatar_url TEXT,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        );
+
+        -- Chat participants
+        CREATE TABLE IF NOT EXISTS chat_participants (
+            chat_id TEXT REFERENCES chats(id),
+            user_id TEXT REFERENCES users(id),
+            role TEXT DEFAULT 'member',
+            joined_at INTEGER,
+            PRIMARY KEY (chat_id, user_id)
+        );
+
+        -- Messages table
+        CREATE TABLE IF NOT EXISTS messages (
+            id TEXT PRIMARY KEY,
+            chat_id TEXT REFERENCES chats(id),
+            sender_id TEXT REFERENCES users(id),
+            content TEXT,
+            message_type TEXT DEFAULT 'text',
+            media_url TEXT,
+            reply_to_id TEXT REFERENCES messages(id),
+            status TEXT DEFAULT 'sent',
+            failure_reason TEXT,
+            created_at INTEGER NOT NULL,
+            edited_at INTEGER
+        );
+
+        -- Public keys table (identity + peers) for E2E encryption
+        CREATE TABLE IF NOT EXISTS public_keys (
+            user_id TEXT PRIMARY KEY REFERENCES users(id),
+            public_key BLOB NOT NULL,
+            key_type TEXT CHECK(key_type IN ('identity', 'peer')) NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        );
+
+        -- URL previews cache table
+        CREATE TABLE IF NOT EXISTS url_previews (
+            url TEXT PRIMARY KEY,
+            title TEXT,
+            description TEXT,
+            image_url TEXT,
+            site_name TEXT,
+            fetched_at INTEGER NOT NULL
+        );
+
+        -- Users we have blocked (mirrored to the server; synced = 0 until it confirms the block)
+        CREATE TABLE IF NOT EXISTS blocked_users (
+            user_id TEXT PRIMARY KEY,
+            blocked_at INTEGER NOT NULL,
+            synced INTEGER NOT NULL DEFAULT 0
+        );
+
+        -- Call history (one row per call; outcome stays NULL while the call is in progress)
+        CREATE TABLE IF NOT EXISTS calls (
+            call_id TEXT PRIMARY KEY,
+            peer_id TEXT NOT NULL,
+            kind TEXT CHECK(kind IN ('video', 'audio')) NOT NULL,
+            direction TEXT CHECK(direction IN ('incoming', 'outgoing')) NOT NULL,
+            started_at INTEGER NOT NULL,
+            answered_at INTEGER,
+            ended_at INTEGER,
+            outcome TEXT CHECK(outcome IN ('answered', 'rejected', 'missed', 'failed'))
+        );
+
+        -- Create indexes for better performance
+        CREATE INDEX IF NOT EXISTS idx_messages_chat_id ON messages(chat_id);
+        CREATE INDEX IF NOT EXISTS idx_messages_created_at ON messages(created_at);
+        CREATE INDEX IF NOT EXISTS idx_chat_participants_user_id ON chat_participants(user_id);
+        CREATE INDEX IF NOT EXISTS idx_public_keys_type ON public_keys(key_type);
+        CREATE INDEX IF NOT EXISTS idx_calls_started_at ON calls(started_at);
+        ",
+    )?;
+    Ok(())
+}
@@ -100,13 +100,24 @@ pub fn run() {
             commands::user::save_contact,
             commands::user::upload_avatar,
             commands::user::save_peer_avatar,
+            commands::user::block_user,
+            commands::user::unblock_user,
+            commands::user::get_blocked_users,
             // URL preview commands
             commands::url_preview::fetch_preview,
             // TURN server commands
             commands::turn::get_turn_credentials,
+            // Call history commands
+            commands::call::record_missed_call,
+            commands::call::log_call_start,
+            commands::call::log_call_answer,
+            commands::call::log_call_end,
+            commands::call::get_call_history,
+            commands::call::delete_call_log,
             // WebSocket commands
             commands::websocket::broadcast_message,
             commands::websocket::get_server_url,
+            commands::websocket::get_device_id,
             commands::websocket::is_connected,
             commands::websocket::connect_websocket,
             commands::websocket::disconnect_websocket,
@@ -124,6 +135,7 @@ pub fn run() {
             crypto::store_peer_key,
             crypto::get_peer_key,
             crypto::ensure_chat_session,
+            crypto::answer_auth_challenge,
         ])
         .on_window_event(|_window, event| {
             if let tauri::WindowEvent::CloseRequested { .. } = event {
@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+/// One entry of the call history
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CallLog {
+    pub call_id: String,
+    pub peer_id: String,
+    /// The peer's display name or name, if they are a known user
+    pub peer_name: Option<String>,
+    /// "video" or "audio"
+    pub kind: String,
+    /// "incoming" or "outgoing"
+    pub direction: String,
+    pub started_at: i64,
+    pub answered_at: Option<i64>,
+    pub ended_at: Option<i64>,
+    /// "answered", "rejected", "missed" or "failed"; None while the call is in progress
+    pub outcome: Option<String>,
+}
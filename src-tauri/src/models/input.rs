@@ -22,7 +22,7 @@ fn validate_message_type(value: &str, _ctx: &()) -> garde::Result {
 /// Custom validation for message status
 fn validate_message_status(value: &str, _ctx: &()) -> garde::Result {
     match value {
-        "sent" | "delivered" | "read" => Ok(()),
+        "sent" | "delivered" | "read" | "failed" => Ok(()),
         _ => Err(garde::Error::new("Invalid message status")),
     }
 }
@@ -1,9 +1,11 @@
+mod call;
 mod chat;
 pub mod input;
 mod message;
 mod url_preview;
 mod user;
 
+pub use call::CallLog;
 pub use chat::Chat;
 pub use message::Message;
 pub use url_preview::UrlPreview;
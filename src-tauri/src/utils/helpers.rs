@@ -10,10 +10,20 @@ pub fn get_self_id(conn: &Connection) -> Result<String, String> {
     .map_err(|e| e.to_string())
 }
 
+/// Check if we have blocked a user
+pub fn is_blocked(conn: &Connection, user_id: &str) -> bool {
+    conn.query_row(
+        "SELECT 1 FROM blocked_users WHERE user_id = ?1",
+        [user_id],
+        |_| Ok(()),
+    )
+    .is_ok()
+}
+
 /// Generate a deterministic chat ID from two user IDs
 /// This ensures both users will have the same chat ID regardless of who initiates
 pub fn generate_deterministic_chat_id(user_id_1: &str, user_id_2: &str) -> String {
-    let mut ids = vec![user_id_1.to_string(), user_id_2.to_string()];
+    let mut ids = [user_id_1.to_string(), user_id_2.to_string()];
     ids.sort();
     let chat_id = format!("chat_{}_{}", ids[0], ids[1]);
 
@@ -1,4 +1,4 @@
 mod helpers;
 pub mod validation;
 
-pub use helpers::{generate_deterministic_chat_id, get_self_id};
+pub use helpers::{generate_deterministic_chat_id, get_self_id, is_blocked};
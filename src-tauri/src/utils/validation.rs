@@ -48,10 +48,9 @@ pub fn validate_phone_id(phone: &str) -> Result<String, String> {
     }
 
     // Check for valid format: optional + followed by digits only
-    let (has_plus, digits_part) = if normalized.starts_with('+') {
-        (true, &normalized[1..])
-    } else {
-        (false, normalized.as_str())
+    let (has_plus, digits_part) = match normalized.strip_prefix('+') {
+        Some(rest) => (true, rest),
+        None => (false, normalized.as_str()),
     };
 
     // All remaining characters must be digits
@@ -1,17 +1,50 @@
-use super::messages::WsMessage;
+use crate::commands::call::save_missed_call;
+use crate::commands::message::{mark_message_failed, mark_message_sent};
+use crate::commands::user::{mark_block_synced, unsynced_blocks};
+use crate::crypto::get_crypto_manager;
+use crate::db::Database;
 use futures_util::{SinkExt, StreamExt};
+use pulse_protocol::{decode_key, AckStatus, Capability, Encoding, WsMessage, PROTOCOL_VERSION};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex as StdMutex;
-use tokio::sync::{broadcast, mpsc, Mutex as TokioMutex};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex as TokioMutex};
+use tokio::time::{interval_at, Duration, Instant, MissedTickBehavior};
 use tokio_tungstenite::{connect_async, tungstenite::Message};
 use tracing::{debug, error, info, trace, warn};
 
 /// Server URL: checked at compile time via env!, falls back to runtime env var, then default
 const DEFAULT_SERVER_URL: &str = "ws://localhost:9001";
 
+/// How often the client pings the server to detect a stalled link
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Reconnect when nothing (not even a pong or a server ping) arrives for this long
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Wait between connection attempts
+const RECONNECT_DELAY: Duration = Duration::from_secs(3);
+
+/// Upper bound on the reconnect delay a shutting-down server may ask for
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+/// How long to wait for the server's key directory to answer a key request
+const KEY_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Optional protocol features requested in Connect (MessagePack frames when the server has them,
+/// and its key directory for fetching peers' public keys)
+const CLIENT_CAPABILITIES: &[Capability] =
+    &[Capability::Acks, Capability::Msgpack, Capability::Keys];
+
+/// Callers waiting for a `key_response`, by the user_id whose key they asked for
+type KeyRequests = Arc<StdMutex<HashMap<String, Vec<oneshot::Sender<Option<[u8; 32]>>>>>>;
+
 /// Internal message type for the write channel
 enum WriteMessage {
-    Data(String),
+    Data(Box<WsMessage>),
     Close,
 }
 
@@ -21,6 +54,11 @@ pub struct WebSocketClient {
     /// Use std::sync::Mutex for write_tx so it can be accessed from sync Tauri commands
     write_tx: Arc<StdMutex<Option<mpsc::UnboundedSender<WriteMessage>>>>,
     connected: Arc<TokioMutex<bool>>,
+    /// Whether the current connection negotiated the server's key directory
+    key_directory: Arc<AtomicBool>,
+    /// Whether the current connection negotiated `server_ack`s for chat messages
+    acks: Arc<AtomicBool>,
+    key_requests: KeyRequests,
     /// Shutdown signal broadcaster
     shutdown_tx: broadcast::Sender<()>,
 }
@@ -56,6 +94,9 @@ impl WebSocketClient {
             server_url: Arc::new(TokioMutex::new(server_url)),
             write_tx: Arc::new(StdMutex::new(None)),
             connected: Arc::new(TokioMutex::new(false)),
+            key_directory: Arc::new(AtomicBool::new(false)),
+            acks: Arc::new(AtomicBool::new(false)),
+            key_requests: Arc::new(StdMutex::new(HashMap::new())),
             shutdown_tx,
         }
     }
@@ -71,14 +112,18 @@ impl WebSocketClient {
     }
 
     /// Connect to the central server
-    pub async fn connect(&self, user_id: &str) -> Result<(), String> {
+    pub async fn connect(&self, user_id: &str, app: AppHandle) -> Result<(), String> {
         let server_url = self.server_url.lock().await.clone();
         let user_id = user_id.to_string();
         let write_tx = self.write_tx.clone();
         let connected = self.connected.clone();
+        let key_directory = self.key_directory.clone();
+        let acks = self.acks.clone();
+        let key_requests = self.key_requests.clone();
         let mut shutdown_rx = self.shutdown_tx.subscribe();
 
         tokio::spawn(async move {
+            let mut reconnect_delay = RECONNECT_DELAY;
             loop {
                 // Check for shutdown before attempting connection
                 if shutdown_rx.try_recv().is_ok() {
@@ -95,56 +140,114 @@ impl WebSocketClient {
 
                         let (mut ws_write, mut ws_read) = ws_stream.split();
 
-                        // Send Connect message
+                        // Send Connect message (with our identity key so the server can bind it)
                         let token = std::env::var("PULSE_ACCESS_TOKEN").ok();
+                        let public_key =
+                            get_crypto_manager().identity_public_key().map(hex::encode);
                         let connect_msg = WsMessage::Connect {
                             user_id: user_id.clone(),
                             token,
+                            public_key,
+                            protocol_version: Some(PROTOCOL_VERSION),
+                            capabilities: CLIENT_CAPABILITIES.to_vec(),
+                            device_id: crate::db::device_id().map(String::from),
                         };
                         let connect_json = serde_json::to_string(&connect_msg).unwrap();
 
-                        if ws_write
-                            .send(Message::Text(connect_json.into()))
-                            .await
-                            .is_err()
-                        {
+                        if ws_write.send(Message::Text(connect_json)).await.is_err() {
                             error!("Failed to send connect message");
                             *connected.lock().await = false;
                             tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
                             continue;
                         }
 
-                        // Wait for auth response
-                        if let Some(Ok(Message::Text(response))) = ws_read.next().await {
-                            if let Ok(msg) = serde_json::from_str::<WsMessage>(&response) {
-                                match msg {
-                                    WsMessage::AuthResponse { success, message } => {
-                                        if success {
-                                            info!("Authenticated with server: {}", message);
-                                        } else {
-                                            error!("Authentication failed: {}", message);
-                                            *connected.lock().await = false;
-                                            tokio::time::sleep(tokio::time::Duration::from_secs(3))
-                                                .await;
-                                            continue;
+                        // Wait for auth response, answering an identity challenge if one is sent.
+                        // Frames are JSON until the server grants a binary encoding.
+                        let mut encoding = Encoding::Json;
+                        let authenticated = loop {
+                            let Some(Ok(Message::Text(response))) = ws_read.next().await else {
+                                warn!("Connection closed before authentication completed");
+                                break false;
+                            };
+                            match serde_json::from_str::<WsMessage>(&response) {
+                                Ok(WsMessage::AuthChallenge { nonce, server_key }) => {
+                                    let proof = match answer_challenge(
+                                        &user_id,
+                                        &nonce,
+                                        &server_key,
+                                    ) {
+                                        Ok(proof) => proof,
+                                        Err(e) => {
+                                            error!(error = %e, "Failed to answer auth challenge");
+                                            break false;
                                         }
+                                    };
+                                    let proof_json =
+                                        serde_json::to_string(&WsMessage::AuthProof { proof })
+                                            .unwrap();
+                                    if ws_write.send(Message::Text(proof_json)).await.is_err() {
+                                        error!("Failed to send auth proof");
+                                        break false;
                                     }
-                                    _ => {
-                                        warn!("Unexpected response during auth");
+                                }
+                                Ok(WsMessage::AuthResponse {
+                                    success,
+                                    message,
+                                    code,
+                                    protocol_version,
+                                    capabilities,
+                                }) => {
+                                    if success {
+                                        info!(
+                                            protocol_version = ?protocol_version,
+                                            capabilities = ?capabilities,
+                                            "Authenticated with server: {}",
+                                            message
+                                        );
+                                        if !capabilities.contains(&Capability::Acks) {
+                                            warn!("Server did not grant acks; sent messages will not be confirmed");
+                                        }
+                                        encoding = Encoding::negotiated(&capabilities);
+                                        key_directory.store(
+                                            capabilities.contains(&Capability::Keys),
+                                            Ordering::Relaxed,
+                                        );
+                                        acks.store(
+                                            capabilities.contains(&Capability::Acks),
+                                            Ordering::Relaxed,
+                                        );
+                                    } else {
+                                        error!(code = ?code, "Authentication failed: {}", message);
                                     }
+                                    break success;
+                                }
+                                _ => {
+                                    warn!("Unexpected response during auth");
+                                    break false;
                                 }
                             }
+                        };
+
+                        if !authenticated {
+                            *connected.lock().await = false;
+                            tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+                            continue;
                         }
 
                         // Create channel for outgoing messages
                         let (tx, mut rx) = mpsc::unbounded_channel::<WriteMessage>();
                         {
                             let mut guard = write_tx.lock().unwrap();
-                            *guard = Some(tx);
+                            *guard = Some(tx.clone());
                         }
+                        resend_unsynced_blocks(&app, &tx);
 
                         // Message loop
                         let mut should_reconnect = true;
+                        let mut last_seen = Instant::now();
+                        let mut heartbeat =
+                            interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+                        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
                         loop {
                             tokio::select! {
                                 // Check for shutdown signal
@@ -160,8 +263,15 @@ impl WebSocketClient {
                                 // Send outgoing messages
                                 Some(msg) = rx.recv() => {
                                     match msg {
-                                        WriteMessage::Data(data) => {
-                                            if ws_write.send(Message::Text(data.into())).await.is_err() {
+                                        WriteMessage::Data(message) => {
+                                            let frame = match encode_frame(&message, encoding) {
+                                                Ok(frame) => frame,
+                                                Err(e) => {
+                                                    error!(error = %e, "Failed to encode message");
+                                                    continue;
+                                                }
+                                            };
+                                            if !send_frame(&mut ws_write, frame).await {
                                                 error!("Failed to send message to server");
                                                 break;
                                             }
@@ -178,14 +288,37 @@ impl WebSocketClient {
                                 }
                                 // Receive incoming messages (frontend handles these directly via its own WS connection)
                                 msg = ws_read.next() => {
+                                    if let Some(Ok(_)) = &msg {
+                                        last_seen = Instant::now();
+                                    }
                                     match msg {
+                                        // Acks and key responses for requests we sent arrive on this connection only
                                         Some(Ok(Message::Text(text))) => {
                                             trace!(preview = %&text[..100.min(text.len())], "Received from server");
+                                            if let Ok(frame) = serde_json::from_str(&text) {
+                                                if let Some(delay) = shutdown_delay(&frame) {
+                                                    reconnect_delay = delay;
+                                                }
+                                                handle_frame(&app, &key_requests, frame);
+                                            }
+                                        }
+                                        Some(Ok(Message::Binary(data))) => {
+                                            trace!(len = data.len(), "Received binary frame from server");
+                                            if let Ok(frame) = encoding.decode(&data) {
+                                                if let Some(delay) = shutdown_delay(&frame) {
+                                                    reconnect_delay = delay;
+                                                }
+                                                handle_frame(&app, &key_requests, frame);
+                                            }
                                         }
                                         Some(Ok(Message::Close(_))) | None => {
                                             info!("Server closed connection");
                                             break;
                                         }
+                                        Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {
+                                            // tungstenite answers server pings itself
+                                            trace!("Heartbeat from server");
+                                        }
                                         Some(Err(e)) => {
                                             error!(error = %e, "WebSocket error");
                                             break;
@@ -193,6 +326,17 @@ impl WebSocketClient {
                                         _ => {}
                                     }
                                 }
+                                // Ping the server; a link silent for too long is treated as dead
+                                _ = heartbeat.tick() => {
+                                    if last_seen.elapsed() > HEARTBEAT_TIMEOUT {
+                                        warn!("No traffic from server in {:?}, reconnecting", HEARTBEAT_TIMEOUT);
+                                        break;
+                                    }
+                                    if !send_frame(&mut ws_write, Message::Ping(Vec::new())).await {
+                                        error!("Failed to ping server");
+                                        break;
+                                    }
+                                }
                             }
                         }
 
@@ -201,6 +345,10 @@ impl WebSocketClient {
                             let mut guard = write_tx.lock().unwrap();
                             *guard = None;
                         }
+                        // Waiting key requests see their sender dropped and give up
+                        key_directory.store(false, Ordering::Relaxed);
+                        acks.store(false, Ordering::Relaxed);
+                        key_requests.lock().unwrap().clear();
                         *connected.lock().await = false;
                         info!("Disconnected from Pulse server");
 
@@ -213,9 +361,10 @@ impl WebSocketClient {
                     }
                 }
 
-                // Reconnect after delay
-                debug!("Reconnecting in 3 seconds");
-                tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+                // Reconnect after delay (the one a shutting-down server asked for, once)
+                let delay = std::mem::replace(&mut reconnect_delay, RECONNECT_DELAY);
+                debug!("Reconnecting in {:?}", delay);
+                tokio::time::sleep(delay).await;
             }
         });
 
@@ -237,9 +386,6 @@ impl WebSocketClient {
 
     /// Send a message to the server
     pub fn send(&self, message: WsMessage) -> Result<(), String> {
-        let json = serde_json::to_string(&message).map_err(|e| e.to_string())?;
-        trace!(preview = %&json[..100.min(json.len())], "Sending message to server");
-
         // Use blocking lock since this is called from sync Tauri commands
         let guard = self
             .write_tx
@@ -247,7 +393,7 @@ impl WebSocketClient {
             .map_err(|e| format!("Lock poisoned: {}", e))?;
 
         if let Some(tx) = guard.as_ref() {
-            tx.send(WriteMessage::Data(json))
+            tx.send(WriteMessage::Data(Box::new(message)))
                 .map_err(|e| format!("Failed to send to server: {}", e))?;
             Ok(())
         } else {
@@ -262,4 +408,234 @@ impl WebSocketClient {
     pub fn broadcast(&self, message: WsMessage) -> Result<(), String> {
         self.send(message)
     }
+
+    /// Whether the server confirms our chat messages with `server_ack`s
+    pub fn acks_granted(&self) -> bool {
+        self.acks.load(Ordering::Relaxed)
+    }
+
+    /// Ask the server's key directory for a user's identity public key.
+    /// Ok(None) if the server has no key on file for them.
+    pub async fn request_public_key(&self, user_id: &str) -> Result<Option<[u8; 32]>, String> {
+        if !self.key_directory.load(Ordering::Relaxed) {
+            return Err("Server has no key directory".to_string());
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.key_requests
+            .lock()
+            .map_err(|e| format!("Lock poisoned: {}", e))?
+            .entry(user_id.to_string())
+            .or_default()
+            .push(tx);
+
+        self.send(WsMessage::KeyRequest {
+            user_id: user_id.to_string(),
+        })?;
+
+        match tokio::time::timeout(KEY_REQUEST_TIMEOUT, rx).await {
+            Ok(Ok(key)) => Ok(key),
+            Ok(Err(_)) => Err("Disconnected before the key arrived".to_string()),
+            Err(_) => Err("Timed out waiting for key".to_string()),
+        }
+    }
+}
+
+/// Encode a message as a text or binary frame for the negotiated encoding
+fn encode_frame(message: &WsMessage, encoding: Encoding) -> Result<Message, String> {
+    if encoding.is_binary() {
+        let data = encoding.encode(message)?;
+        trace!(len = data.len(), "Sending binary frame to server");
+        return Ok(Message::Binary(data));
+    }
+    let json = serde_json::to_string(message).map_err(|e| e.to_string())?;
+    trace!(preview = %&json[..100.min(json.len())], "Sending message to server");
+    Ok(Message::Text(json))
+}
+
+/// Write a frame, giving up if the server stops reading for HEARTBEAT_TIMEOUT
+async fn send_frame<S>(ws_write: &mut S, msg: Message) -> bool
+where
+    S: SinkExt<Message> + Unpin,
+{
+    matches!(
+        tokio::time::timeout(HEARTBEAT_TIMEOUT, ws_write.send(msg)).await,
+        Ok(Ok(()))
+    )
+}
+
+/// Reconnect delay requested by a `server_shutdown` frame, capped and with up to 50% jitter
+/// added so a restarted server is not hit by every client at once
+fn shutdown_delay(frame: &WsMessage) -> Option<Duration> {
+    let WsMessage::ServerShutdown {
+        reconnect_after_secs,
+    } = frame
+    else {
+        return None;
+    };
+    info!(reconnect_after_secs, "Server is shutting down");
+    let delay =
+        Duration::from_secs(*reconnect_after_secs).clamp(RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+    let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+    Some(delay + Duration::from_millis(jitter))
+}
+
+/// Handle a frame addressed to this connection; everything else is left to the frontend socket
+fn handle_frame(app: &AppHandle, key_requests: &KeyRequests, frame: WsMessage) {
+    match frame {
+        ack @ WsMessage::ServerAck { .. } => handle_server_ack(app, ack),
+        missed @ WsMessage::MissedCall { .. } => handle_missed_call(app, missed),
+        error @ WsMessage::Error { .. } => handle_server_error(app, error),
+        WsMessage::BlockList { user_ids } => handle_block_list(app, &user_ids),
+        WsMessage::KeyResponse {
+            user_id,
+            public_key,
+        } => {
+            let key = public_key.as_deref().and_then(decode_key);
+            let waiting = key_requests.lock().unwrap().remove(&user_id);
+            for tx in waiting.into_iter().flatten() {
+                let _ = tx.send(key);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Send blocks the server has not confirmed (made while disconnected, or lost with the last
+/// connection), so the server's block list catches up
+fn resend_unsynced_blocks(app: &AppHandle, tx: &mpsc::UnboundedSender<WriteMessage>) {
+    let db = app.state::<Database>();
+    let Ok(conn) = db.0.lock() else {
+        return;
+    };
+    let blocks = match unsynced_blocks(&conn) {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            error!(error = %e, "Failed to load unsynced blocks");
+            return;
+        }
+    };
+    for user_id in blocks {
+        let msg = WsMessage::BlockUser {
+            user_id: user_id.clone(),
+        };
+        if tx.send(WriteMessage::Data(Box::new(msg))).is_err() {
+            return;
+        }
+    }
+}
+
+/// Mark an acknowledged message as sent (or a rejected one as failed) and forward the ack to the
+/// frontend as a "server-ack" event
+fn handle_server_ack(app: &AppHandle, ack: WsMessage) {
+    let WsMessage::ServerAck {
+        message_id,
+        status,
+        reason,
+    } = &ack
+    else {
+        return;
+    };
+
+    let db = app.state::<Database>();
+    let conn = match db.0.lock() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!(message_id = %message_id, error = %e, "Failed to lock database");
+            return;
+        }
+    };
+    let updated = match status {
+        AckStatus::Delivered | AckStatus::Queued => mark_message_sent(&conn, message_id),
+        AckStatus::Error => {
+            warn!(message_id = %message_id, reason = ?reason, "Server rejected message");
+            mark_message_failed(&conn, message_id, reason.as_deref())
+        }
+    };
+    drop(conn);
+    match updated {
+        Ok(true) => {
+            debug!(message_id = %message_id, status = ?status, "Message acknowledged by server")
+        }
+        // Unknown, or already delivered/read
+        Ok(false) => return,
+        Err(e) => {
+            error!(message_id = %message_id, error = %e, "Failed to update message status");
+            return;
+        }
+    }
+
+    if let Err(e) = app.emit("server-ack", &ack) {
+        warn!(error = %e, "Failed to emit server-ack event");
+    }
+}
+
+/// The server confirmed our block list: the blocks on it are synced
+fn handle_block_list(app: &AppHandle, user_ids: &[String]) {
+    let db = app.state::<Database>();
+    let Ok(conn) = db.0.lock() else {
+        return;
+    };
+    for user_id in user_ids {
+        if let Err(e) = mark_block_synced(&conn, user_id) {
+            error!(error = %e, "Failed to mark block as synced");
+        }
+    }
+}
+
+/// Forward an error reply about one of our frames to the frontend as a "server-error" event
+fn handle_server_error(app: &AppHandle, error: WsMessage) {
+    let WsMessage::Error {
+        message,
+        code,
+        ref_id,
+    } = &error
+    else {
+        return;
+    };
+    warn!(code = ?code, ref_id = ?ref_id, "Server rejected a frame: {}", message);
+
+    if let Err(e) = app.emit("server-error", &error) {
+        warn!(error = %e, "Failed to emit server-error event");
+    }
+}
+
+/// Log a call we missed while offline or busy, and tell the frontend with a "missed-call" event.
+/// Queued frames reach whichever of this device's connections is up first, so the frontend
+/// records the ones it receives itself (`record_missed_call`).
+fn handle_missed_call(app: &AppHandle, missed: WsMessage) {
+    let WsMessage::MissedCall {
+        call_id,
+        from_user_id,
+        kind,
+        timestamp,
+        ..
+    } = &missed
+    else {
+        return;
+    };
+
+    let db = app.state::<Database>();
+    let saved =
+        db.0.lock()
+            .map_err(|e| e.to_string())
+            .and_then(|conn| save_missed_call(&conn, call_id, from_user_id, kind, *timestamp));
+    match saved {
+        Ok(true) => {
+            debug!(call_id = %call_id, "Missed call recorded");
+            if let Err(e) = app.emit("missed-call", &missed) {
+                warn!(error = %e, "Failed to emit missed-call event");
+            }
+        }
+        Ok(false) => {}
+        Err(e) => error!(call_id = %call_id, error = %e, "Failed to record missed call"),
+    }
+}
+
+/// Compute the hex-encoded proof for a server identity challenge
+fn answer_challenge(user_id: &str, nonce: &str, server_key: &str) -> Result<String, String> {
+    let nonce = hex::decode(nonce).map_err(|e| e.to_string())?;
+    let server_key = decode_key(server_key).ok_or("Invalid server key in auth challenge")?;
+    let proof = get_crypto_manager().prove_identity(&server_key, &nonce, user_id)?;
+    Ok(hex::encode(proof))
 }
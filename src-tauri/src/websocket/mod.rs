@@ -1,10 +1,13 @@
 mod client;
-mod messages;
 
 pub use client::WebSocketClient;
-pub use messages::{WsMessage, WsUrlPreview};
+pub use pulse_protocol::{WsMessage, WsUrlPreview};
 
+use crate::crypto::get_crypto_manager;
+use crate::db::Database;
 use std::sync::OnceLock;
+use tauri::AppHandle;
+use tracing::{debug, info, warn};
 
 // Global WebSocket client instance
 static WS_CLIENT: OnceLock<WebSocketClient> = OnceLock::new();
@@ -14,7 +17,36 @@ pub fn get_ws_client() -> &'static WebSocketClient {
 }
 
 /// Initialize WebSocket client and connect to server
-pub async fn init_websocket(user_id: &str) -> Result<(), String> {
+pub async fn init_websocket(user_id: &str, app: AppHandle) -> Result<(), String> {
     let client = get_ws_client();
-    client.connect(user_id).await
+    client.connect(user_id, app).await
+}
+
+/// Fetch a peer's identity key from the server's key directory and cache it,
+/// unless one is already known. Failures are only logged: without a key the
+/// message goes out unencrypted, as before.
+pub async fn fetch_missing_peer_key(db: &Database, peer_id: &str) {
+    let manager = get_crypto_manager();
+    let known = match db.0.lock() {
+        Ok(conn) => manager.get_peer_public_key(&conn, peer_id),
+        Err(e) => Err(e.to_string()),
+    };
+    if !matches!(known, Ok(None)) {
+        return;
+    }
+
+    match get_ws_client().request_public_key(peer_id).await {
+        Ok(Some(key)) => {
+            let stored =
+                db.0.lock()
+                    .map_err(|e| e.to_string())
+                    .and_then(|conn| manager.store_peer_public_key(&conn, peer_id, &key));
+            match stored {
+                Ok(()) => info!(peer_id = %peer_id, "Cached public key from key directory"),
+                Err(e) => warn!(peer_id = %peer_id, error = %e, "Failed to store peer public key"),
+            }
+        }
+        Ok(None) => debug!(peer_id = %peer_id, "Key directory has no key for peer"),
+        Err(e) => debug!(peer_id = %peer_id, error = %e, "Could not fetch peer public key"),
+    }
 }
@@ -1,4 +1,4 @@
-import { Check, CheckCheck } from "lucide-react";
+import { AlertCircle, Check, CheckCheck, Clock } from "lucide-react";
 
 import type { MessageStatus as Status } from "../../types";
 
@@ -11,6 +11,28 @@ interface MessageStatusProps {
 export function MessageStatus({ status, className = "", size = 16 }: MessageStatusProps) {
   const baseClass = "transition-colors duration-200";
 
+  // Not yet acknowledged by the server
+  if (status === "pending") {
+    return (
+      <Clock
+        size={size - 2}
+        strokeWidth={2.5}
+        className={`text-[var(--tick-delivered)] ${baseClass} ${className}`}
+      />
+    );
+  }
+
+  // The server expired or evicted it before the recipient came online
+  if (status === "failed") {
+    return (
+      <AlertCircle
+        size={size - 2}
+        strokeWidth={2.5}
+        className={`text-red-500 ${baseClass} ${className}`}
+      />
+    );
+  }
+
   if (status === "sent") {
     return (
       <Check
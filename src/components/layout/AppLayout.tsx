@@ -1,4 +1,4 @@
-import { Lock } from "lucide-react";
+import { Lock, Megaphone, X } from "lucide-react";
 import { useCallback, useState } from "react";
 
 import { useChatStore } from "../../store/chatStore";
@@ -57,6 +57,9 @@ export function AppLayout() {
       {/* Custom Titlebar */}
       <Titlebar />
 
+      {/* Server announcement */}
+      <AnnouncementBanner />
+
       {/* Main Content */}
       <div className="flex flex-1 overflow-hidden">
         {/* Left Sidebar with icons */}
@@ -98,6 +101,26 @@ export function AppLayout() {
   );
 }
 
+function AnnouncementBanner() {
+  const announcement = useUIStore((state) => state.announcement);
+  const setAnnouncement = useUIStore((state) => state.setAnnouncement);
+
+  if (!announcement) return null;
+
+  return (
+    <div className="flex items-center gap-2 px-4 py-2 bg-[var(--accent)]/10 border-b border-[var(--border-light)] text-sm text-[var(--text-primary)]">
+      <Megaphone size={16} className="text-[var(--accent)] shrink-0" />
+      <span className="flex-1">{announcement}</span>
+      <button
+        onClick={() => setAnnouncement(null)}
+        className="p-1 rounded-full hover:bg-[var(--bg-hover)] transition-colors"
+      >
+        <X size={16} className="text-[var(--text-secondary)]" />
+      </button>
+    </div>
+  );
+}
+
 function EmptyState() {
   return (
     <div className="flex-1 flex flex-col items-center justify-center bg-[var(--bg-secondary)] text-center p-8 relative transition-theme">
@@ -1,11 +1,20 @@
+import { listen } from "@tauri-apps/api/event";
 import { createContext, ReactNode, useCallback, useContext, useEffect, useRef, useState } from "react";
 
-import { callService, messageService, userService, websocketService } from "../services";
+import {
+  callHistoryService,
+  callService,
+  cryptoService,
+  messageService,
+  userService,
+  websocketService,
+} from "../services";
 import { useChatStore } from "../store/chatStore";
 import { useCallStore } from "../store/callStore";
 import { useMessageStore } from "../store/messageStore";
+import { useUIStore } from "../store/uiStore";
 import { useUserStore } from "../store/userStore";
-import type { CallMessage, Message, UrlPreview } from "../types";
+import type { CallMessage, Message, ServerAck, ServerError, UrlPreview } from "../types";
 
 // Get store functions without subscribing to state changes
 const getMessageActions = () => useMessageStore.getState();
@@ -13,6 +22,24 @@ const getChatActions = () => useChatStore.getState();
 
 const SERVER_URL = import.meta.env.VITE_SERVER_URL || "ws://localhost:9001";
 
+// Protocol version and optional features requested in the connect message
+const PROTOCOL_VERSION = 2;
+const CAPABILITIES = ["acks"];
+
+// Wait between reconnect attempts, and the longest wait a shutting-down server may ask for
+const RECONNECT_DELAY_MS = 3000;
+const MAX_RECONNECT_DELAY_MS = 300_000;
+
+// Field naming the sender of frames from blocked users that are ignored locally
+// (chat messages are filtered by receive_message)
+const BLOCKABLE_FRAMES: Record<string, string> = {
+  typing: "user_id",
+  presence: "user_id",
+  profile_update: "user_id",
+  call_invite: "from_user_id",
+  missed_call: "from_user_id",
+};
+
 interface WsMessage {
   type: string;
   [key: string]: unknown;
@@ -38,13 +65,30 @@ export function WebSocketProvider({ children }: { children: ReactNode }) {
   const [typingUsers, setTypingUsers] = useState<Record<string, string[]>>({});
   const [onlineUsers, setOnlineUsers] = useState<Set<string>>(new Set());
   const reconnectTimeoutRef = useRef<number>();
+  const reconnectDelayRef = useRef(RECONNECT_DELAY_MS);
+  const blockedUsersRef = useRef<Set<string>>(new Set());
 
   const currentUser = useUserStore((state) => state.currentUser);
+  // Stable key of our 1:1 chats and their peers, so the subscription is only resent when it changes
+  const subscriptionKey = useChatStore((state) =>
+    JSON.stringify(
+      state.chats
+        .filter((chat) => chat.participant)
+        .map((chat) => ({ chat_id: chat.id, participant_ids: [chat.participant!.id] }))
+        .sort((a, b) => a.chat_id.localeCompare(b.chat_id))
+    )
+  );
 
   const handleMessage = useCallback(
     async (data: WsMessage) => {
       const currentUser = useUserStore.getState().currentUser;
 
+      // The server already drops these between blocked users; this is defense in depth
+      const fromField = BLOCKABLE_FRAMES[data.type];
+      if (fromField && blockedUsersRef.current.has(data[fromField] as string)) {
+        return;
+      }
+
       switch (data.type) {
         case "message":
           // Save incoming message to local database, then add to store
@@ -69,7 +113,8 @@ export function WebSocketProvider({ children }: { children: ReactNode }) {
                 data.content as string,
                 data.timestamp as number,
                 (data.reply_to_id as string) || undefined,
-                urlPreview
+                urlPreview,
+                (data.recipient_id as string) || undefined
               );
 
               // Add message directly to store instead of reloading all
@@ -146,6 +191,31 @@ export function WebSocketProvider({ children }: { children: ReactNode }) {
           }
           break;
 
+        case "delivery_failed":
+          // The server gave up on a queued message (expired, evicted or purged) before it was delivered
+          if (data.message_id) {
+            console.warn("Message not delivered:", data.message_id, data.reason);
+            getMessageActions().updateMessageStatus(data.message_id as string, "failed");
+          }
+          break;
+
+        case "auth_challenge":
+          // Prove possession of our identity key (the private key never leaves the backend)
+          if (currentUser && data.nonce && data.server_key) {
+            try {
+              const proof = await cryptoService.answerAuthChallenge(
+                currentUser.id,
+                data.nonce as string,
+                data.server_key as string
+              );
+              wsRef.current?.send(JSON.stringify({ type: "auth_proof", proof }));
+            } catch (err) {
+              console.error("Failed to answer auth challenge:", err);
+              wsRef.current?.close();
+            }
+          }
+          break;
+
         case "auth_response":
           // Handle authentication response from server
           if (data.success) {
@@ -161,15 +231,46 @@ export function WebSocketProvider({ children }: { children: ReactNode }) {
                     .catch((err: Error) => console.error("Failed to initialize backend WebSocket:", err));
                 });
             }
+          } else if (data.code === "unsupported_version") {
+            console.error(
+              `Pulse server requires protocol version ${data.protocol_version}; this app speaks ${PROTOCOL_VERSION}. Please update.`
+            );
+            setIsConnected(false);
+            wsRef.current?.close();
+          } else if (data.code === "banned") {
+            console.warn("Banned from Pulse server:", data.message);
+            useUIStore.getState().setAnnouncement(data.message as string);
+            setIsConnected(false);
+            wsRef.current?.close();
           } else {
-            console.warn("Server authentication failed:", data.message);
+            console.warn("Server authentication failed:", data.code, data.message);
             setIsConnected(false);
             wsRef.current?.close();
           }
           break;
 
         case "error":
-          console.error("WebSocket error from server:", data.message);
+          console.error("WebSocket error from server:", data.code, data.message, data.ref_id);
+          break;
+
+        case "server_shutdown": {
+          // Come back when the server suggests, with jitter so clients do not reconnect at once
+          const delay = Math.min(
+            Math.max((Number(data.reconnect_after_secs) || 0) * 1000, RECONNECT_DELAY_MS),
+            MAX_RECONNECT_DELAY_MS
+          );
+          console.log(
+            `Pulse server is shutting down, reconnecting in ~${Math.round(delay / 1000)}s`
+          );
+          reconnectDelayRef.current = delay + Math.random() * (delay / 2);
+          break;
+        }
+
+        case "announcement":
+          // Notice from the server operator, shown until dismissed
+          if (data.message) {
+            useUIStore.getState().setAnnouncement(data.message as string);
+          }
           break;
 
         case "profile_update":
@@ -216,7 +317,8 @@ export function WebSocketProvider({ children }: { children: ReactNode }) {
               data.call_id as string,
               data.from_user_id as string,
               callerName,
-              callerAvatar
+              callerAvatar,
+              data.kind === "audio" ? "audio" : "video"
             );
           }
           break;
@@ -256,6 +358,22 @@ export function WebSocketProvider({ children }: { children: ReactNode }) {
             callService.handleIceCandidate(data.candidate as string);
           }
           break;
+
+        case "missed_call":
+          // Sent by the server for a call that could not ring here or was not answered
+          if (data.call_id && data.from_user_id) {
+            try {
+              await callHistoryService.recordMissedCall(
+                data.call_id as string,
+                data.from_user_id as string,
+                data.kind as string,
+                data.timestamp as number
+              );
+            } catch (e) {
+              console.warn("Failed to record missed call:", e);
+            }
+          }
+          break;
       }
     },
     []
@@ -268,13 +386,24 @@ export function WebSocketProvider({ children }: { children: ReactNode }) {
     if (!userId) return;
 
     try {
+      // Shared with the backend connection so the server treats both as one device
+      const deviceId = await websocketService.getDeviceId().catch(() => undefined);
+
       console.log("Connecting to Pulse server at", SERVER_URL);
       const ws = new WebSocket(SERVER_URL);
 
       ws.onopen = () => {
         console.log("WebSocket connected, authenticating...");
         // Send connect message with user ID
-        ws.send(JSON.stringify({ type: "connect", user_id: userId }));
+        ws.send(
+          JSON.stringify({
+            type: "connect",
+            user_id: userId,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+            device_id: deviceId,
+          })
+        );
 
         // Initialize call service with send function
         callService.setSendMessage((msg: CallMessage) => {
@@ -299,9 +428,11 @@ export function WebSocketProvider({ children }: { children: ReactNode }) {
 
         // Only reconnect if this is still the active connection (not replaced by a new one)
         if (wsRef.current === ws) {
+          const delay = reconnectDelayRef.current;
+          reconnectDelayRef.current = RECONNECT_DELAY_MS;
           reconnectTimeoutRef.current = window.setTimeout(() => {
             connect();
-          }, 3000);
+          }, delay);
         }
       };
 
@@ -315,7 +446,7 @@ export function WebSocketProvider({ children }: { children: ReactNode }) {
       // Retry after delay
       reconnectTimeoutRef.current = window.setTimeout(() => {
         connect();
-      }, 3000);
+      }, RECONNECT_DELAY_MS);
     }
   }, [userId, handleMessage]);
 
@@ -335,6 +466,68 @@ export function WebSocketProvider({ children }: { children: ReactNode }) {
     [currentUser]
   );
 
+  // Declare our contacts: the server only relays their presence/profile updates to us,
+  // and only relays our typing indicators to the participants of each chat
+  useEffect(() => {
+    if (!isConnected || wsRef.current?.readyState !== WebSocket.OPEN) return;
+
+    const chats: { chat_id: string; participant_ids: string[] }[] = JSON.parse(subscriptionKey);
+
+    wsRef.current.send(
+      JSON.stringify({
+        type: "subscribe_presence",
+        user_ids: [...new Set(chats.flatMap((chat) => chat.participant_ids))],
+        chats,
+      })
+    );
+  }, [isConnected, subscriptionKey]);
+
+  // Acks for our messages arrive on the backend connection, which has already marked them
+  // sent (or failed, if the server rejected them)
+  useEffect(() => {
+    const unlisten = listen<ServerAck>("server-ack", ({ payload }) => {
+      if (payload.status === "error") {
+        console.warn("Server rejected message", payload.message_id, payload.reason);
+        getMessageActions().setMessageStatus(payload.message_id, "failed");
+        return;
+      }
+      getMessageActions().setMessageStatus(payload.message_id, "sent");
+    });
+
+    return () => {
+      unlisten.then((fn) => fn());
+    };
+  }, []);
+
+  // Frames the server rejected on the backend connection (e.g. rate limited or malformed)
+  useEffect(() => {
+    const unlisten = listen<ServerError>("server-error", ({ payload }) => {
+      console.warn("Server rejected a frame:", payload.code, payload.message, payload.ref_id);
+    });
+
+    return () => {
+      unlisten.then((fn) => fn());
+    };
+  }, []);
+
+  // Mirror the local block list for filtering incoming frames
+  useEffect(() => {
+    userService
+      .getBlockedUsers()
+      .then((ids) => {
+        blockedUsersRef.current = new Set(ids);
+      })
+      .catch((e) => console.error("Failed to load blocked users:", e));
+
+    const unlisten = listen<string[]>("blocked-users-changed", ({ payload }) => {
+      blockedUsersRef.current = new Set(payload);
+    });
+
+    return () => {
+      unlisten.then((fn) => fn());
+    };
+  }, []);
+
   useEffect(() => {
     // Close existing connection when userId changes (e.g., after phone onboarding)
     if (wsRef.current) {
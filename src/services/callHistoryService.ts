@@ -0,0 +1,42 @@
+import { invoke } from "@tauri-apps/api/core";
+import type { CallDirection, CallKind, CallLog, CallOutcome } from "../types";
+
+export const callHistoryService = {
+  /** Log a call the server reported as missed; false if it was already logged */
+  recordMissedCall: (
+    callId: string,
+    fromUserId: string,
+    kind: string,
+    timestamp: number
+  ): Promise<boolean> => {
+    return invoke<boolean>("record_missed_call", { callId, fromUserId, kind, timestamp });
+  },
+
+  /** Log an outgoing call once its invite is sent, or an incoming one once it rings */
+  logCallStart: (
+    callId: string,
+    peerId: string,
+    kind: CallKind,
+    direction: CallDirection
+  ): Promise<boolean> => {
+    return invoke<boolean>("log_call_start", { callId, peerId, kind, direction });
+  },
+
+  logCallAnswer: (callId: string): Promise<boolean> => {
+    return invoke<boolean>("log_call_answer", { callId });
+  },
+
+  /** Only the first end of a call is recorded */
+  logCallEnd: (callId: string, outcome: CallOutcome): Promise<boolean> => {
+    return invoke<boolean>("log_call_end", { callId, outcome });
+  },
+
+  /** Most recent calls first */
+  getCallHistory: (limit?: number): Promise<CallLog[]> => {
+    return invoke<CallLog[]>("get_call_history", { limit });
+  },
+
+  deleteCallLog: (callId: string): Promise<boolean> => {
+    return invoke<boolean>("delete_call_log", { callId });
+  },
+};
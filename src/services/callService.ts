@@ -1,11 +1,15 @@
 import { useCallStore } from "../store/callStore";
 import { useUserStore } from "../store/userStore";
 import { turnService } from "./turnService";
-import type { CallMessage } from "../types";
+import { callHistoryService } from "./callHistoryService";
+import type { CallDirection, CallKind, CallMessage, CallOutcome } from "../types";
 
 // Call timeout in milliseconds (30 seconds)
 const CALL_TIMEOUT_MS = 30000;
 
+// Hangup reasons logged as a failed call rather than an answered or missed one
+const FAILURE_REASONS = ["connection_failed", "offer_error", "rtc_error"];
+
 // LocalStorage keys for device preferences
 const PREFERRED_VIDEO_DEVICE_KEY = "pulse_preferred_video_device";
 const PREFERRED_AUDIO_DEVICE_KEY = "pulse_preferred_audio_device";
@@ -27,6 +31,7 @@ class CallService {
   private callTimeoutId: number | null = null;
   private reconnectTimeoutId: number | null = null;
   private pendingIceCandidates: RTCIceCandidateInit[] = [];
+  private callAnswered = false;
 
   /**
    * Initialize the call service with a WebSocket send function
@@ -282,6 +287,7 @@ class CallService {
       from_user_name: currentUserName,
       from_user_avatar: currentUserAvatar,
     });
+    this.logCallStart(callId, remoteUserId, "video", "outgoing");
 
     // Play dialtone
     this.playDialtone();
@@ -347,6 +353,7 @@ class CallService {
       from_user_id: currentUserId,
       to_user_id: remoteUserId,
     });
+    this.logCallAnswer(callId);
     console.log("[acceptCall] Done!");
   }
 
@@ -401,6 +408,7 @@ class CallService {
       from_user_name: currentUserName,
       from_user_avatar: currentUserAvatar,
     });
+    this.logCallStart(callId, remoteUserId, "video", "outgoing");
 
     // Play dialtone
     this.playDialtone();
@@ -458,6 +466,7 @@ class CallService {
       from_user_id: currentUserId,
       to_user_id: remoteUserId,
     });
+    this.logCallAnswer(callId);
   }
 
   /**
@@ -477,6 +486,9 @@ class CallService {
       });
     }
 
+    this.logCallEnd(
+      reason === "media_error" ? "failed" : reason === "timeout" ? "missed" : "rejected"
+    );
     this.stopRingtone();
     this.playHangup();
     this.cleanup();
@@ -485,7 +497,7 @@ class CallService {
   /**
    * Hang up the current call
    */
-  hangup(_reason: string = "user_hangup"): void {
+  hangup(reason: string = "user_hangup"): void {
     const { callId, remoteUserId, callStatus } = useCallStore.getState();
     const currentUserId = this.getCurrentUserId();
 
@@ -499,6 +511,8 @@ class CallService {
       });
     }
 
+    this.logCallEnd(FAILURE_REASONS.includes(reason) ? "failed" : this.hangupOutcome());
+
     // Stop any playing sounds
     this.stopDialtone();
     this.stopRingtone();
@@ -509,8 +523,15 @@ class CallService {
   /**
    * Handle incoming call invite
    */
-  handleCallInvite(callId: string, fromUserId: string, fromUserName: string, avatarUrl?: string): void {
+  handleCallInvite(
+    callId: string,
+    fromUserId: string,
+    fromUserName: string,
+    avatarUrl?: string,
+    kind: CallKind = "video"
+  ): void {
     useCallStore.getState().receiveIncomingCall(callId, fromUserId, fromUserName, avatarUrl);
+    this.logCallStart(callId, fromUserId, kind, "incoming");
     this.playRingtone();
 
     // Auto-reject after timeout
@@ -533,6 +554,7 @@ class CallService {
       console.error("Invalid state for call accept");
       return;
     }
+    this.logCallAnswer(callId);
 
     // Clear timeout
     if (this.callTimeoutId) {
@@ -581,6 +603,8 @@ class CallService {
       clearTimeout(this.callTimeoutId);
       this.callTimeoutId = null;
     }
+    const declined = reason === "rejected" || reason === "busy";
+    this.logCallEnd(reason === "media_error" ? "failed" : declined ? "rejected" : "missed");
     this.stopDialtone();
     this.playHangup();
     this.cleanup();
@@ -590,6 +614,7 @@ class CallService {
    * Handle remote hangup
    */
   handleRemoteHangup(): void {
+    this.logCallEnd(this.hangupOutcome());
     this.stopRingtone();
     this.stopDialtone();
     this.playHangup();
@@ -704,11 +729,46 @@ class CallService {
 
     // Clear pending candidates
     this.pendingIceCandidates = [];
+    this.callAnswered = false;
 
     // Reset store (this also stops local tracks)
     useCallStore.getState().reset();
   }
 
+  /**
+   * Call history logging; failures are logged and never interrupt the call itself
+   */
+  private logCallStart(
+    callId: string,
+    peerId: string,
+    kind: CallKind,
+    direction: CallDirection
+  ): void {
+    callHistoryService
+      .logCallStart(callId, peerId, kind, direction)
+      .catch((err) => console.warn("Failed to log call start:", err));
+  }
+
+  private logCallAnswer(callId: string): void {
+    this.callAnswered = true;
+    callHistoryService
+      .logCallAnswer(callId)
+      .catch((err) => console.warn("Failed to log call answer:", err));
+  }
+
+  private logCallEnd(outcome: CallOutcome): void {
+    const { callId } = useCallStore.getState();
+    if (callId) {
+      callHistoryService
+        .logCallEnd(callId, outcome)
+        .catch((err) => console.warn("Failed to log call end:", err));
+    }
+  }
+
+  private hangupOutcome(): CallOutcome {
+    return this.callAnswered ? "answered" : "missed";
+  }
+
   /**
    * Get current user ID from the user store
    */
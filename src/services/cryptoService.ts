@@ -39,6 +39,14 @@ export const cryptoService = {
     return invoke<boolean>("ensure_chat_session", { peerUserId, chatId });
   },
 
+  /**
+   * Answer the server's identity challenge for this connection
+   * Returns the hex-encoded proof to send back in an `auth_proof` frame
+   */
+  answerAuthChallenge: (userId: string, nonce: string, serverKey: string): Promise<string> => {
+    return invoke<string>("answer_auth_challenge", { userId, nonce, serverKey });
+  },
+
   // Legacy methods (for backward compatibility)
 
   /**
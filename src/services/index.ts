@@ -1,4 +1,5 @@
 export { callService } from "./callService";
+export { callHistoryService } from "./callHistoryService";
 export { chatService } from "./chatService";
 export { cryptoService } from "./cryptoService";
 export { messageService } from "./messageService";
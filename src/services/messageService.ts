@@ -45,7 +45,8 @@ export const messageService = {
     content: string,
     timestamp: number,
     replyToId?: string,
-    urlPreview?: UrlPreview
+    urlPreview?: UrlPreview,
+    recipientId?: string
   ): Promise<Message> => {
     return invoke<Message>("receive_message", {
       id,
@@ -56,6 +57,7 @@ export const messageService = {
       timestamp,
       replyToId,
       urlPreview,
+      recipientId,
     });
   },
 };
@@ -43,4 +43,18 @@ export const userService = {
   saveContact: (userId: string, displayName: string): Promise<User> => {
     return invoke<User>("save_contact", { userId, displayName });
   },
+
+  /** Block a user (also enforced by the server once it has been sent) */
+  blockUser: (userId: string): Promise<boolean> => {
+    return invoke<boolean>("block_user", { userId });
+  },
+
+  /** Unblock a user (fails while disconnected from the server) */
+  unblockUser: (userId: string): Promise<boolean> => {
+    return invoke<boolean>("unblock_user", { userId });
+  },
+
+  getBlockedUsers: (): Promise<string[]> => {
+    return invoke<string[]>("get_blocked_users");
+  },
 };
@@ -18,6 +18,10 @@ export const websocketService = {
     return invoke<void>("disconnect_websocket");
   },
 
+  /**
+   * Send a chat message. Resolves true if it already counts as sent, false while it
+   * waits for the server's ack; rejects (and the backend marks it failed) if it cannot be sent
+   */
   broadcastMessage: (
     messageId: string,
     chatId: string,
@@ -40,6 +44,13 @@ export const websocketService = {
     return invoke<string>("get_server_url");
   },
 
+  /**
+   * Get this installation's device id, shared by the frontend and backend connections
+   */
+  getDeviceId: (): Promise<string> => {
+    return invoke<string>("get_device_id");
+  },
+
   /**
    * Get the WebSocket authentication token for this session
    */
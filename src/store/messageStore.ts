@@ -16,6 +16,7 @@ interface MessageStore {
   markAsRead: (chatId: string) => Promise<void>;
   searchMessages: (query: string) => Promise<Message[]>;
   updateMessageStatus: (messageId: string, status: Message["status"]) => Promise<void>;
+  setMessageStatus: (messageId: string, status: Message["status"]) => void;
   setReplyingTo: (message: Message | null) => void;
 }
 
@@ -63,9 +64,10 @@ export const useMessageStore = create<MessageStore>((set) => ({
       // Update chat's last message in chat store
       useChatStore.getState().updateChatLastMessage(chatId, message);
 
-      // Broadcast via WebSocket for real-time sync (include URL preview if present)
+      // Broadcast via WebSocket for real-time sync (include URL preview if present).
+      // The message stays pending until the server acks it, unless the server does not send acks.
       try {
-        await websocketService.broadcastMessage(
+        const sent = await websocketService.broadcastMessage(
           message.id,
           chatId,
           content.trim(),
@@ -73,8 +75,12 @@ export const useMessageStore = create<MessageStore>((set) => ({
           replyToId,
           message.url_preview
         );
+        if (sent) {
+          useMessageStore.getState().setMessageStatus(message.id, "sent");
+        }
       } catch (wsError) {
         console.debug("WebSocket broadcast failed:", wsError);
+        useMessageStore.getState().setMessageStatus(message.id, "failed");
       }
     } catch (error) {
       console.error("Failed to send message:", error);
@@ -117,55 +123,58 @@ export const useMessageStore = create<MessageStore>((set) => ({
     try {
       // Update in database first
       await messageService.updateMessageStatus(messageId, status);
+      useMessageStore.getState().setMessageStatus(messageId, status);
+    } catch (error) {
+      console.error("Failed to update message status:", error);
+    }
+  },
 
-      // Update in local state (if message is loaded)
-      // Use retries to handle race condition where delivery receipt
-      // arrives before the message is added to the store
-      const tryUpdate = () => {
-        const state = useMessageStore.getState();
-        const chatIds = Object.keys(state.messages);
-
-        for (const chatId of chatIds) {
-          const messages = state.messages[chatId];
-          const msgIndex = messages.findIndex((msg) => msg.id === messageId);
-
-          if (msgIndex !== -1) {
-            set((s) => ({
-              messages: {
-                ...s.messages,
-                [chatId]: s.messages[chatId].map((msg) =>
-                  msg.id === messageId ? { ...msg, status } : msg
-                ),
-              },
+  setMessageStatus: (messageId: string, status: Message["status"]) => {
+    // Update in local state only (if message is loaded)
+    // Use retries to handle race condition where delivery receipt
+    // arrives before the message is added to the store
+    const tryUpdate = () => {
+      const state = useMessageStore.getState();
+      const chatIds = Object.keys(state.messages);
+
+      for (const chatId of chatIds) {
+        const messages = state.messages[chatId];
+        const msgIndex = messages.findIndex((msg) => msg.id === messageId);
+
+        if (msgIndex !== -1) {
+          set((s) => ({
+            messages: {
+              ...s.messages,
+              [chatId]: s.messages[chatId].map((msg) =>
+                msg.id === messageId ? { ...msg, status } : msg
+              ),
+            },
+          }));
+
+          // Also update chat's last_message if it matches
+          const chatState = useChatStore.getState();
+          const chat = chatState.chats.find((c) => c.id === chatId);
+          if (chat?.last_message?.id === messageId) {
+            useChatStore.setState((cs) => ({
+              chats: cs.chats.map((c) =>
+                c.id === chatId && c.last_message
+                  ? { ...c, last_message: { ...c.last_message, status } }
+                  : c
+              ),
             }));
-
-            // Also update chat's last_message if it matches
-            const chatState = useChatStore.getState();
-            const chat = chatState.chats.find((c) => c.id === chatId);
-            if (chat?.last_message?.id === messageId) {
-              useChatStore.setState((cs) => ({
-                chats: cs.chats.map((c) =>
-                  c.id === chatId && c.last_message
-                    ? { ...c, last_message: { ...c.last_message, status } }
-                    : c
-                ),
-              }));
-            }
-
-            return true;
           }
+
+          return true;
         }
-        return false;
-      };
-
-      // Try immediately, then retry after delays if not found
-      if (!tryUpdate()) {
-        setTimeout(tryUpdate, 100);
-        setTimeout(tryUpdate, 300);
-        setTimeout(tryUpdate, 600);
       }
-    } catch (error) {
-      console.error("Failed to update message status:", error);
+      return false;
+    };
+
+    // Try immediately, then retry after delays if not found
+    if (!tryUpdate()) {
+      setTimeout(tryUpdate, 100);
+      setTimeout(tryUpdate, 300);
+      setTimeout(tryUpdate, 600);
     }
   },
 
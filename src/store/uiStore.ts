@@ -9,6 +9,8 @@ interface UIStore {
   showProfile: boolean;
   showContactInfo: boolean;
   searchQuery: string;
+  /** Latest notice from the server operator, until dismissed */
+  announcement: string | null;
 
   toggleTheme: () => void;
   setTheme: (theme: Theme) => void;
@@ -17,6 +19,7 @@ interface UIStore {
   setShowProfile: (show: boolean) => void;
   setShowContactInfo: (show: boolean) => void;
   setSearchQuery: (query: string) => void;
+  setAnnouncement: (announcement: string | null) => void;
 }
 
 export const useUIStore = create<UIStore>()(
@@ -28,6 +31,7 @@ export const useUIStore = create<UIStore>()(
       showProfile: false,
       showContactInfo: false,
       searchQuery: "",
+      announcement: null,
 
       toggleTheme: () =>
         set((state) => ({ theme: state.theme === "dark" ? "light" : "dark" })),
@@ -43,6 +47,8 @@ export const useUIStore = create<UIStore>()(
       setShowContactInfo: (show) => set({ showContactInfo: show }),
 
       setSearchQuery: (query) => set({ searchQuery: query }),
+
+      setAnnouncement: (announcement) => set({ announcement }),
     }),
     {
       name: "pulse-ui-storage",
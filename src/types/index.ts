@@ -50,12 +50,30 @@ export interface Message {
   media_url?: string;
   reply_to_id?: string;
   url_preview?: UrlPreview;
-  status: "sent" | "delivered" | "read";
+  status: MessageStatus;
   created_at: number;
   edited_at?: number;
 }
 
-export type MessageStatus = "sent" | "delivered" | "read";
+/**
+ * "pending" until the relay acknowledges the message with a server_ack;
+ * "failed" if it could not be sent, the relay rejected it, or gave up on it (delivery_failed)
+ */
+export type MessageStatus = "pending" | "sent" | "delivered" | "read" | "failed";
+
+/** Relay acknowledgement of a message we sent (emitted by the backend as "server-ack") */
+export interface ServerAck {
+  message_id: string;
+  status: "delivered" | "queued" | "error";
+  reason?: string;
+}
+
+/** An `error` frame: the server rejected one of our frames */
+export interface ServerError {
+  message: string;
+  code?: string;
+  ref_id?: string;
+}
 
 export type Theme = "dark" | "light";
 
@@ -72,6 +90,23 @@ export type CallStatus =
   | "connecting"
   | "connected";
 
+export type CallKind = "video" | "audio";
+export type CallDirection = "incoming" | "outgoing";
+export type CallOutcome = "answered" | "rejected" | "missed" | "failed";
+
+/** A call history entry; outcome is null while the call is in progress */
+export interface CallLog {
+  call_id: string;
+  peer_id: string;
+  peer_name: string | null;
+  kind: CallKind;
+  direction: CallDirection;
+  started_at: number;
+  answered_at: number | null;
+  ended_at: number | null;
+  outcome: CallOutcome | null;
+}
+
 export interface CallInviteMessage {
   type: "call_invite";
   call_id: string;